@@ -0,0 +1,142 @@
+use crate::protocol;
+use crate::storage;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+const ALIASES_FILE: &str = "aliases";
+
+/// What an alias names: every instance on a host, or the one listening on
+/// that port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AliasTarget {
+    Ip(IpAddr),
+    Addr(SocketAddr),
+}
+
+impl fmt::Display for AliasTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AliasTarget::Ip(ip) => write!(f, "{}", ip),
+            AliasTarget::Addr(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+impl FromStr for AliasTarget {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        s.parse()
+            .map(AliasTarget::Ip)
+            .or_else(|_| s.parse().map(AliasTarget::Addr))
+            .map_err(|_| ())
+    }
+}
+
+/// Names the user gave peers, shown instead of the nicknames they
+/// announce. Persisted to `~/.sandesh/aliases` as one `<ip[:port]> <name>`
+/// line per alias. Global because the chat window and the request prompt
+/// show them as well as the peer list.
+static ALIASES: Mutex<BTreeMap<AliasTarget, String>> = Mutex::new(BTreeMap::new());
+
+/// Loads the aliases at startup. A missing or unreadable file leaves none;
+/// malformed lines are skipped.
+pub fn load() {
+    let contents = storage::data_file(ALIASES_FILE).and_then(|p| fs::read_to_string(p).ok());
+    let aliases = contents
+        .iter()
+        .flat_map(|c| c.lines())
+        .filter_map(|line| {
+            let (target, name) = line.split_once(' ')?;
+            let target = target.parse().ok()?;
+            protocol::valid_nickname(name).then(|| (target, name.to_string()))
+        })
+        .collect();
+    *ALIASES.lock().unwrap() = aliases;
+}
+
+/// Names `target`, replacing any alias it had.
+pub fn set(target: AliasTarget, name: &str) -> io::Result<()> {
+    let mut aliases = ALIASES.lock().unwrap();
+    aliases.insert(target, name.to_string());
+    save(&aliases)
+}
+
+/// Drops every alias called `name`, ignoring case, and returns what they
+/// named.
+pub fn remove(name: &str) -> io::Result<Vec<AliasTarget>> {
+    let mut aliases = ALIASES.lock().unwrap();
+    let removed: Vec<AliasTarget> = aliases
+        .iter()
+        .filter(|(_, n)| same_name(n, name))
+        .map(|(target, _)| *target)
+        .collect();
+    if !removed.is_empty() {
+        aliases.retain(|target, _| !removed.contains(target));
+        save(&aliases)?;
+    }
+    Ok(removed)
+}
+
+/// Every alias, sorted by what it names.
+pub fn list() -> Vec<(AliasTarget, String)> {
+    ALIASES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(target, name)| (*target, name.clone()))
+        .collect()
+}
+
+/// What the alias `name` names, ignoring case.
+pub fn target_named(name: &str) -> Option<AliasTarget> {
+    ALIASES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, n)| same_name(n, name))
+        .map(|(target, _)| *target)
+}
+
+/// The alias for the peer at `addr`: the one for that exact address, else
+/// the one for its IP, else the one every aliased port on that IP shares.
+/// Chat requests come from another port than the peer listens on, which
+/// the last case covers.
+pub fn name_for(addr: &SocketAddr) -> Option<String> {
+    let aliases = ALIASES.lock().unwrap();
+    if let Some(name) = aliases
+        .get(&AliasTarget::Addr(*addr))
+        .or_else(|| aliases.get(&AliasTarget::Ip(addr.ip())))
+    {
+        return Some(name.clone());
+    }
+    let mut names = aliases
+        .iter()
+        .filter(|(target, _)| matches!(target, AliasTarget::Addr(a) if a.ip() == addr.ip()))
+        .map(|(_, name)| name);
+    let first = names.next()?;
+    names.all(|name| name == first).then(|| first.clone())
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+fn save(aliases: &BTreeMap<AliasTarget, String>) -> io::Result<()> {
+    let path = storage::data_file(ALIASES_FILE).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "no home directory to store aliases in",
+        )
+    })?;
+    let contents: String = aliases
+        .iter()
+        .map(|(target, name)| format!("{} {}\n", target, name))
+        .collect();
+    storage::write_atomic(&path, &contents)
+}
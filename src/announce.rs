@@ -0,0 +1,243 @@
+use crate::interfaces::{self, NetInterface};
+use crate::network::DiscoveryMode;
+use crate::protocol;
+use crate::storage;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+
+const ANNOUNCE_FILE: &str = "announce";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnnounceScope {
+    /// Broadcast to the whole LAN.
+    All,
+    /// Unicast only to the addresses in `AnnounceSettings::targets`.
+    List,
+}
+
+impl fmt::Display for AnnounceScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnnounceScope::All => write!(f, "all"),
+            AnnounceScope::List => write!(f, "list"),
+        }
+    }
+}
+
+/// Who gets our discovery beacons. Incoming beacons are processed the
+/// same way whatever the scope. Persisted to `~/.sandesh/announce` as a
+/// `scope <all|list>` line, optional `interface <name>` and
+/// `nickname <name>` lines, and one `to <ip>` line per target.
+pub struct AnnounceSettings {
+    pub scope: AnnounceScope,
+    pub targets: Vec<IpAddr>,
+    /// Broadcast only on this interface instead of every physical one.
+    pub interface: Option<String>,
+    /// Set by `--interface` for this run only; never saved.
+    pub interface_override: Option<String>,
+    /// Sent in beacons and at the start of each chat so peers see a name
+    /// next to our address.
+    pub nickname: Option<String>,
+    /// Set by `--nick` for this run only; never saved.
+    pub nickname_override: Option<String>,
+}
+
+pub type SharedAnnounce = Arc<Mutex<AnnounceSettings>>;
+
+impl AnnounceSettings {
+    pub fn load() -> SharedAnnounce {
+        let contents = storage::data_file(ANNOUNCE_FILE).and_then(|p| fs::read_to_string(p).ok());
+        Arc::new(Mutex::new(AnnounceSettings::parse(
+            contents.as_deref().unwrap_or_default(),
+        )))
+    }
+
+    fn parse(contents: &str) -> Self {
+        let mut settings = AnnounceSettings {
+            scope: AnnounceScope::All,
+            targets: Vec::new(),
+            interface: None,
+            interface_override: None,
+            nickname: None,
+            nickname_override: None,
+        };
+        for line in contents.lines() {
+            match line.split_once(' ') {
+                Some(("scope", "list")) => settings.scope = AnnounceScope::List,
+                Some(("scope", "all")) => settings.scope = AnnounceScope::All,
+                Some(("interface", name)) => settings.interface = Some(name.to_string()),
+                Some(("nickname", name)) if protocol::valid_nickname(name) => {
+                    settings.nickname = Some(name.to_string())
+                }
+                Some(("to", ip)) => {
+                    if let Ok(ip) = ip.parse()
+                        && !settings.targets.contains(&ip)
+                    {
+                        settings.targets.push(ip);
+                    }
+                }
+                _ => {}
+            }
+        }
+        settings
+    }
+
+    /// The interface to announce on, if one was picked.
+    pub fn interface(&self) -> Option<&str> {
+        self.interface_override
+            .as_deref()
+            .or(self.interface.as_deref())
+    }
+
+    /// The name to announce, if one was picked.
+    pub fn nickname(&self) -> Option<&str> {
+        self.nickname_override
+            .as_deref()
+            .or(self.nickname.as_deref())
+    }
+
+    fn render(&self) -> String {
+        let mut contents = format!("scope {}\n", self.scope);
+        if let Some(name) = &self.interface {
+            contents.push_str(&format!("interface {}\n", name));
+        }
+        if let Some(name) = &self.nickname {
+            contents.push_str(&format!("nickname {}\n", name));
+        }
+        for ip in &self.targets {
+            contents.push_str(&format!("to {}\n", ip));
+        }
+        contents
+    }
+
+    /// Where one round of beacons goes. In list scope that is only the
+    /// listed targets. Otherwise it is the group in multicast `mode`, or
+    /// the broadcast address of each interface `plan_broadcast` picks
+    /// from `interfaces` (the limited broadcast address if none), plus
+    /// the IPv6 group with `ipv6`.
+    pub fn destinations(
+        &self,
+        mode: DiscoveryMode,
+        ipv6: bool,
+        interfaces: &[NetInterface],
+    ) -> Vec<IpAddr> {
+        if self.scope == AnnounceScope::List {
+            return self.targets.clone();
+        }
+        let mut hosts = if mode == DiscoveryMode::Multicast {
+            vec![IpAddr::V4(protocol::MULTICAST_GROUP)]
+        } else {
+            let plan = interfaces::plan_broadcast(interfaces, self.interface());
+            if plan.targets.is_empty() {
+                vec![IpAddr::V4(Ipv4Addr::BROADCAST)]
+            } else {
+                plan.targets.into_iter().map(IpAddr::V4).collect()
+            }
+        };
+        if ipv6 {
+            hosts.push(IpAddr::V6(protocol::MULTICAST_GROUP_V6));
+        }
+        hosts
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = storage::data_file(ANNOUNCE_FILE).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "no home directory to store settings in",
+            )
+        })?;
+        storage::write_atomic(&path, &self.render())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interfaces::InterfaceKind;
+    use std::net::Ipv6Addr;
+
+    fn lan() -> Vec<NetInterface> {
+        vec![NetInterface {
+            name: "eth0".to_string(),
+            ip: "192.168.1.20".parse().unwrap(),
+            broadcast: Some(Ipv4Addr::new(192, 168, 1, 255)),
+            kind: InterfaceKind::Physical,
+            up: true,
+            index: Some(2),
+        }]
+    }
+
+    fn is_group_or_broadcast(ip: &IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => ip.is_multicast() || ip.is_broadcast() || ip.octets()[3] == 255,
+            IpAddr::V6(ip) => ip.is_multicast(),
+        }
+    }
+
+    #[test]
+    fn scope_changes_move_beacons_between_the_lan_and_the_list() {
+        let mut settings = AnnounceSettings::parse("");
+        assert_eq!(settings.scope, AnnounceScope::All, "all until changed");
+        let broadcast = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 255));
+        assert_eq!(
+            settings.destinations(DiscoveryMode::Broadcast, false, &lan()),
+            [broadcast]
+        );
+
+        // `announce scope list` with nobody listed yet sends nothing.
+        settings.scope = AnnounceScope::List;
+        assert!(
+            settings
+                .destinations(DiscoveryMode::Broadcast, true, &lan())
+                .is_empty()
+        );
+
+        // `announce to`, and the choice survives a restart.
+        let friend: IpAddr = "192.168.1.7".parse().unwrap();
+        settings.targets.push(friend);
+        let mut settings = AnnounceSettings::parse(&settings.render());
+        assert_eq!(settings.scope, AnnounceScope::List);
+        assert_eq!(
+            settings.destinations(DiscoveryMode::Broadcast, true, &lan()),
+            [friend]
+        );
+
+        // Back to all: the list is kept but not used.
+        settings.scope = AnnounceScope::All;
+        let settings = AnnounceSettings::parse(&settings.render());
+        assert_eq!(settings.targets, [friend]);
+        assert_eq!(
+            settings.destinations(DiscoveryMode::Multicast, true, &lan()),
+            [
+                IpAddr::V4(protocol::MULTICAST_GROUP),
+                IpAddr::V6(protocol::MULTICAST_GROUP_V6)
+            ]
+        );
+        assert_eq!(
+            settings.destinations(DiscoveryMode::Broadcast, false, &[]),
+            [IpAddr::V4(Ipv4Addr::BROADCAST)],
+            "no interface to pick falls back to the limited broadcast"
+        );
+    }
+
+    #[test]
+    fn list_scope_never_broadcasts() {
+        let mut settings = AnnounceSettings::parse("scope list\n");
+        settings.targets = vec![
+            "10.0.0.4".parse().unwrap(),
+            IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 9)),
+        ];
+        settings.interface = Some("eth0".to_string());
+        for mode in [DiscoveryMode::Broadcast, DiscoveryMode::Multicast] {
+            for interfaces in [lan(), Vec::new()] {
+                let hosts = settings.destinations(mode, true, &interfaces);
+                assert_eq!(hosts, settings.targets);
+                assert!(!hosts.iter().any(is_group_or_broadcast), "{:?}", hosts);
+            }
+        }
+    }
+}
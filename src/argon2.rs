@@ -0,0 +1,379 @@
+//! Argon2id (RFC 9106, version 0x13), for turning a passphrase into the key
+//! that seals files at rest. Single-threaded: lanes are filled one after
+//! another, which gives the same output as filling them in parallel.
+
+use blake2::digest::{Digest, Update, VariableOutput};
+use blake2::{Blake2b512, Blake2bVar};
+use zeroize::Zeroize;
+
+const VERSION: u32 = 0x13;
+const ARGON2ID: u32 = 2;
+const BLOCK_WORDS: usize = 128;
+const SYNC_POINTS: u32 = 4;
+
+/// Cost settings, stored next to the salt so they can be raised later
+/// without breaking existing files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Params {
+    /// Memory in KiB.
+    pub memory: u32,
+    pub passes: u32,
+    pub lanes: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Block([u64; BLOCK_WORDS]);
+
+impl Block {
+    const ZERO: Block = Block([0; BLOCK_WORDS]);
+
+    fn from_bytes(bytes: &[u8]) -> Block {
+        let mut block = Block::ZERO;
+        for (word, chunk) in block.0.iter_mut().zip(bytes.chunks_exact(8)) {
+            *word = u64::from_le_bytes(chunk.try_into().expect("8-byte chunk"));
+        }
+        block
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        self.0.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    fn xor(&mut self, other: &Block) {
+        for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+            *a ^= b;
+        }
+    }
+}
+
+/// Fills `out` with the Argon2id tag of `password` and `salt`.
+pub fn hash(password: &[u8], salt: &[u8], params: Params, out: &mut [u8]) {
+    hash_with(password, salt, &[], &[], params, out);
+}
+
+fn hash_with(
+    password: &[u8],
+    salt: &[u8],
+    secret: &[u8],
+    data: &[u8],
+    params: Params,
+    out: &mut [u8],
+) {
+    let lanes = params.lanes.max(1);
+    let memory = params.memory.max(2 * SYNC_POINTS * lanes);
+    let segment_len = memory / (SYNC_POINTS * lanes);
+    let lane_len = segment_len * SYNC_POINTS;
+    let total = (lane_len * lanes) as usize;
+
+    let mut h0 = Blake2b512::new();
+    for value in [
+        lanes,
+        out.len() as u32,
+        params.memory,
+        params.passes,
+        VERSION,
+        ARGON2ID,
+    ] {
+        Digest::update(&mut h0, value.to_le_bytes());
+    }
+    for input in [password, salt, secret, data] {
+        Digest::update(&mut h0, (input.len() as u32).to_le_bytes());
+        Digest::update(&mut h0, input);
+    }
+    let mut h0 = h0.finalize().to_vec();
+
+    let mut memory_blocks = vec![Block::ZERO; total];
+    let mut seed = [0u8; 1024];
+    for lane in 0..lanes {
+        for first in 0..2u32 {
+            let mut input = h0.clone();
+            input.extend_from_slice(&first.to_le_bytes());
+            input.extend_from_slice(&lane.to_le_bytes());
+            variable_hash(&input, &mut seed);
+            input.zeroize();
+            memory_blocks[(lane * lane_len + first) as usize] = Block::from_bytes(&seed);
+        }
+    }
+    seed.zeroize();
+    h0.zeroize();
+
+    let layout = Layout {
+        lanes,
+        segment_len,
+        lane_len,
+        total: total as u32,
+        passes: params.passes,
+    };
+    for pass in 0..params.passes {
+        for slice in 0..SYNC_POINTS {
+            for lane in 0..lanes {
+                fill_segment(&mut memory_blocks, &layout, pass, slice, lane);
+            }
+        }
+    }
+
+    let mut last = memory_blocks[(lane_len - 1) as usize];
+    for lane in 1..lanes {
+        last.xor(&memory_blocks[(lane * lane_len + lane_len - 1) as usize]);
+    }
+    let mut last_bytes = last.to_bytes();
+    variable_hash(&last_bytes, out);
+    last_bytes.zeroize();
+    for block in &mut memory_blocks {
+        block.0.zeroize();
+    }
+}
+
+struct Layout {
+    lanes: u32,
+    segment_len: u32,
+    lane_len: u32,
+    total: u32,
+    passes: u32,
+}
+
+fn fill_segment(memory: &mut [Block], layout: &Layout, pass: u32, slice: u32, lane: u32) {
+    // Argon2id picks references independently of the data for the first
+    // half of the first pass, and from the data after that.
+    let independent = pass == 0 && slice < SYNC_POINTS / 2;
+    let mut address = Block::ZERO;
+    let mut address_input = Block::ZERO;
+    if independent {
+        address_input.0[..6].copy_from_slice(&[
+            pass as u64,
+            lane as u64,
+            slice as u64,
+            layout.total as u64,
+            layout.passes as u64,
+            ARGON2ID as u64,
+        ]);
+    }
+
+    let start = if pass == 0 && slice == 0 {
+        if independent {
+            next_addresses(&mut address, &mut address_input);
+        }
+        2
+    } else {
+        0
+    };
+
+    for index in start..layout.segment_len {
+        let column = slice * layout.segment_len + index;
+        let current = (lane * layout.lane_len + column) as usize;
+        let previous = if column == 0 {
+            current + layout.lane_len as usize - 1
+        } else {
+            current - 1
+        };
+
+        let pseudo_random = if independent {
+            if (index as usize).is_multiple_of(BLOCK_WORDS) {
+                next_addresses(&mut address, &mut address_input);
+            }
+            address.0[index as usize % BLOCK_WORDS]
+        } else {
+            memory[previous].0[0]
+        };
+
+        let ref_lane = if pass == 0 && slice == 0 {
+            lane
+        } else {
+            ((pseudo_random >> 32) % layout.lanes as u64) as u32
+        };
+        let ref_column = reference_column(
+            layout,
+            pass,
+            slice,
+            index,
+            pseudo_random as u32,
+            ref_lane == lane,
+        );
+        let reference = (ref_lane * layout.lane_len + ref_column) as usize;
+
+        let mut next = compress(&memory[previous], &memory[reference]);
+        if pass > 0 {
+            next.xor(&memory[current]);
+        }
+        memory[current] = next;
+    }
+}
+
+/// Where in the reference lane the block at `index` of this segment draws
+/// from, following the RFC's mapping of `j1` onto the blocks allowed.
+fn reference_column(
+    layout: &Layout,
+    pass: u32,
+    slice: u32,
+    index: u32,
+    j1: u32,
+    same_lane: bool,
+) -> u32 {
+    let finished = if pass == 0 {
+        slice * layout.segment_len
+    } else {
+        layout.lane_len - layout.segment_len
+    };
+    let area = if same_lane {
+        finished + index - 1
+    } else if index == 0 {
+        finished - 1
+    } else {
+        finished
+    } as u64;
+
+    let x = (j1 as u64 * j1 as u64) >> 32;
+    let relative = area - 1 - ((area * x) >> 32);
+    let start = if pass == 0 || slice == SYNC_POINTS - 1 {
+        0
+    } else {
+        (slice + 1) * layout.segment_len
+    };
+    ((start as u64 + relative) % layout.lane_len as u64) as u32
+}
+
+fn next_addresses(address: &mut Block, input: &mut Block) {
+    input.0[6] += 1;
+    let first = compress(&Block::ZERO, input);
+    *address = compress(&Block::ZERO, &first);
+}
+
+/// The compression function G: the permutation applied to the rows and
+/// then the columns of `x ^ y`, XORed back onto it.
+fn compress(x: &Block, y: &Block) -> Block {
+    let mut r = *x;
+    r.xor(y);
+    let mut q = r;
+    for row in 0..8 {
+        let mut words = [0usize; 16];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = row * 16 + i;
+        }
+        permute(&mut q.0, &words);
+    }
+    for column in 0..8 {
+        let mut words = [0usize; 16];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = (i / 2) * 16 + column * 2 + i % 2;
+        }
+        permute(&mut q.0, &words);
+    }
+    q.xor(&r);
+    q
+}
+
+fn permute(v: &mut [u64; BLOCK_WORDS], w: &[usize; 16]) {
+    mix(v, w[0], w[4], w[8], w[12]);
+    mix(v, w[1], w[5], w[9], w[13]);
+    mix(v, w[2], w[6], w[10], w[14]);
+    mix(v, w[3], w[7], w[11], w[15]);
+    mix(v, w[0], w[5], w[10], w[15]);
+    mix(v, w[1], w[6], w[11], w[12]);
+    mix(v, w[2], w[7], w[8], w[13]);
+    mix(v, w[3], w[4], w[9], w[14]);
+}
+
+fn mix(v: &mut [u64; BLOCK_WORDS], a: usize, b: usize, c: usize, d: usize) {
+    let blamka = |x: u64, y: u64| {
+        x.wrapping_add(y)
+            .wrapping_add(2u64.wrapping_mul((x as u32 as u64) * (y as u32 as u64)))
+    };
+    v[a] = blamka(v[a], v[b]);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = blamka(v[c], v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = blamka(v[a], v[b]);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = blamka(v[c], v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+/// H': BLAKE2b stretched to any output length.
+fn variable_hash(input: &[u8], out: &mut [u8]) {
+    let len = (out.len() as u32).to_le_bytes();
+    if out.len() <= 64 {
+        let mut hasher = Blake2bVar::new(out.len()).expect("BLAKE2b output of 1-64 bytes");
+        hasher.update(&len);
+        hasher.update(input);
+        hasher
+            .finalize_variable(out)
+            .expect("buffer matches the output size");
+        return;
+    }
+
+    let mut v = Blake2b512::new()
+        .chain_update(len)
+        .chain_update(input)
+        .finalize();
+    let mut written = 0;
+    loop {
+        out[written..written + 32].copy_from_slice(&v[..32]);
+        written += 32;
+        if out.len() - written <= 64 {
+            break;
+        }
+        v = Blake2b512::digest(v);
+    }
+    let mut last = Blake2bVar::new(out.len() - written).expect("BLAKE2b output of 1-64 bytes");
+    last.update(&v);
+    last.finalize_variable(&mut out[written..])
+        .expect("buffer matches the output size");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::encode_hex;
+
+    /// RFC 9106 section 5.3, the Argon2id test vector.
+    #[test]
+    fn matches_the_rfc_test_vector() {
+        let params = Params {
+            memory: 32,
+            passes: 3,
+            lanes: 4,
+        };
+        let mut tag = [0u8; 32];
+        hash_with(
+            &[0x01; 32],
+            &[0x02; 16],
+            &[0x03; 8],
+            &[0x04; 12],
+            params,
+            &mut tag,
+        );
+        assert_eq!(
+            encode_hex(&tag),
+            "0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659"
+        );
+    }
+
+    #[test]
+    fn every_input_changes_the_tag() {
+        let params = Params {
+            memory: 64,
+            passes: 1,
+            lanes: 1,
+        };
+        let tag = |password: &[u8], salt: &[u8], params| {
+            let mut out = [0u8; 32];
+            hash(password, salt, params, &mut out);
+            out
+        };
+        let base = tag(b"passphrase", b"saltsaltsaltsalt", params);
+        assert_eq!(base, tag(b"passphrase", b"saltsaltsaltsalt", params));
+        assert_ne!(base, tag(b"passphrasf", b"saltsaltsaltsalt", params));
+        assert_ne!(base, tag(b"passphrase", b"saltsaltsaltsalu", params));
+        assert_ne!(
+            base,
+            tag(
+                b"passphrase",
+                b"saltsaltsaltsalt",
+                Params {
+                    passes: 2,
+                    ..params
+                }
+            )
+        );
+    }
+}
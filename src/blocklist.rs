@@ -0,0 +1,95 @@
+use crate::storage;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+const BLOCKLIST_FILE: &str = "blocklist";
+
+/// Peer IPs whose chat requests are turned away without asking and whose
+/// beacons are not listed, persisted to `~/.sandesh/blocklist` as one IP
+/// per line. Global because the network threads check it on every
+/// connection and beacon.
+static BLOCKED: Mutex<BTreeSet<IpAddr>> = Mutex::new(BTreeSet::new());
+
+/// Loads the blocklist at startup. A missing or unreadable file leaves it
+/// empty; malformed lines are skipped.
+pub fn load() {
+    let contents = storage::data_file(BLOCKLIST_FILE).and_then(|p| fs::read_to_string(p).ok());
+    *BLOCKED.lock().unwrap() = contents.as_deref().map(parse).unwrap_or_default();
+}
+
+fn parse(contents: &str) -> BTreeSet<IpAddr> {
+    contents
+        .lines()
+        .filter_map(|line| line.trim().parse().ok())
+        .collect()
+}
+
+/// Blocks `ip` without saving, so tests never touch `~/.sandesh`.
+#[cfg(test)]
+pub(crate) fn block_for_tests(ip: IpAddr) {
+    BLOCKED.lock().unwrap().insert(ip);
+}
+
+pub fn is_blocked(ip: IpAddr) -> bool {
+    BLOCKED.lock().unwrap().contains(&ip)
+}
+
+/// Returns false if the IP was already blocked.
+pub fn block(ip: IpAddr) -> io::Result<bool> {
+    let mut blocked = BLOCKED.lock().unwrap();
+    let added = blocked.insert(ip);
+    if added {
+        save(&blocked)?;
+    }
+    Ok(added)
+}
+
+/// Returns false if the IP was not blocked.
+pub fn unblock(ip: IpAddr) -> io::Result<bool> {
+    let mut blocked = BLOCKED.lock().unwrap();
+    let removed = blocked.remove(&ip);
+    if removed {
+        save(&blocked)?;
+    }
+    Ok(removed)
+}
+
+/// Every blocked IP, sorted.
+pub fn blocked() -> Vec<IpAddr> {
+    BLOCKED.lock().unwrap().iter().copied().collect()
+}
+
+fn save(blocked: &BTreeSet<IpAddr>) -> io::Result<()> {
+    let path = storage::data_file(BLOCKLIST_FILE).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "no home directory to store the blocklist in",
+        )
+    })?;
+    let contents: String = blocked.iter().map(|ip| format!("{}\n", ip)).collect();
+    storage::write_atomic(&path, &contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_lists_both_families_and_skips_junk() {
+        let blocked = parse("192.168.1.5\n  fe80::1 \nnot an ip\n192.168.1.5:3001\n\n");
+        let v4: IpAddr = "192.168.1.5".parse().unwrap();
+        let v6: IpAddr = "fe80::1".parse().unwrap();
+        assert_eq!(blocked.into_iter().collect::<Vec<_>>(), [v4, v6]);
+    }
+
+    #[test]
+    fn only_the_exact_address_is_blocked() {
+        let ip: IpAddr = "10.9.8.7".parse().unwrap();
+        block_for_tests(ip);
+        assert!(is_blocked(ip));
+        assert!(!is_blocked("10.9.8.8".parse().unwrap()));
+    }
+}
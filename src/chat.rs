@@ -1,218 +1,1519 @@
-use crate::crypto;
-use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use crate::aliases;
+use crate::clipboard;
+use crate::crypto::{self, CryptoError};
+use crate::identity;
+use crate::keystore::{self, KeyStore, PinStatus};
+use crate::protocol::{
+    ACK_TIMEOUT, ANSWER_TIMEOUT, FileMessage, HEARTBEAT_INTERVAL, HEARTBEAT_TIMEOUT, HOLD_TIMEOUT,
+    SIGNAL_ACCEPT, SIGNAL_HOLD, SIGNAL_REJECT, TYPING_IDLE, TYPING_MIN_GAP, TYPING_REFRESH,
+    TYPING_TIMEOUT,
+};
+use crate::state::{self, PeerInfo, PeerMap};
+use crate::terminal::{self, TerminalGuard};
+use crate::transcript::Transcript;
+use crate::transfer::{self, Collision, Receiving, Resume, Sending};
+use chrono::{DateTime, Local, Utc};
 use colored::*;
 use crossterm::{
     cursor,
     event::{self, Event, KeyCode},
     execute,
     style::{Color, Print, SetForegroundColor},
-    terminal::{
-        Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode,
-        enable_raw_mode, size,
-    },
+    terminal::{Clear, ClearType, size},
 };
+use std::collections::HashMap;
 use std::io::{self, Read, Write};
-use std::net::TcpStream;
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+use zeroize::Zeroizing;
 
-const SIGNAL_ACCEPT: u8 = b'Y';
-const SIGNAL_REJECT: u8 = b'N';
+mod history;
+mod room;
+mod tabs;
 
-pub fn handle_incoming_request(mut stream: TcpStream) -> io::Result<()> {
-    let peer_addr = stream.peer_addr()?;
+pub use history::view_history;
+pub use room::host_room;
+pub use tabs::{ChatSession, Tabs};
 
-    print!(
-        "\r\n{} {} {} (y/n)? ",
-        "Incoming connection from".yellow(),
-        peer_addr,
-        "Accept".bold()
-    );
-    io::stdout().flush()?;
+const AUTO_CONNECT_ATTEMPTS: u32 = 5;
+const AUTO_CONNECT_BACKOFF: Duration = Duration::from_secs(2);
+/// Longest clipboard text `/paste` sends as a message; anything longer,
+/// or on several lines, goes as a file.
+const PASTE_LINE_MAX: usize = 1024;
+/// History rows moved per notch of the mouse wheel.
+const WHEEL_ROWS: usize = 3;
+/// How often a request on hold checks for Esc while it waits.
+const HOLD_POLL: Duration = Duration::from_millis(200);
 
-    let mut response = String::new();
-    io::stdin().read_line(&mut response)?;
+const POLL_ACTIVE: Duration = Duration::from_millis(10);
+const POLL_IDLE_MAX: Duration = Duration::from_millis(250);
+const ACTIVITY_WINDOW: Duration = Duration::from_secs(1);
 
-    if response.trim().eq_ignore_ascii_case("y") {
-        stream.write_all(&[SIGNAL_ACCEPT])?;
-        enter_chat_window(stream)?;
+/// Frames handled per pass of the chat loop, so typing stays responsive
+/// during a file transfer. The receiver takes more per pass than the sender
+/// sends, so it keeps up.
+const FILE_CHUNKS_PER_TICK: usize = 8;
+const FRAMES_PER_TICK: usize = 32;
+
+/// Chooses how long the chat loop waits for terminal input. Polls tightly
+/// while the session is busy and doubles the wait while it is idle, so an
+/// open but quiet chat doesn't wake the CPU 100 times a second.
+struct PollBackoff {
+    last_activity: Instant,
+    interval: Duration,
+}
+
+impl PollBackoff {
+    fn new(now: Instant) -> Self {
+        PollBackoff {
+            last_activity: now,
+            interval: POLL_ACTIVE,
+        }
+    }
+
+    fn record_activity(&mut self, now: Instant) {
+        self.last_activity = now;
+        self.interval = POLL_ACTIVE;
+    }
+
+    fn next_interval(&mut self, now: Instant) -> Duration {
+        if now.duration_since(self.last_activity) < ACTIVITY_WINDOW {
+            return POLL_ACTIVE;
+        }
+        self.interval = (self.interval * 2).min(POLL_IDLE_MAX);
+        self.interval
+    }
+}
+
+/// Sends pings on `HEARTBEAT_INTERVAL` and notices when the peer's stop.
+/// A peer killed or cut off without a FIN never makes the socket readable,
+/// so without this the session would wait on it forever.
+struct Heartbeat {
+    /// `None` until the first ping, which goes out straight away so the
+    /// peer learns early that this side pings.
+    last_ping: Option<Instant>,
+    last_received: Instant,
+    /// Whether the peer pings at all; only then is its silence a sign.
+    peer_pings: bool,
+}
+
+impl Heartbeat {
+    fn new(now: Instant) -> Self {
+        Heartbeat {
+            last_ping: None,
+            last_received: now,
+            peer_pings: false,
+        }
+    }
+
+    fn received(&mut self, incoming: &crypto::Incoming, now: Instant) {
+        self.last_received = now;
+        self.peer_pings |= matches!(incoming, crypto::Incoming::Ping);
+    }
+
+    /// True once per interval, when the next ping should go out.
+    fn ping_due(&mut self, now: Instant) -> bool {
+        let due = self
+            .last_ping
+            .is_none_or(|at| now.duration_since(at) >= HEARTBEAT_INTERVAL);
+        if due {
+            self.last_ping = Some(now);
+        }
+        due
+    }
+
+    fn peer_lost(&self, now: Instant) -> bool {
+        self.peer_pings && now.duration_since(self.last_received) >= HEARTBEAT_TIMEOUT
+    }
+}
+
+/// Both ends of the typing hint: what to tell the peer about the input
+/// line, and whether the peer said it is typing. See `MSG_TYPING`.
+struct Typing {
+    /// What the peer was last told.
+    sent: bool,
+    last_sent: Option<Instant>,
+    last_key: Instant,
+    /// When the peer's last "typing" lapses, if it hasn't stopped.
+    peer_until: Option<Instant>,
+}
+
+impl Typing {
+    fn new(now: Instant) -> Self {
+        Typing {
+            sent: false,
+            last_sent: None,
+            last_key: now,
+            peer_until: None,
+        }
+    }
+
+    fn key_pressed(&mut self, now: Instant) {
+        self.last_key = now;
+    }
+
+    /// The hint to send now for `input_buffer`, if any. Commands aren't
+    /// messages, so typing one doesn't count.
+    fn due(&mut self, input_buffer: &str, now: Instant) -> Option<bool> {
+        let typing = !input_buffer.is_empty()
+            && !input_buffer.starts_with('/')
+            && now.duration_since(self.last_key) < TYPING_IDLE;
+        let refresh = typing
+            && self
+                .last_sent
+                .is_some_and(|at| now.duration_since(at) >= TYPING_REFRESH);
+        let allowed = self
+            .last_sent
+            .is_none_or(|at| now.duration_since(at) >= TYPING_MIN_GAP);
+        if (typing == self.sent && !refresh) || !allowed {
+            return None;
+        }
+        self.sent = typing;
+        self.last_sent = Some(now);
+        Some(typing)
+    }
+
+    /// A sent message ends the typing on the peer's side by itself.
+    fn message_sent(&mut self) {
+        self.sent = false;
+    }
+
+    fn peer_hint(&mut self, typing: bool, now: Instant) {
+        self.peer_until = typing.then(|| now + TYPING_TIMEOUT);
+    }
+
+    fn peer_message(&mut self) {
+        self.peer_until = None;
+    }
+
+    fn peer_typing(&self, now: Instant) -> bool {
+        self.peer_until.is_some_and(|until| now < until)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LineKind {
+    /// A message typed by either side.
+    Chat,
+    /// Notices from the app itself; can be hidden with `/filter system off`.
+    System,
+    Error,
+}
+
+static REQUEST_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(20);
+
+/// How long an incoming request waits for an answer before it is rejected.
+/// Capped below `ANSWER_TIMEOUT` so the peer hears the rejection instead of
+/// timing out first.
+pub fn set_request_timeout(timeout: Duration) {
+    let max = ANSWER_TIMEOUT.as_secs() - 1;
+    REQUEST_TIMEOUT_SECS.store(timeout.as_secs().clamp(1, max), Ordering::Relaxed);
+}
+
+pub fn request_timeout() -> Duration {
+    Duration::from_secs(REQUEST_TIMEOUT_SECS.load(Ordering::Relaxed))
+}
+
+static CHAT_OPEN: AtomicBool = AtomicBool::new(false);
+/// Requests put on hold since the chat tab in front last looked.
+static HELD: Mutex<Vec<SocketAddr>> = Mutex::new(Vec::new());
+
+/// Whether a chat tab is in front. Nobody can answer a request meanwhile,
+/// so the accept thread puts new ones on hold.
+pub fn chat_open() -> bool {
+    CHAT_OPEN.load(Ordering::SeqCst)
+}
+
+/// Tells the chat tab in front that `addr` was put on hold.
+pub fn note_held(addr: SocketAddr) {
+    HELD.lock().unwrap().push(addr);
+}
+
+/// Marks a chat tab in front for as long as it lives.
+struct ChatOpen;
+
+impl ChatOpen {
+    fn mark() -> Self {
+        HELD.lock().unwrap().clear();
+        CHAT_OPEN.store(true, Ordering::SeqCst);
+        ChatOpen
+    }
+}
+
+impl Drop for ChatOpen {
+    fn drop(&mut self) {
+        CHAT_OPEN.store(false, Ordering::SeqCst);
+    }
+}
+
+// Timestamps read `HH:MM:SS` unless `/time 12h` switched them over.
+static CLOCK_12H: AtomicBool = AtomicBool::new(false);
+// Peers are told what we've read unless `/receipts off` said not to.
+static READ_RECEIPTS: AtomicBool = AtomicBool::new(true);
+
+/// Whether the peer has a message we sent; see `protocol::MSG_ACK`.
+#[derive(Clone, Copy)]
+enum Delivery {
+    Pending {
+        id: u64,
+        sent: Instant,
+    },
+    /// Still unconfirmed after `ACK_TIMEOUT`.
+    Late {
+        id: u64,
+    },
+    Delivered {
+        id: u64,
+    },
+    /// The peer has had it on screen; see `protocol::MSG_READ`.
+    Read,
+    /// Never confirmed before the connection dropped, so kept in the
+    /// outbox for the next chat with the peer; see `outbox`.
+    Queued,
+}
+
+struct ChatLine {
+    kind: LineKind,
+    text: String,
+    /// When the line was sent, received or raised, in local time.
+    at: DateTime<Local>,
+    expires_at: Option<Instant>,
+    /// Set on messages sent in a one-to-one chat.
+    delivery: Option<Delivery>,
+    /// Where the line starts in the transcript, if it was logged; see
+    /// `transcript::Marks`.
+    logged: Option<u64>,
+    bookmarked: bool,
+    /// A private note shown dimmed under the line.
+    note: Option<String>,
+    /// Highlighted for a bookmark or `/note`.
+    selected: bool,
+}
+
+impl ChatLine {
+    fn chat(text: impl Into<String>, ttl: Option<Duration>) -> Self {
+        ChatLine {
+            kind: LineKind::Chat,
+            text: text.into(),
+            at: Local::now(),
+            expires_at: ttl.map(|ttl| Instant::now() + ttl),
+            delivery: None,
+            logged: None,
+            bookmarked: false,
+            note: None,
+            selected: false,
+        }
+    }
+
+    /// Our chat message number `id`, waiting for the peer to confirm it.
+    fn sent(text: impl Into<String>, ttl: Option<Duration>, id: u64) -> Self {
+        ChatLine {
+            delivery: Some(Delivery::Pending {
+                id,
+                sent: Instant::now(),
+            }),
+            ..ChatLine::chat(text, ttl)
+        }
+    }
+
+    /// A message that couldn't go out and waits in the outbox.
+    fn unsent(text: impl Into<String>, ttl: Option<Duration>) -> Self {
+        ChatLine {
+            delivery: Some(Delivery::Queued),
+            ..ChatLine::chat(text, ttl)
+        }
+    }
+
+    fn system(text: impl Into<String>) -> Self {
+        ChatLine {
+            kind: LineKind::System,
+            text: text.into(),
+            at: Local::now(),
+            expires_at: None,
+            delivery: None,
+            logged: None,
+            bookmarked: false,
+            note: None,
+            selected: false,
+        }
+    }
+
+    fn error(text: impl Into<String>) -> Self {
+        ChatLine {
+            kind: LineKind::Error,
+            ..ChatLine::system(text)
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|at| now >= at)
+    }
+
+    /// Marks the message delivered if the peer's `received` count covers
+    /// it. Returns whether that changed anything.
+    fn acknowledge(&mut self, received: u64) -> bool {
+        match self.delivery {
+            Some(Delivery::Pending { id, .. } | Delivery::Late { id }) if id < received => {
+                self.delivery = Some(Delivery::Delivered { id });
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Marks the message read if the peer's `shown` count covers it. A
+    /// receipt can overtake the ack, so any unread state upgrades.
+    fn mark_read(&mut self, shown: u64) -> bool {
+        match self.delivery {
+            Some(
+                Delivery::Pending { id, .. } | Delivery::Late { id } | Delivery::Delivered { id },
+            ) if id < shown => {
+                self.delivery = Some(Delivery::Read);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Marks a message the peer hasn't confirmed as kept in the outbox.
+    fn queue(&mut self) {
+        if matches!(
+            self.delivery,
+            Some(Delivery::Pending { .. } | Delivery::Late { .. })
+        ) {
+            self.delivery = Some(Delivery::Queued);
+        }
+    }
+
+    /// Flags the message once it has waited `ACK_TIMEOUT` for the peer.
+    /// Returns whether it just went late.
+    fn check_late(&mut self, now: Instant) -> bool {
+        match self.delivery {
+            Some(Delivery::Pending { id, sent }) if now.duration_since(sent) >= ACK_TIMEOUT => {
+                self.delivery = Some(Delivery::Late { id });
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn render(&self) -> String {
+        let format = if CLOCK_12H.load(Ordering::Relaxed) {
+            "%I:%M:%S %p"
+        } else {
+            "%H:%M:%S"
+        };
+        let body = match self.kind {
+            LineKind::Chat => {
+                let unicode = terminal::supports_unicode();
+                let marker = match self.delivery {
+                    None => "  ".normal(),
+                    Some(Delivery::Pending { .. }) => {
+                        if unicode { "\u{2026} " } else { ". " }.dimmed()
+                    }
+                    Some(Delivery::Delivered { .. }) => {
+                        if unicode { "\u{2713} " } else { "+ " }.green()
+                    }
+                    Some(Delivery::Read) => if unicode { "\u{2713}\u{2713}" } else { "++" }
+                        .green()
+                        .bold(),
+                    Some(Delivery::Late { .. }) => "! ".red().bold(),
+                    Some(Delivery::Queued) => if unicode { "\u{21bb} " } else { "~ " }.yellow(),
+                };
+                format!("{} {}", marker, self.text)
+            }
+            LineKind::System => format!("* {}", self.text).dimmed().to_string(),
+            LineKind::Error => format!("! {}", self.text).red().to_string(),
+        };
+        let time = self.at.format(format).to_string();
+        let time = if self.selected {
+            time.reversed()
+        } else {
+            time.dimmed()
+        };
+        let gutter = match (self.bookmarked, terminal::supports_unicode()) {
+            (false, _) => String::new(),
+            (true, true) => format!("{} ", "\u{2605}".yellow()),
+            (true, false) => format!("{} ", "#".yellow()),
+        };
+        format!("{}{} {}", gutter, time, body)
+    }
+
+    /// The line wrapped to `cols`, with its note below it.
+    fn rows(&self, cols: usize) -> Vec<String> {
+        let mut rows = wrap(&self.render(), cols);
+        if let Some(note) = &self.note {
+            let note = format!("    {} {}", terminal::dash(), note);
+            rows.extend(wrap(&note.dimmed().to_string(), cols));
+        }
+        rows
+    }
+}
+
+/// The next bookmarked line after line `from` (or from the top), going
+/// round to the first once past the last.
+fn next_bookmark(messages: &[ChatLine], from: Option<usize>) -> Option<usize> {
+    let start = from.map_or(0, |n| n + 1).min(messages.len());
+    (start..messages.len())
+        .chain(0..start)
+        .find(|&n| messages[n].bookmarked)
+}
+
+/// The lines currently on screen. Scrolling counts only these, so hiding
+/// system lines never leaves the offset pointing past the end.
+fn visible_lines(messages: &[ChatLine], show_system: bool) -> Vec<&ChatLine> {
+    messages
+        .iter()
+        .filter(|m| show_system || m.kind != LineKind::System)
+        .collect()
+}
+
+const DISCOVERY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long after a chat's connection drops the side that dialed keeps
+/// dialing again, and the other side takes that without asking.
+const RECONNECT_WINDOW: Duration = Duration::from_secs(60);
+/// Pause between reconnect attempts while the peer can't be reached.
+const RECONNECT_RETRY: Duration = Duration::from_secs(3);
+/// How long one reconnect attempt waits for the TCP connection.
+const RECONNECT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Follows the connected peer's discovery beacons during a chat, so the
+/// window can point out when they stop arriving or come from a new
+/// address, and a reconnect dials where the peer is now. Peers that never
+/// announced (e.g. reached across subnets) produce no hint.
+struct DiscoveryWatch {
+    /// Whose signed beacons to follow; a legacy peer's bare ones are
+    /// matched on its address instead.
+    fingerprint: String,
+    /// Where the peer accepts chats, as last discovered.
+    addr: SocketAddr,
+    moved: bool,
+    seen_announcing: bool,
+    announcing: bool,
+    last_check: Option<Instant>,
+}
+
+impl DiscoveryWatch {
+    fn new(fingerprint: &str, addr: SocketAddr) -> Self {
+        DiscoveryWatch {
+            fingerprint: fingerprint.to_string(),
+            addr,
+            moved: false,
+            seen_announcing: false,
+            announcing: false,
+            last_check: None,
+        }
+    }
+
+    /// Re-reads the peer map at most once per interval. Returns true when
+    /// the hint changed and the window should be redrawn.
+    fn refresh(&mut self, peers: &PeerMap, now: Instant) -> bool {
+        if self
+            .last_check
+            .is_some_and(|t| now.duration_since(t) < DISCOVERY_CHECK_INTERVAL)
+        {
+            return false;
+        }
+        self.last_check = Some(now);
+
+        let found = discovered_at(&peers.read().unwrap(), &self.fingerprint, self.addr.ip());
+        let before = self.hint();
+        // The first sighting only settles the port: the side that was
+        // dialed knows just the one the peer dialed from.
+        if let Some(addr) = found {
+            self.moved |= self.seen_announcing && addr != self.addr;
+            self.addr = addr;
+        }
+        self.announcing = found.is_some();
+        self.seen_announcing |= self.announcing;
+        before != self.hint()
+    }
+
+    fn hint(&self) -> Option<String> {
+        if self.seen_announcing && !self.announcing {
+            Some("peer no longer announcing".to_string())
+        } else if self.moved {
+            Some(format!("peer address changed to {}", self.addr))
+        } else {
+            None
+        }
+    }
+}
+
+/// Where the peer with `fingerprint` accepts chats, going by its signed
+/// beacons, else by a legacy beacon from `legacy_ip`.
+fn discovered_at(
+    peers: &HashMap<SocketAddr, PeerInfo>,
+    fingerprint: &str,
+    legacy_ip: IpAddr,
+) -> Option<SocketAddr> {
+    let signed = peers.iter().find(|(_, info)| {
+        info.identity
+            .is_some_and(|key| identity::fingerprint(&key) == fingerprint)
+    });
+    signed
+        .or_else(|| {
+            peers
+                .iter()
+                .find(|(addr, info)| info.identity.is_none() && addr.ip() == legacy_ip)
+        })
+        .map(|(addr, _)| *addr)
+}
+
+/// Dials the peer with `fingerprint` again after its chat dropped, each
+/// time at wherever it was last discovered, else at `last_addr`, until it
+/// accepts or `RECONNECT_WINDOW` has passed.
+fn redial(
+    peers: PeerMap,
+    fingerprint: String,
+    last_addr: SocketAddr,
+) -> Receiver<io::Result<TcpStream>> {
+    let (tx, rx) = mpsc::channel();
+    let _ = thread::Builder::new()
+        .name("redial".to_string())
+        .spawn(move || {
+            let deadline = Instant::now() + RECONNECT_WINDOW;
+            let dialed = loop {
+                let addr = discovered_at(&peers.read().unwrap(), &fingerprint, last_addr.ip())
+                    .unwrap_or(last_addr);
+                match dial_accepted(addr, deadline) {
+                    Err(e)
+                        if e.kind() != io::ErrorKind::PermissionDenied
+                            && Instant::now() + RECONNECT_RETRY < deadline =>
+                    {
+                        thread::sleep(RECONNECT_RETRY)
+                    }
+                    dialed => break dialed,
+                }
+            };
+            let _ = tx.send(dialed);
+        });
+    rx
+}
+
+/// Connects to `addr` and waits, until `deadline` at most, for the peer to
+/// accept, through any time on hold. A rejection is `PermissionDenied`.
+fn dial_accepted(addr: SocketAddr, deadline: Instant) -> io::Result<TcpStream> {
+    let left = || {
+        deadline
+            .saturating_duration_since(Instant::now())
+            .max(Duration::from_millis(1))
+    };
+    let mut stream = TcpStream::connect_timeout(&addr, left().min(RECONNECT_CONNECT_TIMEOUT))?;
+    let mut answer = [0u8; 1];
+    loop {
+        stream.set_read_timeout(Some(left()))?;
+        stream.read_exact(&mut answer)?;
+        match answer[0] {
+            SIGNAL_HOLD => {}
+            SIGNAL_ACCEPT => {
+                stream.set_read_timeout(None)?;
+                return Ok(stream);
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "rejected by peer",
+                ));
+            }
+        }
+    }
+}
+
+/// Peers whose chat dropped on the side that was dialed, by fingerprint,
+/// with the address they were last discovered at and until when their
+/// reconnect is taken without asking.
+static EXPECTED: Mutex<Vec<(String, IpAddr, Instant)>> = Mutex::new(Vec::new());
+
+fn expect_reconnect(fingerprint: &str, ip: IpAddr, until: Instant) {
+    let mut expected = EXPECTED.lock().unwrap();
+    expected.retain(|(f, _, _)| f != fingerprint);
+    expected.push((fingerprint.to_string(), ip, until));
+}
+
+/// The fingerprint of the peer whose dropped chat a request from `ip` is
+/// expected to reconnect, going by where that peer is discovered now.
+/// The handshake still has to show that key.
+fn expected_reconnect(peers: &PeerMap, ip: IpAddr, now: Instant) -> Option<String> {
+    let mut expected = EXPECTED.lock().unwrap();
+    expected.retain(|(_, _, until)| now < *until);
+    let peers = peers.read().unwrap();
+    expected
+        .iter()
+        .find(|(fingerprint, last_ip, _)| {
+            discovered_at(&peers, fingerprint, *last_ip).map_or(*last_ip, |addr| addr.ip()) == ip
+        })
+        .map(|(fingerprint, _, _)| fingerprint.clone())
+}
+
+/// An incoming request shown at the main prompt. The main loop keeps
+/// running while it waits: keys go to `answer` until Enter, and once
+/// `request_timeout` has passed the request is rejected on its own.
+pub struct PendingRequest {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    /// Our alias for the peer, else the nickname from its beacons; the
+    /// handshake hasn't happened yet.
+    nickname: Option<String>,
+    /// When the chat with the key last seen at the peer's address ended;
+    /// whether it is that key is only known after the handshake.
+    last_chatted: Option<DateTime<Utc>>,
+    /// The key of the peer whose dropped chat this reconnects, if it is
+    /// one; see `expect_reconnect`.
+    reconnecting: Option<String>,
+    deadline: Instant,
+    pub answer: String,
+}
+
+impl PendingRequest {
+    /// Fails if the peer already hung up, e.g. after waiting on hold for
+    /// longer than it would.
+    pub fn new(stream: TcpStream, peers: &PeerMap) -> io::Result<Self> {
+        if hung_up(&stream) {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "peer stopped waiting",
+            ));
+        }
+        let peer_addr = stream.peer_addr()?;
+        Ok(PendingRequest {
+            nickname: aliases::name_for(&peer_addr)
+                .or_else(|| state::nickname_at(&peers.read().unwrap(), peer_addr.ip())),
+            last_chatted: KeyStore::load()
+                .history_at(peer_addr.ip())
+                .and_then(|(_, history)| history.last_chatted),
+            reconnecting: expected_reconnect(peers, peer_addr.ip(), Instant::now()),
+            peer_addr,
+            stream,
+            deadline: Instant::now() + request_timeout(),
+            answer: String::new(),
+        })
+    }
+
+    /// Prints the question with whatever was typed so far, on a fresh line
+    /// so it can be redrawn after other output.
+    pub fn show(&self) -> io::Result<()> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        let last_chatted = self.last_chatted.map_or(String::new(), |at| {
+            format!(" (last chatted {})", keystore::format_ago(at, Utc::now()))
+        });
+        print!(
+            "\r\n{} {}{} {} (y/n, p = with passphrase; rejected in {}s)? {}",
+            "Incoming connection from".yellow(),
+            self.peer(),
+            last_chatted.dimmed(),
+            "Accept".bold(),
+            left.as_millis().div_ceil(1000),
+            self.answer
+        );
+        io::stdout().flush()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expired_at(Instant::now())
+    }
+
+    fn expired_at(&self, now: Instant) -> bool {
+        now >= self.deadline
+    }
+
+    /// Turns the peer away after nobody answered in time.
+    pub fn expire(mut self) {
+        let _ = self.stream.write_all(&[SIGNAL_REJECT]);
+        print!(
+            "\r\n{}",
+            format!(
+                "No answer within {}s; rejected {}.",
+                request_timeout().as_secs(),
+                self.peer()
+            )
+            .red()
+        );
+    }
+
+    /// The key of a peer reconnecting a dropped chat, else the trusted key
+    /// last seen at the peer's address. Such a request is accepted with
+    /// `auto_accept` instead of being asked about.
+    pub fn trusted_key(&self) -> Option<String> {
+        self.reconnecting
+            .clone()
+            .or_else(|| KeyStore::load().trusted_at(self.peer_addr.ip()))
+    }
+
+    /// Accepts without asking. The chat is refused after the handshake if
+    /// the peer doesn't present `fingerprint`.
+    pub fn auto_accept(mut self, fingerprint: &str) -> io::Result<Option<ChatSession>> {
+        let why = match self.reconnecting {
+            Some(_) => "reconnecting",
+            None => "trusted",
+        };
+        print!(
+            "\r\n{}\r\n",
+            format!("Auto-accepted connection from {} ({}).", self.peer(), why).green()
+        );
+        let _cooked = TerminalGuard::cooked()?;
+        self.stream.write_all(&[SIGNAL_ACCEPT])?;
+        ChatSession::start(
+            self.stream,
+            crypto::Role::Responder,
+            None,
+            None,
+            Some(fingerprint),
+        )
+    }
+
+    /// `nickname (ip:port)`, or just the address.
+    fn peer(&self) -> String {
+        match &self.nickname {
+            Some(name) => format!("{} ({})", name, self.peer_addr),
+            None => self.peer_addr.to_string(),
+        }
+    }
+}
+
+/// The connecting side sends nothing until it is answered, so a readable
+/// socket means it closed.
+fn hung_up(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let closed =
+        !matches!(stream.peek(&mut [0u8; 1]), Err(e) if e.kind() == io::ErrorKind::WouldBlock);
+    let _ = stream.set_nonblocking(false);
+    closed
+}
+
+/// Acts on the answer typed for `request`: opens the chat, asking for the
+/// passphrase first for `p`, or rejects it.
+pub fn answer_incoming_request(request: PendingRequest) -> io::Result<Option<ChatSession>> {
+    let PendingRequest {
+        mut stream, answer, ..
+    } = request;
+    print!("\r\n");
+    let _cooked = TerminalGuard::cooked()?;
+    let response = answer.trim();
+
+    let passphrase = if response.eq_ignore_ascii_case("p") {
+        match ask_passphrase()? {
+            Some(passphrase) => Some(passphrase),
+            None => {
+                let _ = stream.write_all(&[SIGNAL_REJECT]);
+                println!("{}", "Connection rejected.".red());
+                return Ok(None);
+            }
+        }
     } else {
-        let _ = stream.write_all(&[SIGNAL_REJECT]);
-        println!("{}", "Connection rejected.".red());
+        None
+    };
+
+    if response.eq_ignore_ascii_case("y") || passphrase.is_some() {
+        stream.write_all(&[SIGNAL_ACCEPT])?;
+        return ChatSession::start(
+            stream,
+            crypto::Role::Responder,
+            None,
+            passphrase.as_deref().map(String::as_str),
+            None,
+        );
     }
-    Ok(())
+    let _ = stream.write_all(&[SIGNAL_REJECT]);
+    println!("{}", "Connection rejected.".red());
+    Ok(None)
+}
+
+/// Prompts for the passphrase both sides agreed on out of band. `None` if
+/// the user cancelled or entered nothing.
+pub fn ask_passphrase() -> io::Result<Option<Zeroizing<String>>> {
+    let passphrase = terminal::read_secret("Passphrase (not shown): ")?;
+    Ok(passphrase.filter(|p| !p.is_empty()))
 }
 
-pub fn initiate_connection(target_ip: &str) -> io::Result<()> {
+pub fn initiate_connection(
+    target_ip: &str,
+    passphrase: Option<&str>,
+) -> io::Result<Option<ChatSession>> {
     println!("{}", format!("Connecting to {}...", target_ip).yellow());
 
     match TcpStream::connect(target_ip) {
-        Ok(mut stream) => {
-            stream.set_read_timeout(Some(Duration::from_secs(10)))?;
-            println!("Waiting for peer to accept...");
-
-            let mut buffer = [0u8; 1];
-            match stream.read_exact(&mut buffer) {
-                Ok(_) => {
-                    if buffer[0] == SIGNAL_ACCEPT {
-                        stream.set_read_timeout(None)?;
-                        enter_chat_window(stream)?;
-                    } else {
-                        println!("{}", "Connection was rejected by peer.".red());
-                    }
+        Ok(stream) => await_acceptance(stream, None, passphrase),
+        Err(e) => {
+            println!("{} {}", "Failed to connect:".red(), e);
+            Ok(None)
+        }
+    }
+}
+
+/// Dials `target_ip` at startup, retrying with a growing delay while the
+/// peer is unreachable. Once the chat is up, `initial_message` is sent as
+/// if it had been typed. Gives up with a message rather than an error so
+/// the caller can fall back to the regular prompt.
+pub fn auto_connect(
+    target_ip: &str,
+    initial_message: Option<&str>,
+) -> io::Result<Option<ChatSession>> {
+    for attempt in 1..=AUTO_CONNECT_ATTEMPTS {
+        println!(
+            "{}",
+            format!(
+                "Connecting to {} (attempt {}/{})...",
+                target_ip, attempt, AUTO_CONNECT_ATTEMPTS
+            )
+            .yellow()
+        );
+
+        match TcpStream::connect(target_ip) {
+            Ok(stream) => return await_acceptance(stream, initial_message, None),
+            Err(e) => {
+                println!("{} {}", "Failed to connect:".red(), e);
+                if attempt < AUTO_CONNECT_ATTEMPTS {
+                    std::thread::sleep(AUTO_CONNECT_BACKOFF * attempt);
                 }
-                Err(_) => println!("{}", "Connection timed out or peer disconnected.".red()),
             }
         }
-        Err(e) => println!("{} {}", "Failed to connect:".red(), e),
     }
-    Ok(())
+
+    println!(
+        "{}",
+        format!("Giving up on {}, continuing to the prompt.", target_ip).red()
+    );
+    Ok(None)
 }
 
-fn enter_chat_window(mut stream: TcpStream) -> io::Result<()> {
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, Clear(ClearType::All))?;
-    println!("Performing Secure Handshake...");
+fn await_acceptance(
+    mut stream: TcpStream,
+    initial_message: Option<&str>,
+    passphrase: Option<&str>,
+) -> io::Result<Option<ChatSession>> {
+    stream.set_read_timeout(Some(ANSWER_TIMEOUT))?;
+    println!("Waiting for peer to accept...");
 
-    let shared_secret = match crypto::perform_handshake(&stream) {
-        Ok(s) => s,
-        Err(e) => {
-            println!("Handshake failed: {}", e);
-            std::thread::sleep(Duration::from_secs(2));
-            return Ok(());
+    let mut buffer = [0u8; 1];
+    let mut answer = stream.read_exact(&mut buffer).map(|_| buffer[0]);
+    if matches!(answer, Ok(SIGNAL_HOLD)) {
+        println!(
+            "Peer is in another chat; waiting until it answers (Esc to give up, {} min at most).",
+            HOLD_TIMEOUT.as_secs() / 60
+        );
+        answer = wait_on_hold(&mut stream);
+    }
+    match answer {
+        Ok(SIGNAL_ACCEPT) => {
+            stream.set_read_timeout(None)?;
+            return ChatSession::start(
+                stream,
+                crypto::Role::Initiator,
+                initial_message,
+                passphrase,
+                None,
+            );
+        }
+        Ok(_) => println!("{}", "Connection was rejected by peer.".red()),
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+            println!("{}", "Stopped waiting.".yellow())
+        }
+        Err(_) => println!("{}", "Connection timed out or peer disconnected.".red()),
+    }
+    Ok(None)
+}
+
+/// Reads the answer to a request the peer put on hold, giving up after
+/// `HOLD_TIMEOUT` or when the user presses Esc.
+fn wait_on_hold(stream: &mut TcpStream) -> io::Result<u8> {
+    let _raw = TerminalGuard::raw()?;
+    stream.set_read_timeout(Some(HOLD_POLL))?;
+    let deadline = Instant::now() + HOLD_TIMEOUT;
+    let mut buffer = [0u8; 1];
+    while Instant::now() < deadline {
+        match stream.read(&mut buffer) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) => return Ok(buffer[0]),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => return Err(e),
+        }
+        while event::poll(Duration::ZERO)? {
+            if let Event::Key(key) = event::read()?
+                && (key.code == KeyCode::Esc || terminal::is_interrupt(&key))
+            {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
         }
+    }
+    Err(io::ErrorKind::TimedOut.into())
+}
+
+/// `[name]` for each side, or `[You]` and `[They]` without one, padded
+/// to the same width so the messages line up.
+fn speaker_labels(ours: Option<&str>, theirs: Option<&str>) -> (String, String) {
+    let you = format!("[{}]", ours.unwrap_or("You"));
+    let they = format!("[{}]", theirs.unwrap_or("They"));
+    let width = you.width().max(they.width());
+    let pad = |label: String| format!("{}{}", " ".repeat(width - label.width()), label);
+    (pad(you), pad(they))
+}
+
+fn verify_notice(sas: &str) -> String {
+    format!(
+        "Verification code: {} (read it to your peer; a different code means someone is in between)",
+        sas
+    )
+}
+
+/// Applies a file frame from the peer to this side's transfers and returns
+/// what to answer, if anything. Frames about a transfer that was already
+/// cancelled here can still be on their way, so those are ignored.
+fn handle_file_message(
+    message: FileMessage,
+    sending: &mut Option<Sending>,
+    receiving: &mut Option<Receiving>,
+    peer_fingerprint: &str,
+    peer_label: &str,
+    messages: &mut Vec<ChatLine>,
+    needs_redraw: &mut bool,
+) -> Option<FileMessage> {
+    match message {
+        FileMessage::Offer { size, sha256, name } => {
+            let offer = Receiving::offered(&name, size, sha256, peer_fingerprint, peer_label);
+            *needs_redraw = true;
+            if receiving.is_some() {
+                messages.push(ChatLine::system(format!(
+                    "Declined {} while another file is coming in.",
+                    offer.name
+                )));
+                return Some(FileMessage::Refuse);
+            }
+            let collision = offer.collides();
+            let folder = offer
+                .folder()
+                .map(|folder| folder.display().to_string())
+                .unwrap_or_default();
+            if collision == Some(Collision::Reject) {
+                messages.push(ChatLine::system(format!(
+                    "Declined {}: a file by that name is already in {} (see set downloads collisions).",
+                    offer.name, folder
+                )));
+                return Some(FileMessage::Refuse);
+            }
+            let offered = format!(
+                "Peer wants to send you {} ({}).",
+                offer.name,
+                transfer::format_size(size)
+            );
+            messages.push(ChatLine::system(match offer.resume() {
+                Resume::From(received) if received > 0 => format!(
+                    "{} Type /accept to resume at {}, or /decline.",
+                    offered,
+                    transfer::percent(received, size)
+                ),
+                _ => format!("{} Type /accept or /decline.", offered),
+            }));
+            if collision == Some(Collision::Overwrite) {
+                messages.push(ChatLine::error(format!(
+                    "Accepting replaces the {} already in {}.",
+                    offer.name, folder
+                )));
+            }
+            if offer.resume() == Resume::Changed {
+                messages.push(ChatLine::error(format!(
+                    "The sender's {} changed since your download of it was interrupted; accepting starts over.",
+                    offer.name
+                )));
+            }
+            *receiving = Some(offer);
+        }
+        FileMessage::Accept { offset } => {
+            if let Some(file) = sending.as_mut().filter(|file| !file.is_accepted()) {
+                *needs_redraw = true;
+                if let Err(e) = file.accept(offset) {
+                    messages.push(ChatLine::error(format!(
+                        "Stopped sending {}: {}",
+                        file.name, e
+                    )));
+                    *sending = None;
+                    return Some(FileMessage::Abort);
+                }
+                messages.push(ChatLine::system(if offset == 0 {
+                    format!("Peer accepted {}.", file.name)
+                } else {
+                    format!(
+                        "Peer accepted {}, resuming at {}.",
+                        file.name,
+                        transfer::percent(offset, file.size)
+                    )
+                }));
+            }
+        }
+        FileMessage::Chunk { offset, data } => {
+            if let Some(file) = receiving.as_mut().filter(|file| file.is_accepted())
+                && let Err(e) = file.write_chunk(offset, &data)
+            {
+                messages.push(ChatLine::error(format!(
+                    "Stopped receiving {}: {}",
+                    file.name, e
+                )));
+                *receiving = None;
+                *needs_redraw = true;
+                return Some(FileMessage::Refuse);
+            }
+        }
+        FileMessage::Refuse => {
+            if let Some(file) = sending.take() {
+                messages.push(ChatLine::system(if file.is_accepted() {
+                    format!("Peer stopped receiving {}.", file.name)
+                } else {
+                    format!("Peer declined {}.", file.name)
+                }));
+                *needs_redraw = true;
+            }
+        }
+        FileMessage::Abort => {
+            if let Some(file) = receiving.take() {
+                messages.push(ChatLine::system(if file.is_accepted() {
+                    format!("Peer stopped sending {}; partial file removed.", file.name)
+                } else {
+                    format!("Peer withdrew {}.", file.name)
+                }));
+                *needs_redraw = true;
+            }
+        }
+    }
+    None
+}
+
+/// Offers `file` to the peer and, once the offer is out, makes it the file
+/// being sent.
+fn offer_file(
+    stream: &mut TcpStream,
+    cipher: &mut crypto::CipherState,
+    file: Sending,
+    sending: &mut Option<Sending>,
+    messages: &mut Vec<ChatLine>,
+) -> Result<(), CryptoError> {
+    let offer = FileMessage::Offer {
+        size: file.size,
+        sha256: file.sha256,
+        name: file.name.clone(),
     };
+    crypto::send_file_message(stream, cipher, &offer)?;
+    messages.push(ChatLine::system(format!(
+        "Offered {} ({}); waiting for the peer to accept.",
+        file.name,
+        transfer::format_size(file.size)
+    )));
+    *sending = Some(file);
+    Ok(())
+}
 
-    let cipher = ChaCha20Poly1305::new_from_slice(&shared_secret)
-        .map_err(|_| io::Error::other("Invalid Key"))?;
+/// Clipboard text as a chat line, if it is one: short, on a single line
+/// (a trailing newline aside) and not starting with `/`, which would be
+/// taken for a command.
+fn pasted_line(text: &str) -> Option<&str> {
+    let line = text.strip_suffix('\n').unwrap_or(text);
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    (!line.is_empty()
+        && line.len() <= PASTE_LINE_MAX
+        && !line.contains(['\n', '\r'])
+        && !line.starts_with('/'))
+    .then_some(line)
+}
 
-    stream.set_nonblocking(true)?;
+/// Clipboard contents as a file named after when they were pasted, e.g.
+/// `clipboard-20240101-120301.png`.
+fn pasted_file(contents: clipboard::Contents) -> Sending {
+    let stamp = Local::now().format("%Y%m%d-%H%M%S");
+    match contents {
+        clipboard::Contents::Text(text) => {
+            Sending::from_bytes(format!("clipboard-{}.txt", stamp), text.into_bytes())
+        }
+        clipboard::Contents::Png(png) => {
+            Sending::from_bytes(format!("clipboard-{}.png", stamp), png)
+        }
+    }
+}
 
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, Clear(ClearType::All))?;
+/// Sends the next few chunks of an accepted file. Returns the line to show
+/// once the transfer is over, because the whole file went out or it can't
+/// be read any more.
+fn send_chunks(
+    stream: &mut TcpStream,
+    cipher: &mut crypto::CipherState,
+    file: &mut Sending,
+) -> Result<Option<ChatLine>, CryptoError> {
+    for _ in 0..FILE_CHUNKS_PER_TICK {
+        match file.next_chunk() {
+            Ok(Some((offset, data))) => {
+                crypto::send_file_message(stream, cipher, &FileMessage::Chunk { offset, data })?
+            }
+            Ok(None) => return Ok(Some(ChatLine::system(format!("Sent {}.", file.name)))),
+            Err(e) => {
+                crypto::send_file_message(stream, cipher, &FileMessage::Abort)?;
+                return Ok(Some(ChatLine::error(format!(
+                    "Stopped sending {}: {}",
+                    file.name, e
+                ))));
+            }
+        }
+    }
+    Ok(None)
+}
 
-    stream.set_nonblocking(true)?;
+/// The separator's label: progress of running transfers, or else the
+/// discovery hint.
+fn status_line(
+    discovery: &DiscoveryWatch,
+    sending: Option<&Sending>,
+    receiving: Option<&Receiving>,
+) -> Option<String> {
+    let progress: Vec<String> = sending
+        .filter(|file| file.is_accepted())
+        .map(Sending::progress)
+        .into_iter()
+        .chain(
+            receiving
+                .filter(|file| file.is_accepted())
+                .map(Receiving::progress),
+        )
+        .collect();
+    if progress.is_empty() {
+        discovery.hint()
+    } else {
+        Some(progress.join(" | "))
+    }
+}
 
-    let peer_addr = stream.peer_addr()?.to_string();
-    let mut input_buffer = String::new();
-    let mut messages: Vec<String> = Vec::new();
-    let mut scroll_offset: usize = 0;
+/// The connection going away, as opposed to the session being closed on
+/// purpose; only these are reconnected.
+const PEER_DISCONNECTED: &str = "Peer disconnected.";
+const PEER_UNREACHABLE: &str = "Peer unreachable.";
 
-    messages.push(format!("Connected to {}.", peer_addr));
-    messages.push("End-to-End Encrypted.".to_string());
-    messages.push("Press 'Esc' to disconnect.".to_string());
-    messages.push("---------------------------------".to_string());
+/// What to tell the user when a send or receive leaves the connection
+/// unusable.
+fn lost_reason(e: &CryptoError) -> &'static str {
+    match e {
+        CryptoError::Stalled => PEER_UNREACHABLE,
+        CryptoError::Io(e) if e.kind() == io::ErrorKind::TimedOut => PEER_UNREACHABLE,
+        CryptoError::NonceReuse { .. } => {
+            "Session closed: nonce reuse detected, aborting for safety."
+        }
+        CryptoError::KeyExhausted => {
+            "Session closed: the peer never completed a rekey, aborting for safety."
+        }
+        _ => PEER_DISCONNECTED,
+    }
+}
 
-    draw_ui(&mut stdout, &messages, &input_buffer, scroll_offset)?;
+fn pin_notice(pin: io::Result<PinStatus>, fingerprint: &str) -> Vec<ChatLine> {
+    match pin {
+        Ok(PinStatus::Trusted) => vec![ChatLine::system(format!(
+            "Peer identity: {} (trusted)",
+            fingerprint
+        ))],
+        Ok(PinStatus::Known) => vec![ChatLine::system(format!("Peer identity: {}", fingerprint))],
+        Ok(PinStatus::New) => vec![ChatLine::system(format!(
+            "New peer identity: {} (pinned; confirm it with your peer, then 'trust' it)",
+            fingerprint
+        ))],
+        Ok(PinStatus::Changed { previous }) => vec![
+            ChatLine::error("WARNING: THIS PEER'S IDENTITY KEY HAS CHANGED."),
+            ChatLine::error(format!("Pinned: {}", previous)),
+            ChatLine::error(format!("Now:    {}", fingerprint)),
+            ChatLine::error(
+                "Someone may be intercepting this connection. If the change is expected, \
+                 run 'trust <fingerprint>' at the prompt.",
+            ),
+        ],
+        Err(e) => vec![
+            ChatLine::system(format!("Peer identity: {}", fingerprint)),
+            ChatLine::error(format!("Could not save known peers: {}", e)),
+        ],
+    }
+}
 
-    loop {
-        let mut needs_redraw = false;
+fn format_last_activity(at: Option<Instant>, now: Instant) -> String {
+    match at {
+        Some(at) => format!("{} ago", format_age(now.duration_since(at))),
+        None => "never".to_string(),
+    }
+}
 
-        if event::poll(Duration::from_millis(10))?
-            && let Event::Key(key) = event::read()?
-        {
-            match key.code {
-                KeyCode::Esc => break,
-                KeyCode::Enter => {
-                    if !input_buffer.is_empty() {
-                        if let Err(e) =
-                            crypto::encrypt_and_send(&mut stream, &cipher, &input_buffer)
-                        {
-                            messages.push(format!("Error: {}", e));
-                        } else {
-                            messages.push(format!("{} >> {}", " [You]".green(), input_buffer));
-                            input_buffer.clear();
-                            scroll_offset = 0;
-                        }
-                        needs_redraw = true;
-                    }
-                }
-                KeyCode::Char(c) => {
-                    input_buffer.push(c);
-                    needs_redraw = true;
-                }
-                KeyCode::Backspace => {
-                    input_buffer.pop();
-                    needs_redraw = true;
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 3600 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60)
+    }
+}
+
+/// Parses the argument of `/expire`: `off` disables expiry, a positive
+/// number of seconds enables it. Returns `None` for anything else.
+fn parse_ttl(arg: &str) -> Option<Option<Duration>> {
+    if arg.eq_ignore_ascii_case("off") {
+        return Some(None);
+    }
+    match arg.parse::<u64>() {
+        Ok(secs) if secs > 0 => Some(Some(Duration::from_secs(secs))),
+        _ => None,
+    }
+}
+
+/// A disappearing-messages TTL we proposed and the peer hasn't agreed to
+/// yet; see `protocol::MSG_EXPIRE`.
+struct ExpireProposal {
+    ttl: Duration,
+    sent: Instant,
+}
+
+impl ExpireProposal {
+    /// Whether the peer let `ACK_TIMEOUT` pass without agreeing, as a
+    /// build that ignores the hint does.
+    fn is_unanswered(&self, now: Instant) -> bool {
+        now.duration_since(self.sent) >= ACK_TIMEOUT
+    }
+}
+
+/// Opens the peer's transcript and says where it goes, or why it can't.
+fn start_transcript(
+    transcript: &mut Option<Transcript>,
+    messages: &mut Vec<ChatLine>,
+    peer: IpAddr,
+) {
+    match Transcript::open(peer) {
+        Ok(log) => {
+            messages.push(ChatLine::system(format!(
+                "Logging this chat to {}.",
+                log.path().display()
+            )));
+            match log.integrity() {
+                Some(integrity) if integrity.is_intact() => {
+                    messages.push(ChatLine::system(format!("{}.", integrity)))
                 }
-                KeyCode::PageUp | KeyCode::Up => {
-                    let (_cols, rows) = size()?;
-                    let view_height = (rows as usize).saturating_sub(2);
-                    let max_scroll = messages.len().saturating_sub(view_height);
-
-                    if scroll_offset < max_scroll {
-                        scroll_offset += 1;
-                        needs_redraw = true;
-                    }
+                Some(integrity) => {
+                    messages.push(ChatLine::error(format!("WARNING: {}.", integrity)))
                 }
-                KeyCode::PageDown | KeyCode::Down => {
-                    if scroll_offset > 0 {
-                        scroll_offset -= 1;
-                        needs_redraw = true;
+                None => {}
+            }
+            *transcript = Some(log);
+        }
+        Err(e) => messages.push(ChatLine::error(format!("Can't log this chat: {}", e))),
+    }
+}
+
+/// Adds a chat line to the transcript, if one is open, and returns where
+/// it starts there. A failed write stops logging instead of ending the
+/// chat.
+fn log_line(
+    transcript: &mut Option<Transcript>,
+    messages: &mut Vec<ChatLine>,
+    speaker: &str,
+    text: &str,
+) -> Option<u64> {
+    match transcript.as_mut()?.record(speaker, text) {
+        Ok(start) => Some(start),
+        Err(e) => {
+            messages.push(ChatLine::error(format!("Logging stopped: {}", e)));
+            *transcript = None;
+            None
+        }
+    }
+}
+
+/// Every message rendered and wrapped to `cols`, one entry per screen row.
+/// Scrolling counts these rows, not messages.
+fn wrapped_rows(messages: &[&ChatLine], cols: usize) -> Vec<String> {
+    messages.iter().flat_map(|msg| msg.rows(cols)).collect()
+}
+
+/// How far up the history can scroll before its first row is at the top,
+/// with `header_rows` taken above it.
+fn max_scroll(messages: &[&ChatLine], header_rows: usize) -> io::Result<usize> {
+    let (cols, rows) = size()?;
+    let view_height = (rows as usize).saturating_sub(2 + header_rows);
+    Ok(scroll_limit(messages, cols as usize, view_height))
+}
+
+/// `max_scroll` for a view `view_height` rows tall and `cols` wide.
+fn scroll_limit(messages: &[&ChatLine], cols: usize, view_height: usize) -> usize {
+    wrapped_rows(messages, cols)
+        .len()
+        .saturating_sub(view_height)
+}
+
+/// The scroll offset that keeps the same line at the bottom of the view
+/// once system lines are shown or hidden. A bottom line that gets hidden
+/// gives way to the one above it. Never past the new `scroll_limit`.
+fn refiltered_offset(
+    messages: &[ChatLine],
+    scroll_offset: usize,
+    show_system: [bool; 2],
+    cols: usize,
+    view_height: usize,
+) -> usize {
+    let [before, after] = show_system;
+    let shown = |line: &ChatLine, show: bool| show || line.kind != LineKind::System;
+    let limit = scroll_limit(&visible_lines(messages, after), cols, view_height);
+
+    // The bottom line, and how many of its rows are below the view.
+    let mut below = scroll_offset;
+    let bottom = messages
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, line)| shown(line, before))
+        .find_map(|(n, line)| {
+            let rows = line.rows(cols).len();
+            if below < rows {
+                return Some(n);
+            }
+            below -= rows;
+            None
+        });
+    let Some(bottom) = bottom else {
+        return scroll_offset.min(limit);
+    };
+    let after_bottom: usize = messages[bottom + 1..]
+        .iter()
+        .filter(|line| shown(line, after))
+        .map(|line| line.rows(cols).len())
+        .sum();
+    let within = if shown(&messages[bottom], after) {
+        below
+    } else {
+        0
+    };
+    (after_bottom + within).min(limit)
+}
+
+/// Splits `line` into rows at most `cols` cells wide, breaking after
+/// whitespace where there is some and mid-word otherwise. ANSI escape
+/// sequences are kept but take no width; a colour carries over to the
+/// next row because the terminal keeps it until the reset.
+fn wrap(line: &str, cols: usize) -> Vec<String> {
+    let cols = cols.max(1);
+    let mut rows = Vec::new();
+    let mut row = String::new();
+    let mut width = 0;
+    // Byte offset and width just after the last whitespace in `row`.
+    let mut last_break: Option<(usize, usize)> = None;
+
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            row.push(c);
+            if chars.next_if_eq(&'[').is_some() {
+                row.push('[');
+                for c in chars.by_ref() {
+                    row.push(c);
+                    if ('@'..='~').contains(&c) {
+                        break;
                     }
                 }
-                _ => {}
             }
+            continue;
         }
 
-        match crypto::receive_and_decrypt(&mut stream, &cipher) {
-            Ok(msg) => {
-                if !msg.is_empty() {
-                    messages.push(format!("{} >> {}", "[They]".cyan(), msg));
-                    needs_redraw = true;
+        let w = c.width().unwrap_or(0);
+        if width + w > cols && width > 0 {
+            match last_break.take() {
+                Some((at, break_width)) => {
+                    let rest = row.split_off(at);
+                    rows.push(std::mem::replace(&mut row, rest));
+                    width -= break_width;
+                    if width + w > cols {
+                        rows.push(std::mem::take(&mut row));
+                        width = 0;
+                    }
+                }
+                None => {
+                    rows.push(std::mem::take(&mut row));
+                    width = 0;
                 }
-            }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                // No data waiting
-            }
-            Err(_) => {
-                messages.push("Peer disconnected.".red().to_string());
-                draw_ui(&mut stdout, &messages, &input_buffer, scroll_offset)?;
-                std::thread::sleep(Duration::from_secs(2));
-                break;
             }
         }
 
-        if needs_redraw {
-            draw_ui(&mut stdout, &messages, &input_buffer, scroll_offset)?;
+        row.push(c);
+        width += w;
+        if c.is_whitespace() {
+            last_break = Some((row.len(), width));
         }
     }
+    rows.push(row);
+    rows
+}
 
-    execute!(stdout, LeaveAlternateScreen)?;
-    disable_raw_mode()?;
-    println!("{}", "Session ended.".yellow());
-    Ok(())
+/// The longest suffix of `input` that fits in `cells` terminal columns, so
+/// the cursor stays on the input row however long the draft gets.
+fn visible_tail(input: &str, cells: usize) -> &str {
+    let mut used = 0;
+    for (index, c) in input.char_indices().rev() {
+        used += c.width().unwrap_or(0);
+        if used > cells {
+            return &input[index + c.len_utf8()..];
+        }
+    }
+    input
+}
+
+/// What the bottom row of a chat window holds.
+enum InputRow<'a> {
+    /// The draft being typed, after the `>>` prompt.
+    Draft(&'a str),
+    /// A bar in place of the prompt, for a window nothing can be sent from.
+    ReadOnly(&'a str),
 }
 
 fn draw_ui(
     stdout: &mut io::Stdout,
-    messages: &[String],
-    input_buffer: &str,
+    messages: &[&ChatLine],
+    input: InputRow,
     scroll_offset: usize,
+    status: Option<&str>,
+    indicator: Option<&str>,
+    header: &[String],
 ) -> io::Result<()> {
     let (cols, rows) = size()?;
     execute!(stdout, Clear(ClearType::All))?;
 
-    let available_lines = (rows as usize).saturating_sub(2);
+    let reserved = 2 + usize::from(indicator.is_some()) + header.len();
+    let available_lines = (rows as usize).saturating_sub(reserved);
 
-    let total_msgs = messages.len();
-    let end_index = total_msgs.saturating_sub(scroll_offset);
+    let screen_rows = wrapped_rows(messages, cols as usize);
+    let end_index = screen_rows.len().saturating_sub(scroll_offset);
     let start_index = end_index.saturating_sub(available_lines);
 
-    let slice = if start_index < messages.len() && end_index <= messages.len() {
-        &messages[start_index..end_index]
-    } else {
-        &[]
-    };
-
     execute!(stdout, cursor::MoveTo(0, 0))?;
-    for msg in slice {
-        print!("{}\r\n", msg);
+    for row in header {
+        print!("{}\r\n", row);
+    }
+    for row in &screen_rows[start_index..end_index] {
+        print!("{}\r\n", row);
+    }
+
+    if let Some(indicator) = indicator {
+        execute!(stdout, cursor::MoveTo(0, rows.saturating_sub(3)))?;
+        print!("{}", visible_tail(indicator, cols as usize).dimmed());
     }
 
     let separator_row = rows.saturating_sub(2);
     execute!(stdout, cursor::MoveTo(0, separator_row))?;
-    let line = "-".repeat(cols as usize);
+    let line = match status {
+        Some(status) => {
+            let label = format!("-- {} ", status);
+            let dashes = (cols as usize).saturating_sub(label.width());
+            format!("{}{}", label, "-".repeat(dashes))
+        }
+        None => "-".repeat(cols as usize),
+    };
     execute!(
         stdout,
         SetForegroundColor(Color::DarkGrey),
@@ -222,8 +1523,328 @@ fn draw_ui(
 
     let input_row = rows.saturating_sub(1);
     execute!(stdout, cursor::MoveTo(0, input_row))?;
-    print!("{} {}", ">>".green().bold(), input_buffer);
+    match input {
+        InputRow::Draft(draft) => print!(
+            "{} {}",
+            ">>".green().bold(),
+            visible_tail(draft, (cols as usize).saturating_sub(3))
+        ),
+        InputRow::ReadOnly(bar) => {
+            let bar = visible_tail(bar, cols as usize);
+            let fill = (cols as usize).saturating_sub(bar.width());
+            print!("{}", format!("{}{}", bar, " ".repeat(fill)).reversed());
+        }
+    }
 
     io::stdout().flush()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+    use std::net::TcpListener;
+    use std::sync::{Arc, RwLock};
+
+    /// A peer map with the peer whose key is `key` at each of `addrs`.
+    fn discovered(key: &SigningKey, addrs: &[SocketAddr]) -> HashMap<SocketAddr, PeerInfo> {
+        addrs
+            .iter()
+            .map(|&addr| {
+                let info = PeerInfo {
+                    identity: Some(key.verifying_key()),
+                    ..PeerInfo::legacy(Instant::now())
+                };
+                (addr, info)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bookmark_jumps_go_round_the_chat() {
+        let mut messages: Vec<ChatLine> = (0..6)
+            .map(|n| ChatLine::chat(format!("line {}", n), None))
+            .collect();
+        assert_eq!(next_bookmark(&messages, None), None);
+        for n in [4, 1] {
+            messages[n].bookmarked = true;
+        }
+        let mut at = None;
+        let jumps: Vec<usize> = (0..3)
+            .map(|_| {
+                at = next_bookmark(&messages, at);
+                at.unwrap()
+            })
+            .collect();
+        assert_eq!(jumps, [1, 4, 1]);
+        assert_eq!(next_bookmark(&messages, Some(2)), Some(4));
+        assert_eq!(next_bookmark(&messages, Some(5)), Some(1));
+    }
+
+    #[test]
+    fn notes_go_under_their_line() {
+        let mut line = ChatLine::chat("They >> the fix", None);
+        assert_eq!(line.rows(80).len(), 1);
+        line.note = Some("try this first".to_string());
+        line.bookmarked = true;
+        let rows = line.rows(80);
+        assert_eq!(rows.len(), 2);
+        assert!(rows[1].contains("try this first"));
+    }
+
+    #[test]
+    fn filtering_system_lines_keeps_the_view_in_place() {
+        // Every third line is a system notice; each line is one row.
+        let messages: Vec<ChatLine> = (0..30)
+            .map(|n| match n % 3 {
+                0 => ChatLine::system(format!("notice {}", n)),
+                _ => ChatLine::chat(format!("line {}", n), None),
+            })
+            .collect();
+        let (cols, height) = (80, 8);
+        let bottom = |show: bool, offset: usize| {
+            let lines = visible_lines(&messages, show);
+            lines[lines.len() - 1 - offset].text.clone()
+        };
+
+        for offset in 0..=scroll_limit(&visible_lines(&messages, true), cols, height) {
+            let hidden = refiltered_offset(&messages, offset, [true, false], cols, height);
+            let limit = scroll_limit(&visible_lines(&messages, false), cols, height);
+            assert!(hidden <= limit, "{} scrolls past the end", offset);
+            let was = bottom(true, offset);
+            if was.starts_with("line") && hidden < limit {
+                assert_eq!(bottom(false, hidden), was, "view jumped from {}", offset);
+            }
+
+            let shown = refiltered_offset(&messages, hidden, [false, true], cols, height);
+            assert!(shown <= scroll_limit(&visible_lines(&messages, true), cols, height));
+            assert_eq!(bottom(true, shown), bottom(false, hidden));
+        }
+        assert_eq!(
+            refiltered_offset(&messages, 0, [true, false], cols, height),
+            0,
+            "the newest line stays in view"
+        );
+    }
+
+    #[test]
+    fn poll_backoff_doubles_while_idle_up_to_the_cap() {
+        let start = Instant::now();
+        let mut backoff = PollBackoff::new(start);
+        assert_eq!(backoff.next_interval(start), POLL_ACTIVE);
+
+        let idle = start + ACTIVITY_WINDOW;
+        let waits: Vec<_> = (0..8).map(|_| backoff.next_interval(idle)).collect();
+        assert_eq!(waits[0], POLL_ACTIVE * 2);
+        assert_eq!(waits[1], POLL_ACTIVE * 4);
+        assert!(waits.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(*waits.last().unwrap(), POLL_IDLE_MAX);
+    }
+
+    #[test]
+    fn poll_backoff_snaps_back_on_activity() {
+        let start = Instant::now();
+        let mut backoff = PollBackoff::new(start);
+        let idle = start + ACTIVITY_WINDOW * 5;
+        for _ in 0..10 {
+            backoff.next_interval(idle);
+        }
+        assert_eq!(backoff.next_interval(idle), POLL_IDLE_MAX);
+
+        backoff.record_activity(idle);
+        assert_eq!(backoff.next_interval(idle), POLL_ACTIVE);
+        assert_eq!(
+            backoff.next_interval(idle + ACTIVITY_WINDOW / 2),
+            POLL_ACTIVE
+        );
+        // Once the window passes, it backs off again from the bottom.
+        assert_eq!(
+            backoff.next_interval(idle + ACTIVITY_WINDOW),
+            POLL_ACTIVE * 2
+        );
+    }
+
+    #[test]
+    fn unanswered_request_is_rejected_after_the_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut dialer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, peer_addr) = listener.accept().unwrap();
+        let start = Instant::now();
+        let request = PendingRequest {
+            stream,
+            peer_addr,
+            nickname: None,
+            last_chatted: None,
+            reconnecting: None,
+            deadline: start + request_timeout(),
+            answer: String::new(),
+        };
+        assert!(!request.expired_at(start));
+        assert!(!request.expired_at(start + request_timeout() - Duration::from_millis(1)));
+        assert!(request.expired_at(start + request_timeout()));
+
+        request.expire();
+        let mut signal = [0u8; 1];
+        dialer.read_exact(&mut signal).unwrap();
+        assert_eq!(signal[0], SIGNAL_REJECT);
+        assert_eq!(
+            dialer.read(&mut signal).unwrap(),
+            0,
+            "closed after rejecting"
+        );
+    }
+
+    #[test]
+    fn request_timeout_stays_below_the_peers_wait() {
+        set_request_timeout(Duration::from_secs(600));
+        assert!(request_timeout() < ANSWER_TIMEOUT);
+        set_request_timeout(Duration::ZERO);
+        assert_eq!(request_timeout(), Duration::from_secs(1));
+        set_request_timeout(Duration::from_secs(20));
+    }
+
+    #[test]
+    fn pings_go_out_at_once_then_once_per_interval() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(start);
+        assert!(heartbeat.ping_due(start));
+        assert!(!heartbeat.ping_due(start));
+        assert!(!heartbeat.ping_due(start + HEARTBEAT_INTERVAL - Duration::from_millis(1)));
+        assert!(heartbeat.ping_due(start + HEARTBEAT_INTERVAL));
+        assert!(!heartbeat.ping_due(start + HEARTBEAT_INTERVAL));
+    }
+
+    #[test]
+    fn only_a_pinging_peer_is_lost_to_silence() {
+        let start = Instant::now();
+        let mut heartbeat = Heartbeat::new(start);
+        let silent = start + HEARTBEAT_TIMEOUT * 3;
+        heartbeat.received(&crypto::Incoming::Chat("hi".to_string()), start);
+        assert!(!heartbeat.peer_lost(silent), "an old peer never pings");
+
+        heartbeat.received(&crypto::Incoming::Ping, start);
+        assert!(!heartbeat.peer_lost(start + HEARTBEAT_TIMEOUT - Duration::from_millis(1)));
+        assert!(heartbeat.peer_lost(start + HEARTBEAT_TIMEOUT));
+
+        let later = start + HEARTBEAT_TIMEOUT;
+        heartbeat.received(&crypto::Incoming::Chat("back".to_string()), later);
+        assert!(!heartbeat.peer_lost(later + HEARTBEAT_INTERVAL));
+        assert!(heartbeat.peer_lost(later + HEARTBEAT_TIMEOUT));
+    }
+
+    #[test]
+    fn an_expiry_proposal_waits_as_long_as_a_message() {
+        let sent = Instant::now();
+        let proposal = ExpireProposal {
+            ttl: Duration::from_secs(30),
+            sent,
+        };
+        assert!(!proposal.is_unanswered(sent + ACK_TIMEOUT - Duration::from_millis(1)));
+        assert!(proposal.is_unanswered(sent + ACK_TIMEOUT));
+    }
+
+    #[test]
+    fn input_tail_is_measured_in_cells() {
+        assert_eq!(visible_tail("hello", 10), "hello");
+        assert_eq!(visible_tail("hello", 3), "llo");
+        assert_eq!(visible_tail("日本語", 4), "本語");
+        assert_eq!(
+            visible_tail("日本語", 5),
+            "本語",
+            "never half a wide character"
+        );
+        assert_eq!(visible_tail("ae\u{301}", 1), "e\u{301}");
+    }
+
+    #[test]
+    fn first_sighting_settles_the_port_quietly() {
+        let key = SigningKey::generate(&mut OsRng);
+        let fingerprint = identity::fingerprint(&key.verifying_key());
+        let dialed_from: SocketAddr = "192.0.2.7:50123".parse().unwrap();
+        let listening: SocketAddr = "192.0.2.7:3001".parse().unwrap();
+        let peers: PeerMap = Arc::new(RwLock::new(discovered(&key, &[listening])));
+        let start = Instant::now();
+        let mut watch = DiscoveryWatch::new(&fingerprint, dialed_from);
+        assert!(!watch.refresh(&peers, start));
+        assert_eq!(watch.addr, listening);
+
+        peers.write().unwrap().clear();
+        assert!(watch.refresh(&peers, start + DISCOVERY_CHECK_INTERVAL));
+        assert_eq!(watch.hint().as_deref(), Some("peer no longer announcing"));
+    }
+
+    #[test]
+    fn reconnect_dials_where_the_peer_moved_mid_session() {
+        let key = SigningKey::generate(&mut OsRng);
+        let fingerprint = identity::fingerprint(&key.verifying_key());
+        let old = TcpListener::bind("127.0.0.1:0").unwrap();
+        let new = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (old_addr, new_addr) = (old.local_addr().unwrap(), new.local_addr().unwrap());
+        let peers: PeerMap = Arc::new(RwLock::new(discovered(&key, &[old_addr])));
+        let start = Instant::now();
+        let mut watch = DiscoveryWatch::new(&fingerprint, old_addr);
+        assert!(!watch.refresh(&peers, start));
+        assert_eq!(watch.hint(), None);
+
+        // Its beacons now come from elsewhere, which replaces the entry.
+        *peers.write().unwrap() = discovered(&key, &[new_addr]);
+        assert!(!watch.refresh(&peers, start), "checked once per interval");
+        assert!(watch.refresh(&peers, start + DISCOVERY_CHECK_INTERVAL));
+        assert_eq!(
+            watch.hint(),
+            Some(format!("peer address changed to {}", new_addr))
+        );
+
+        // The chat drops and the old address is gone.
+        drop(old);
+        let dialed = redial(peers.clone(), fingerprint, watch.addr);
+        let (mut accepted, _) = new.accept().unwrap();
+        accepted.write_all(&[SIGNAL_HOLD, SIGNAL_ACCEPT]).unwrap();
+        let stream = dialed
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), new_addr);
+    }
+
+    #[test]
+    fn a_rejected_reconnect_is_not_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peers: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let dialed = redial(peers, "nobody".to_string(), addr);
+        let (mut accepted, _) = listener.accept().unwrap();
+        accepted.write_all(&[SIGNAL_REJECT]).unwrap();
+        let err = dialed
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn a_reconnect_is_expected_from_where_the_peer_is_now() {
+        let key = SigningKey::generate(&mut OsRng);
+        let fingerprint = identity::fingerprint(&key.verifying_key());
+        let old_ip: IpAddr = "192.0.2.10".parse().unwrap();
+        let new_addr: SocketAddr = "192.0.2.11:3001".parse().unwrap();
+        let peers: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let now = Instant::now();
+        expect_reconnect(&fingerprint, old_ip, now + RECONNECT_WINDOW);
+        assert_eq!(
+            expected_reconnect(&peers, old_ip, now),
+            Some(fingerprint.clone())
+        );
+
+        *peers.write().unwrap() = discovered(&key, &[new_addr]);
+        assert_eq!(expected_reconnect(&peers, old_ip, now), None);
+        assert_eq!(
+            expected_reconnect(&peers, new_addr.ip(), now),
+            Some(fingerprint.clone())
+        );
+        let later = now + RECONNECT_WINDOW;
+        assert_eq!(expected_reconnect(&peers, new_addr.ip(), later), None);
+    }
+}
@@ -0,0 +1,302 @@
+//! `history view`: a peer's transcript shown in the chat window, read-only
+//! apart from its bookmarks. Lines are read from the log as they scroll
+//! into view rather than all at once, so a long history opens as quickly as
+//! a short one. There is no mouse capture, so the terminal selects and
+//! copies text as usual.
+
+use super::{ChatLine, InputRow, draw_ui};
+use crate::terminal::{self, TerminalGuard};
+use crate::transcript::{Entry, Marks, Reader};
+use chrono::{DateTime, Local, NaiveDate};
+use colored::*;
+use crossterm::event::{self, Event, KeyCode};
+use crossterm::terminal::size;
+use std::io;
+use std::net::IpAddr;
+
+/// Shows the transcript kept for `peer`, titled `label`, until the user
+/// presses q or Esc. With a `date`, it opens at the first line logged that
+/// day or later; otherwise at the end. Bookmarks and notes made in chats
+/// show up as they did there; `m` bookmarks the bottom line and `'` jumps
+/// to the next bookmark.
+pub fn view_history(peer: IpAddr, label: &str, date: Option<NaiveDate>) -> io::Result<()> {
+    let mut reader = Reader::open(peer)?;
+    if reader.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "the transcript is empty",
+        ));
+    }
+    let start = match date {
+        Some(date) => Some(reader.first_on(date)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("nothing was logged on {} or later", date),
+            )
+        })?),
+        None => None,
+    };
+
+    let marks = Marks::load(peer)?;
+
+    let _screen = TerminalGuard::fullscreen()?;
+    let mut view = View {
+        end: reader.len(),
+        reader,
+        peer,
+        marks,
+        label,
+        searching: None,
+        query: String::new(),
+        found: None,
+        notice: None,
+    };
+    if let Some(start) = start {
+        view.end = view.end_from(start)?;
+    }
+    loop {
+        view.draw()?;
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if let Some(query) = view.searching.as_mut() {
+            match key.code {
+                KeyCode::Esc => view.searching = None,
+                KeyCode::Enter => {
+                    view.query = view.searching.take().unwrap_or_default();
+                    view.search(true)?;
+                }
+                KeyCode::Backspace => {
+                    query.pop();
+                }
+                KeyCode::Char(c) => query.push(c),
+                _ => {}
+            }
+            continue;
+        }
+        view.notice = None;
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => break,
+            _ if terminal::is_interrupt(&key) => break,
+            KeyCode::Up | KeyCode::Char('k') => view.end = (view.end - 1).max(view.end_from(0)?),
+            KeyCode::Down | KeyCode::Char('j') => view.end = (view.end + 1).min(view.reader.len()),
+            KeyCode::PageUp => {
+                let (first, _) = view.window()?;
+                view.end = (first + 1).max(view.end_from(0)?);
+            }
+            KeyCode::PageDown => view.end = view.end_from(view.end - 1)?,
+            KeyCode::Home | KeyCode::Char('g') => view.end = view.end_from(0)?,
+            KeyCode::End | KeyCode::Char('G') => view.end = view.reader.len(),
+            KeyCode::Char('/') => view.searching = Some(String::new()),
+            KeyCode::Char('n') => view.search(true)?,
+            KeyCode::Char('N') => view.search(false)?,
+            KeyCode::Char('m') => view.toggle_bookmark(),
+            KeyCode::Char('\'') => view.next_bookmark()?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+struct View<'a> {
+    reader: Reader,
+    peer: IpAddr,
+    marks: Marks,
+    label: &'a str,
+    /// One past the line at the bottom of the screen.
+    end: usize,
+    /// What is being typed after `/`.
+    searching: Option<String>,
+    /// The last search, for `n` and `N`.
+    query: String,
+    /// The line the last search landed on, highlighted.
+    found: Option<usize>,
+    notice: Option<String>,
+}
+
+impl View<'_> {
+    /// Rows the lines get, between the status line and the bar.
+    fn height() -> io::Result<(usize, usize)> {
+        let (cols, rows) = size()?;
+        Ok((cols as usize, (rows as usize).saturating_sub(2)))
+    }
+
+    fn line(&mut self, n: usize) -> io::Result<ChatLine> {
+        let entry = self.reader.entry(n)?;
+        let start = self.reader.start(n);
+        Ok(ChatLine {
+            bookmarked: self.marks.is_bookmarked(start),
+            note: self.marks.note(start).map(str::to_string),
+            ..history_line(entry, self.found == Some(n))
+        })
+    }
+
+    /// Bookmarks the line at the bottom, or takes its bookmark off.
+    fn toggle_bookmark(&mut self) {
+        let start = self.reader.start(self.end - 1);
+        let bookmarked = self.marks.toggle(start);
+        self.notice = Some(match self.marks.save(self.peer) {
+            Ok(()) if bookmarked => format!("Bookmarked line {}", self.end),
+            Ok(()) => format!("Took the bookmark off line {}", self.end),
+            Err(e) => format!("Can't keep the bookmark: {}", e),
+        });
+    }
+
+    /// Brings the next bookmarked line below the bottom one to the
+    /// bottom, going round to the first after the last.
+    fn next_bookmark(&mut self) -> io::Result<()> {
+        let bottom = self.reader.start(self.end - 1);
+        let next = self
+            .marks
+            .next_bookmark(Some(bottom))
+            .and_then(|start| self.reader.line_at(start));
+        match next {
+            Some(n) => {
+                self.found = Some(n);
+                self.end = (n + 1).max(self.end_from(0)?);
+            }
+            None => self.notice = Some("No bookmarks yet; m bookmarks the bottom line".to_string()),
+        }
+        Ok(())
+    }
+
+    /// The lines that fill the screen above `end`, oldest first, with the
+    /// number of the first. A day's first line is headed by its date.
+    fn window(&mut self) -> io::Result<(usize, Vec<ChatLine>)> {
+        let (cols, rows) = View::height()?;
+        let mut lines: Vec<ChatLine> = Vec::new();
+        let mut used = 0;
+        let mut first = self.end;
+        // A row is kept for the date above the top line.
+        while first > 0 && used + 1 < rows {
+            first -= 1;
+            let line = self.line(first)?;
+            let day = line.at.date_naive();
+            if let Some(later) = lines.last()
+                && later.at.date_naive() != day
+            {
+                let header = day_header(later.at);
+                used += header.rows(cols).len();
+                lines.push(header);
+            }
+            used += line.rows(cols).len();
+            lines.push(line);
+        }
+        if let Some(top) = lines.last() {
+            lines.push(day_header(top.at));
+        }
+        lines.reverse();
+        Ok((first, lines))
+    }
+
+    /// Where `end` has to be for line `start` to be at the top, or the
+    /// last line at the bottom if the lines from `start` don't fill the
+    /// screen.
+    fn end_from(&mut self, start: usize) -> io::Result<usize> {
+        let (cols, rows) = View::height()?;
+        let mut end = start;
+        let mut used = 0;
+        while end < self.reader.len() && used < rows {
+            used += self.line(end)?.rows(cols).len();
+            end += 1;
+        }
+        // The line that overflowed is only partly on screen.
+        Ok(if used > rows { end - 1 } else { end }.max(start + 1))
+    }
+
+    /// Moves to the next line containing the query, ignoring case: older
+    /// than the one at the bottom, or with `older` false newer.
+    fn search(&mut self, older: bool) -> io::Result<()> {
+        if self.query.is_empty() {
+            return Ok(());
+        }
+        let needle = self.query.to_lowercase();
+        let bottom = self.end - 1;
+        let candidates: Box<dyn Iterator<Item = usize>> = if older {
+            Box::new((0..bottom).rev())
+        } else {
+            Box::new(bottom + 1..self.reader.len())
+        };
+        for n in candidates {
+            if self.reader.entry(n)?.text.to_lowercase().contains(&needle) {
+                self.found = Some(n);
+                self.end = n + 1;
+                return Ok(());
+            }
+        }
+        self.notice = Some(format!(
+            "No {} line contains \"{}\"",
+            if older { "older" } else { "newer" },
+            self.query
+        ));
+        Ok(())
+    }
+
+    fn draw(&mut self) -> io::Result<()> {
+        let (_, lines) = self.window()?;
+        let status = match (&self.searching, &self.notice) {
+            (Some(_), _) => "search (Enter finds older lines, Esc cancels)".to_string(),
+            (None, Some(notice)) => notice.clone(),
+            (None, None) => format!(
+                "history of {}, line {} of {}",
+                self.label,
+                self.end,
+                self.reader.len()
+            ),
+        };
+        let dash = if terminal::supports_unicode() {
+            "\u{2014}"
+        } else {
+            "-"
+        };
+        let bar = format!(
+            "read-only {} q to exit, / to search, n/N for older/newer, m/' to bookmark/jump",
+            dash
+        );
+        let input = match &self.searching {
+            Some(query) => InputRow::Draft(query),
+            None => InputRow::ReadOnly(&bar),
+        };
+        draw_ui(
+            &mut io::stdout(),
+            &lines.iter().collect::<Vec<_>>(),
+            input,
+            0,
+            Some(&status),
+            None,
+            &[],
+        )
+    }
+}
+
+/// A logged line as the chat window showed it, with the time it was
+/// logged. A line that doesn't parse is shown as it is.
+fn history_line(entry: Entry, found: bool) -> ChatLine {
+    let Some(at) = entry.at else {
+        return ChatLine {
+            at: DateTime::<Local>::default(),
+            ..ChatLine::system(entry.text)
+        };
+    };
+    let text = if found {
+        entry.text.reversed().to_string()
+    } else {
+        entry.text
+    };
+    let speaker = if entry.speaker == "You" {
+        entry.speaker.green()
+    } else {
+        entry.speaker.cyan()
+    };
+    ChatLine {
+        at: at.with_timezone(&Local),
+        ..ChatLine::chat(format!("{} >> {}", speaker, text), None)
+    }
+}
+
+fn day_header(at: DateTime<Local>) -> ChatLine {
+    ChatLine {
+        at,
+        ..ChatLine::system(format!("--- {} ---", at.format("%A %-d %B %Y")))
+    }
+}
@@ -0,0 +1,529 @@
+//! The host's side of a group room. Every member holds an ordinary chat
+//! with the host, each on its own thread with its own handshake and
+//! cipher; the room window fans messages out between them.
+
+use super::{
+    ChatLine, FRAMES_PER_TICK, Heartbeat, InputRow, PollBackoff, draw_ui, hung_up, max_scroll,
+    pin_notice, request_timeout, visible_lines,
+};
+use crate::aliases;
+use crate::crypto::{self, CryptoError};
+use crate::keystore::KeyStore;
+use crate::network;
+use crate::protocol::{FileMessage, RoomMessage, SIGNAL_ACCEPT, SIGNAL_REJECT};
+use crate::resources::Resources;
+use crate::shout;
+use crate::state::{self, PeerMap};
+use crate::terminal::{self, TerminalGuard};
+use colored::*;
+use crossterm::{
+    event::{self, Event, KeyCode},
+    execute,
+    terminal::{Clear, ClearType},
+};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Members besides the host, counting those still knocking.
+const ROOM_CAPACITY: usize = 8;
+/// How long a member thread waits for something to send before it checks
+/// the socket again.
+const MEMBER_POLL: Duration = Duration::from_millis(10);
+
+/// What the room window hands a member thread to send.
+enum Outgoing {
+    Chat(String),
+    Room(RoomMessage),
+}
+
+/// What member threads report to the room window.
+enum RoomEvent {
+    Joined {
+        id: usize,
+        nickname: Option<String>,
+        fingerprint: String,
+        sas: String,
+    },
+    Message {
+        id: usize,
+        text: String,
+    },
+    /// The last event from a member; its thread has ended.
+    Left {
+        id: usize,
+        reason: String,
+    },
+}
+
+/// Someone asking to join, waiting for `/admit` or `/deny`.
+struct Knock {
+    stream: TcpStream,
+    addr: SocketAddr,
+    nickname: Option<String>,
+    deadline: Instant,
+}
+
+impl Knock {
+    fn peer(&self) -> String {
+        match &self.nickname {
+            Some(name) => format!("{} ({})", name, self.addr),
+            None => self.addr.to_string(),
+        }
+    }
+}
+
+struct Member {
+    addr: SocketAddr,
+    /// Set once the handshake is done; until then the member is neither
+    /// listed nor sent anything.
+    name: Option<String>,
+    outbox: Sender<Outgoing>,
+    thread: JoinHandle<()>,
+}
+
+/// Opens room `name` and runs its window until the host presses Esc.
+/// Chat requests arriving on `requests` meanwhile ask to join it.
+pub fn host_room(
+    name: &str,
+    requests: &Receiver<TcpStream>,
+    peers: &PeerMap,
+    resources: &Resources,
+) -> io::Result<()> {
+    let screen = TerminalGuard::alternate_screen()?;
+    let raw = TerminalGuard::fullscreen()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, Clear(ClearType::All))?;
+
+    let host_name = crypto::nickname().unwrap_or_else(|| "Host".to_string());
+    let you = format!("[{}]", host_name);
+    let (events_tx, events) = mpsc::channel();
+    let mut members: BTreeMap<usize, Member> = BTreeMap::new();
+    let mut knocks: Vec<Knock> = Vec::new();
+    let mut next_id = 0;
+    let mut input_buffer = String::new();
+    let mut messages: Vec<ChatLine> = Vec::new();
+    let mut scroll_offset: usize = 0;
+
+    messages.push(ChatLine::system(format!(
+        "Hosting room {}. Peers who connect to you ask to join it.",
+        name
+    )));
+    messages.push(ChatLine::system(
+        "/admit or /deny the oldest request, /who lists members; Esc closes the room.",
+    ));
+    messages.push(ChatLine::system("---------------------------------"));
+
+    let mut shown_status = None;
+    let mut backoff = PollBackoff::new(Instant::now());
+    let mut needs_redraw = true;
+
+    loop {
+        if let Ok(stream) = requests.try_recv() {
+            resources.request_taken();
+            if let Some(knock) = knock(stream, peers, members.len() + knocks.len()) {
+                messages.push(ChatLine::system(format!(
+                    "{} wants to join; /admit or /deny (rejected in {}s).",
+                    knock.peer(),
+                    request_timeout().as_secs()
+                )));
+                knocks.push(knock);
+                needs_redraw = true;
+            }
+        }
+
+        let now = Instant::now();
+        for knock in knocks.extract_if(.., |k| now >= k.deadline || hung_up(&k.stream)) {
+            let mut stream = knock.stream;
+            let gone = if now >= knock.deadline {
+                let _ = stream.write_all(&[SIGNAL_REJECT]);
+                "no answer in time; rejected"
+            } else {
+                "stopped waiting"
+            };
+            messages.push(ChatLine::system(format!("{}: {}.", knock.addr, gone)));
+            needs_redraw = true;
+        }
+
+        let event = if event::poll(backoff.next_interval(Instant::now()))? {
+            Some(event::read()?)
+        } else {
+            None
+        };
+        if let Some(Event::Resize(..)) = event {
+            let max_scroll = max_scroll(&visible_lines(&messages, true), 0)?;
+            scroll_offset = scroll_offset.min(max_scroll);
+            needs_redraw = true;
+        }
+        if let Some(Event::Key(key)) = event {
+            backoff.record_activity(Instant::now());
+            match key.code {
+                KeyCode::Esc => break,
+                _ if terminal::is_interrupt(&key) => break,
+                KeyCode::Enter if input_buffer == "/admit" => {
+                    if knocks.is_empty() {
+                        messages.push(ChatLine::system("Nobody is asking to join."));
+                    } else {
+                        let knock = knocks.remove(0);
+                        match admit(knock, next_id, events_tx.clone()) {
+                            Ok(member) => {
+                                messages.push(ChatLine::system(format!(
+                                    "Admitted {}; securing the connection...",
+                                    member.addr
+                                )));
+                                members.insert(next_id, member);
+                                next_id += 1;
+                            }
+                            Err(e) => {
+                                messages.push(ChatLine::error(format!("Couldn't admit: {}", e)))
+                            }
+                        }
+                    }
+                    input_buffer.clear();
+                    needs_redraw = true;
+                }
+                KeyCode::Enter if input_buffer == "/deny" => {
+                    if knocks.is_empty() {
+                        messages.push(ChatLine::system("Nobody is asking to join."));
+                    } else {
+                        let mut knock = knocks.remove(0);
+                        let _ = knock.stream.write_all(&[SIGNAL_REJECT]);
+                        messages.push(ChatLine::system(format!("Turned {} away.", knock.peer())));
+                    }
+                    input_buffer.clear();
+                    needs_redraw = true;
+                }
+                KeyCode::Enter if input_buffer == "/who" => {
+                    let names: Vec<String> = std::iter::once(format!("{} (you)", host_name))
+                        .chain(member_names(&members))
+                        .collect();
+                    messages.push(ChatLine::system(format!(
+                        "In {}: {}",
+                        name,
+                        names.join(", ")
+                    )));
+                    input_buffer.clear();
+                    needs_redraw = true;
+                }
+                KeyCode::Enter if !input_buffer.is_empty() => {
+                    let text = std::mem::take(&mut input_buffer);
+                    send_to(&members, None, || Outgoing::Chat(text.clone()));
+                    messages.push(ChatLine::chat(format!("{} >> {}", you.green(), text), None));
+                    scroll_offset = 0;
+                    needs_redraw = true;
+                }
+                KeyCode::Char(c) => {
+                    input_buffer.push(c);
+                    needs_redraw = true;
+                }
+                KeyCode::Backspace => {
+                    input_buffer.pop();
+                    needs_redraw = true;
+                }
+                KeyCode::PageUp | KeyCode::Up => {
+                    let max_scroll = max_scroll(&visible_lines(&messages, true), 0)?;
+                    if scroll_offset < max_scroll {
+                        scroll_offset += 1;
+                        needs_redraw = true;
+                    }
+                }
+                KeyCode::PageDown | KeyCode::Down if scroll_offset > 0 => {
+                    scroll_offset -= 1;
+                    needs_redraw = true;
+                }
+                _ => {}
+            }
+        }
+
+        while let Ok(event) = events.try_recv() {
+            backoff.record_activity(Instant::now());
+            needs_redraw = true;
+            match event {
+                RoomEvent::Joined {
+                    id,
+                    nickname,
+                    fingerprint,
+                    sas,
+                } => {
+                    let Some(addr) = members.get(&id).map(|m| m.addr) else {
+                        continue;
+                    };
+                    let shown = aliases::name_for(&addr)
+                        .or(nickname)
+                        .unwrap_or_else(|| addr.to_string());
+                    let taken = shown == host_name
+                        || member_names(&members).any(|n| n.eq_ignore_ascii_case(&shown));
+                    let member_name = if taken {
+                        format!("{} ({})", shown, addr)
+                    } else {
+                        shown
+                    };
+                    messages.push(ChatLine::system(format!("{} joined.", member_name)));
+                    messages.extend(pin_notice(
+                        KeyStore::load().check(addr.ip(), &fingerprint),
+                        &fingerprint,
+                    ));
+                    messages.push(ChatLine::system(format!(
+                        "Verification code with {}: {}",
+                        member_name, sas
+                    )));
+                    let notice = format!("{} joined.", member_name);
+                    send_to(&members, Some(id), || {
+                        Outgoing::Room(RoomMessage::Notice(notice.clone()))
+                    });
+                    if let Some(member) = members.get_mut(&id) {
+                        member.name = Some(member_name);
+                    }
+                    send_members(name, &host_name, &members);
+                }
+                RoomEvent::Message { id, text } => {
+                    let Some(from) = members.get(&id).and_then(|m| m.name.clone()) else {
+                        continue;
+                    };
+                    messages.push(ChatLine::chat(
+                        format!("{} >> {}", format!("[{}]", from).cyan(), text),
+                        None,
+                    ));
+                    send_to(&members, Some(id), || {
+                        Outgoing::Room(RoomMessage::Relay {
+                            from: from.clone(),
+                            text: text.clone(),
+                        })
+                    });
+                }
+                RoomEvent::Left { id, reason } => {
+                    let Some(member) = members.remove(&id) else {
+                        continue;
+                    };
+                    let _ = member.thread.join();
+                    match member.name {
+                        Some(member_name) => {
+                            messages.push(ChatLine::system(format!(
+                                "{} left ({})",
+                                member_name, reason
+                            )));
+                            let notice = format!("{} left.", member_name);
+                            send_to(&members, None, || {
+                                Outgoing::Room(RoomMessage::Notice(notice.clone()))
+                            });
+                            send_members(name, &host_name, &members);
+                        }
+                        None => messages.push(ChatLine::error(format!(
+                            "Handshake with {} failed: {}",
+                            member.addr, reason
+                        ))),
+                    }
+                }
+            }
+        }
+
+        for shout in shout::take_received() {
+            messages.push(ChatLine::chat(shout.line().magenta().to_string(), None));
+            needs_redraw = true;
+        }
+
+        let joined = member_names(&members).count();
+        let status = Some(format!(
+            "room {} | {} member(s){}",
+            name,
+            joined,
+            match knocks.len() {
+                0 => String::new(),
+                n => format!(" | {} asking to join", n),
+            }
+        ));
+        if status != shown_status {
+            shown_status = status;
+            needs_redraw = true;
+        }
+
+        if needs_redraw {
+            draw_ui(
+                &mut stdout,
+                &visible_lines(&messages, true),
+                InputRow::Draft(&input_buffer),
+                scroll_offset,
+                shown_status.as_deref(),
+                None,
+                &[],
+            )?;
+            needs_redraw = false;
+        }
+    }
+
+    for mut knock in knocks {
+        let _ = knock.stream.write_all(&[SIGNAL_REJECT]);
+    }
+    send_to(&members, None, || {
+        Outgoing::Room(RoomMessage::Notice("The host closed the room.".to_string()))
+    });
+    // Dropping the outboxes ends each thread once it has sent what's queued.
+    for member in members.into_values() {
+        drop(member.outbox);
+        let _ = member.thread.join();
+    }
+
+    drop(raw);
+    drop(screen);
+    println!("{}", format!("Closed room {}.", name).yellow());
+    Ok(())
+}
+
+/// Holds an incoming request as a knock, or turns it away if the room is
+/// full. `None` if it was turned away or the peer already gave up.
+fn knock(mut stream: TcpStream, peers: &PeerMap, occupied: usize) -> Option<Knock> {
+    if hung_up(&stream) {
+        return None;
+    }
+    if occupied >= ROOM_CAPACITY {
+        let _ = stream.write_all(&[SIGNAL_REJECT]);
+        return None;
+    }
+    let addr = stream.peer_addr().ok()?;
+    Some(Knock {
+        nickname: aliases::name_for(&addr)
+            .or_else(|| state::nickname_at(&peers.read().unwrap(), addr.ip())),
+        addr,
+        stream,
+        deadline: Instant::now() + request_timeout(),
+    })
+}
+
+/// Accepts a knock and starts the thread that serves the new member.
+fn admit(knock: Knock, id: usize, events: Sender<RoomEvent>) -> io::Result<Member> {
+    let mut stream = knock.stream;
+    stream.write_all(&[SIGNAL_ACCEPT])?;
+    let (outbox, queue) = mpsc::channel();
+    let thread = thread::Builder::new()
+        .name("room-member".to_string())
+        .spawn(move || {
+            let reason = match serve_member(id, &mut stream, &queue, &events) {
+                Ok(()) => "room closed".to_string(),
+                Err(CryptoError::Disconnected) => "disconnected".to_string(),
+                Err(e) => e.to_string(),
+            };
+            let _ = events.send(RoomEvent::Left { id, reason });
+        })?;
+    Ok(Member {
+        addr: knock.addr,
+        name: None,
+        outbox,
+        thread,
+    })
+}
+
+/// Runs one member's session: the handshake, then passing its messages to
+/// the room and the room's to it, until either side goes away.
+fn serve_member(
+    id: usize,
+    stream: &mut TcpStream,
+    queue: &Receiver<Outgoing>,
+    events: &Sender<RoomEvent>,
+) -> Result<(), CryptoError> {
+    network::configure_session_socket(stream)?;
+    let handshake = crypto::perform_handshake(stream, crypto::Role::Responder, None)?;
+    let _ = events.send(RoomEvent::Joined {
+        id,
+        fingerprint: handshake.peer_fingerprint(),
+        nickname: handshake.peer_nickname,
+        sas: handshake.sas,
+    });
+    let mut cipher = handshake.cipher;
+    stream.set_nonblocking(true)?;
+    let mut heartbeat = Heartbeat::new(Instant::now());
+    // Chat frames from the member, confirmed once they reach the host.
+    let mut received: u64 = 0;
+    let mut acked: u64 = 0;
+
+    loop {
+        for _ in 0..FRAMES_PER_TICK {
+            let incoming = crypto::receive_and_decrypt(stream, &mut cipher);
+            if let Ok(Some(incoming)) = &incoming {
+                heartbeat.received(incoming, Instant::now());
+            }
+            if matches!(
+                incoming,
+                Ok(Some(crypto::Incoming::Chat(_))) | Err(CryptoError::InvalidUtf8)
+            ) {
+                received += 1;
+            }
+            match incoming {
+                Ok(None) => break,
+                Ok(Some(crypto::Incoming::Chat(text))) if !text.is_empty() => {
+                    let _ = events.send(RoomEvent::Message { id, text });
+                }
+                // Rooms don't carry files; decline so the sender isn't left
+                // waiting.
+                Ok(Some(crypto::Incoming::File(FileMessage::Offer { .. }))) => {
+                    crypto::send_file_message(stream, &mut cipher, &FileMessage::Refuse)?
+                }
+                Ok(Some(_)) | Err(CryptoError::InvalidUtf8) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if received > acked {
+            crypto::send_ack(stream, &mut cipher, received)?;
+            acked = received;
+        }
+
+        let sent = match queue.recv_timeout(MEMBER_POLL) {
+            Ok(Outgoing::Chat(text)) => crypto::encrypt_and_send(stream, &mut cipher, &text),
+            Ok(Outgoing::Room(message)) => crypto::send_room_message(stream, &mut cipher, &message),
+            Err(RecvTimeoutError::Timeout) => Ok(()),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        // A relayed message with the sender's name added can run over the
+        // limit; that member misses it, the room goes on.
+        match sent {
+            Ok(()) | Err(CryptoError::MessageTooLong) => {}
+            Err(e) => return Err(e),
+        }
+
+        if cipher.rekey_due() {
+            crypto::start_rekey(stream, &mut cipher)?;
+        }
+
+        let now = Instant::now();
+        if heartbeat.peer_lost(now) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "unreachable").into());
+        }
+        if heartbeat.ping_due(now) {
+            crypto::send_ping(stream, &mut cipher)?;
+        }
+    }
+}
+
+/// The names of the members who finished joining, in the order they came.
+fn member_names(members: &BTreeMap<usize, Member>) -> impl Iterator<Item = String> + '_ {
+    members.values().filter_map(|m| m.name.clone())
+}
+
+/// Queues `message()` for every member who finished joining, except
+/// `except`. A member whose thread has ended is skipped; its `Left` event
+/// is on the way.
+fn send_to(
+    members: &BTreeMap<usize, Member>,
+    except: Option<usize>,
+    message: impl Fn() -> Outgoing,
+) {
+    for (id, member) in members {
+        if member.name.is_some() && Some(*id) != except {
+            let _ = member.outbox.send(message());
+        }
+    }
+}
+
+fn send_members(room: &str, host_name: &str, members: &BTreeMap<usize, Member>) {
+    let names: Vec<String> = std::iter::once(host_name.to_string())
+        .chain(member_names(members))
+        .collect();
+    send_to(members, None, || {
+        Outgoing::Room(RoomMessage::Members {
+            room: room.to_string(),
+            names: names.clone(),
+        })
+    });
+}
@@ -0,0 +1,2257 @@
+//! Chats as tabs. Every open chat is a `ChatSession` whose connection is
+//! read by a thread of its own, which also pings, acknowledges and rekeys,
+//! so a chat stays up while another tab or the prompt is in front. `Tabs`
+//! switches between them; tab 0 is the prompt.
+
+use super::{
+    ACK_TIMEOUT, CLOCK_12H, ChatLine, ChatOpen, DiscoveryWatch, ExpireProposal, FRAMES_PER_TICK,
+    HELD, Heartbeat, InputRow, LineKind, PEER_DISCONNECTED, PEER_UNREACHABLE, PollBackoff,
+    READ_RECEIPTS, RECONNECT_WINDOW, Typing, WHEEL_ROWS, draw_ui, expect_reconnect, format_age,
+    format_last_activity, handle_file_message, log_line, lost_reason, max_scroll, next_bookmark,
+    offer_file, parse_ttl, pasted_file, pasted_line, pin_notice, redial, refiltered_offset,
+    send_chunks, speaker_labels, start_transcript, status_line, verify_notice, visible_lines, wrap,
+};
+use crate::aliases;
+use crate::clipboard;
+use crate::crypto::{self, CryptoError};
+use crate::filter::{self, Outgoing};
+use crate::keystore::{KeyStore, PinStatus};
+use crate::network;
+use crate::outbox;
+use crate::pad::Pad;
+use crate::protocol::{self, Features, FileMessage, MAX_PAD_LINE_LEN, PadEdit, RoomMessage};
+use crate::shout;
+use crate::state::PeerMap;
+use crate::terminal::{self, TerminalGuard};
+use crate::transcript::{self, Marks, Reader, Transcript};
+use crate::transfer::{self, Receiving, Resume, Sending};
+use chrono::Local;
+use colored::*;
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers, MouseEventKind},
+    terminal::size,
+};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use unicode_width::UnicodeWidthStr;
+
+/// Rows above the history taken by the tab bar.
+const TAB_BAR_ROWS: usize = 1;
+/// Frames a reader thread holds for a window that is busy elsewhere
+/// before it leaves the rest in the socket. File chunks make this up to
+/// 16 MiB.
+const WAITING_MAX: usize = 256;
+/// The status line while a message is being picked.
+const SELECT_HINT: &str =
+    "picking: Up/Down move, m bookmarks, ' jumps to the next bookmark, Esc is done";
+
+/// A session's connection, shared by its window and its reader thread.
+/// Each side locks it for whole frames, so theirs never interleave.
+struct Link {
+    stream: TcpStream,
+    cipher: crypto::CipherState,
+}
+
+type Frame = Result<crypto::Incoming, CryptoError>;
+
+/// What a key press in a chat asks of the tabs around it.
+enum Action {
+    Stay,
+    Close,
+    Switch(usize),
+}
+
+/// One open chat: its history and input line, and the thread reading it.
+pub struct ChatSession {
+    link: Arc<Mutex<Link>>,
+    /// Frames in the order the reader thread took them. A connection
+    /// error is the last thing it sends.
+    frames: Receiver<Frame>,
+    stop: Arc<AtomicBool>,
+    peer_socket: SocketAddr,
+    /// Our alias for the peer, else the name it sent, else its address.
+    label: String,
+    you: String,
+    they: String,
+    fingerprint: String,
+    sas: String,
+    discovery: DiscoveryWatch,
+    input_buffer: String,
+    messages: Vec<ChatLine>,
+    scroll_offset: usize,
+    message_ttl: Option<Duration>,
+    /// Our last `/expire` while the peer hasn't agreed to it.
+    expire_proposal: Option<ExpireProposal>,
+    /// What the peer's build supports, as it said at the start of the
+    /// chat; `None` from builds that don't say.
+    peer_features: Option<Features>,
+    held: filter::Held,
+    show_system: bool,
+    started_at: Instant,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+    // Chat frames each way, which number them for acks.
+    sent_count: u64,
+    /// Our messages the peer hasn't confirmed yet, by id, kept for the
+    /// outbox should the connection drop.
+    unconfirmed: Vec<(u64, String)>,
+    received_count: u64,
+    // What has been on screen, and what the peer was told of that.
+    shown_count: u64,
+    read_count: u64,
+    sending: Option<Sending>,
+    receiving: Option<Receiving>,
+    // The room and its members, once the peer turns out to be hosting one.
+    room: Option<(String, Vec<String>)>,
+    pad: Pad,
+    /// Whether the pad is split off above the history; see `/pad`.
+    show_pad: bool,
+    transcript: Option<Transcript>,
+    typing: Typing,
+    shown_typing: bool,
+    typing_line: String,
+    shown_status: Option<String>,
+    // Wheel scrolling until `/mouse off` hands the mouse back to the
+    // terminal for selecting text without Shift.
+    mouse: bool,
+    /// Whether Up and Down move the highlight (see `select_key`) rather
+    /// than scroll.
+    selecting: bool,
+    /// Messages that came while the tab was in the background.
+    unread: usize,
+    /// Set once the connection is gone; the tab stays until it is closed.
+    ended: bool,
+    /// Whether this side dialed, and so dials again should the connection
+    /// drop; see `reconnect`.
+    dialed: bool,
+    /// Set when the connection dropped, until the tabs start reconnecting.
+    dropped: bool,
+    /// A reconnect under way, on the side that dialed.
+    redial: Option<Receiver<io::Result<TcpStream>>>,
+    /// Until when a reconnect carries the chat on in this tab.
+    reconnect_until: Option<Instant>,
+    needs_redraw: bool,
+    backoff: PollBackoff,
+}
+
+impl ChatSession {
+    /// Runs the handshake on `stream`, printing on the prompt's screen, and
+    /// opens the session. `None` if the handshake failed, after saying why,
+    /// or if the peer didn't present the `trusted` key it was accepted for.
+    pub(super) fn start(
+        stream: TcpStream,
+        role: crypto::Role,
+        initial_message: Option<&str>,
+        passphrase: Option<&str>,
+        trusted: Option<&str>,
+    ) -> io::Result<Option<Self>> {
+        network::configure_session_socket(&stream)?;
+        println!("Performing Secure Handshake...");
+
+        let handshake = match crypto::perform_handshake(&stream, role, passphrase) {
+            Ok(h) => h,
+            Err(CryptoError::ConfirmationFailed) => {
+                println!(
+                    "{}",
+                    "Handshake key confirmation failed — possible interference.".red()
+                );
+                if passphrase.is_some() {
+                    println!("Check that you both typed the same passphrase.");
+                } else {
+                    println!("The peer may have used a passphrase; both sides need the same one.");
+                }
+                return Ok(None);
+            }
+            Err(e) => {
+                println!("Handshake failed: {}", e);
+                return Ok(None);
+            }
+        };
+
+        let fingerprint = handshake.peer_fingerprint();
+        let peer_socket = stream.peer_addr()?;
+        if let Some(trusted) = trusted
+            && trusted != fingerprint
+        {
+            println!(
+                "{}",
+                format!(
+                    "Refused {}: it presented {}, not the trusted key {}.",
+                    peer_socket, fingerprint, trusted
+                )
+                .red()
+            );
+            return Ok(None);
+        }
+        // Our alias for the peer wins over the name it sent.
+        let peer_name = aliases::name_for(&peer_socket).or(handshake.peer_nickname.clone());
+        let (you, they) = speaker_labels(crypto::nickname().as_deref(), peer_name.as_deref());
+        stream.set_nonblocking(true)?;
+
+        let mut messages = vec![
+            ChatLine::system(match &peer_name {
+                Some(name) => format!("Connected to {} ({}).", name, peer_socket),
+                None => format!("Connected to {}.", peer_socket),
+            }),
+            ChatLine::system(format!(
+                "End-to-End Encrypted ({}).",
+                handshake.suite.name()
+            )),
+            ChatLine::system("Press 'Esc' to disconnect, Ctrl+Left/Right to switch tabs."),
+            ChatLine::system("---------------------------------"),
+        ];
+        let pin = KeyStore::load().check(peer_socket.ip(), &fingerprint);
+        let key_changed = matches!(pin, Ok(PinStatus::Changed { .. }));
+        messages.extend(pin_notice(pin, &fingerprint));
+        messages.push(ChatLine::system(verify_notice(&handshake.sas)));
+
+        let socket = stream.try_clone()?;
+        let link = Arc::new(Mutex::new(Link {
+            stream,
+            cipher: handshake.cipher,
+        }));
+        let (frames_tx, frames) = mpsc::sync_channel(FRAMES_PER_TICK);
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let link = link.clone();
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("chat".to_string())
+                .spawn(move || read_frames(&link, &socket, &frames_tx, &stop))?;
+        }
+
+        let now = Instant::now();
+        let discovery = DiscoveryWatch::new(&fingerprint, peer_socket);
+        let mut session = ChatSession {
+            link,
+            frames,
+            stop,
+            peer_socket,
+            label: peer_name
+                .clone()
+                .unwrap_or_else(|| peer_socket.ip().to_string()),
+            you,
+            they,
+            fingerprint,
+            sas: handshake.sas,
+            discovery,
+            input_buffer: String::new(),
+            messages,
+            scroll_offset: 0,
+            message_ttl: None,
+            expire_proposal: None,
+            peer_features: None,
+            held: filter::Held::default(),
+            show_system: true,
+            started_at: now,
+            last_sent: None,
+            last_received: None,
+            sent_count: 0,
+            unconfirmed: Vec::new(),
+            received_count: 0,
+            shown_count: 0,
+            read_count: 0,
+            sending: None,
+            receiving: None,
+            room: None,
+            pad: Pad::new(role),
+            show_pad: false,
+            transcript: None,
+            typing: Typing::new(now),
+            shown_typing: false,
+            typing_line: format!(
+                "{} is typing{}",
+                peer_name.as_deref().unwrap_or("Peer"),
+                terminal::ellipsis()
+            ),
+            shown_status: None,
+            mouse: true,
+            selecting: false,
+            unread: 0,
+            ended: false,
+            dialed: role == crypto::Role::Initiator,
+            dropped: false,
+            redial: None,
+            reconnect_until: None,
+            needs_redraw: true,
+            backoff: PollBackoff::new(now),
+        };
+        if transcript::is_enabled() {
+            start_transcript(
+                &mut session.transcript,
+                &mut session.messages,
+                peer_socket.ip(),
+            );
+        }
+
+        // Before anything else, so the peer knows what it may ask of us.
+        {
+            let link = &mut *session.link.lock().unwrap();
+            if let Err(e) =
+                crypto::send_features(&mut link.stream, &mut link.cipher, Features::LOCAL)
+            {
+                session
+                    .messages
+                    .push(ChatLine::error(format!("Error: {}", e)));
+            }
+        }
+
+        // What didn't reach this peer last time goes out before anything
+        // new. The outbox is keyed by identity, so a changed key gets none.
+        match outbox::take(&session.fingerprint) {
+            Ok(queued) if !queued.is_empty() => {
+                session.messages.push(ChatLine::system(format!(
+                    "Sending {} message(s) kept from an earlier chat.",
+                    queued.len()
+                )));
+                let mut queued = queued.into_iter();
+                for text in queued.by_ref() {
+                    if session.send_message(&text).is_err() {
+                        session.record_unsent(text);
+                        break;
+                    }
+                }
+                for text in queued {
+                    session.record_unsent(text);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => session.messages.push(ChatLine::error(format!(
+                "Could not read the messages kept for this peer: {}",
+                e
+            ))),
+        }
+
+        // Don't auto-send anything to a peer that might not be who it was.
+        if let Some(msg) = initial_message.filter(|_| !key_changed)
+            && let Err(e) = session.send_message(msg)
+        {
+            session
+                .messages
+                .push(ChatLine::error(format!("Error: {}", e)));
+        }
+        Ok(Some(session))
+    }
+
+    /// Sends a chat message and shows it, numbered for the ack.
+    fn send_message(&mut self, text: &str) -> Result<(), CryptoError> {
+        {
+            let link = &mut *self.link.lock().unwrap();
+            crypto::encrypt_and_send(&mut link.stream, &mut link.cipher, text)?;
+        }
+        self.record_sent(text.to_string());
+        Ok(())
+    }
+
+    /// Shows a message that couldn't be sent because the connection broke.
+    /// It joins the unconfirmed ones, so it ends up in the outbox; it was
+    /// never numbered, and no ack can cover it.
+    fn record_unsent(&mut self, text: String) {
+        self.messages.push(ChatLine::unsent(
+            format!("{} >> {}", self.you.green(), text),
+            self.message_ttl,
+        ));
+        self.unconfirmed.push((self.sent_count, text));
+    }
+
+    /// Shows a message that just went out and numbers it for the ack.
+    fn record_sent(&mut self, text: String) {
+        self.last_sent = Some(Instant::now());
+        self.typing.message_sent();
+        let logged = self.log("You", &text);
+        self.messages.push(ChatLine {
+            logged,
+            ..ChatLine::sent(
+                format!("{} >> {}", self.you.green(), text),
+                self.message_ttl,
+                self.sent_count,
+            )
+        });
+        self.unconfirmed.push((self.sent_count, text));
+        self.sent_count += 1;
+    }
+
+    /// Records a chat line in the transcript, unless messages disappear:
+    /// nothing is written that would have to be deleted later. Returns
+    /// where the line starts in the log.
+    fn log(&mut self, speaker: &str, text: &str) -> Option<u64> {
+        if self.message_ttl.is_some() {
+            return None;
+        }
+        log_line(&mut self.transcript, &mut self.messages, speaker, text)
+    }
+
+    /// The highlighted line, if there is one.
+    fn selected(&self) -> Option<usize> {
+        self.messages.iter().position(|m| m.selected)
+    }
+
+    /// Highlights line `n`, or nothing with `None`, and scrolls it into
+    /// view.
+    fn select(&mut self, n: Option<usize>) -> io::Result<()> {
+        for line in &mut self.messages {
+            line.selected = false;
+        }
+        if let Some(n) = n {
+            self.messages[n].selected = true;
+            self.scroll_to(n)?;
+        }
+        self.needs_redraw = true;
+        Ok(())
+    }
+
+    /// Moves the highlight to the chat message above it, or with `older`
+    /// false below it. It stays put at either end.
+    fn select_step(&mut self, older: bool) -> io::Result<()> {
+        let from = self.selected();
+        let is_chat = |line: &ChatLine| line.kind == LineKind::Chat;
+        let to = match (from, older) {
+            (Some(from), true) => self.messages[..from].iter().rposition(is_chat),
+            (Some(from), false) => self.messages[from + 1..]
+                .iter()
+                .position(is_chat)
+                .map(|n| from + 1 + n),
+            (None, _) => self.messages.iter().rposition(is_chat),
+        };
+        if to.is_some() {
+            self.select(to)?;
+        }
+        Ok(())
+    }
+
+    /// Scrolls just far enough for line `n` to be on screen.
+    fn scroll_to(&mut self, n: usize) -> io::Result<()> {
+        let (cols, rows) = size()?;
+        let cols = cols as usize;
+        let view = (rows as usize).saturating_sub(2 + TAB_BAR_ROWS + self.pad_rows()?.len());
+        let lines = visible_lines(&self.messages, self.show_system);
+        let Some(at) = lines
+            .iter()
+            .position(|line| std::ptr::eq(*line, &self.messages[n]))
+        else {
+            return Ok(());
+        };
+        let below: usize = lines[at + 1..]
+            .iter()
+            .map(|line| line.rows(cols).len())
+            .sum();
+        let lowest = (below + lines[at].rows(cols).len())
+            .saturating_sub(view)
+            .min(below);
+        self.scroll_offset = self.scroll_offset.clamp(lowest, below);
+        Ok(())
+    }
+
+    /// Handles `key` while picking a message: Up and Down move the
+    /// highlight, `m` bookmarks the message, `'` jumps to the next
+    /// bookmark, and Esc or Enter puts the highlight away. Any other key
+    /// ends picking and is handled as usual (returning false), leaving the
+    /// highlight for `/note`.
+    fn select_key(&mut self, key: KeyEvent) -> io::Result<bool> {
+        match key.code {
+            KeyCode::Up => self.select_step(true)?,
+            KeyCode::Down => self.select_step(false)?,
+            KeyCode::Char('m') => self.toggle_bookmark(),
+            KeyCode::Char('\'') => match next_bookmark(&self.messages, self.selected()) {
+                Some(n) => self.select(Some(n))?,
+                None => {
+                    self.messages.push(ChatLine::system(
+                        "No bookmarks in this chat yet; m bookmarks the highlighted message.",
+                    ));
+                    self.needs_redraw = true;
+                }
+            },
+            KeyCode::Esc | KeyCode::Enter => {
+                self.selecting = false;
+                self.select(None)?;
+            }
+            _ => {
+                self.selecting = false;
+                self.needs_redraw = true;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Bookmarks the highlighted message, or takes its bookmark off. Only
+    /// a logged message keeps it past this chat.
+    fn toggle_bookmark(&mut self) {
+        let Some(n) = self.selected() else {
+            return;
+        };
+        let line = &mut self.messages[n];
+        line.bookmarked = !line.bookmarked;
+        let (bookmarked, logged) = (line.bookmarked, line.logged);
+        match logged {
+            Some(start) => self.update_marks(|marks| {
+                if marks.is_bookmarked(start) != bookmarked {
+                    marks.toggle(start);
+                }
+            }),
+            None if bookmarked => self.messages.push(ChatLine::system(
+                "Bookmarked for this chat only: the message isn't in the log (see /log).",
+            )),
+            None => {}
+        }
+        self.needs_redraw = true;
+    }
+
+    /// `/note <text>` puts a private note under the highlighted message,
+    /// or the newest one, and `/note` alone takes it off. Notes are never
+    /// sent; a logged message keeps its note with its bookmarks.
+    fn note_command(&mut self, text: &str) -> io::Result<()> {
+        let target = self.selected().or_else(|| {
+            self.messages
+                .iter()
+                .rposition(|line| line.kind == LineKind::Chat)
+        });
+        let Some(n) = target else {
+            self.messages
+                .push(ChatLine::system("There's no message to put a note on yet."));
+            return Ok(());
+        };
+        let line = &mut self.messages[n];
+        line.note = (!text.is_empty()).then(|| text.to_string());
+        match line.logged {
+            Some(start) => self.update_marks(|marks| marks.set_note(start, text)),
+            None if !text.is_empty() => self.messages.push(ChatLine::system(
+                "Noted for this chat only: the message isn't in the log (see /log).",
+            )),
+            None => {}
+        }
+        self.select(None)
+    }
+
+    /// `/bookmarks` lists this chat's bookmarks after those the log keeps
+    /// from earlier chats, numbered; `/bookmarks <n>` highlights one of
+    /// this chat's.
+    fn bookmarks_command(&mut self, arg: &str) -> io::Result<()> {
+        let earlier = self.earlier_bookmarks();
+        let here: Vec<usize> = (0..self.messages.len())
+            .filter(|&n| self.messages[n].bookmarked)
+            .collect();
+        if arg.is_empty() {
+            if earlier.is_empty() && here.is_empty() {
+                self.messages.push(ChatLine::system(
+                    "No bookmarks yet: Alt+Up picks a message and m bookmarks it.",
+                ));
+                return Ok(());
+            }
+            let mut list = vec![ChatLine::system(
+                "Bookmarks (/bookmarks <n> highlights one from this chat):",
+            )];
+            for (i, line) in earlier.iter().enumerate() {
+                list.push(ChatLine::system(format!(
+                    "{:>3}. {} (an earlier chat)",
+                    i + 1,
+                    line
+                )));
+            }
+            for (i, &n) in here.iter().enumerate() {
+                let line = &self.messages[n];
+                list.push(ChatLine::system(format!(
+                    "{:>3}. {} {}",
+                    earlier.len() + i + 1,
+                    line.at.format("%Y-%m-%d %H:%M:%S"),
+                    line.text
+                )));
+            }
+            self.messages.extend(list);
+            return Ok(());
+        }
+        match arg.parse::<usize>() {
+            Ok(n) if (1..=earlier.len()).contains(&n) => {
+                self.messages.push(ChatLine::system(format!(
+                    "Bookmark {} is from an earlier chat; `history view` shows it, and ' there jumps to it.",
+                    n
+                )))
+            }
+            Ok(n) if n > earlier.len() && n - earlier.len() <= here.len() => {
+                self.selecting = true;
+                self.select(Some(here[n - earlier.len() - 1]))?;
+            }
+            _ => self.messages.push(ChatLine::system(
+                "Usage: /bookmarks [n], with n from the list /bookmarks shows",
+            )),
+        }
+        Ok(())
+    }
+
+    /// The bookmarked lines of the peer's log that weren't logged by this
+    /// chat, as logged, oldest first.
+    fn earlier_bookmarks(&mut self) -> Vec<String> {
+        let peer = self.peer_socket.ip();
+        let marks = match Marks::load(peer) {
+            Ok(marks) => marks,
+            Err(e) => {
+                self.messages.push(ChatLine::error(format!(
+                    "Can't read the bookmarks kept with the log: {}",
+                    e
+                )));
+                return Vec::new();
+            }
+        };
+        let mut earlier = marks
+            .bookmarks()
+            .filter(|&start| !self.messages.iter().any(|m| m.logged == Some(start)))
+            .peekable();
+        if earlier.peek().is_none() {
+            return Vec::new();
+        }
+        let mut reader = match Reader::open(peer) {
+            Ok(reader) => reader,
+            Err(e) => {
+                self.messages.push(ChatLine::error(format!(
+                    "Can't read the log the bookmarks are in: {}",
+                    e
+                )));
+                return Vec::new();
+            }
+        };
+        let lines: Vec<usize> = earlier.filter_map(|start| reader.line_at(start)).collect();
+        lines
+            .into_iter()
+            .filter_map(|n| reader.entry(n).ok())
+            .map(|entry| {
+                let at = entry.at.map_or(String::new(), |at| {
+                    at.with_timezone(&Local)
+                        .format("%Y-%m-%d %H:%M:%S ")
+                        .to_string()
+                });
+                format!("{}{} >> {}", at, entry.speaker, entry.text)
+            })
+            .collect()
+    }
+
+    /// Changes the marks kept with the peer's log, saying so if it can't.
+    fn update_marks(&mut self, change: impl FnOnce(&mut Marks)) {
+        let peer = self.peer_socket.ip();
+        let result = Marks::load(peer).and_then(|mut marks| {
+            change(&mut marks);
+            marks.save(peer)
+        });
+        if let Err(e) = result {
+            self.messages
+                .push(ChatLine::error(format!("Can't keep the mark: {}", e)));
+        }
+    }
+
+    fn max_scroll(&self) -> io::Result<usize> {
+        max_scroll(
+            &visible_lines(&self.messages, self.show_system),
+            TAB_BAR_ROWS + self.pad_rows()?.len(),
+        )
+    }
+
+    /// The pad split off above the history: a title row, then the lines,
+    /// numbered for `/pad edit`. It takes at most a third of the window,
+    /// showing the last lines if they don't all fit.
+    fn pad_rows(&self) -> io::Result<Vec<String>> {
+        if !self.show_pad {
+            return Ok(Vec::new());
+        }
+        let (cols, rows) = size()?;
+        let cols = cols as usize;
+        let mut lines: Vec<String> = self
+            .pad
+            .lines()
+            .enumerate()
+            .flat_map(|(n, text)| wrap(&format!("{:>2}. {}", n + 1, text), cols))
+            .collect();
+        let hidden = lines
+            .len()
+            .saturating_sub((rows as usize / 3).saturating_sub(1).max(1));
+        lines.drain(..hidden);
+
+        let title = match (self.pad.lines().count(), hidden) {
+            (0, _) => "pad: empty, /pad add <text> to write on it".to_string(),
+            (n, 0) => format!("pad: {} line(s)", n),
+            (n, _) => format!("pad: {} line(s), the last shown", n),
+        };
+        let title = format!("-- {} ", title);
+        let dashes = cols.saturating_sub(title.width());
+        let mut rows = vec![
+            format!("{}{}", title, "-".repeat(dashes))
+                .dimmed()
+                .to_string(),
+        ];
+        rows.extend(lines);
+        Ok(rows)
+    }
+
+    /// `/pad` shows or hides the pad, `/pad add`, `/pad edit` and `/pad
+    /// del` change it on both sides, and `/pad save` writes it to a file.
+    fn pad_command(&mut self, arg: &str, connection_lost: &mut Option<&'static str>) {
+        let (verb, rest) = arg.split_once(' ').unwrap_or((arg, ""));
+        let rest = rest.trim();
+        let edit = match verb {
+            "" => {
+                self.show_pad = !self.show_pad;
+                return;
+            }
+            "save" if !rest.is_empty() => {
+                match self.pad.save(Path::new(rest)) {
+                    Ok(n) => self.messages.push(ChatLine::system(format!(
+                        "Saved {} pad line(s) to {}.",
+                        n, rest
+                    ))),
+                    Err(e) => self.messages.push(ChatLine::error(format!(
+                        "Can't save the pad to {}: {}",
+                        rest, e
+                    ))),
+                }
+                return;
+            }
+            "add" | "edit" | "del" if self.ended => {
+                self.messages.push(ChatLine::system(
+                    "This chat has ended; the pad can still be saved with /pad save <file>.",
+                ));
+                return;
+            }
+            "add" if !rest.is_empty() => self.pad_text(rest).and_then(|text| {
+                let edit = self.pad.add(text);
+                if edit.is_none() {
+                    self.messages.push(ChatLine::system(format!(
+                        "The pad is full ({} lines).",
+                        protocol::MAX_PAD_LINES
+                    )));
+                }
+                edit
+            }),
+            "edit" => match rest.split_once(' ') {
+                Some((n, text)) => match n.parse() {
+                    Ok(n) => self
+                        .pad_text(text.trim())
+                        .and_then(|text| self.pad_line(n, text)),
+                    Err(_) => self.pad_usage(),
+                },
+                None => self.pad_usage(),
+            },
+            "del" => match rest.parse() {
+                Ok(n) => self.pad_line(n, ""),
+                Err(_) => self.pad_usage(),
+            },
+            _ => self.pad_usage(),
+        };
+        let Some(edit) = edit else {
+            return;
+        };
+        self.show_pad = true;
+        let link = &mut *self.link.lock().unwrap();
+        if let Err(e) = crypto::send_pad(&mut link.stream, &mut link.cipher, &edit) {
+            *connection_lost = Some(lost_reason(&e));
+        }
+    }
+
+    /// `text` if it can go on the pad, else `None` after saying why.
+    fn pad_text<'a>(&mut self, text: &'a str) -> Option<&'a str> {
+        if protocol::valid_pad_line(text) {
+            return Some(text);
+        }
+        self.messages
+            .push(ChatLine::system(if text.len() > MAX_PAD_LINE_LEN {
+                format!("Pad lines are at most {} bytes.", MAX_PAD_LINE_LEN)
+            } else {
+                "Pad lines need some text, on one line.".to_string()
+            }));
+        None
+    }
+
+    /// Rewrites line `n` of the pad, or removes it with empty `text`.
+    fn pad_line(&mut self, n: usize, text: &str) -> Option<PadEdit> {
+        let edit = self.pad.edit(n, text);
+        if edit.is_none() {
+            self.messages
+                .push(ChatLine::system(format!("The pad has no line {}.", n)));
+        }
+        edit
+    }
+
+    fn pad_usage(&mut self) -> Option<PadEdit> {
+        self.messages.push(ChatLine::system(
+            "Usage: /pad [add <text> | edit <n> <text> | del <n> | save <file>]",
+        ));
+        None
+    }
+
+    fn handle_event(&mut self, event: Event) -> io::Result<Action> {
+        let mut connection_lost: Option<&str> = None;
+        match event {
+            Event::Mouse(wheel) => {
+                self.backoff.record_activity(Instant::now());
+                let before = self.scroll_offset;
+                match wheel.kind {
+                    MouseEventKind::ScrollUp => {
+                        let max_scroll = self.max_scroll()?;
+                        if self.scroll_offset < max_scroll {
+                            self.scroll_offset = (self.scroll_offset + WHEEL_ROWS).min(max_scroll);
+                        }
+                    }
+                    MouseEventKind::ScrollDown => {
+                        self.scroll_offset = self.scroll_offset.saturating_sub(WHEEL_ROWS);
+                    }
+                    _ => {}
+                }
+                self.needs_redraw |= self.scroll_offset != before;
+            }
+            Event::Resize(..) => {
+                // Rows rewrap at the new width, so the old offset may now be
+                // past the top.
+                self.scroll_offset = self.scroll_offset.min(self.max_scroll()?);
+                self.needs_redraw = true;
+            }
+            Event::Key(key) => {
+                let action = self.handle_key(key, &mut connection_lost)?;
+                if let Some(reason) = connection_lost {
+                    self.lost(reason);
+                }
+                return Ok(action);
+            }
+            _ => {}
+        }
+        Ok(Action::Stay)
+    }
+
+    fn handle_key(
+        &mut self,
+        key: KeyEvent,
+        connection_lost: &mut Option<&'static str>,
+    ) -> io::Result<Action> {
+        self.backoff.record_activity(Instant::now());
+        if self.selecting && self.select_key(key)? {
+            return Ok(Action::Stay);
+        }
+        self.typing.key_pressed(Instant::now());
+        // Pasted text that fits on the input line takes the place of
+        // `/paste` and is sent below like a typed message; anything else
+        // is offered as a file. Both go through the filter.
+        if key.code == KeyCode::Enter && self.input_buffer == "/paste" && !self.ended {
+            self.input_buffer.clear();
+            self.needs_redraw = true;
+            match clipboard::read() {
+                Ok(clipboard::Contents::Text(text)) if pasted_line(&text).is_some() => {
+                    self.input_buffer = pasted_line(&text).unwrap_or_default().to_string();
+                }
+                Ok(contents) => match self.held.check(Outgoing::Paste(contents)) {
+                    Ok(outgoing) => self.send_outgoing(outgoing, connection_lost),
+                    Err(category) => self.messages.push(ChatLine::error(format!(
+                        "Not pasted: the clipboard looks like it contains a {}. Type /send-anyway to offer it anyway.",
+                        category
+                    ))),
+                },
+                Err(e) => self
+                    .messages
+                    .push(ChatLine::error(format!("Can't paste: {}", e))),
+            }
+        }
+        match key.code {
+            KeyCode::Esc => return Ok(Action::Close),
+            _ if terminal::is_interrupt(&key) => return Ok(Action::Close),
+            KeyCode::Enter
+                if self.input_buffer == "/switch" || self.input_buffer.starts_with("/switch ") =>
+            {
+                let arg = self.input_buffer["/switch".len()..].trim().to_string();
+                self.input_buffer.clear();
+                self.needs_redraw = true;
+                match arg.parse() {
+                    Ok(n) => return Ok(Action::Switch(n)),
+                    Err(_) => self
+                        .messages
+                        .push(ChatLine::system("Usage: /switch <n> (0 is the prompt)")),
+                }
+            }
+            KeyCode::Enter
+                if self.input_buffer == "/pad" || self.input_buffer.starts_with("/pad ") =>
+            {
+                let arg = self.input_buffer["/pad".len()..].trim().to_string();
+                self.pad_command(&arg, connection_lost);
+                self.scroll_offset = self.scroll_offset.min(self.max_scroll()?);
+                self.input_buffer.clear();
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter
+                if self.input_buffer == "/bookmarks"
+                    || self.input_buffer.starts_with("/bookmarks ") =>
+            {
+                let arg = self.input_buffer["/bookmarks".len()..].trim().to_string();
+                self.input_buffer.clear();
+                self.bookmarks_command(&arg)?;
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter
+                if self.input_buffer == "/note" || self.input_buffer.starts_with("/note ") =>
+            {
+                let text = self.input_buffer["/note".len()..].trim().to_string();
+                self.input_buffer.clear();
+                self.note_command(&text)?;
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter if self.ended && !self.input_buffer.is_empty() => {
+                self.messages.push(ChatLine::system(
+                    "This chat has ended; press Esc to close it.",
+                ));
+                self.input_buffer.clear();
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter if self.input_buffer.starts_with("/expire") => {
+                let arg = self.input_buffer["/expire".len()..].trim();
+                match parse_ttl(arg) {
+                    Some(ttl) => {
+                        self.message_ttl = ttl;
+                        self.expire_proposal = None;
+                        // A build that says nothing may still agree.
+                        let honoured = self
+                            .peer_features
+                            .is_none_or(|features| features.contains(Features::DISAPPEARING));
+                        let notice = match (ttl, honoured) {
+                            (Some(ttl), true) => format!(
+                                "New messages will disappear after {}s, and stay out of the transcript. Asking {} to do the same...",
+                                ttl.as_secs(),
+                                self.label
+                            ),
+                            (Some(ttl), false) => format!(
+                                "New messages will disappear from this screen after {}s, and stay out of the transcript. {} can't delete messages, so they stay on its side.",
+                                ttl.as_secs(),
+                                self.label
+                            ),
+                            (None, true) => format!(
+                                "Disappearing messages off. Asking {} to do the same...",
+                                self.label
+                            ),
+                            (None, false) => "Disappearing messages off.".to_string(),
+                        };
+                        self.messages.push(ChatLine::system(notice));
+                        if honoured {
+                            let link = &mut *self.link.lock().unwrap();
+                            match crypto::send_expire(
+                                &mut link.stream,
+                                &mut link.cipher,
+                                ttl,
+                                false,
+                            ) {
+                                Ok(()) => {
+                                    self.expire_proposal = ttl.map(|ttl| ExpireProposal {
+                                        ttl,
+                                        sent: Instant::now(),
+                                    })
+                                }
+                                Err(e) => *connection_lost = Some(lost_reason(&e)),
+                            }
+                        }
+                    }
+                    None => self
+                        .messages
+                        .push(ChatLine::system("Usage: /expire <seconds>|off")),
+                }
+                self.input_buffer.clear();
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter if self.input_buffer.starts_with("/filter") => {
+                let show_system = match self.input_buffer["/filter".len()..].trim() {
+                    "system on" => true,
+                    "system off" => false,
+                    _ => {
+                        self.messages
+                            .push(ChatLine::system("Usage: /filter system on|off"));
+                        self.show_system
+                    }
+                };
+                // Keep the same line at the bottom of the view.
+                let (cols, rows) = size()?;
+                let view_height =
+                    (rows as usize).saturating_sub(2 + TAB_BAR_ROWS + self.pad_rows()?.len());
+                self.scroll_offset = refiltered_offset(
+                    &self.messages,
+                    self.scroll_offset,
+                    [self.show_system, show_system],
+                    cols as usize,
+                    view_height,
+                );
+                self.show_system = show_system;
+                self.input_buffer.clear();
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter if self.input_buffer.starts_with("/mouse") => {
+                match self.input_buffer["/mouse".len()..].trim() {
+                    "on" if !self.mouse => {
+                        self.mouse = true;
+                        self.messages
+                            .push(ChatLine::system("Mouse wheel scrolls the history."));
+                    }
+                    "off" if self.mouse => {
+                        self.mouse = false;
+                        self.messages.push(ChatLine::system(
+                            "Mouse handed back to the terminal; scroll with PageUp/PageDown.",
+                        ));
+                    }
+                    "on" | "off" => {}
+                    _ => self.messages.push(ChatLine::system("Usage: /mouse on|off")),
+                }
+                self.input_buffer.clear();
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter if self.input_buffer.starts_with("/receipts") => {
+                match self.input_buffer["/receipts".len()..].trim() {
+                    "on" => {
+                        READ_RECEIPTS.store(true, Ordering::Relaxed);
+                        self.messages.push(ChatLine::system(
+                            "Peers will see when you've read their messages.",
+                        ));
+                    }
+                    "off" => {
+                        READ_RECEIPTS.store(false, Ordering::Relaxed);
+                        self.messages.push(ChatLine::system(
+                            "Read receipts off; peers still see your messages delivered.",
+                        ));
+                    }
+                    _ => self
+                        .messages
+                        .push(ChatLine::system("Usage: /receipts on|off")),
+                }
+                self.input_buffer.clear();
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter if self.input_buffer.starts_with("/time") => {
+                match self.input_buffer["/time".len()..].trim() {
+                    "12h" => CLOCK_12H.store(true, Ordering::Relaxed),
+                    "24h" => CLOCK_12H.store(false, Ordering::Relaxed),
+                    _ => self.messages.push(ChatLine::system("Usage: /time 12h|24h")),
+                }
+                self.input_buffer.clear();
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter if self.input_buffer.starts_with("/log") => {
+                match self.input_buffer["/log".len()..].trim() {
+                    "on" if self.transcript.is_some() => self
+                        .messages
+                        .push(ChatLine::system("Already logging this chat.")),
+                    "on" => start_transcript(
+                        &mut self.transcript,
+                        &mut self.messages,
+                        self.peer_socket.ip(),
+                    ),
+                    "off" if self.transcript.take().is_some() => {
+                        self.messages.push(ChatLine::system("Logging stopped."))
+                    }
+                    "off" => self
+                        .messages
+                        .push(ChatLine::system("Not logging this chat.")),
+                    _ => self.messages.push(ChatLine::system("Usage: /log on|off")),
+                }
+                self.input_buffer.clear();
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter if self.input_buffer == "/verify" => {
+                self.messages
+                    .push(ChatLine::system(verify_notice(&self.sas)));
+                self.input_buffer.clear();
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter if self.input_buffer == "/who" => {
+                self.messages.push(ChatLine::system(match &self.room {
+                    Some((name, members)) => {
+                        format!("Room {}: {}", name, members.join(", "))
+                    }
+                    None => "Just the two of you; the peer isn't hosting a room.".to_string(),
+                }));
+                self.input_buffer.clear();
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter if self.input_buffer == "/stats" => {
+                let now = Instant::now();
+                // The socket's times count every frame, pings and acks
+                // too, so they show whether the link itself is alive.
+                let (socket_read, socket_written) = {
+                    let link = self.link.lock().unwrap();
+                    (link.cipher.last_read(), link.cipher.last_written())
+                };
+                let stats = format!(
+                    "Session open {} | last sent {} | last received {} | socket read {}, written {} | peer {}",
+                    format_age(now.duration_since(self.started_at)),
+                    format_last_activity(self.last_sent, now),
+                    format_last_activity(self.last_received, now),
+                    format_last_activity(socket_read, now),
+                    format_last_activity(socket_written, now),
+                    self.fingerprint,
+                );
+                self.messages.push(ChatLine::system(stats));
+                self.input_buffer.clear();
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter
+                if self.input_buffer == "/send" || self.input_buffer.starts_with("/send ") =>
+            {
+                let path = self.input_buffer["/send".len()..].trim();
+                if path.is_empty() {
+                    self.messages.push(ChatLine::system("Usage: /send <path>"));
+                } else if let Some(file) = &self.sending {
+                    self.messages.push(ChatLine::system(format!(
+                        "Still sending {}; /cancel it first.",
+                        file.name
+                    )));
+                } else {
+                    match Sending::open(Path::new(path)) {
+                        Ok(file) => {
+                            let link = &mut *self.link.lock().unwrap();
+                            if let Err(e) = offer_file(
+                                &mut link.stream,
+                                &mut link.cipher,
+                                file,
+                                &mut self.sending,
+                                &mut self.messages,
+                            ) {
+                                *connection_lost = Some(lost_reason(&e));
+                            }
+                        }
+                        Err(e) => self
+                            .messages
+                            .push(ChatLine::error(format!("Can't send {}: {}", path, e))),
+                    }
+                }
+                self.input_buffer.clear();
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter if self.input_buffer == "/accept" => {
+                match self.receiving.as_mut().filter(|file| !file.is_accepted()) {
+                    Some(file) => {
+                        let resume = file.resume();
+                        let answer = match file.accept() {
+                            Ok(offset) => {
+                                if offset > 0 {
+                                    self.messages.push(ChatLine::system(format!(
+                                        "Resuming {} at {}.",
+                                        file.name,
+                                        transfer::percent(offset, file.size)
+                                    )));
+                                } else {
+                                    if matches!(resume, Resume::From(received) if received > 0) {
+                                        self.messages.push(ChatLine::error(format!(
+                                            "The partial {} was changed on disk; starting over.",
+                                            file.name
+                                        )));
+                                    }
+                                    self.messages.push(ChatLine::system(format!(
+                                        "Receiving {} ({}).",
+                                        file.name,
+                                        transfer::format_size(file.size)
+                                    )));
+                                }
+                                FileMessage::Accept { offset }
+                            }
+                            Err(e) => {
+                                self.messages.push(ChatLine::error(format!(
+                                    "Can't save {}: {}",
+                                    file.name, e
+                                )));
+                                self.receiving = None;
+                                FileMessage::Refuse
+                            }
+                        };
+                        let link = &mut *self.link.lock().unwrap();
+                        if let Err(e) =
+                            crypto::send_file_message(&mut link.stream, &mut link.cipher, &answer)
+                        {
+                            *connection_lost = Some(lost_reason(&e));
+                        }
+                    }
+                    None => self
+                        .messages
+                        .push(ChatLine::system("No file offer to accept.")),
+                }
+                self.input_buffer.clear();
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter if self.input_buffer == "/decline" => {
+                match self.receiving.take_if(|file| !file.is_accepted()) {
+                    Some(file) => {
+                        self.messages
+                            .push(ChatLine::system(format!("Declined {}.", file.name)));
+                        let link = &mut *self.link.lock().unwrap();
+                        if let Err(e) = crypto::send_file_message(
+                            &mut link.stream,
+                            &mut link.cipher,
+                            &FileMessage::Refuse,
+                        ) {
+                            *connection_lost = Some(lost_reason(&e));
+                        }
+                    }
+                    None => self
+                        .messages
+                        .push(ChatLine::system("No file offer to decline.")),
+                }
+                self.input_buffer.clear();
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter if self.input_buffer == "/cancel" => {
+                let mut answers = Vec::new();
+                if let Some(file) = self.sending.take() {
+                    self.messages
+                        .push(ChatLine::system(format!("Stopped sending {}.", file.name)));
+                    answers.push(FileMessage::Abort);
+                }
+                if let Some(file) = self.receiving.take() {
+                    self.messages.push(ChatLine::system(if file.is_accepted() {
+                        format!("Stopped receiving {}; partial file removed.", file.name)
+                    } else {
+                        format!("Declined {}.", file.name)
+                    }));
+                    answers.push(FileMessage::Refuse);
+                }
+                if answers.is_empty() {
+                    self.messages
+                        .push(ChatLine::system("No file transfer to cancel."));
+                }
+                let link = &mut *self.link.lock().unwrap();
+                for answer in answers {
+                    if let Err(e) =
+                        crypto::send_file_message(&mut link.stream, &mut link.cipher, &answer)
+                    {
+                        *connection_lost = Some(lost_reason(&e));
+                        break;
+                    }
+                }
+                self.input_buffer.clear();
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter if self.input_buffer == "/send-anyway" => {
+                self.input_buffer.clear();
+                match self.held.release() {
+                    Some(outgoing) => self.send_outgoing(outgoing, connection_lost),
+                    None => self
+                        .messages
+                        .push(ChatLine::system("Nothing is waiting to be sent.")),
+                }
+                self.needs_redraw = true;
+            }
+            KeyCode::Enter if !self.input_buffer.is_empty() => {
+                let text = std::mem::take(&mut self.input_buffer);
+                match self.held.check(Outgoing::Message(text)) {
+                    Ok(outgoing) => self.send_outgoing(outgoing, connection_lost),
+                    Err(category) => self.messages.push(ChatLine::error(format!(
+                        "Not sent: message looks like it contains a {}. Type /send-anyway to send it.",
+                        category
+                    ))),
+                }
+                self.needs_redraw = true;
+            }
+            KeyCode::Char(c) => {
+                self.input_buffer.push(c);
+                self.needs_redraw = true;
+            }
+            KeyCode::Backspace => {
+                self.input_buffer.pop();
+                self.needs_redraw = true;
+            }
+            KeyCode::Up if key.modifiers.contains(KeyModifiers::ALT) => {
+                // A highlight left for `/note` is where picking resumes.
+                self.selecting = true;
+                if self.selected().is_none() {
+                    self.select_step(true)?;
+                }
+                if self.selected().is_none() {
+                    self.selecting = false;
+                    self.messages
+                        .push(ChatLine::system("There's no message to pick yet."));
+                }
+                self.needs_redraw = true;
+            }
+            KeyCode::PageUp | KeyCode::Up => {
+                let max_scroll = self.max_scroll()?;
+                if self.scroll_offset < max_scroll {
+                    self.scroll_offset += 1;
+                    self.needs_redraw = true;
+                }
+            }
+            KeyCode::PageDown | KeyCode::Down if self.scroll_offset > 0 => {
+                self.scroll_offset -= 1;
+                self.needs_redraw = true;
+            }
+            _ => {}
+        }
+        Ok(Action::Stay)
+    }
+
+    /// Takes what the reader thread passed on and keeps transfers, hints
+    /// and receipts going, whether or not the tab is `in_front`.
+    fn tick(&mut self, peers: &PeerMap, in_front: bool) {
+        if self.ended {
+            return;
+        }
+        let mut connection_lost: Option<&str> = None;
+        let mut peer_left = false;
+
+        for _ in 0..FRAMES_PER_TICK {
+            let frame = match self.frames.try_recv() {
+                Ok(frame) => frame,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    connection_lost = Some(PEER_DISCONNECTED);
+                    break;
+                }
+            };
+            self.backoff.record_activity(Instant::now());
+            match frame {
+                Ok(crypto::Incoming::Bye) => peer_left = true,
+                Ok(incoming) => self.handle_incoming(incoming, in_front, &mut connection_lost),
+                Err(e @ CryptoError::DecryptFailed { .. }) => {
+                    self.messages.push(ChatLine::error(e.to_string()));
+                    connection_lost = Some("Session closed: the message stream can't be trusted.");
+                }
+                Err(CryptoError::InvalidUtf8) => {
+                    // Still a chat frame, so it counts towards the receipt.
+                    self.received_count += 1;
+                    self.messages.push(ChatLine::error(
+                        "Dropped a message from the peer that isn't valid text.",
+                    ));
+                    self.needs_redraw = true;
+                }
+                Err(e @ (CryptoError::MalformedFrame(_) | CryptoError::FrameTooLarge(_))) => {
+                    self.messages
+                        .push(ChatLine::error(format!("Bad frame from peer: {}", e)));
+                    connection_lost = Some("Session closed.");
+                }
+                Err(e) => connection_lost = Some(lost_reason(&e)),
+            }
+            if connection_lost.is_some() || peer_left {
+                break;
+            }
+        }
+
+        if let Some(file) = self.receiving.take_if(|file| file.is_complete()) {
+            let name = file.name.clone();
+            match file.finish() {
+                Ok(path) => self.messages.push(ChatLine::system(format!(
+                    "Saved {} to {} (SHA-256 verified).",
+                    name,
+                    path.display()
+                ))),
+                Err(e) => self
+                    .messages
+                    .push(ChatLine::error(format!("Couldn't save {}: {}", name, e))),
+            }
+            self.needs_redraw = true;
+        }
+
+        if connection_lost.is_none() && !peer_left {
+            let link = &mut *self.link.lock().unwrap();
+            if let Some(file) = self.sending.as_mut()
+                && file.is_accepted()
+            {
+                self.backoff.record_activity(Instant::now());
+                match send_chunks(&mut link.stream, &mut link.cipher, file) {
+                    Ok(None) => {}
+                    Ok(Some(line)) => {
+                        self.messages.push(line);
+                        self.sending = None;
+                        self.needs_redraw = true;
+                    }
+                    Err(e) => connection_lost = Some(lost_reason(&e)),
+                }
+            }
+
+            if connection_lost.is_none()
+                && self.shown_count > self.read_count
+                && READ_RECEIPTS.load(Ordering::Relaxed)
+            {
+                match crypto::send_read(&mut link.stream, &mut link.cipher, self.shown_count) {
+                    Ok(()) => self.read_count = self.shown_count,
+                    Err(e) => connection_lost = Some(lost_reason(&e)),
+                }
+            }
+
+            if connection_lost.is_none()
+                && let Some(hint) = self.typing.due(&self.input_buffer, Instant::now())
+                && let Err(e) = crypto::send_typing(&mut link.stream, &mut link.cipher, hint)
+            {
+                connection_lost = Some(lost_reason(&e));
+            }
+        }
+
+        if let Some(reason) = connection_lost {
+            self.lost(reason);
+            return;
+        }
+        if peer_left {
+            let line = ChatLine::system(format!("{} ended the chat.", self.label));
+            self.end(line);
+            return;
+        }
+
+        let now = Instant::now();
+        if self.discovery.refresh(peers, now) {
+            self.needs_redraw = true;
+        }
+
+        let mut went_late = false;
+        for message in self.messages.iter_mut() {
+            went_late |= message.check_late(now);
+        }
+        if went_late {
+            self.messages.push(ChatLine::system(format!(
+                "The peer hasn't confirmed a message for {}s (marked !); it may be asleep or disconnected.",
+                ACK_TIMEOUT.as_secs()
+            )));
+            self.needs_redraw = true;
+        }
+        if let Some(proposal) = self
+            .expire_proposal
+            .take_if(|proposal| proposal.is_unanswered(now))
+        {
+            self.messages.push(ChatLine::system(format!(
+                "{} hasn't agreed to delete messages after {}s; its build may not support disappearing messages, so they may stay on its side.",
+                self.label,
+                proposal.ttl.as_secs()
+            )));
+            self.needs_redraw = true;
+        }
+        if self.messages.iter().any(|m| m.is_expired(now)) {
+            self.messages.retain(|m| !m.is_expired(now));
+            self.needs_redraw = true;
+        }
+
+        let status = status_line(
+            &self.discovery,
+            self.sending.as_ref(),
+            self.receiving.as_ref(),
+        );
+        if status != self.shown_status {
+            self.shown_status = status;
+            self.needs_redraw = true;
+        }
+
+        if self.typing.peer_typing(now) != self.shown_typing {
+            self.shown_typing = !self.shown_typing;
+            self.needs_redraw = true;
+        }
+    }
+
+    fn handle_incoming(
+        &mut self,
+        incoming: crypto::Incoming,
+        in_front: bool,
+        connection_lost: &mut Option<&'static str>,
+    ) {
+        match incoming {
+            crypto::Incoming::Chat(msg) => {
+                self.last_received = Some(Instant::now());
+                self.received_count += 1;
+                self.typing.peer_message();
+                if !msg.is_empty() {
+                    let logged = self.log("They", &msg);
+                    self.messages.push(ChatLine {
+                        logged,
+                        ..ChatLine::chat(
+                            format!("{} >> {}", self.they.cyan(), msg),
+                            self.message_ttl,
+                        )
+                    });
+                    self.unread += usize::from(!in_front);
+                    self.needs_redraw = true;
+                }
+            }
+            // Shouts come on connections of their own, and `tick` sees to
+            // goodbyes.
+            crypto::Incoming::Control
+            | crypto::Incoming::Ping
+            | crypto::Incoming::Shout(_)
+            | crypto::Incoming::Bye => {}
+            crypto::Incoming::Expire { ttl, agreed: true } => {
+                // An answer to an older proposal is stale.
+                if ttl == self.message_ttl {
+                    self.expire_proposal = None;
+                    let notice = match ttl {
+                        Some(ttl) => format!(
+                            "{} will delete messages after {}s too.",
+                            self.label,
+                            ttl.as_secs()
+                        ),
+                        None => format!("{} turned disappearing messages off too.", self.label),
+                    };
+                    self.messages.push(ChatLine::system(notice));
+                    self.needs_redraw = true;
+                }
+            }
+            crypto::Incoming::Expire { ttl, agreed: false } => {
+                self.message_ttl = ttl;
+                self.expire_proposal = None;
+                let notice = match ttl {
+                    Some(ttl) => format!(
+                        "{} turned on disappearing messages: new messages go after {}s, and stay out of the transcript.",
+                        self.label,
+                        ttl.as_secs()
+                    ),
+                    None => format!("{} turned disappearing messages off.", self.label),
+                };
+                self.messages.push(ChatLine::system(notice));
+                self.needs_redraw = true;
+                let link = &mut *self.link.lock().unwrap();
+                if let Err(e) = crypto::send_expire(&mut link.stream, &mut link.cipher, ttl, true) {
+                    *connection_lost = Some(lost_reason(&e));
+                }
+            }
+            crypto::Incoming::Features(features) => {
+                self.peer_features = Some(features);
+                if !features.contains(Features::DISAPPEARING)
+                    && let Some(proposal) = self.expire_proposal.take()
+                {
+                    self.messages.push(ChatLine::system(format!(
+                        "{} can't delete messages, so they stay on its side; yours still go after {}s.",
+                        self.label,
+                        proposal.ttl.as_secs()
+                    )));
+                    self.needs_redraw = true;
+                }
+            }
+            crypto::Incoming::Typing(peer_typing) => {
+                self.typing.peer_hint(peer_typing, Instant::now());
+            }
+            crypto::Incoming::Ack(received) => {
+                self.unconfirmed.retain(|(id, _)| *id >= received);
+                for message in self.messages.iter_mut() {
+                    self.needs_redraw |= message.acknowledge(received);
+                }
+            }
+            crypto::Incoming::Read(shown) => {
+                self.unconfirmed.retain(|(id, _)| *id >= shown);
+                for message in self.messages.iter_mut() {
+                    self.needs_redraw |= message.mark_read(shown);
+                }
+            }
+            crypto::Incoming::Pad(edit) => {
+                if self.pad.apply(edit) {
+                    if !self.show_pad {
+                        self.messages.push(ChatLine::system(format!(
+                            "{} changed the pad; /pad shows it.",
+                            self.label
+                        )));
+                        self.unread += usize::from(!in_front);
+                    }
+                    self.needs_redraw = true;
+                }
+            }
+            crypto::Incoming::Rekeyed => {
+                self.messages.push(ChatLine::system("Session rekeyed."));
+                self.needs_redraw = true;
+            }
+            crypto::Incoming::Room(message) => {
+                self.last_received = Some(Instant::now());
+                match message {
+                    RoomMessage::Relay { from, text } => {
+                        let logged = self.log(&from, &text);
+                        self.messages.push(ChatLine {
+                            logged,
+                            ..ChatLine::chat(
+                                format!("{} >> {}", format!("[{}]", from).cyan(), text),
+                                self.message_ttl,
+                            )
+                        });
+                        self.unread += usize::from(!in_front);
+                    }
+                    RoomMessage::Notice(text) => self.messages.push(ChatLine::system(text)),
+                    RoomMessage::Members { room: name, names } => {
+                        if self.room.is_none() {
+                            self.messages.push(ChatLine::system(format!(
+                                "Joined room {} ({} here; /who lists them).",
+                                name,
+                                names.len()
+                            )));
+                        }
+                        self.room = Some((name, names));
+                    }
+                }
+                self.needs_redraw = true;
+            }
+            crypto::Incoming::File(message) => {
+                let answer = handle_file_message(
+                    message,
+                    &mut self.sending,
+                    &mut self.receiving,
+                    &self.fingerprint,
+                    &self.label,
+                    &mut self.messages,
+                    &mut self.needs_redraw,
+                );
+                if let Some(answer) = answer {
+                    let link = &mut *self.link.lock().unwrap();
+                    if let Err(e) =
+                        crypto::send_file_message(&mut link.stream, &mut link.cipher, &answer)
+                    {
+                        *connection_lost = Some(lost_reason(&e));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Ends the session after the connection broke, leaving the tab open
+    /// with `reason` as its last line.
+    fn lost(&mut self, reason: &str) {
+        self.dropped = [PEER_DISCONNECTED, PEER_UNREACHABLE].contains(&reason);
+        self.end(ChatLine::error(reason));
+    }
+
+    /// After the connection dropped, the side that dialed dials again,
+    /// wherever the peer was last discovered, and the other side takes
+    /// that without asking; either way the chat carries on in this tab.
+    fn reconnect(&mut self, peers: &PeerMap) {
+        let now = Instant::now();
+        self.reconnect_until = Some(now + RECONNECT_WINDOW);
+        let addr = self.discovery.addr;
+        let line = if self.dialed {
+            self.redial = Some(redial(peers.clone(), self.fingerprint.clone(), addr));
+            format!(
+                "Reconnecting to {} at {}{}",
+                self.label,
+                addr,
+                terminal::ellipsis()
+            )
+        } else {
+            expect_reconnect(&self.fingerprint, addr.ip(), now + RECONNECT_WINDOW);
+            format!(
+                "Waiting {}s for {} to reconnect{}",
+                RECONNECT_WINDOW.as_secs(),
+                self.label,
+                terminal::ellipsis()
+            )
+        };
+        self.messages.push(ChatLine::system(line));
+        self.needs_redraw = true;
+    }
+
+    /// The connection a reconnect got, once the peer accepted it.
+    fn redialed(&mut self) -> Option<TcpStream> {
+        let dialed = match self.redial.as_ref()?.try_recv() {
+            Err(TryRecvError::Empty) => return None,
+            Ok(dialed) => dialed,
+            Err(TryRecvError::Disconnected) => Err(io::ErrorKind::Interrupted.into()),
+        };
+        self.redial = None;
+        match dialed {
+            Ok(stream) => Some(stream),
+            Err(e) => {
+                self.messages.push(ChatLine::error(format!(
+                    "Couldn't reconnect to {}: {}",
+                    self.label, e
+                )));
+                self.needs_redraw = true;
+                None
+            }
+        }
+    }
+
+    /// Sends a message, or offers clipboard contents as a file unless one
+    /// is still going out. A message too long to send goes back on the
+    /// input line.
+    fn send_outgoing(&mut self, outgoing: Outgoing, connection_lost: &mut Option<&'static str>) {
+        match outgoing {
+            Outgoing::Message(text) => match self.send_message(&text) {
+                Err(e @ (CryptoError::MessageTooLong | CryptoError::EncryptFailed)) => {
+                    self.messages.push(ChatLine::error(format!("Error: {}", e)));
+                    self.input_buffer = text;
+                }
+                Err(e) => {
+                    self.record_unsent(text);
+                    *connection_lost = Some(lost_reason(&e));
+                }
+                Ok(()) => self.scroll_offset = 0,
+            },
+            Outgoing::Paste(_) if self.sending.is_some() => self.messages.push(ChatLine::system(
+                "Still sending a file; /cancel it before pasting another.",
+            )),
+            Outgoing::Paste(contents) => {
+                let link = &mut *self.link.lock().unwrap();
+                if let Err(e) = offer_file(
+                    &mut link.stream,
+                    &mut link.cipher,
+                    pasted_file(contents),
+                    &mut self.sending,
+                    &mut self.messages,
+                ) {
+                    *connection_lost = Some(lost_reason(&e));
+                }
+            }
+        }
+    }
+
+    /// Whether `fresh`, a new chat, reconnects this one.
+    fn reconnected_by(&self, fresh: &ChatSession) -> bool {
+        self.ended
+            && self.fingerprint == fresh.fingerprint
+            && self
+                .reconnect_until
+                .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Carries the chat on in `fresh`, below the history so far.
+    fn carry_on(&mut self, mut fresh: ChatSession) {
+        let mut messages = std::mem::take(&mut self.messages);
+        messages.push(ChatLine::system("---------------------------------"));
+        messages.append(&mut fresh.messages);
+        fresh.messages = messages;
+        fresh.unread = self.unread;
+        *self = fresh;
+    }
+
+    /// Ends the session with `last` as the tab's last line, saying what
+    /// became of any transfer under way.
+    fn end(&mut self, last: ChatLine) {
+        if let Some(file) = self.sending.take().filter(|file| file.is_accepted()) {
+            self.messages.push(ChatLine::error(format!(
+                "Sending {} interrupted.",
+                file.name
+            )));
+        }
+        if let Some(file) = self.receiving.take().filter(|file| file.is_accepted()) {
+            let at = format!(
+                "Receiving {} interrupted at {} of {}",
+                file.name,
+                transfer::format_size(file.received()),
+                transfer::format_size(file.size)
+            );
+            self.messages.push(ChatLine::error(match file.suspend() {
+                Ok(()) => format!("{}; it resumes if the peer offers it again.", at),
+                Err(_) => format!("{}; partial file removed.", at),
+            }));
+        }
+        self.messages.push(last);
+        self.count_chat();
+        if !self.unconfirmed.is_empty() {
+            let texts: Vec<String> = self.unconfirmed.drain(..).map(|(_, text)| text).collect();
+            match outbox::queue(&self.fingerprint, &texts) {
+                Ok(()) => {
+                    for message in self.messages.iter_mut() {
+                        message.queue();
+                    }
+                    self.messages.push(ChatLine::system(format!(
+                        "{} message(s) the peer hadn't confirmed (marked {}) go out again when you next chat with {}.",
+                        texts.len(),
+                        if terminal::supports_unicode() { "\u{21bb}" } else { "~" },
+                        self.label
+                    )));
+                }
+                Err(e) => self.messages.push(ChatLine::error(format!(
+                    "Could not keep the unconfirmed messages for later: {}",
+                    e
+                ))),
+            }
+        }
+        self.typing.peer_message();
+        self.shown_typing = false;
+        self.ended = true;
+        self.needs_redraw = true;
+        self.hang_up();
+    }
+
+    /// Counts the chat in the keystore's history of the peer's key.
+    fn count_chat(&self) {
+        let _ = KeyStore::load().record_chat(&self.fingerprint);
+    }
+
+    fn hang_up(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Ok(link) = self.link.lock() {
+            let _ = link.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Hangs up and says so at the prompt, which is back in front.
+    fn close(mut self) {
+        print!(
+            "\r\n{}",
+            format!("Session with {} ended.", self.label).yellow()
+        );
+        if let Some(file) = self.receiving.take().filter(|file| file.is_accepted()) {
+            let name = file.name.clone();
+            if file.suspend().is_ok() {
+                print!("\r\n{} can be resumed if the peer offers it again.", name);
+            }
+        }
+    }
+
+    fn draw(&mut self, tab_bar: &str, focused: bool) -> io::Result<()> {
+        let mut header = vec![tab_bar.to_string()];
+        header.extend(self.pad_rows()?);
+        draw_ui(
+            &mut io::stdout(),
+            &visible_lines(&self.messages, self.show_system),
+            InputRow::Draft(&self.input_buffer),
+            self.scroll_offset,
+            if self.selecting {
+                Some(SELECT_HINT)
+            } else {
+                self.shown_status.as_deref()
+            },
+            self.shown_typing.then_some(self.typing_line.as_str()),
+            &header,
+        )?;
+        if focused && self.scroll_offset == 0 {
+            self.shown_count = self.received_count;
+        }
+        self.needs_redraw = false;
+        Ok(())
+    }
+
+    /// `bob`, `bob (3)` with unread messages, or `bob (ended)`.
+    fn tab_label(&self) -> String {
+        if self.ended {
+            format!("{} (ended)", self.label)
+        } else if self.unread > 0 {
+            format!("{} ({})", self.label, self.unread)
+        } else {
+            self.label.clone()
+        }
+    }
+}
+
+impl Drop for ChatSession {
+    /// Says goodbye first if the peer is still there, so it knows the chat
+    /// was closed rather than cut off.
+    fn drop(&mut self) {
+        if !self.ended
+            && let Ok(mut link) = self.link.lock()
+        {
+            let Link { stream, cipher } = &mut *link;
+            let _ = crypto::send_bye(stream, cipher);
+        }
+        if !self.ended {
+            self.count_chat();
+        }
+        self.hang_up();
+    }
+}
+
+/// The reader thread of a session. Frames go to the window through
+/// `frames`; while it is full, because the window is busy elsewhere (a
+/// prompt command, say), they queue up to `WAITING_MAX`, and past that the
+/// thread stops reading but keeps pinging, so the peer doesn't take the
+/// pause for a dead link.
+fn read_frames(
+    link: &Mutex<Link>,
+    socket: &TcpStream,
+    frames: &SyncSender<Frame>,
+    stop: &AtomicBool,
+) {
+    let mut heartbeat = Heartbeat::new(Instant::now());
+    let mut backoff = PollBackoff::new(Instant::now());
+    let mut received_count: u64 = 0;
+    let mut acked_count: u64 = 0;
+    let mut waiting: VecDeque<Frame> = VecDeque::new();
+
+    while !stop.load(Ordering::Relaxed) {
+        let mut link = link.lock().unwrap();
+        let Link { stream, cipher } = &mut *link;
+        let now = Instant::now();
+        let mut result = Ok(());
+        let mut peer_left = false;
+        if waiting.len() < WAITING_MAX {
+            for _ in 0..FRAMES_PER_TICK {
+                match crypto::receive_and_decrypt(stream, cipher) {
+                    Ok(None) => break,
+                    Ok(Some(crypto::Incoming::Bye)) => {
+                        waiting.push_back(Ok(crypto::Incoming::Bye));
+                        peer_left = true;
+                        break;
+                    }
+                    Ok(Some(incoming)) => {
+                        backoff.record_activity(now);
+                        heartbeat.received(&incoming, now);
+                        if matches!(incoming, crypto::Incoming::Chat(_)) {
+                            received_count += 1;
+                        }
+                        waiting.push_back(Ok(incoming));
+                    }
+                    // Still a chat frame, so it counts towards the ack.
+                    Err(CryptoError::InvalidUtf8) => {
+                        received_count += 1;
+                        waiting.push_back(Err(CryptoError::InvalidUtf8));
+                    }
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+        } else {
+            // Unread frames wait in the socket, so the peer's silence
+            // says nothing until they are read again.
+            heartbeat.last_received = now;
+        }
+        // Nothing is sent after a goodbye.
+        let result = result.and_then(|()| {
+            if peer_left {
+                return Ok(());
+            }
+            if received_count > acked_count {
+                crypto::send_ack(stream, cipher, received_count)?;
+                acked_count = received_count;
+            }
+            if cipher.rekey_due() {
+                crypto::start_rekey(stream, cipher)?;
+            }
+            if heartbeat.peer_lost(now) {
+                return Err(CryptoError::Stalled);
+            }
+            if heartbeat.ping_due(now) {
+                crypto::send_ping(stream, cipher)?;
+            }
+            Ok(())
+        });
+        drop(link);
+
+        let done = peer_left || result.is_err();
+        if let Err(e) = result {
+            waiting.push_back(Err(e));
+        }
+        if done {
+            for frame in waiting {
+                if frames.send(frame).is_err() {
+                    return;
+                }
+            }
+            return;
+        }
+
+        // Hand over what came in, and wake the window to take it.
+        let mut handed_over = false;
+        while let Some(frame) = waiting.pop_front() {
+            match frames.try_send(frame) {
+                Ok(()) => handed_over = true,
+                Err(TrySendError::Full(frame)) => {
+                    waiting.push_front(frame);
+                    break;
+                }
+                Err(TrySendError::Disconnected(_)) => return,
+            }
+        }
+        if handed_over {
+            terminal::wake_input();
+        }
+
+        let interval = backoff.next_interval(Instant::now());
+        if waiting.len() < WAITING_MAX {
+            // Back as soon as the peer sends anything; the interval only
+            // keeps pings and rekeys on time.
+            let _ = network::wait_readable(socket, interval);
+        } else {
+            // The socket stays unread until the window catches up.
+            thread::sleep(interval);
+        }
+    }
+}
+
+/// What is on screen while the prompt isn't.
+struct Screen {
+    mouse: Option<TerminalGuard>,
+    _raw: TerminalGuard,
+    _open: ChatOpen,
+}
+
+/// The open chats and which of them, or the prompt, is in front.
+pub struct Tabs {
+    sessions: Vec<ChatSession>,
+    /// 0 for the prompt, `n` for `sessions[n - 1]`.
+    active: usize,
+    screen: Option<Screen>,
+    focused: bool,
+    /// Requests put on hold since the prompt was last in front.
+    waiting: usize,
+    shown_bar: String,
+}
+
+impl Default for Tabs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tabs {
+    pub fn new() -> Self {
+        Tabs {
+            sessions: Vec::new(),
+            active: 0,
+            screen: None,
+            focused: true,
+            waiting: 0,
+            shown_bar: String::new(),
+        }
+    }
+
+    pub fn at_home(&self) -> bool {
+        self.active == 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Adds `session` as the last tab and brings it to the front, unless
+    /// it reconnects a chat whose connection dropped, which it then
+    /// carries on in that chat's tab.
+    pub fn open(&mut self, session: ChatSession) -> io::Result<()> {
+        match self
+            .sessions
+            .iter()
+            .position(|s| s.reconnected_by(&session))
+        {
+            Some(n) => {
+                self.sessions[n].carry_on(session);
+                self.sessions[n].needs_redraw = true;
+                self.switch_to(n + 1)?;
+            }
+            None => {
+                self.sessions.push(session);
+                self.switch_to(self.sessions.len())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Brings tab `n` to the front. False if there is no such tab.
+    pub fn switch_to(&mut self, n: usize) -> io::Result<bool> {
+        if n > self.sessions.len() {
+            return Ok(false);
+        }
+        if n == self.active {
+            return Ok(true);
+        }
+        if n == 0 {
+            self.leave_screen();
+            print!("\r\n{}", self.list().dimmed());
+            return Ok(true);
+        }
+        self.active = n;
+        if self.screen.is_none() {
+            self.screen = Some(Screen {
+                _open: ChatOpen::mark(),
+                _raw: TerminalGuard::fullscreen()?,
+                mouse: None,
+            });
+        }
+        let session = &mut self.sessions[n - 1];
+        session.unread = 0;
+        session.needs_redraw = true;
+        self.sync_mouse()?;
+        Ok(true)
+    }
+
+    /// Puts the prompt back in front.
+    fn leave_screen(&mut self) {
+        self.active = 0;
+        self.screen = None;
+        self.waiting = 0;
+    }
+
+    /// Steps to the next tab, or the previous one, wrapping around
+    /// through the prompt.
+    pub fn step(&mut self, forward: bool) -> io::Result<()> {
+        let count = self.sessions.len() + 1;
+        let n = if forward {
+            (self.active + 1) % count
+        } else {
+            (self.active + count - 1) % count
+        };
+        self.switch_to(n)?;
+        Ok(())
+    }
+
+    /// Every tab as `n name`, for the prompt.
+    pub fn list(&self) -> String {
+        let tabs: Vec<String> = self
+            .sessions
+            .iter()
+            .enumerate()
+            .map(|(n, session)| format!("{} {}", n + 1, session.tab_label()))
+            .collect();
+        format!(
+            "Open chats: {} (Ctrl+Right or 'switch <n>' to return)",
+            tabs.join(", ")
+        )
+    }
+
+    /// Makes the mouse guard match what the chat in front asked for.
+    fn sync_mouse(&mut self) -> io::Result<()> {
+        let (Some(screen), Some(session)) = (
+            self.screen.as_mut(),
+            self.active.checked_sub(1).map(|n| &self.sessions[n]),
+        ) else {
+            return Ok(());
+        };
+        if session.mouse && screen.mouse.is_none() {
+            screen.mouse = Some(TerminalGuard::fullscreen_with_mouse()?);
+        } else if !session.mouse {
+            screen.mouse = None;
+        }
+        Ok(())
+    }
+
+    /// Keeps every session going and redraws the one in front. Returns
+    /// what the prompt should mention about the others.
+    pub fn tick(&mut self, peers: &PeerMap) -> io::Result<Vec<String>> {
+        let mut notices = Vec::new();
+        for (n, session) in self.sessions.iter_mut().enumerate() {
+            let in_front = self.active == n + 1;
+            let (ended, unread) = (session.ended, session.unread);
+            session.tick(peers, in_front);
+            if std::mem::take(&mut session.dropped) {
+                session.reconnect(peers);
+            }
+            if let Some(stream) = session.redialed() {
+                let cooked = TerminalGuard::cooked()?;
+                let fresh = ChatSession::start(
+                    stream,
+                    crypto::Role::Initiator,
+                    None,
+                    None,
+                    Some(&session.fingerprint),
+                )?;
+                drop(cooked);
+                match fresh {
+                    Some(fresh) => {
+                        session.carry_on(fresh);
+                        notices.push(format!(
+                            "Chat with {} (tab {}) reconnected.",
+                            session.label,
+                            n + 1
+                        ));
+                    }
+                    None => session.messages.push(ChatLine::error(format!(
+                        "Reconnected to {}, but the handshake failed.",
+                        session.label
+                    ))),
+                }
+                session.needs_redraw = true;
+            }
+            if session.ended && !ended {
+                notices.push(format!(
+                    "Chat with {} (tab {}) ended.",
+                    session.label,
+                    n + 1
+                ));
+            } else if session.unread > 0 && unread == 0 {
+                notices.push(format!(
+                    "New message from {} in tab {} (Ctrl+Right or 'switch {}').",
+                    session.label,
+                    n + 1,
+                    n + 1
+                ));
+            }
+        }
+        if let Some(n) = self.active.checked_sub(1) {
+            let bar = self.tab_bar()?;
+            let session = &mut self.sessions[n];
+            if session.needs_redraw || bar != self.shown_bar {
+                session.draw(&bar, self.focused)?;
+                self.shown_bar = bar;
+            }
+        }
+        Ok(notices)
+    }
+
+    /// One pass of the loop while a chat is in front: sessions, then
+    /// terminal input. Returns true once the prompt is back in front.
+    pub fn run(&mut self, peers: &PeerMap) -> io::Result<bool> {
+        self.tick(peers)?;
+        let Some(n) = self.active.checked_sub(1) else {
+            return Ok(true);
+        };
+        let session = &mut self.sessions[n];
+        for shout in shout::take_received() {
+            session
+                .messages
+                .push(ChatLine::chat(shout.line().magenta().to_string(), None));
+            session.needs_redraw = true;
+        }
+        for addr in HELD.lock().unwrap().drain(..) {
+            session.messages.push(ChatLine::system(format!(
+                "{} wants to chat; they'll wait until you answer at the prompt (/switch 0).",
+                addr
+            )));
+            session.needs_redraw = true;
+            self.waiting += 1;
+        }
+
+        if !terminal::poll_input(session.backoff.next_interval(Instant::now()))? {
+            return Ok(false);
+        }
+        let event = event::read()?;
+        match event {
+            Event::FocusGained => {
+                self.focused = true;
+                session.needs_redraw = true;
+            }
+            Event::FocusLost => self.focused = false,
+            Event::Key(KeyEvent {
+                code: code @ (KeyCode::Left | KeyCode::Right),
+                modifiers,
+                ..
+            }) if modifiers.contains(KeyModifiers::CONTROL) => {
+                self.step(code == KeyCode::Right)?;
+            }
+            event => match session.handle_event(event)? {
+                Action::Stay => {}
+                Action::Switch(to) => {
+                    if !self.switch_to(to)? {
+                        let session = &mut self.sessions[n];
+                        session
+                            .messages
+                            .push(ChatLine::system(format!("No tab {}.", to)));
+                        session.needs_redraw = true;
+                    }
+                }
+                Action::Close => {
+                    let session = self.sessions.remove(n);
+                    self.leave_screen();
+                    session.close();
+                    if !self.sessions.is_empty() {
+                        print!("\r\n{}", self.list().dimmed());
+                    }
+                }
+            },
+        }
+        self.sync_mouse()?;
+        io::stdout().flush()?;
+        Ok(self.at_home())
+    }
+
+    /// One cell per tab across the top row, the one in front reversed and
+    /// those with news in bold, cut off where the terminal ends.
+    fn tab_bar(&self) -> io::Result<String> {
+        let (cols, _) = size()?;
+        let home = match self.waiting {
+            0 => "0 home".to_string(),
+            n => format!("0 home ({} waiting)", n),
+        };
+        let labels = std::iter::once((home, self.waiting > 0)).chain(
+            self.sessions
+                .iter()
+                .enumerate()
+                .map(|(n, s)| (format!("{} {}", n + 1, s.tab_label()), s.unread > 0)),
+        );
+        let mut bar = String::new();
+        let mut width = 0;
+        for (n, (label, news)) in labels.enumerate() {
+            let cell = format!(" {} ", label);
+            width += cell.width() + 1;
+            if width > cols as usize {
+                break;
+            }
+            let cell = if n == self.active {
+                cell.reversed()
+            } else if news {
+                cell.bold()
+            } else {
+                cell.dimmed()
+            };
+            bar.push_str(&format!("{} ", cell));
+        }
+        Ok(bar)
+    }
+}
@@ -0,0 +1,147 @@
+//! Reads the system clipboard for `/paste`, through the tool each platform
+//! already ships or commonly has installed: `wl-paste` or `xclip`/`xsel`
+//! on Linux and the BSDs, `pbpaste` and `osascript` on macOS, PowerShell
+//! on Windows.
+
+use std::io;
+use std::process::{Command, Stdio};
+
+pub enum Contents {
+    Text(String),
+    /// A copied image, as PNG.
+    Png(Vec<u8>),
+}
+
+/// The clipboard's image if it holds one, else its text. Empty, non-UTF-8
+/// and other contents fail with a message fit to show to the user.
+pub fn read() -> io::Result<Contents> {
+    match platform::read()? {
+        Contents::Text(text) if text.is_empty() => Err(empty()),
+        Contents::Png(data) if data.is_empty() => Err(empty()),
+        contents => Ok(contents),
+    }
+}
+
+fn empty() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "the clipboard is empty or holds neither text nor an image",
+    )
+}
+
+fn text(bytes: Vec<u8>) -> io::Result<Contents> {
+    String::from_utf8(bytes)
+        .map(Contents::Text)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "the clipboard text isn't UTF-8"))
+}
+
+/// Runs a clipboard tool and returns what it printed. A missing tool
+/// fails with `NotFound`; a tool that fails, usually because the
+/// clipboard holds nothing of the asked-for type, is reported as empty.
+fn run(program: &str, args: &[&str]) -> io::Result<Vec<u8>> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()?;
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(empty())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::{Contents, run, text};
+    use crate::identity::decode_hex;
+    use std::io;
+
+    pub fn read() -> io::Result<Contents> {
+        // AppleScript prints a copied image as `«data PNGf<hex>»`.
+        let image = run("osascript", &["-e", "get the clipboard as «class PNGf»"])
+            .ok()
+            .and_then(|out| {
+                let out = String::from_utf8(out).ok()?;
+                let hex = out.trim().strip_prefix("«data PNGf")?.strip_suffix('»')?;
+                decode_hex(hex)
+            });
+        match image {
+            Some(png) => Ok(Contents::Png(png)),
+            None => text(run("pbpaste", &[])?),
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::{Contents, run, text};
+    use std::io;
+
+    const IMAGE: &str = "Add-Type -AssemblyName System.Windows.Forms; \
+        $image = [Windows.Forms.Clipboard]::GetImage(); \
+        if ($image) { $png = New-Object IO.MemoryStream; \
+        $image.Save($png, [Drawing.Imaging.ImageFormat]::Png); \
+        [Console]::OpenStandardOutput().Write($png.ToArray(), 0, $png.Length) }";
+    const TEXT: &str = "[Console]::OutputEncoding = [Text.Encoding]::UTF8; Get-Clipboard -Raw";
+
+    pub fn read() -> io::Result<Contents> {
+        let image = run("powershell", &["-NoProfile", "-STA", "-Command", IMAGE])?;
+        if !image.is_empty() {
+            return Ok(Contents::Png(image));
+        }
+        text(run("powershell", &["-NoProfile", "-Command", TEXT])?)
+    }
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+mod platform {
+    use super::{Contents, run, text};
+    use std::io;
+
+    pub fn read() -> io::Result<Contents> {
+        let wayland = std::env::var_os("WAYLAND_DISPLAY").is_some();
+        let result = if wayland { wl_paste() } else { xclip() };
+        result.or_else(|e| match e.kind() {
+            io::ErrorKind::NotFound => xsel().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no clipboard tool found; install wl-clipboard, xclip or xsel",
+                )
+            }),
+            _ => Err(e),
+        })
+    }
+
+    fn wl_paste() -> io::Result<Contents> {
+        let types = String::from_utf8_lossy(&run("wl-paste", &["--list-types"])?).into_owned();
+        if types.lines().any(|t| t == "image/png") {
+            Ok(Contents::Png(run("wl-paste", &["--type", "image/png"])?))
+        } else {
+            text(run("wl-paste", &["--no-newline"])?)
+        }
+    }
+
+    fn xclip() -> io::Result<Contents> {
+        let clipboard = ["-selection", "clipboard", "-out"];
+        let targets = run("xclip", &[&clipboard[..], &["-target", "TARGETS"]].concat())?;
+        if String::from_utf8_lossy(&targets)
+            .lines()
+            .any(|t| t == "image/png")
+        {
+            Ok(Contents::Png(run(
+                "xclip",
+                &[&clipboard[..], &["-target", "image/png"]].concat(),
+            )?))
+        } else {
+            text(run(
+                "xclip",
+                &[&clipboard[..], &["-target", "UTF8_STRING"]].concat(),
+            )?)
+        }
+    }
+
+    fn xsel() -> io::Result<Contents> {
+        text(run("xsel", &["--clipboard", "--output"])?)
+    }
+}
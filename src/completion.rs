@@ -0,0 +1,168 @@
+//! Tab completion for the command line.
+
+use crate::aliases;
+use crate::state::PeerMap;
+
+/// Every command the prompt takes, for completing the first word.
+pub const COMMANDS: &[&str] = &[
+    "add-peer",
+    "alias",
+    "announce",
+    "block",
+    "blocklist",
+    #[cfg(feature = "update-check")]
+    "changelog",
+    "clear",
+    "cls",
+    "connect",
+    "exit",
+    "find",
+    "find-quick",
+    "help",
+    "history",
+    "host",
+    "netstats",
+    "outbox",
+    "peer",
+    "remove-peer",
+    "resources",
+    "set",
+    "shout",
+    "status",
+    "switch",
+    "trust",
+    "unblock",
+    "unwatch",
+    "watch",
+    "watches",
+    "whoami",
+];
+
+/// What `connect` can be given for the peers known right now: their
+/// addresses, nicknames and aliases. Names with spaces can't be typed as
+/// one argument, so they are left out.
+pub fn peer_words(peers: &PeerMap) -> Vec<String> {
+    let mut words: Vec<String> = {
+        let peers = peers.read().unwrap();
+        peers
+            .iter()
+            .flat_map(|(addr, info)| [Some(addr.to_string()), info.nickname.clone()])
+            .flatten()
+            .collect()
+    };
+    words.extend(aliases::list().into_iter().map(|(_, name)| name));
+    words.retain(|word| !word.contains(char::is_whitespace));
+    words
+}
+
+/// Where the word being completed starts in `line`, and what it can
+/// become: a command while it is the first word, one of `peer_words` while
+/// it is `connect`'s argument, one of `tags` after `--tag` or as the tag
+/// of `peer tag` and `peer untag`, else nothing. Matches ignore case and
+/// come sorted without duplicates.
+pub fn candidates(line: &str, peer_words: &[String], tags: &[&str]) -> (usize, Vec<String>) {
+    let start = line.len()
+        - line
+            .chars()
+            .rev()
+            .take_while(|c| !c.is_whitespace())
+            .map(char::len_utf8)
+            .sum::<usize>();
+    let word = line[start..].to_lowercase();
+    let before: Vec<&str> = line[..start].split_whitespace().collect();
+    let words: Vec<&str> = match before.as_slice() {
+        [] => COMMANDS.to_vec(),
+        ["connect"] => peer_words.iter().map(String::as_str).collect(),
+        ["find" | "find-quick" | "block" | "unblock", "--tag"] => tags.to_vec(),
+        ["peer", "tag" | "untag", _] => tags.to_vec(),
+        _ => Vec::new(),
+    };
+    let mut matches: Vec<String> = words
+        .into_iter()
+        .filter(|candidate| candidate.to_lowercase().starts_with(&word))
+        .map(str::to_string)
+        .collect();
+    matches.sort();
+    matches.dedup();
+    (start, matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn first_word_completes_commands() {
+        assert_eq!(candidates("conn", &[], &[]), (0, words(&["connect"])));
+        assert_eq!(candidates("CONN", &[], &[]), (0, words(&["connect"])));
+        assert_eq!(candidates("  wh", &[], &[]), (2, words(&["whoami"])));
+        assert_eq!(candidates("zz", &[], &[]), (0, Vec::new()));
+        // Everything, for an empty line.
+        assert_eq!(candidates("", &[], &[]).1.len(), COMMANDS.len());
+    }
+
+    #[test]
+    fn connect_completes_peers() {
+        let peers = words(&["192.168.1.34:3000", "alice", "Alex", "bob"]);
+        assert_eq!(
+            candidates("connect al", &peers, &[]),
+            (8, words(&["Alex", "alice"]))
+        );
+        assert_eq!(
+            candidates("connect 192", &peers, &[]),
+            (8, words(&["192.168.1.34:3000"]))
+        );
+        // Only connect's first argument is a peer, and only connect's.
+        assert_eq!(candidates("connect bob al", &peers, &[]), (12, Vec::new()));
+        assert_eq!(candidates("trust al", &peers, &[]), (6, Vec::new()));
+    }
+
+    #[test]
+    fn several_matches_come_sorted_once_each() {
+        assert_eq!(
+            candidates("wat", &[], &[]),
+            (0, words(&["watch", "watches"]))
+        );
+        let peers = words(&["carol", "bob", "carol", "carl"]);
+        assert_eq!(
+            candidates("connect ca", &peers, &[]),
+            (8, words(&["carl", "carol"]))
+        );
+        assert_eq!(
+            candidates("connect ", &peers, &[]),
+            (8, words(&["bob", "carl", "carol"]))
+        );
+    }
+
+    #[test]
+    fn tags_complete_where_a_tag_goes() {
+        let tags = ["guests", "lab", "lab-2"];
+        assert_eq!(
+            candidates("find --tag la", &[], &tags),
+            (11, words(&["lab", "lab-2"]))
+        );
+        assert_eq!(
+            candidates("find-quick --tag g", &[], &tags),
+            (17, words(&["guests"]))
+        );
+        assert_eq!(
+            candidates("block --tag ", &[], &tags),
+            (12, words(&["guests", "lab", "lab-2"]))
+        );
+        assert_eq!(
+            candidates("peer tag 10.0.0.5 l", &[], &tags),
+            (18, words(&["lab", "lab-2"]))
+        );
+        assert_eq!(
+            candidates("peer untag 10.0.0.5 g", &[], &tags),
+            (20, words(&["guests"]))
+        );
+        // Not in place of the peer, nor after other options.
+        assert_eq!(candidates("peer tag l", &[], &tags), (9, Vec::new()));
+        assert_eq!(candidates("connect --tag l", &[], &tags), (14, Vec::new()));
+    }
+}
@@ -1,9 +1,28 @@
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use chacha20poly1305::{ChaCha20Poly1305, Nonce, aead::Aead};
-use rand::{RngCore, rngs::OsRng};
+use crate::identity;
+use crate::protocol::{
+    self, CONFIRM_LABEL, CipherSuite, EXPIRE_AGREE, EXPIRE_PROPOSE, FLAG_COMPRESSED,
+    FRAME_LEN_PREFIX, Features, FileMessage, Frame, HANDSHAKE_MAGIC, IDENTITY_KEY_LEN, KDF_SALT,
+    MAX_FRAME_LEN, MAX_MESSAGE_LEN, MAX_NICKNAME_LEN, MSG_ACK, MSG_BYE, MSG_CHAT, MSG_CONFIRM,
+    MSG_EXPIRE, MSG_FEATURES, MSG_HINT_MIN, MSG_NICKNAME, MSG_PAD, MSG_PING, MSG_READ,
+    MSG_REKEY_ACK, MSG_REKEY_DONE, MSG_REKEY_INIT, MSG_SHOUT, MSG_TYPING, PAD_BUCKET,
+    PASSPHRASE_LABEL, PLAINTEXT_HEADER_LEN, PROTOCOL_VERSION, PadEdit, REKEY_INFO, REKEY_KEY_LEN,
+    RoomMessage, SAS_LABEL, SESSION_KEY_LEN, SUITE_NONE, TAG_LEN,
+};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use snow::{HandshakeState, TransportState};
 use std::io::{self, Read, Write};
 use std::net::TcpStream;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use x25519_dalek::{EphemeralSecret, PublicKey};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
 pub fn generate_keypair() -> (EphemeralSecret, PublicKey) {
     let secret = EphemeralSecret::random_from_rng(OsRng);
@@ -11,108 +30,1691 @@ pub fn generate_keypair() -> (EphemeralSecret, PublicKey) {
     (secret, public)
 }
 
-pub fn perform_handshake(mut stream: &TcpStream) -> io::Result<[u8; 32]> {
-    let (our_secret, our_public) = generate_keypair();
-    let our_pub_bytes = our_public.as_bytes();
+/// Keys from a rekey, one per direction. Wiped from memory when dropped.
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub struct SessionKeys {
+    pub send: [u8; SESSION_KEY_LEN],
+    pub receive: [u8; SESSION_KEY_LEN],
+}
+
+/// Which end of the Noise handshake this side plays: the one that dialled
+/// is the initiator.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+pub struct Handshake {
+    /// Already used for the confirmation and nickname frames, one of each
+    /// in each direction.
+    pub cipher: CipherState,
+    /// Its X25519 form is the static key the peer proved it holds.
+    pub peer_identity: VerifyingKey,
+    /// Short authentication string; see `compute_sas`.
+    pub sas: String,
+    pub suite: CipherSuite,
+    /// What the peer calls itself, already checked to be printable.
+    pub peer_nickname: Option<String>,
+}
+
+impl Handshake {
+    pub fn peer_fingerprint(&self) -> String {
+        identity::fingerprint(&self.peer_identity)
+    }
+}
+
+const ANY_SUITE: u8 = CipherSuite::ChaCha20Poly1305 as u8 | CipherSuite::Aes256Gcm as u8;
+
+// Bitmask of the `CipherSuite`s this side offers or accepts.
+static CIPHER_SUITES: AtomicU8 = AtomicU8::new(ANY_SUITE);
+
+/// Restricts new chats to one suite, or with `None` allows every suite and
+/// lets the usual preference decide. A peer restricted to another suite
+/// then can't connect.
+pub fn set_cipher_suite(suite: Option<CipherSuite>) {
+    CIPHER_SUITES.store(suite.map_or(ANY_SUITE, CipherSuite::id), Ordering::Relaxed);
+}
+
+static NICKNAME: Mutex<Option<String>> = Mutex::new(None);
+
+/// Sets the name new chats tell the peer, or with `None` sends none. A
+/// name that fails `protocol::valid_nickname` is sent as none.
+pub fn set_nickname(name: Option<&str>) {
+    *NICKNAME.lock().unwrap() = name
+        .filter(|n| protocol::valid_nickname(n))
+        .map(str::to_string);
+}
+
+pub fn nickname() -> Option<String> {
+    NICKNAME.lock().unwrap().clone()
+}
+
+/// The handshake runs on the UI thread, so a peer that connects and goes
+/// quiet must not hang it.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Hard limit on frames sealed under one sending key. Far below what the
+/// AEADs allow, and twice the point where a rekey is forced whatever the
+/// policy says, so it is only reached if the peer never answers one.
+const MAX_FRAMES_PER_KEY: u64 = 1 << 20;
+
+/// Agrees on a cipher suite, runs its Noise handshake and returns the transport
+/// both sides derived. Fails if the peer's identity key isn't the one its
+/// static key belongs to, or if the peer doesn't finish within
+/// `HANDSHAKE_TIMEOUT`. It also fails with `ConfirmationFailed` unless the
+/// peer proves it derived the same keys, which with a `passphrase` means
+/// the peer used the same one.
+pub fn perform_handshake(
+    stream: &TcpStream,
+    role: Role,
+    passphrase: Option<&str>,
+) -> Result<Handshake, CryptoError> {
+    let suites = CIPHER_SUITES.load(Ordering::Relaxed);
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let result = exchange_keys(stream, role, passphrase, suites, identity::local());
+    stream.set_read_timeout(None)?;
+    result.map_err(|e| match e {
+        CryptoError::Io(ref io_error)
+            if matches!(
+                io_error.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            CryptoError::HandshakeTimeout
+        }
+        e => e,
+    })
+}
+
+fn exchange_keys(
+    mut stream: &TcpStream,
+    role: Role,
+    passphrase: Option<&str>,
+    suites: u8,
+    local: &SigningKey,
+) -> Result<Handshake, CryptoError> {
+    let preamble = [HANDSHAKE_MAGIC, &[PROTOCOL_VERSION]].concat();
+    stream.write_all(&preamble)?;
+    let mut peer_preamble = [0u8; HANDSHAKE_MAGIC.len() + 1];
+    stream.read_exact(&mut peer_preamble).map_err(closed)?;
+    let (magic, version) = peer_preamble.split_at(HANDSHAKE_MAGIC.len());
+    if magic != HANDSHAKE_MAGIC {
+        return Err(CryptoError::NotSandesh);
+    }
+    if version[0] != PROTOCOL_VERSION {
+        return Err(CryptoError::IncompatibleVersion {
+            peer: version[0],
+            ours: PROTOCOL_VERSION,
+        });
+    }
+
+    let (offer, suite) = negotiate_suite(stream, role, suites)?;
+    let prologue = [preamble.as_slice(), &[offer, suite.id()]].concat();
+
+    let static_secret = identity::static_secret(local);
+    let psk = passphrase_key(passphrase);
+    let builder = snow::Builder::new(suite.noise_params().parse().map_err(noise_error)?)
+        .local_private_key(static_secret.as_slice())
+        .and_then(|b| b.psk(3, &psk))
+        .and_then(|b| b.prologue(&prologue))
+        .map_err(noise_error)?;
+    let mut noise = match role {
+        Role::Initiator => builder.build_initiator(),
+        Role::Responder => builder.build_responder(),
+    }
+    .map_err(noise_error)?;
+
+    let our_identity = local.verifying_key();
+    let peer_identity_bytes = match role {
+        Role::Initiator => {
+            write_handshake(stream, &mut noise, &[])?;
+            let payload = read_handshake(stream, &mut noise)?;
+            write_handshake(stream, &mut noise, our_identity.as_bytes())?;
+            payload
+        }
+        Role::Responder => {
+            read_handshake(stream, &mut noise)?;
+            write_handshake(stream, &mut noise, our_identity.as_bytes())?;
+            read_handshake(stream, &mut noise)?
+        }
+    };
+
+    let invalid = |msg: &str| CryptoError::HandshakeFailed(msg.to_string());
+    let peer_identity_bytes: [u8; IDENTITY_KEY_LEN] = peer_identity_bytes
+        .try_into()
+        .map_err(|_| invalid("Invalid identity key"))?;
+    let peer_identity = VerifyingKey::from_bytes(&peer_identity_bytes)
+        .map_err(|_| invalid("Invalid identity key"))?;
+    let peer_static = noise.get_remote_static().unwrap_or_default();
+    if !identity::owns_static(&peer_identity, peer_static) {
+        return Err(invalid(
+            "Peer's handshake key doesn't belong to its identity key",
+        ));
+    }
+
+    let sas = compute_sas(noise.get_handshake_hash());
+    let mut cipher = CipherState::new(noise.into_transport_mode().map_err(noise_error)?);
+
+    // Sending ours before checking theirs means both sides get to see the
+    // failure, not just the one that checks first. The nickname goes along
+    // in the same write to save a round trip.
+    let confirm = seal(&mut cipher, MSG_CONFIRM, CONFIRM_LABEL)?;
+    let nickname = protocol::encode_nickname(nickname().as_deref());
+    let nickname = seal(&mut cipher, MSG_NICKNAME, &nickname)?;
+    stream.write_all(&[confirm, nickname].concat())?;
+    read_confirmation(stream, &mut cipher)?;
+    let peer_nickname = read_nickname(stream, &mut cipher)?;
+
+    Ok(Handshake {
+        cipher,
+        peer_identity,
+        sas,
+        suite,
+        peer_nickname,
+    })
+}
+
+/// The initiator's offer and the suite the responder picked from it, with
+/// `ours` the suites this side allows. Both go into the prologue, so an
+/// offer rewritten on the way fails the handshake instead of quietly
+/// downgrading it.
+fn negotiate_suite(
+    mut stream: &TcpStream,
+    role: Role,
+    ours: u8,
+) -> Result<(u8, CipherSuite), CryptoError> {
+    match role {
+        Role::Initiator => {
+            stream.write_all(&[ours])?;
+            let mut pick = [0u8; 1];
+            stream.read_exact(&mut pick).map_err(closed)?;
+            if pick[0] == SUITE_NONE {
+                return Err(CryptoError::NoCommonSuite);
+            }
+            match CipherSuite::from_id(pick[0]) {
+                Some(suite) if ours & suite.id() != 0 => Ok((ours, suite)),
+                _ => Err(CryptoError::HandshakeFailed(
+                    "Peer picked a cipher suite that wasn't offered".to_string(),
+                )),
+            }
+        }
+        Role::Responder => {
+            let mut offer = [0u8; 1];
+            stream.read_exact(&mut offer).map_err(closed)?;
+            let suite = CipherSuite::pick(offer[0], ours);
+            stream.write_all(&[suite.map_or(SUITE_NONE, CipherSuite::id)])?;
+            suite
+                .map(|suite| (offer[0], suite))
+                .ok_or(CryptoError::NoCommonSuite)
+        }
+    }
+}
+
+/// The Noise pre-shared key; see `PASSPHRASE_LABEL`.
+fn passphrase_key(passphrase: Option<&str>) -> Zeroizing<[u8; 32]> {
+    let digest = Sha256::new()
+        .chain_update(PASSPHRASE_LABEL)
+        .chain_update(passphrase.unwrap_or_default().as_bytes())
+        .finalize();
+    Zeroizing::new(digest.into())
+}
+
+fn noise_error(e: snow::Error) -> CryptoError {
+    CryptoError::HandshakeFailed(format!("Noise error: {}", e))
+}
+
+fn write_handshake(
+    mut stream: &TcpStream,
+    noise: &mut HandshakeState,
+    payload: &[u8],
+) -> Result<(), CryptoError> {
+    let mut message = vec![0u8; MAX_FRAME_LEN];
+    let len = noise
+        .write_message(payload, &mut message)
+        .map_err(noise_error)?;
+    message.truncate(len);
+    stream.write_all(
+        &Frame {
+            ciphertext: message,
+        }
+        .encode(),
+    )?;
+    Ok(())
+}
+
+/// Reads the next handshake message and returns its payload. One that
+/// doesn't decrypt means the two sides' keys differ, which shows up as
+/// `ConfirmationFailed` like a confirmation frame that doesn't open.
+fn read_handshake(
+    mut stream: &TcpStream,
+    noise: &mut HandshakeState,
+) -> Result<Vec<u8>, CryptoError> {
+    let mut len_buf = [0u8; FRAME_LEN_PREFIX];
+    stream.read_exact(&mut len_buf).map_err(hung_up)?;
+    let mut message = vec![0u8; frame_len(len_buf)?];
+    stream.read_exact(&mut message).map_err(hung_up)?;
+    let mut payload = vec![0u8; message.len()];
+    let len = noise
+        .read_message(&message, &mut payload)
+        .map_err(|e| match e {
+            snow::Error::Decrypt => CryptoError::ConfirmationFailed,
+            e => noise_error(e),
+        })?;
+    payload.truncate(len);
+    Ok(payload)
+}
+
+/// Before any keys are in play, a peer that hangs up has turned us away,
+/// e.g. over the version or cipher suite.
+fn closed(e: io::Error) -> CryptoError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset => CryptoError::Disconnected,
+        _ => CryptoError::Io(e),
+    }
+}
+
+/// A peer whose own check failed hangs up rather than answer, so that
+/// counts as a failed confirmation too.
+fn hung_up(e: io::Error) -> CryptoError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset => {
+            CryptoError::ConfirmationFailed
+        }
+        _ => CryptoError::Io(e),
+    }
+}
+
+/// Reads the peer's first frame, which has to be `MSG_CONFIRM` carrying
+/// `CONFIRM_LABEL`.
+fn read_confirmation(stream: &TcpStream, cipher: &mut CipherState) -> Result<(), CryptoError> {
+    match read_handshake_frame(stream, cipher, CONFIRM_LABEL.len())? {
+        Some((MSG_CONFIRM, body)) if body == CONFIRM_LABEL => Ok(()),
+        _ => Err(CryptoError::ConfirmationFailed),
+    }
+}
+
+/// Reads the peer's second frame, which has to be `MSG_NICKNAME`. A name
+/// that isn't printable is dropped rather than failing the handshake.
+fn read_nickname(
+    stream: &TcpStream,
+    cipher: &mut CipherState,
+) -> Result<Option<String>, CryptoError> {
+    match read_handshake_frame(stream, cipher, 1 + MAX_NICKNAME_LEN)? {
+        Some((MSG_NICKNAME, body)) => Ok(protocol::decode_nickname(&body)),
+        _ => Err(CryptoError::HandshakeFailed(
+            "Peer didn't send its nickname".to_string(),
+        )),
+    }
+}
+
+/// One of the frames that end the handshake, as its type and body, or
+/// `None` if it is longer than a `max_body`-byte body pads to or doesn't
+/// decrypt.
+fn read_handshake_frame(
+    mut stream: &TcpStream,
+    cipher: &mut CipherState,
+    max_body: usize,
+) -> Result<Option<(u8, Vec<u8>)>, CryptoError> {
+    let mut len_buf = [0u8; FRAME_LEN_PREFIX];
+    stream.read_exact(&mut len_buf).map_err(hung_up)?;
+    // Check the length before reading, so a bogus one can't stall us
+    // waiting for bytes that were never sent.
+    match frame_len(len_buf) {
+        Ok(len) if len <= padded_len(PLAINTEXT_HEADER_LEN + max_body) + TAG_LEN => {
+            let mut body = vec![0u8; len];
+            stream.read_exact(&mut body)?;
+            Ok(open(cipher, &body).ok().and_then(|plaintext| {
+                unpad(&plaintext)
+                    .ok()
+                    .map(|(kind, body)| (kind, body.to_vec()))
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// A code both peers can read to each other over the phone. The Noise
+/// handshake hash covers every key exchanged, so a man-in-the-middle, who
+/// has to run a separate handshake with each side, can't make the two
+/// codes match.
+pub fn compute_sas(handshake_hash: &[u8]) -> String {
+    let digest = Sha256::new()
+        .chain_update(SAS_LABEL)
+        .chain_update(handshake_hash)
+        .finalize();
+    let value = u64::from_be_bytes(digest[..8].try_into().expect("digest is 32 bytes"));
+    let digits = format!("{:015}", value % 1_000_000_000_000_000);
+    format!("{} {} {}", &digits[..5], &digits[5..10], &digits[10..])
+}
+
+/// The raw X25519 output isn't uniformly distributed, so it goes through
+/// HKDF before being used as a cipher key. Both rekey keys go into the
+/// info after `label`, lowest first, binding the keys to this particular
+/// exchange. The first half of the output encrypts what the side with the
+/// lower key sends, the second half the other direction.
+fn derive_session_keys(
+    label: &[u8],
+    shared_secret: &[u8],
+    our_public: &[u8; 32],
+    peer_public: &[u8; 32],
+) -> SessionKeys {
+    let (low, high) = lowest_first(our_public, peer_public);
+    let info = [label, low.as_slice(), high.as_slice()].concat();
+    let mut okm = Zeroizing::new([0u8; 2 * SESSION_KEY_LEN]);
+    Hkdf::<Sha256>::new(Some(KDF_SALT), shared_secret)
+        .expand(&info, okm.as_mut_slice())
+        .expect("64 bytes is a valid HKDF-SHA256 output length");
 
-    stream.write_all(our_pub_bytes)?;
+    // Copied straight into the keys so no loose copy is left behind.
+    let (from_low, from_high) = okm.split_at(SESSION_KEY_LEN);
+    let (send, receive) = if our_public <= peer_public {
+        (from_low, from_high)
+    } else {
+        (from_high, from_low)
+    };
+    let mut keys = SessionKeys {
+        send: [0u8; SESSION_KEY_LEN],
+        receive: [0u8; SESSION_KEY_LEN],
+    };
+    keys.send.copy_from_slice(send);
+    keys.receive.copy_from_slice(receive);
+    keys
+}
+
+fn rekey_public(body: &[u8]) -> Result<PublicKey, CryptoError> {
+    let bytes: [u8; REKEY_KEY_LEN] = body
+        .try_into()
+        .map_err(|_| CryptoError::MalformedFrame("Invalid rekey key"))?;
+    Ok(PublicKey::from(bytes))
+}
+
+/// Orders the two sides' public keys the same way on both peers.
+fn lowest_first<'a>(a: &'a [u8; 32], b: &'a [u8; 32]) -> (&'a [u8; 32], &'a [u8; 32]) {
+    if a <= b { (a, b) } else { (b, a) }
+}
 
-    let mut peer_pub_bytes = [0u8; 32];
-    stream.read_exact(&mut peer_pub_bytes)?;
-    let peer_public = PublicKey::from(peer_pub_bytes);
+static PADDING: AtomicBool = AtomicBool::new(true);
+
+/// Whether frames we send are padded to `PAD_BUCKET`. Receiving works
+/// either way, so the two sides don't have to agree.
+pub fn set_padding(enabled: bool) {
+    PADDING.store(enabled, Ordering::Relaxed);
+}
+
+static COMPRESSION: AtomicBool = AtomicBool::new(false);
+/// Bodies shorter than this are sent as they are; they rarely shrink, and
+/// padding would round the saving away.
+const COMPRESS_MIN_LEN: usize = 128;
+
+/// Whether bodies we send are deflated when that makes them shorter.
+/// Receiving works either way. Off by default: how well text compresses
+/// says something about what it is, which padding only partly hides.
+pub fn set_compression(enabled: bool) {
+    COMPRESSION.store(enabled, Ordering::Relaxed);
+}
+
+/// `body` deflated, if that makes it shorter.
+fn deflate(body: &[u8]) -> Option<Zeroizing<Vec<u8>>> {
+    if body.len() < COMPRESS_MIN_LEN {
+        return None;
+    }
+    // Sized up front so growing it doesn't leave copies behind.
+    let mut deflated = Zeroizing::new(Vec::with_capacity(body.len() + 64));
+    let mut encoder = DeflateEncoder::new(&mut *deflated, Compression::default());
+    encoder.write_all(body).ok()?;
+    encoder.finish().ok()?;
+    (deflated.len() < body.len()).then_some(deflated)
+}
+
+/// Undoes `deflate`, refusing anything that inflates past a message's
+/// worth, so a small frame can't make us allocate without bound.
+fn inflate(body: &[u8]) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+    let mut inflated = Zeroizing::new(Vec::with_capacity(MAX_MESSAGE_LEN + 1));
+    DeflateDecoder::new(body)
+        .take(MAX_MESSAGE_LEN as u64 + 1)
+        .read_to_end(&mut inflated)
+        .map_err(|_| CryptoError::MalformedFrame("Compressed body doesn't inflate"))?;
+    if inflated.len() > MAX_MESSAGE_LEN {
+        return Err(CryptoError::MalformedFrame(
+            "Compressed body inflates past the message limit",
+        ));
+    }
+    Ok(inflated)
+}
+
+/// Plaintext length after padding `len` bytes.
+fn padded_len(len: usize) -> usize {
+    len.next_multiple_of(PAD_BUCKET)
+        .min(MAX_FRAME_LEN - TAG_LEN)
+}
 
-    let shared_secret = our_secret.diffie_hellman(&peer_public);
-    Ok(*shared_secret.as_bytes())
+/// Splits a decrypted plaintext into its type byte and body, dropping the
+/// padding.
+fn unpad(plaintext: &[u8]) -> Result<(u8, &[u8]), CryptoError> {
+    let (header, rest) = plaintext
+        .split_at_checked(PLAINTEXT_HEADER_LEN)
+        .ok_or(CryptoError::MalformedFrame("Empty frame"))?;
+    let body_len = u16::from_be_bytes([header[1], header[2]]) as usize;
+    let body = rest.get(..body_len).ok_or(CryptoError::MalformedFrame(
+        "Frame body longer than the frame",
+    ))?;
+    Ok((header[0], body))
+}
+
+static REKEY_AFTER_FRAMES: AtomicU64 = AtomicU64::new(1000);
+static REKEY_AFTER_SECS: AtomicU64 = AtomicU64::new(10 * 60);
+
+/// Sessions switch to fresh keys after this many frames (both directions
+/// together) or this much time under the current keys, whichever is first.
+pub fn set_rekey_policy(frames: u64, interval: Duration) {
+    REKEY_AFTER_FRAMES.store(frames, Ordering::Relaxed);
+    REKEY_AFTER_SECS.store(interval.as_secs(), Ordering::Relaxed);
+}
+
+pub fn rekey_policy() -> (u64, Duration) {
+    (
+        REKEY_AFTER_FRAMES.load(Ordering::Relaxed),
+        Duration::from_secs(REKEY_AFTER_SECS.load(Ordering::Relaxed)),
+    )
+}
+
+/// Where this side is in a rekey; see `MSG_REKEY_INIT` for the exchange.
+enum Rekey {
+    Idle,
+    /// We sent INIT and wait for the peer's ACK.
+    Initiated(EphemeralSecret, PublicKey),
+    /// We sent ACK and switched our sending key; the peer switches its own
+    /// right after its DONE.
+    AwaitingDone(Zeroizing<[u8; SESSION_KEY_LEN]>),
+}
+
+/// Per-session cipher state: the Noise transport plus our rekey progress.
+/// Nonces are never sent: Noise counts the frames in each direction, so a
+/// replayed, dropped or reordered frame is sealed under a different nonce
+/// than the receiver expects and fails to authenticate. The sending
+/// counter carries on across rekeys, and `seal` refuses to encrypt unless
+/// it has moved past the last nonce used.
+pub struct CipherState {
+    transport: TransportState,
+    rekey: Rekey,
+    frames_since_rekey: u64,
+    keyed_at: Instant,
+    last_sent_nonce: Option<u64>,
+    sent_under_key: u64,
+    /// As much of the next frame, length prefix included, as has arrived.
+    partial: Vec<u8>,
+    // When the socket last gave us bytes and last took a whole frame,
+    // pings and acks included.
+    last_read: Option<Instant>,
+    last_written: Option<Instant>,
+}
+
+impl CipherState {
+    fn new(transport: TransportState) -> Self {
+        CipherState {
+            transport,
+            rekey: Rekey::Idle,
+            frames_since_rekey: 0,
+            keyed_at: Instant::now(),
+            last_sent_nonce: None,
+            sent_under_key: 0,
+            partial: Vec::new(),
+            last_read: None,
+            last_written: None,
+        }
+    }
+
+    /// When bytes last arrived on the connection, of any frame.
+    pub fn last_read(&self) -> Option<Instant> {
+        self.last_read
+    }
+
+    /// When a frame of any kind was last written out whole.
+    pub fn last_written(&self) -> Option<Instant> {
+        self.last_written
+    }
+
+    /// True once the current keys have been used for long enough under the
+    /// rekey policy and no rekey is in progress.
+    pub fn rekey_due(&self) -> bool {
+        let (frames, interval) = rekey_policy();
+        let frames = frames.min(MAX_FRAMES_PER_KEY / 2);
+        matches!(self.rekey, Rekey::Idle)
+            && (self.frames_since_rekey >= frames || self.keyed_at.elapsed() >= interval)
+    }
+
+    fn switch_send(&mut self, key: &[u8; SESSION_KEY_LEN]) {
+        if self.transport.is_initiator() {
+            self.transport.rekey_initiator_manually(key);
+        } else {
+            self.transport.rekey_responder_manually(key);
+        }
+        self.sent_under_key = 0;
+    }
+
+    fn switch_receive(&mut self, key: &[u8; SESSION_KEY_LEN]) {
+        if self.transport.is_initiator() {
+            self.transport.rekey_responder_manually(key);
+        } else {
+            self.transport.rekey_initiator_manually(key);
+        }
+    }
+
+    fn rekeyed(&mut self) {
+        self.rekey = Rekey::Idle;
+        self.frames_since_rekey = 0;
+        self.keyed_at = Instant::now();
+    }
+}
+
+/// What a received frame turned out to be.
+pub enum Incoming {
+    Chat(String),
+    /// Nothing for the user: part of a rekey still in progress, or a hint
+    /// this build doesn't know.
+    Control,
+    /// Both directions now use fresh keys.
+    Rekeyed,
+    /// A heartbeat; see `protocol::HEARTBEAT_INTERVAL`.
+    Ping,
+    /// A one-off announcement; see `protocol::SIGNAL_SHOUT`.
+    Shout(String),
+    /// Whether the peer is typing; see `protocol::MSG_TYPING`.
+    Typing(bool),
+    /// How many of our chat messages the peer has; see `protocol::MSG_ACK`.
+    Ack(u64),
+    /// How many of them it has seen; see `protocol::MSG_READ`.
+    Read(u64),
+    /// The peer closed the chat; see `protocol::MSG_BYE`.
+    Bye,
+    /// A disappearing-messages TTL, `None` for off, and whether the peer
+    /// is agreeing to ours rather than proposing its own; see
+    /// `protocol::MSG_EXPIRE`.
+    Expire {
+        ttl: Option<Duration>,
+        agreed: bool,
+    },
+    /// A line of the shared notes pad; see `protocol::MSG_PAD`.
+    Pad(PadEdit),
+    /// What the peer's build supports; see `protocol::MSG_FEATURES`.
+    Features(Features),
+    File(FileMessage),
+    Room(RoomMessage),
+}
+
+/// Everything the handshake and the frame layer can fail with, split by
+/// what the chat window should do about it.
+#[derive(Debug, thiserror::Error)]
+pub enum CryptoError {
+    #[error("Peer is not a Sandesh client")]
+    NotSandesh,
+    #[error("Peer is running an incompatible version (protocol {peer}, this build speaks {ours})")]
+    IncompatibleVersion { peer: u8, ours: u8 },
+    #[error("No cipher suite in common with the peer (see `set cipher`)")]
+    NoCommonSuite,
+    /// The peer broke the handshake protocol, e.g. with an identity key
+    /// that isn't its own.
+    #[error("{0}")]
+    HandshakeFailed(String),
+    /// The peer's handshake or confirmation didn't open under the keys we
+    /// derived, so the two sides don't share a key: a passphrase mismatch,
+    /// or something in the middle altering the exchange.
+    #[error("Handshake key confirmation failed")]
+    ConfirmationFailed,
+    #[error("Peer didn't complete the handshake in time")]
+    HandshakeTimeout,
+    /// A frame that didn't authenticate as the next one expected from the
+    /// peer. The two sides no longer agree on the counter, so nothing after
+    /// it can be trusted and the session has to end.
+    #[error("Message #{expected} failed to decrypt: replayed, dropped or tampered with")]
+    DecryptFailed { expected: u64 },
+    #[error("Frame of {0} bytes is over the {max} byte limit", max = MAX_FRAME_LEN)]
+    FrameTooLarge(usize),
+    /// Decrypted fine but doesn't follow the frame layout or the rekey
+    /// exchange.
+    #[error("{0}")]
+    MalformedFrame(&'static str),
+    /// A chat message that authenticated, so the stream is still in step,
+    /// but isn't text.
+    #[error("Message isn't valid UTF-8")]
+    InvalidUtf8,
+    #[error("Message too long (limit {max} bytes)", max = MAX_MESSAGE_LEN)]
+    MessageTooLong,
+    #[error("Encryption failed")]
+    EncryptFailed,
+    /// The sending nonce didn't move past the last one used. Sealing
+    /// anyway would reuse a nonce under the same key, which breaks the
+    /// AEAD, so the session has to end.
+    #[error("Nonce reuse detected (message #{nonce}), aborting for safety")]
+    NonceReuse { nonce: u64 },
+    /// The sending key sealed `MAX_FRAMES_PER_KEY` frames without the peer
+    /// completing a rekey.
+    #[error("Session key used for {max} messages without a rekey, aborting for safety", max = MAX_FRAMES_PER_KEY)]
+    KeyExhausted,
+    #[error("Peer disconnected")]
+    Disconnected,
+    /// A send that didn't finish within the socket's write timeout.
+    #[error("Send stalled")]
+    Stalled,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// The body length in a frame's length prefix; see `Frame::body_len`.
+fn frame_len(prefix: [u8; FRAME_LEN_PREFIX]) -> Result<usize, CryptoError> {
+    Frame::body_len(prefix).map_err(|_| match u32::from_be_bytes(prefix) as usize {
+        len if len > MAX_FRAME_LEN => CryptoError::FrameTooLarge(len),
+        _ => CryptoError::MalformedFrame("Frame too short to hold a tag"),
+    })
 }
 
 pub fn encrypt_and_send(
     stream: &mut TcpStream,
-    cipher: &ChaCha20Poly1305,
+    state: &mut CipherState,
     msg: &str,
-) -> io::Result<()> {
-    let mut nonce_bytes = [0u8; 12];
-    OsRng.fill_bytes(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
+) -> Result<(), CryptoError> {
+    if msg.len() > MAX_MESSAGE_LEN {
+        return Err(CryptoError::MessageTooLong);
+    }
+    send_frame(stream, state, MSG_CHAT, msg.as_bytes())
+}
+
+pub fn send_file_message(
+    stream: &mut TcpStream,
+    state: &mut CipherState,
+    message: &FileMessage,
+) -> Result<(), CryptoError> {
+    send_frame(stream, state, message.kind(), &message.encode())
+}
+
+pub fn send_room_message(
+    stream: &mut TcpStream,
+    state: &mut CipherState,
+    message: &RoomMessage,
+) -> Result<(), CryptoError> {
+    send_frame(stream, state, message.kind(), &message.encode())
+}
+
+pub fn send_shout(
+    stream: &mut TcpStream,
+    state: &mut CipherState,
+    text: &str,
+) -> Result<(), CryptoError> {
+    if text.len() > MAX_MESSAGE_LEN {
+        return Err(CryptoError::MessageTooLong);
+    }
+    send_frame(stream, state, MSG_SHOUT, text.as_bytes())
+}
+
+pub fn send_ack(
+    stream: &mut TcpStream,
+    state: &mut CipherState,
+    received: u64,
+) -> Result<(), CryptoError> {
+    send_frame(stream, state, MSG_ACK, &received.to_be_bytes())
+}
+
+pub fn send_read(
+    stream: &mut TcpStream,
+    state: &mut CipherState,
+    shown: u64,
+) -> Result<(), CryptoError> {
+    send_frame(stream, state, MSG_READ, &shown.to_be_bytes())
+}
+
+pub fn send_typing(
+    stream: &mut TcpStream,
+    state: &mut CipherState,
+    typing: bool,
+) -> Result<(), CryptoError> {
+    send_frame(stream, state, MSG_TYPING, &[typing as u8])
+}
 
-    let ciphertext = cipher
-        .encrypt(nonce, msg.as_bytes())
-        .map_err(|_| io::Error::other("Encryption failed"))?;
+pub fn send_ping(stream: &mut TcpStream, state: &mut CipherState) -> Result<(), CryptoError> {
+    send_frame(stream, state, MSG_PING, &[])
+}
+
+pub fn send_bye(stream: &mut TcpStream, state: &mut CipherState) -> Result<(), CryptoError> {
+    send_frame(stream, state, MSG_BYE, &[])
+}
+
+/// Proposes `ttl` for disappearing messages, or with `agree`, accepts the
+/// one the peer proposed. TTLs are whole seconds.
+pub fn send_expire(
+    stream: &mut TcpStream,
+    state: &mut CipherState,
+    ttl: Option<Duration>,
+    agree: bool,
+) -> Result<(), CryptoError> {
+    let secs = ttl.map_or(0, |ttl| ttl.as_secs().clamp(1, u32::MAX as u64) as u32);
+    let mut body = secs.to_be_bytes().to_vec();
+    body.push(if agree { EXPIRE_AGREE } else { EXPIRE_PROPOSE });
+    send_frame(stream, state, MSG_EXPIRE, &body)
+}
 
-    let total_len = 12 + ciphertext.len();
+pub fn send_features(
+    stream: &mut TcpStream,
+    state: &mut CipherState,
+    features: Features,
+) -> Result<(), CryptoError> {
+    send_frame(stream, state, MSG_FEATURES, &[features.bits()])
+}
 
-    stream.write_u32::<BigEndian>(total_len as u32)?;
-    stream.write_all(&nonce_bytes)?;
-    stream.write_all(&ciphertext)?;
+pub fn send_pad(
+    stream: &mut TcpStream,
+    state: &mut CipherState,
+    edit: &PadEdit,
+) -> Result<(), CryptoError> {
+    send_frame(stream, state, MSG_PAD, &edit.encode())
+}
 
+/// Starts a rekey by offering the peer a fresh ephemeral key. The current
+/// keys stay in use until the peer answers.
+pub fn start_rekey(stream: &mut TcpStream, state: &mut CipherState) -> Result<(), CryptoError> {
+    let (secret, public) = generate_keypair();
+    send_frame(stream, state, MSG_REKEY_INIT, public.as_bytes())?;
+    state.rekey = Rekey::Initiated(secret, public);
     Ok(())
 }
 
+/// Encrypts `kind || body` as the next Noise transport message and returns
+/// the encoded frame.
+fn seal(state: &mut CipherState, kind: u8, body: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if body.len() > MAX_MESSAGE_LEN {
+        return Err(CryptoError::MessageTooLong);
+    }
+    let deflated = COMPRESSION
+        .load(Ordering::Relaxed)
+        .then(|| deflate(body))
+        .flatten();
+    let (kind, body) = match &deflated {
+        Some(deflated) => (kind | FLAG_COMPRESSED, deflated.as_slice()),
+        None => (kind, body),
+    };
+    let len = PLAINTEXT_HEADER_LEN + body.len();
+    let mut plaintext = Zeroizing::new(Vec::with_capacity(padded_len(len)));
+    plaintext.push(kind);
+    plaintext.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    plaintext.extend_from_slice(body);
+    if PADDING.load(Ordering::Relaxed) {
+        plaintext.resize(padded_len(len), 0);
+    }
+
+    let nonce = state.transport.sending_nonce();
+    if state.last_sent_nonce.is_some_and(|last| nonce <= last) {
+        return Err(CryptoError::NonceReuse { nonce });
+    }
+    if state.sent_under_key >= MAX_FRAMES_PER_KEY {
+        return Err(CryptoError::KeyExhausted);
+    }
+
+    let mut ciphertext = vec![0u8; plaintext.len() + TAG_LEN];
+    state
+        .transport
+        .write_message(&plaintext, &mut ciphertext)
+        .map_err(|_| CryptoError::EncryptFailed)?;
+    state.last_sent_nonce = Some(nonce);
+    state.sent_under_key += 1;
+    state.frames_since_rekey += 1;
+
+    Ok(Frame { ciphertext }.encode())
+}
+
+/// Decrypts a frame body as the next Noise transport message from the peer.
+fn open(state: &mut CipherState, body: &[u8]) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+    let expected = state.transport.receiving_nonce();
+    let mut plaintext = Zeroizing::new(vec![0u8; body.len()]);
+    let len = state
+        .transport
+        .read_message(body, &mut plaintext)
+        .map_err(|_| CryptoError::DecryptFailed { expected })?;
+    plaintext.truncate(len);
+    state.frames_since_rekey += 1;
+    Ok(plaintext)
+}
+
+fn send_frame(
+    stream: &mut TcpStream,
+    state: &mut CipherState,
+    kind: u8,
+    body: &[u8],
+) -> Result<(), CryptoError> {
+    let frame = seal(state, kind, body)?;
+
+    // Write in blocking mode so the socket's write timeout applies; a
+    // non-blocking write would give up the moment the send buffer is full.
+    stream.set_nonblocking(false)?;
+    let write_result = write_within_timeout(stream, &frame);
+    stream.set_nonblocking(true)?;
+
+    match write_result {
+        Ok(_) => {
+            state.last_written = Some(Instant::now());
+            Ok(())
+        }
+        Err(ref e)
+            if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+        {
+            Err(CryptoError::Stalled)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `write_all` with the socket's write timeout bounding the whole frame.
+/// A write that times out after taking part of it returns that part, and
+/// `write_all` would then wait all over again for the rest.
+fn write_within_timeout(stream: &mut TcpStream, frame: &[u8]) -> io::Result<()> {
+    let Some(timeout) = stream.write_timeout()? else {
+        return stream.write_all(frame);
+    };
+    let deadline = Instant::now() + timeout;
+    let mut rest = frame;
+    let result = loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if rest.is_empty() {
+            break Ok(());
+        } else if left.is_zero() {
+            break Err(io::ErrorKind::TimedOut.into());
+        } else if let Err(e) = stream.set_write_timeout(Some(left)) {
+            break Err(e);
+        }
+        match stream.write(rest) {
+            Ok(0) => break Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => rest = &rest[n..],
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => break Err(e),
+        }
+    };
+    stream.set_write_timeout(Some(timeout))?;
+    result
+}
+
+/// Moves what the stream has of the next frame into `state.partial`, and
+/// returns the frame's body once all of it is there. The length is
+/// checked as soon as the prefix is in, before room is made for the body.
+fn read_frame(
+    stream: &mut TcpStream,
+    state: &mut CipherState,
+) -> Result<Option<Vec<u8>>, CryptoError> {
+    loop {
+        let have = state.partial.len();
+        let wanted = match state.partial.first_chunk::<FRAME_LEN_PREFIX>() {
+            Some(prefix) => FRAME_LEN_PREFIX + frame_len(*prefix)?,
+            None => FRAME_LEN_PREFIX,
+        };
+        if have == wanted {
+            let body = state.partial.split_off(FRAME_LEN_PREFIX);
+            state.partial.clear();
+            return Ok(Some(body));
+        }
+        state.partial.resize(wanted, 0);
+        let read = stream.read(&mut state.partial[have..]);
+        state.partial.truncate(have + *read.as_ref().unwrap_or(&0));
+        match read {
+            Ok(0) => return Err(CryptoError::Disconnected),
+            Ok(_) => state.last_read = Some(Instant::now()),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Reads and decrypts the next frame if one has fully arrived, or returns
+/// `None` straight away if not. It never waits on the stream: what has
+/// come of a frame so far is kept in `state` until the rest arrives, so a
+/// peer that stops halfway through a frame can't hold up a thread that
+/// shares the session.
 pub fn receive_and_decrypt(
     stream: &mut TcpStream,
-    cipher: &ChaCha20Poly1305,
-) -> io::Result<String> {
-    // 1. PEEK
-    let mut len_buf = [0u8; 4];
-    match stream.peek(&mut len_buf) {
-        Ok(4) => { /* Header ready */ }
-
-        // FIX: Explicitly check for 0. This means the connection is closed.
-        Ok(0) => {
-            return Err(io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "Peer disconnected",
+    state: &mut CipherState,
+) -> Result<Option<Incoming>, CryptoError> {
+    let Some(buffer) = read_frame(stream, state)? else {
+        return Ok(None);
+    };
+
+    let plaintext_bytes = open(state, &buffer)?;
+
+    let invalid = CryptoError::MalformedFrame;
+    let (kind, body) = unpad(&plaintext_bytes)?;
+    let inflated;
+    let (kind, body) = if kind & FLAG_COMPRESSED != 0 {
+        inflated = inflate(body)?;
+        (kind & !FLAG_COMPRESSED, inflated.as_slice())
+    } else {
+        (kind, body)
+    };
+    let incoming = match kind {
+        MSG_CHAT => {
+            let text = String::from_utf8(body.to_vec()).map_err(|_| CryptoError::InvalidUtf8)?;
+            Incoming::Chat(text)
+        }
+        MSG_REKEY_INIT => {
+            let peer_public = rekey_public(body)?;
+            match state.rekey {
+                Rekey::AwaitingDone(_) => return Err(invalid("Unexpected rekey request")),
+                // Both sides started at once: the Noise initiator's exchange
+                // wins and the responder drops its own.
+                Rekey::Initiated(..) if state.transport.is_initiator() => {
+                    return Ok(Some(Incoming::Control));
+                }
+                _ => {}
+            }
+            let (secret, public) = generate_keypair();
+            let keys = derive_session_keys(
+                REKEY_INFO,
+                secret.diffie_hellman(&peer_public).as_bytes(),
+                public.as_bytes(),
+                peer_public.as_bytes(),
+            );
+            send_frame(stream, state, MSG_REKEY_ACK, public.as_bytes())?;
+            state.switch_send(&keys.send);
+            state.rekey = Rekey::AwaitingDone(Zeroizing::new(keys.receive));
+            Incoming::Control
+        }
+        MSG_REKEY_ACK => {
+            let peer_public = rekey_public(body)?;
+            let Rekey::Initiated(secret, public) = std::mem::replace(&mut state.rekey, Rekey::Idle)
+            else {
+                return Err(invalid("Unexpected rekey answer"));
+            };
+            let keys = derive_session_keys(
+                REKEY_INFO,
+                secret.diffie_hellman(&peer_public).as_bytes(),
+                public.as_bytes(),
+                peer_public.as_bytes(),
+            );
+            state.switch_receive(&keys.receive);
+            send_frame(stream, state, MSG_REKEY_DONE, &[])?;
+            state.switch_send(&keys.send);
+            state.rekeyed();
+            Incoming::Rekeyed
+        }
+        MSG_REKEY_DONE => {
+            let Rekey::AwaitingDone(receive) = std::mem::replace(&mut state.rekey, Rekey::Idle)
+            else {
+                return Err(invalid("Unexpected rekey confirmation"));
+            };
+            state.switch_receive(&receive);
+            state.rekeyed();
+            Incoming::Rekeyed
+        }
+        MSG_SHOUT => {
+            let text = String::from_utf8(body.to_vec()).map_err(|_| CryptoError::InvalidUtf8)?;
+            Incoming::Shout(text)
+        }
+        MSG_TYPING => match body {
+            [0] => Incoming::Typing(false),
+            [1] => Incoming::Typing(true),
+            _ => return Err(invalid("Bad typing hint")),
+        },
+        MSG_ACK => match <[u8; 8]>::try_from(body) {
+            Ok(count) => Incoming::Ack(u64::from_be_bytes(count)),
+            Err(_) => return Err(invalid("Bad acknowledgement")),
+        },
+        MSG_READ => match <[u8; 8]>::try_from(body) {
+            Ok(count) => Incoming::Read(u64::from_be_bytes(count)),
+            Err(_) => return Err(invalid("Bad read receipt")),
+        },
+        MSG_BYE if body.is_empty() => Incoming::Bye,
+        MSG_BYE => return Err(invalid("Goodbye with a body")),
+        MSG_EXPIRE => match body {
+            &[a, b, c, d, flag @ (EXPIRE_PROPOSE | EXPIRE_AGREE)] => {
+                let secs = u32::from_be_bytes([a, b, c, d]);
+                Incoming::Expire {
+                    ttl: (secs != 0).then(|| Duration::from_secs(secs.into())),
+                    agreed: flag == EXPIRE_AGREE,
+                }
+            }
+            _ => return Err(invalid("Bad expiry hint")),
+        },
+        MSG_PAD => match PadEdit::decode(body) {
+            Some(edit) => Incoming::Pad(edit),
+            None => return Err(invalid("Bad pad line")),
+        },
+        MSG_FEATURES => match body {
+            &[bits] => Incoming::Features(Features::from_bits(bits)),
+            _ => return Err(invalid("Bad feature hint")),
+        },
+        // A hint from a newer build.
+        kind if (MSG_HINT_MIN..FLAG_COMPRESSED).contains(&kind) => Incoming::Control,
+        MSG_PING if body.is_empty() => Incoming::Ping,
+        MSG_PING => return Err(invalid("Ping with a body")),
+        kind => match (
+            FileMessage::decode(kind, body),
+            RoomMessage::decode(kind, body),
+        ) {
+            (Some(message), _) => Incoming::File(message),
+            (None, Some(message)) => Incoming::Room(message),
+            (None, None) => return Err(invalid("Unknown message type or bad file or room frame")),
+        },
+    };
+    Ok(Some(incoming))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{connected, receive};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    #[test]
+    fn expiry_hint_round_trips() {
+        let ((mut a, mut a_hs), (mut b, mut b_hs)) = connected();
+        let ttl = Some(Duration::from_secs(30));
+        send_expire(&mut a, &mut a_hs.cipher, ttl, false).unwrap();
+        send_expire(&mut a, &mut a_hs.cipher, None, true).unwrap();
+        assert!(matches!(
+            receive(&mut b, &mut b_hs.cipher).unwrap(),
+            Incoming::Expire { ttl: Some(t), agreed: false } if t == Duration::from_secs(30)
+        ));
+        assert!(matches!(
+            receive(&mut b, &mut b_hs.cipher).unwrap(),
+            Incoming::Expire {
+                ttl: None,
+                agreed: true
+            }
+        ));
+    }
+
+    #[test]
+    fn feature_hint_round_trips() {
+        let ((mut a, mut a_hs), (mut b, mut b_hs)) = connected();
+        send_features(&mut a, &mut a_hs.cipher, Features::LOCAL).unwrap();
+        assert!(matches!(
+            receive(&mut b, &mut b_hs.cipher).unwrap(),
+            Incoming::Features(features) if features == Features::LOCAL
+        ));
+    }
+
+    /// Fixed inputs and the keys they must give, so a change to the
+    /// derivation can't slip through unnoticed: it would split a rekey
+    /// between builds.
+    #[test]
+    fn rekey_derivation_matches_known_vector() {
+        let shared = [0x11; 32];
+        let (low, high) = ([0x22; 32], [0x33; 32]);
+        let from_low = "1172c16d467b673a7676060da1c50e022e058be44f09118b4ff26a8029b88a53";
+        let from_high = "2ec4b59e2a7094243699b11b6ca5bcd1c54cb649c3bd40f997c45a86a6f651a5";
+
+        let ours = derive_session_keys(REKEY_INFO, &shared, &low, &high);
+        assert_eq!(identity::encode_hex(&ours.send), from_low);
+        assert_eq!(identity::encode_hex(&ours.receive), from_high);
+        // The other side gets the same pair the other way round.
+        let theirs = derive_session_keys(REKEY_INFO, &shared, &high, &low);
+        assert_eq!(theirs.send, ours.receive);
+        assert_eq!(theirs.receive, ours.send);
+
+        let other_label = derive_session_keys(b"sandesh-v2 rekey", &shared, &low, &high);
+        assert_ne!(other_label.send, ours.send);
+        let other_key = derive_session_keys(REKEY_INFO, &shared, &low, &[0x34; 32]);
+        assert_ne!(other_key.send, ours.send);
+    }
+
+    #[test]
+    fn session_keys_are_wiped() {
+        let mut keys = derive_session_keys(REKEY_INFO, &[0x11; 32], &[0x22; 32], &[0x33; 32]);
+        assert_ne!(keys.send, [0; SESSION_KEY_LEN]);
+        assert_ne!(keys.receive, [0; SESSION_KEY_LEN]);
+        // Drop runs the same zeroize; the memory can't be looked at
+        // afterwards without unsafe code.
+        keys.zeroize();
+        assert_eq!(keys.send, [0; SESSION_KEY_LEN]);
+        assert_eq!(keys.receive, [0; SESSION_KEY_LEN]);
+
+        fn wiped_on_drop<T: ZeroizeOnDrop>() {}
+        wiped_on_drop::<SessionKeys>();
+    }
+
+    /// Seals a chat frame as `from` would send it, lets `tamper` change
+    /// the bytes, and writes them out raw.
+    fn send_altered(stream: &mut TcpStream, from: &mut CipherState, tamper: fn(&mut [u8])) {
+        let mut frame = seal(from, MSG_CHAT, b"pay 10 to alice").unwrap();
+        tamper(&mut frame);
+        stream.write_all(&frame).unwrap();
+    }
+
+    fn flip_body(frame: &mut [u8]) {
+        frame[FRAME_LEN_PREFIX] ^= 1;
+    }
+
+    fn flip_tag(frame: &mut [u8]) {
+        frame[frame.len() - 1] ^= 1;
+    }
+
+    /// One byte shorter: the last byte is left over for the next frame.
+    fn shorten(frame: &mut [u8]) {
+        frame[FRAME_LEN_PREFIX - 1] -= 1;
+    }
+
+    #[test]
+    fn tampered_frames_fail_and_end_the_stream() {
+        let tampers: [fn(&mut [u8]); 3] = [flip_body, flip_tag, shorten];
+        for tamper in tampers {
+            let ((mut a, mut a_hs), (mut b, mut b_hs)) = connected();
+            encrypt_and_send(&mut a, &mut a_hs.cipher, "first").unwrap();
+            assert!(matches!(
+                receive(&mut b, &mut b_hs.cipher),
+                Ok(Incoming::Chat(_))
+            ));
+
+            send_altered(&mut a, &mut a_hs.cipher, tamper);
+            assert!(matches!(
+                receive(&mut b, &mut b_hs.cipher),
+                Err(CryptoError::DecryptFailed { expected: 3 })
             ));
+            // Genuine frames after it don't open either, so the chat
+            // window has to close the session rather than carry on.
+            encrypt_and_send(&mut a, &mut a_hs.cipher, "after").unwrap();
+            assert!(receive(&mut b, &mut b_hs.cipher).is_err());
         }
+    }
+
+    #[test]
+    fn reflected_frame_is_rejected() {
+        let ((mut a, mut a_hs), (mut b, _)) = connected();
+        // Someone in the middle echoes our own frame back to us.
+        let frame = seal(&mut a_hs.cipher, MSG_CHAT, b"reflect me").unwrap();
+        b.write_all(&frame).unwrap();
+        assert!(matches!(
+            receive(&mut a, &mut a_hs.cipher),
+            Err(CryptoError::DecryptFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn captured_frame_replayed_later_is_rejected() {
+        let ((mut a, mut a_hs), (mut b, mut b_hs)) = connected();
+        let captured = seal(&mut a_hs.cipher, MSG_CHAT, b"open the door").unwrap();
+        a.write_all(&captured).unwrap();
+        encrypt_and_send(&mut a, &mut a_hs.cipher, "and close it").unwrap();
+        assert!(
+            matches!(receive(&mut b, &mut b_hs.cipher), Ok(Incoming::Chat(text)) if text == "open the door")
+        );
+        assert!(
+            matches!(receive(&mut b, &mut b_hs.cipher), Ok(Incoming::Chat(text)) if text == "and close it")
+        );
 
-        // Less than 4 bytes means data is trickling in, but not ready yet.
-        Ok(_) => return Err(io::Error::from(io::ErrorKind::WouldBlock)),
+        a.write_all(&captured).unwrap();
+        assert!(matches!(
+            receive(&mut b, &mut b_hs.cipher),
+            Err(CryptoError::DecryptFailed { expected: 4 })
+        ));
+    }
+
+    #[test]
+    fn frame_delivered_twice_is_rejected_the_second_time() {
+        let ((mut a, mut a_hs), (mut b, mut b_hs)) = connected();
+        let frame = seal(&mut a_hs.cipher, MSG_CHAT, b"once only").unwrap();
+        a.write_all(&frame).unwrap();
+        a.write_all(&frame).unwrap();
+        assert!(matches!(
+            receive(&mut b, &mut b_hs.cipher),
+            Ok(Incoming::Chat(_))
+        ));
+        let Err(e) = receive(&mut b, &mut b_hs.cipher) else {
+            panic!("the duplicate was accepted");
+        };
+        // What the chat window shows before closing the session.
+        assert_eq!(
+            e.to_string(),
+            "Message #3 failed to decrypt: replayed, dropped or tampered with"
+        );
+    }
+
+    #[test]
+    fn frame_length_prefix_is_bounded() {
+        let prefix = |len: usize| (len as u32).to_be_bytes();
+        assert_eq!(frame_len(prefix(MAX_FRAME_LEN)).unwrap(), MAX_FRAME_LEN);
+        assert_eq!(frame_len(prefix(TAG_LEN)).unwrap(), TAG_LEN);
+        assert!(matches!(
+            frame_len(prefix(MAX_FRAME_LEN + 1)),
+            Err(CryptoError::FrameTooLarge(len)) if len == MAX_FRAME_LEN + 1
+        ));
+        assert!(matches!(
+            frame_len([0xff; FRAME_LEN_PREFIX]),
+            Err(CryptoError::FrameTooLarge(len)) if len == u32::MAX as usize
+        ));
+        assert!(matches!(
+            frame_len(prefix(0)),
+            Err(CryptoError::MalformedFrame(_))
+        ));
+        assert!(matches!(
+            frame_len(prefix(TAG_LEN - 1)),
+            Err(CryptoError::MalformedFrame(_))
+        ));
+    }
 
-        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+    #[test]
+    fn oversized_frame_is_refused_before_reading_its_body() {
+        let ((mut a, _), (mut b, mut b_hs)) = connected();
+        // If the body were waited for, this would time out instead.
+        b.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        a.write_all(&[0xff; FRAME_LEN_PREFIX]).unwrap();
+        let Err(e) = receive(&mut b, &mut b_hs.cipher) else {
+            panic!("an oversized frame was accepted");
+        };
+        assert!(matches!(e, CryptoError::FrameTooLarge(len) if len == u32::MAX as usize));
+        assert_eq!(
+            e.to_string(),
+            "Frame of 4294967295 bytes is over the 65535 byte limit"
+        );
+    }
+
+    /// Runs both sides of a handshake over loopback, initiator first, each
+    /// with its passphrase and the suites it allows. With `rewrite_offer`,
+    /// the initiator's suite offer is replaced on the way.
+    fn handshake_with(
+        passphrases: [Option<&'static str>; 2],
+        suites: [u8; 2],
+        rewrite_offer: Option<u8>,
+    ) -> (
+        Result<Handshake, CryptoError>,
+        Result<Handshake, CryptoError>,
+    ) {
+        identity::init_for_tests();
+        let keys = [identity::local().clone(), identity::local().clone()];
+        handshake_between(keys, passphrases, suites, rewrite_offer)
+    }
+
+    /// `handshake_with`, each side proving it holds its own identity key.
+    fn handshake_between(
+        keys: [SigningKey; 2],
+        passphrases: [Option<&'static str>; 2],
+        suites: [u8; 2],
+        rewrite_offer: Option<u8>,
+    ) -> (
+        Result<Handshake, CryptoError>,
+        Result<Handshake, CryptoError>,
+    ) {
+        let [initiator_key, responder_key] = keys;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut addr = listener.local_addr().unwrap();
+        if let Some(offer) = rewrite_offer {
+            addr = rewriting_relay(addr, HANDSHAKE_MAGIC.len() + 1, offer);
+        }
+        let dialler = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).unwrap();
+            let result = exchange_keys(
+                &stream,
+                Role::Initiator,
+                passphrases[0],
+                suites[0],
+                &initiator_key,
+            );
+            // Keep the socket open until the other side is done with it.
+            (result, stream)
+        });
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).unwrap();
+        let answered = exchange_keys(
+            &stream,
+            Role::Responder,
+            passphrases[1],
+            suites[1],
+            &responder_key,
+        );
+        drop(stream);
+        (dialler.join().unwrap().0, answered)
+    }
+
+    /// Listens on a port of its own and passes one connection through to
+    /// `to`, replacing the byte at `offset` from the dialling side.
+    fn rewriting_relay(to: SocketAddr, offset: usize, byte: u8) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (inbound, _) = listener.accept().unwrap();
+            let outbound = TcpStream::connect(to).unwrap();
+            let (mut from_in, mut to_out) =
+                (inbound.try_clone().unwrap(), outbound.try_clone().unwrap());
+            thread::spawn(move || {
+                let mut seen = 0;
+                let mut buf = [0u8; 4096];
+                while let Ok(n @ 1..) = from_in.read(&mut buf) {
+                    if (seen..seen + n).contains(&offset) {
+                        buf[offset - seen] = byte;
+                    }
+                    seen += n;
+                    if to_out.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                }
+                let _ = to_out.shutdown(std::net::Shutdown::Write);
+            });
+            let (mut from_out, mut to_in) = (outbound, inbound);
+            let _ = io::copy(&mut from_out, &mut to_in);
+            let _ = to_in.shutdown(std::net::Shutdown::Write);
+        });
+        addr
+    }
+
+    #[test]
+    fn matching_passphrases_confirm() {
+        let (a, b) = handshake_with([Some("rosebud"); 2], [ANY_SUITE; 2], None);
+        assert_eq!(a.unwrap().sas, b.unwrap().sas);
+    }
+
+    #[test]
+    fn verification_codes_agree_and_change_with_the_keys() {
+        identity::init_for_tests();
+        let ours = identity::local().clone();
+        let theirs = SigningKey::generate(&mut OsRng);
+        let sas = |keys: [&SigningKey; 2], passphrase| {
+            let (a, b) = handshake_between(
+                keys.map(SigningKey::clone),
+                [passphrase; 2],
+                [ANY_SUITE; 2],
+                None,
+            );
+            let (a, b) = (a.unwrap(), b.unwrap());
+            assert_eq!(a.sas, b.sas, "both ends read the same code");
+            assert_eq!(b.peer_identity, keys[0].verifying_key());
+            a.sas
+        };
+
+        let plain = sas([&ours, &theirs], None);
+        let with_psk = sas([&ours, &theirs], Some("rosebud"));
+        let other_psk = sas([&ours, &theirs], Some("rosebuds"));
+        let other_static = sas([&ours, &ours], Some("rosebud"));
+        let swapped = sas([&theirs, &ours], Some("rosebud"));
+        let codes = [plain, with_psk, other_psk, other_static, swapped];
+        for (i, code) in codes.iter().enumerate() {
+            assert!(!codes[i + 1..].contains(code), "{:?}", codes);
         }
-        Err(e) => return Err(e),
     }
 
-    // 2. READ LENGTH
-    let len = stream.read_u32::<BigEndian>()?;
-    if len < 12 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Message too short",
+    #[test]
+    fn different_passphrases_fail_confirmation_on_both_sides() {
+        let (a, b) = handshake_with([Some("rosebud"), Some("rosebuds")], [ANY_SUITE; 2], None);
+        assert!(matches!(a, Err(CryptoError::ConfirmationFailed)));
+        assert!(matches!(b, Err(CryptoError::ConfirmationFailed)));
+        let (a, b) = handshake_with([None, Some("rosebud")], [ANY_SUITE; 2], None);
+        assert!(matches!(a, Err(CryptoError::ConfirmationFailed)));
+        assert!(matches!(b, Err(CryptoError::ConfirmationFailed)));
+    }
+
+    #[test]
+    fn corrupted_confirmation_is_refused() {
+        let ((mut a, mut a_hs), (b, mut b_hs)) = connected();
+        b.set_nonblocking(false).unwrap();
+        let mut label = CONFIRM_LABEL.to_vec();
+        label[0] ^= 1;
+        a.write_all(&seal(&mut a_hs.cipher, MSG_CONFIRM, &label).unwrap())
+            .unwrap();
+        assert!(matches!(
+            read_confirmation(&b, &mut b_hs.cipher),
+            Err(CryptoError::ConfirmationFailed)
+        ));
+        // Nor does a genuine label under the wrong frame type pass.
+        a.write_all(&seal(&mut a_hs.cipher, MSG_CHAT, CONFIRM_LABEL).unwrap())
+            .unwrap();
+        assert!(matches!(
+            read_confirmation(&b, &mut b_hs.cipher),
+            Err(CryptoError::ConfirmationFailed)
         ));
     }
 
-    // 3. TOGGLE BLOCKING
-    stream.set_nonblocking(false)?;
+    #[test]
+    fn suites_are_negotiated_to_the_common_preference() {
+        const CHACHA: u8 = CipherSuite::ChaCha20Poly1305 as u8;
+        const AES: u8 = CipherSuite::Aes256Gcm as u8;
+        for (suites, expected) in [
+            ([CHACHA, CHACHA], CipherSuite::ChaCha20Poly1305),
+            ([AES, AES], CipherSuite::Aes256Gcm),
+            ([ANY_SUITE, ANY_SUITE], CipherSuite::ChaCha20Poly1305),
+            ([ANY_SUITE, AES], CipherSuite::Aes256Gcm),
+            ([AES, ANY_SUITE], CipherSuite::Aes256Gcm),
+            ([CHACHA, ANY_SUITE], CipherSuite::ChaCha20Poly1305),
+        ] {
+            let (a, b) = handshake_with([None; 2], suites, None);
+            let (mut a, mut b) = (a.unwrap(), b.unwrap());
+            assert_eq!((a.suite, b.suite), (expected, expected));
+            assert_eq!(a.sas, b.sas);
+            // The transports agree too.
+            let frame = seal(&mut a.cipher, MSG_CHAT, b"hi").unwrap();
+            let plaintext = open(&mut b.cipher, &frame[FRAME_LEN_PREFIX..]).unwrap();
+            assert_eq!(unpad(&plaintext).unwrap(), (MSG_CHAT, b"hi".as_slice()));
+        }
+    }
 
-    let mut buffer = vec![0u8; len as usize];
-    let read_result = stream.read_exact(&mut buffer);
+    #[test]
+    fn no_common_suite_fails_on_both_sides() {
+        let chacha = CipherSuite::ChaCha20Poly1305.id();
+        let aes = CipherSuite::Aes256Gcm.id();
+        let (a, b) = handshake_with([None; 2], [chacha, aes], None);
+        assert!(matches!(a, Err(CryptoError::NoCommonSuite)));
+        assert!(matches!(b, Err(CryptoError::NoCommonSuite)));
+    }
 
-    // 4. RESTORE NON-BLOCKING
-    stream.set_nonblocking(true)?;
+    #[test]
+    fn rewritten_offer_fails_through_the_prologue() {
+        // Stripping ChaCha20 from the offer would steer the responder to
+        // AES-256-GCM, which the initiator also allows.
+        let aes = CipherSuite::Aes256Gcm.id();
+        let (a, b) = handshake_with([None; 2], [ANY_SUITE; 2], Some(aes));
+        assert!(matches!(a, Err(CryptoError::ConfirmationFailed)));
+        assert!(b.is_err());
+    }
 
-    match read_result {
-        Ok(_) => {}
-        // If the peer disconnects *during* the body transmission
-        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-            return Err(io::Error::new(
-                io::ErrorKind::ConnectionAborted,
-                "Peer disconnected",
-            ));
+    #[test]
+    fn repeated_nonce_is_refused() {
+        let ((mut a, mut a_hs), _) = connected();
+        let state = &mut a_hs.cipher;
+        let nonce = state.transport.sending_nonce();
+        // As if a bug had wound the counter back to a nonce already used.
+        state.last_sent_nonce = Some(nonce);
+        assert!(matches!(
+            encrypt_and_send(&mut a, state, "hello"),
+            Err(CryptoError::NonceReuse { nonce: n }) if n == nonce
+        ));
+        state.last_sent_nonce = Some(nonce + 5);
+        let Err(e) = seal(state, MSG_CHAT, b"hello") else {
+            panic!("sealed under a used nonce");
+        };
+        assert!(e.to_string().contains("aborting for safety"));
+        // Refusing didn't use the nonce up.
+        assert_eq!(state.transport.sending_nonce(), nonce);
+    }
+
+    #[test]
+    fn key_is_retired_after_its_frame_limit() {
+        let ((mut a, mut a_hs), (mut b, mut b_hs)) = connected();
+        a_hs.cipher.sent_under_key = MAX_FRAMES_PER_KEY - 1;
+        encrypt_and_send(&mut a, &mut a_hs.cipher, "last one").unwrap();
+        assert!(matches!(
+            encrypt_and_send(&mut a, &mut a_hs.cipher, "one too many"),
+            Err(CryptoError::KeyExhausted)
+        ));
+        assert!(
+            matches!(receive(&mut b, &mut b_hs.cipher), Ok(Incoming::Chat(text)) if text == "last one")
+        );
+    }
+
+    #[test]
+    fn padding_rounds_up_to_the_bucket() {
+        assert_eq!(padded_len(1), PAD_BUCKET);
+        assert_eq!(padded_len(PAD_BUCKET - 1), PAD_BUCKET);
+        assert_eq!(padded_len(PAD_BUCKET), PAD_BUCKET);
+        assert_eq!(padded_len(PAD_BUCKET + 1), 2 * PAD_BUCKET);
+        // The last bucket is cut short so the frame stays within bounds.
+        assert_eq!(
+            padded_len(PLAINTEXT_HEADER_LEN + MAX_MESSAGE_LEN),
+            MAX_FRAME_LEN - TAG_LEN
+        );
+    }
+
+    #[test]
+    fn padded_frames_round_trip() {
+        let ((_, mut a_hs), (_, mut b_hs)) = connected();
+        let fits = PAD_BUCKET - PLAINTEXT_HEADER_LEN;
+        for len in [0, fits - 1, fits, fits + 1, MAX_MESSAGE_LEN] {
+            let body = vec![b'x'; len];
+            let frame = seal(&mut a_hs.cipher, MSG_CHAT, &body).unwrap();
+            let ciphertext = &frame[FRAME_LEN_PREFIX..];
+            let expected = padded_len(PLAINTEXT_HEADER_LEN + len) + TAG_LEN;
+            assert_eq!(ciphertext.len(), expected, "body of {} bytes", len);
+            let plaintext = open(&mut b_hs.cipher, ciphertext).unwrap();
+            assert_eq!(unpad(&plaintext).unwrap(), (MSG_CHAT, body.as_slice()));
         }
-        Err(e) => return Err(e),
     }
 
-    // 5. DECRYPT
-    let (nonce_bytes, ciphertext_bytes) = buffer.split_at(12);
-    let nonce = Nonce::from_slice(nonce_bytes);
+    #[test]
+    fn malformed_padding_is_rejected() {
+        assert!(unpad(&[]).is_err());
+        assert!(unpad(&[MSG_CHAT, 0]).is_err());
+        // A body length past the end of the plaintext.
+        assert!(unpad(&[MSG_CHAT, 0, 4, b'a', b'b', b'c']).is_err());
+        assert!(unpad(&[MSG_CHAT, 0xff, 0xff, 0, 0]).is_err());
+        // Padding is whatever follows the body, and is dropped.
+        assert_eq!(
+            unpad(&[MSG_CHAT, 0, 2, b'o', b'k', 7, 7]).unwrap(),
+            (MSG_CHAT, b"ok".as_slice())
+        );
+        assert_eq!(unpad(&[MSG_PING, 0, 0]).unwrap(), (MSG_PING, [].as_slice()));
+    }
 
-    let plaintext_bytes = cipher
-        .decrypt(nonce, ciphertext_bytes)
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Decryption failed"))?;
+    #[test]
+    fn compressible_bodies_deflate_and_round_trip() {
+        let text = "the build failed again on the lab machines\n".repeat(40);
+        let deflated = deflate(text.as_bytes()).unwrap();
+        assert!(deflated.len() < text.len() / 4);
+        assert_eq!(inflate(&deflated).unwrap().as_slice(), text.as_bytes());
 
-    let plaintext = String::from_utf8(plaintext_bytes)
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF8"))?;
+        // Through the frame layer, flag and all.
+        let ((mut a, mut a_hs), (mut b, mut b_hs)) = connected();
+        let frame = seal(&mut a_hs.cipher, MSG_CHAT | FLAG_COMPRESSED, &deflated).unwrap();
+        a.write_all(&frame).unwrap();
+        encrypt_and_send(&mut a, &mut a_hs.cipher, "plain").unwrap();
+        assert!(matches!(receive(&mut b, &mut b_hs.cipher), Ok(Incoming::Chat(t)) if t == text));
+        assert!(matches!(receive(&mut b, &mut b_hs.cipher), Ok(Incoming::Chat(t)) if t == "plain"));
+    }
 
-    Ok(plaintext)
+    #[test]
+    fn incompressible_and_short_bodies_are_sent_as_they_are() {
+        let mut seed = 1u32;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (seed >> 24) as u8
+            })
+            .collect();
+        assert!(deflate(&noise).is_none());
+        assert!(deflate(&[b'a'; COMPRESS_MIN_LEN - 1]).is_none());
+    }
+
+    #[test]
+    fn inflating_past_the_message_limit_is_refused() {
+        // Ten megabytes of zeros deflate to a few kilobytes.
+        let mut bomb = Vec::new();
+        let mut encoder = DeflateEncoder::new(&mut bomb, Compression::best());
+        encoder.write_all(&vec![0u8; 10 << 20]).unwrap();
+        encoder.finish().unwrap();
+        assert!(bomb.len() < MAX_MESSAGE_LEN);
+        assert!(matches!(
+            inflate(&bomb),
+            Err(CryptoError::MalformedFrame(_))
+        ));
+
+        let mut exact = Vec::new();
+        let mut encoder = DeflateEncoder::new(&mut exact, Compression::best());
+        encoder.write_all(&vec![0u8; MAX_MESSAGE_LEN]).unwrap();
+        encoder.finish().unwrap();
+        assert_eq!(inflate(&exact).unwrap().len(), MAX_MESSAGE_LEN);
+
+        assert!(inflate(b"not deflate at all").is_err());
+    }
+
+    /// Chat still flows both ways, under whatever keys are current.
+    fn assert_chatting(
+        (a, a_state): (&mut TcpStream, &mut CipherState),
+        (b, b_state): (&mut TcpStream, &mut CipherState),
+    ) {
+        encrypt_and_send(a, a_state, "ping").unwrap();
+        assert!(matches!(receive(b, b_state), Ok(Incoming::Chat(t)) if t == "ping"));
+        encrypt_and_send(b, b_state, "pong").unwrap();
+        assert!(matches!(receive(a, a_state), Ok(Incoming::Chat(t)) if t == "pong"));
+    }
+
+    #[test]
+    fn rekey_round_trips() {
+        let ((mut a, mut a_hs), (mut b, mut b_hs)) = connected();
+        let (a_state, b_state) = (&mut a_hs.cipher, &mut b_hs.cipher);
+        for _ in 0..3 {
+            start_rekey(&mut a, a_state).unwrap();
+            assert!(matches!(receive(&mut b, b_state), Ok(Incoming::Control)));
+            assert!(matches!(receive(&mut a, a_state), Ok(Incoming::Rekeyed)));
+            assert!(matches!(receive(&mut b, b_state), Ok(Incoming::Rekeyed)));
+            assert!(matches!(a_state.rekey, Rekey::Idle));
+            assert!(matches!(b_state.rekey, Rekey::Idle));
+            assert_eq!(a_state.sent_under_key, 0);
+            assert_chatting((&mut a, a_state), (&mut b, b_state));
+        }
+        // The responder can start one too.
+        start_rekey(&mut b, b_state).unwrap();
+        assert!(matches!(receive(&mut a, a_state), Ok(Incoming::Control)));
+        assert!(matches!(receive(&mut b, b_state), Ok(Incoming::Rekeyed)));
+        assert!(matches!(receive(&mut a, a_state), Ok(Incoming::Rekeyed)));
+        assert_chatting((&mut a, a_state), (&mut b, b_state));
+    }
+
+    #[test]
+    fn simultaneous_rekeys_settle_on_the_initiators() {
+        let ((mut a, mut a_hs), (mut b, mut b_hs)) = connected();
+        let (a_state, b_state) = (&mut a_hs.cipher, &mut b_hs.cipher);
+        start_rekey(&mut a, a_state).unwrap();
+        start_rekey(&mut b, b_state).unwrap();
+        // The responder gives its own up and answers the initiator's...
+        assert!(matches!(receive(&mut b, b_state), Ok(Incoming::Control)));
+        assert!(matches!(b_state.rekey, Rekey::AwaitingDone(_)));
+        // ...while the initiator ignores the responder's.
+        assert!(matches!(receive(&mut a, a_state), Ok(Incoming::Control)));
+        assert!(matches!(a_state.rekey, Rekey::Initiated(..)));
+        assert!(matches!(receive(&mut a, a_state), Ok(Incoming::Rekeyed)));
+        assert!(matches!(receive(&mut b, b_state), Ok(Incoming::Rekeyed)));
+        assert_chatting((&mut a, a_state), (&mut b, b_state));
+    }
+
+    #[test]
+    fn unexpected_rekey_steps_are_refused() {
+        let ((mut a, mut a_hs), (mut b, mut b_hs)) = connected();
+        send_frame(&mut a, &mut a_hs.cipher, MSG_REKEY_DONE, &[]).unwrap();
+        assert!(matches!(
+            receive(&mut b, &mut b_hs.cipher),
+            Err(CryptoError::MalformedFrame(_))
+        ));
+        send_frame(&mut a, &mut a_hs.cipher, MSG_REKEY_ACK, &[7; REKEY_KEY_LEN]).unwrap();
+        assert!(matches!(
+            receive(&mut b, &mut b_hs.cipher),
+            Err(CryptoError::MalformedFrame(_))
+        ));
+    }
+
+    #[test]
+    fn frame_trickling_in_is_put_together_without_waiting() {
+        let ((mut a, mut a_hs), (mut b, mut b_hs)) = connected();
+        let frame = seal(&mut a_hs.cipher, MSG_CHAT, b"slowly").unwrap();
+        for piece in [
+            &frame[..2],
+            &frame[2..FRAME_LEN_PREFIX + 5],
+            &frame[FRAME_LEN_PREFIX + 5..],
+        ] {
+            // Until the whole frame is in, each call comes straight back.
+            assert!(matches!(
+                receive_and_decrypt(&mut b, &mut b_hs.cipher),
+                Ok(None)
+            ));
+            a.write_all(piece).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(
+            matches!(receive(&mut b, &mut b_hs.cipher), Ok(Incoming::Chat(t)) if t == "slowly")
+        );
+        encrypt_and_send(&mut a, &mut a_hs.cipher, "next").unwrap();
+        assert!(matches!(receive(&mut b, &mut b_hs.cipher), Ok(Incoming::Chat(t)) if t == "next"));
+    }
 }
@@ -0,0 +1,135 @@
+use crate::storage;
+use crate::vault;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use std::io;
+use std::sync::OnceLock;
+use zeroize::Zeroizing;
+
+const IDENTITY_FILE: &str = "identity";
+
+const FINGERPRINT_BYTES: usize = 16;
+
+/// Groups of `fingerprint` kept by `short_fingerprint`.
+const SHORT_FINGERPRINT_GROUPS: usize = 2;
+
+static LOCAL: OnceLock<SigningKey> = OnceLock::new();
+
+/// Loads the long-term identity key from `~/.sandesh/identity`, creating it
+/// on first run. Must be called once at startup before any handshake.
+pub fn init() -> io::Result<()> {
+    let key = load_or_create()?;
+    let _ = LOCAL.set(key);
+    Ok(())
+}
+
+fn load_or_create() -> io::Result<SigningKey> {
+    let path = storage::data_file(IDENTITY_FILE).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "no home directory to store the identity key in",
+        )
+    })?;
+
+    match vault::read(&path) {
+        Ok(contents) => {
+            let seed = decode_hex(contents.trim())
+                .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} is not a valid identity key", path.display()),
+                    )
+                })?;
+            Ok(SigningKey::from_bytes(&seed))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let key = SigningKey::generate(&mut OsRng);
+            vault::write(&path, &encode_hex(&key.to_bytes()))?;
+            Ok(key)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// A throwaway identity for tests, so they never touch `~/.sandesh`.
+#[cfg(test)]
+pub(crate) fn init_for_tests() {
+    LOCAL.get_or_init(|| SigningKey::generate(&mut OsRng));
+}
+
+/// The identity key, for a handshake to prove it holds.
+pub(crate) fn local() -> &'static SigningKey {
+    LOCAL.get().expect("identity::init was not called")
+}
+
+pub fn public_key() -> VerifyingKey {
+    local().verifying_key()
+}
+
+/// Signs with the identity key, for discovery beacons.
+pub fn sign(message: &[u8]) -> Signature {
+    local().sign(message)
+}
+
+/// The X25519 form of an identity key, used as the Noise static key, so
+/// the key a peer pins is the one the handshake authenticates.
+pub fn static_secret(key: &SigningKey) -> Zeroizing<[u8; 32]> {
+    Zeroizing::new(key.to_scalar_bytes())
+}
+
+/// Whether `static_public`, a Noise static key the peer proved it holds, is
+/// the X25519 form of `identity`.
+pub fn owns_static(identity: &VerifyingKey, static_public: &[u8]) -> bool {
+    identity.to_montgomery().as_bytes() == static_public
+}
+
+/// Short, stable name for an identity key: the first 16 bytes of its
+/// SHA-256, as dash-separated groups of four hex digits.
+pub fn fingerprint(key: &VerifyingKey) -> String {
+    let digest = Sha256::digest(key.as_bytes());
+    let groups: Vec<String> = digest[..FINGERPRINT_BYTES]
+        .chunks(2)
+        .map(encode_hex)
+        .collect();
+    groups.join("-")
+}
+
+/// The first groups of `fingerprint`, enough to tell peers apart in a
+/// list but not to verify one.
+pub fn short_fingerprint(key: &VerifyingKey) -> String {
+    fingerprint(key)
+        .split('-')
+        .take(SHORT_FINGERPRINT_GROUPS)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Brings a fingerprint typed by the user into the form `fingerprint`
+/// prints: any case, with or without `-`/`:` separators. `None` if it isn't
+/// a fingerprint.
+pub fn normalize_fingerprint(input: &str) -> Option<String> {
+    let hex: String = input
+        .chars()
+        .filter(|c| !matches!(c, '-' | ':'))
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    let bytes = decode_hex(&hex).filter(|b| b.len() == FINGERPRINT_BYTES)?;
+    let groups: Vec<String> = bytes.chunks(2).map(encode_hex).collect();
+    Some(groups.join("-"))
+}
+
+pub(crate) fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,385 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV6};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InterfaceKind {
+    Loopback,
+    /// Tunnels, VPNs, container bridges and hypervisor adapters, where a
+    /// LAN broadcast reaches no real peers.
+    Virtual,
+    Physical,
+}
+
+#[derive(Clone, Debug)]
+pub struct NetInterface {
+    pub name: String,
+    pub ip: IpAddr,
+    pub broadcast: Option<Ipv4Addr>,
+    pub kind: InterfaceKind,
+    pub up: bool,
+    /// What IPv6 multicast and scope ids name the interface by.
+    pub index: Option<u32>,
+}
+
+impl NetInterface {
+    pub fn is_link_local(&self) -> bool {
+        match self.ip {
+            IpAddr::V4(ip) => ip.is_link_local(),
+            IpAddr::V6(ip) => ip.is_unicast_link_local(),
+        }
+    }
+
+    /// What a peer dials to reach `port` on this address. Link-local IPv6
+    /// needs the scope id, e.g. `[fe80::1%2]:3001`.
+    pub fn chat_address(&self, port: u16) -> SocketAddr {
+        match self.ip {
+            IpAddr::V6(ip) if ip.is_unicast_link_local() => {
+                SocketAddrV6::new(ip, port, 0, self.index.unwrap_or(0)).into()
+            }
+            ip => SocketAddr::new(ip, port),
+        }
+    }
+
+    /// Up, physical, with a routable IPv4 address and a broadcast address.
+    pub fn is_discovery_candidate(&self) -> bool {
+        self.up
+            && self.kind == InterfaceKind::Physical
+            && self.broadcast.is_some()
+            && !self.is_link_local()
+    }
+}
+
+// Name prefixes used by Linux and macOS for tunnels, VPN clients, bridges
+// and VM/container networking.
+const VIRTUAL_PREFIXES: &[&str] = &[
+    "tun",
+    "tap",
+    "utun",
+    "wg",
+    "ppp",
+    "ipsec",
+    "gpd",
+    "zt",
+    "tailscale",
+    "docker",
+    "br-",
+    "veth",
+    "virbr",
+    "vboxnet",
+    "vmnet",
+    "vnic",
+    "bridge",
+    "lxc",
+    "lxd",
+    "cni",
+    "flannel",
+    "podman",
+    "awdl",
+    "llw",
+    "anpi",
+];
+
+// Substrings of Windows adapter names for the same kinds of adapters.
+const VIRTUAL_KEYWORDS: &[&str] = &[
+    "virtual",
+    "vpn",
+    "tap-",
+    "hyper-v",
+    "vethernet",
+    "vmware",
+    "virtualbox",
+    "wireguard",
+    "tailscale",
+    "zerotier",
+    "pseudo",
+];
+
+pub fn classify(name: &str, is_loopback: bool, is_point_to_point: bool) -> InterfaceKind {
+    if is_loopback {
+        return InterfaceKind::Loopback;
+    }
+    let lower = name.to_ascii_lowercase();
+    if is_point_to_point
+        || VIRTUAL_PREFIXES.iter().any(|p| lower.starts_with(p))
+        || VIRTUAL_KEYWORDS.iter().any(|k| lower.contains(k))
+    {
+        return InterfaceKind::Virtual;
+    }
+    InterfaceKind::Physical
+}
+
+/// All addresses on this host, one entry per (interface, address) pair.
+pub fn list() -> Vec<NetInterface> {
+    let Ok(raw) = if_addrs::get_if_addrs() else {
+        return Vec::new();
+    };
+    raw.iter()
+        .map(|iface| NetInterface {
+            name: iface.name.clone(),
+            ip: iface.ip(),
+            broadcast: match &iface.addr {
+                if_addrs::IfAddr::V4(v4) => v4.broadcast,
+                if_addrs::IfAddr::V6(_) => None,
+            },
+            kind: classify(&iface.name, iface.is_loopback(), iface.is_p2p()),
+            // Platforms that can't report the state say Unknown; don't
+            // exclude those.
+            up: iface.oper_status != if_addrs::IfOperStatus::Down,
+            index: iface.index,
+        })
+        .collect()
+}
+
+/// The addresses another host could dial: up and not loopback, IPv6 only
+/// when chats are accepted over it.
+pub fn connectable(interfaces: Vec<NetInterface>, ipv6: bool) -> Vec<NetInterface> {
+    interfaces
+        .into_iter()
+        .filter(|iface| {
+            iface.up && iface.kind != InterfaceKind::Loopback && (iface.ip.is_ipv4() || ipv6)
+        })
+        .collect()
+}
+
+/// Where the broadcaster should send announcements.
+pub struct BroadcastPlan {
+    /// Directed broadcast addresses, one per chosen interface. Empty means
+    /// fall back to the limited broadcast address.
+    pub targets: Vec<Ipv4Addr>,
+    pub chosen: Vec<String>,
+    pub warning: Option<String>,
+}
+
+/// Picks the interfaces to announce on: only `only` when given, otherwise
+/// every discovery candidate. Explains in `warning` when nothing suitable is
+/// found so the fallback isn't silent.
+pub fn plan_broadcast(interfaces: &[NetInterface], only: Option<&str>) -> BroadcastPlan {
+    let chosen: Vec<&NetInterface> = match only {
+        Some(name) => interfaces
+            .iter()
+            .filter(|i| i.name == name && i.broadcast.is_some())
+            .collect(),
+        None => interfaces
+            .iter()
+            .filter(|i| i.is_discovery_candidate())
+            .collect(),
+    };
+
+    let warning = if !chosen.is_empty() {
+        None
+    } else if let Some(name) = only {
+        Some(format!(
+            "Interface '{}' not found or has no IPv4 broadcast address; announcing on all interfaces.",
+            name
+        ))
+    } else {
+        let virtual_names: Vec<&str> = interfaces
+            .iter()
+            .filter(|i| i.kind == InterfaceKind::Virtual && i.up)
+            .map(|i| i.name.as_str())
+            .collect();
+        if virtual_names.is_empty() {
+            Some(
+                "No physical network interface found; peers on the LAN may not see you."
+                    .to_string(),
+            )
+        } else {
+            Some(format!(
+                "Only virtual interfaces found ({}); announcements may not reach any LAN peers. Use --interface to pick one.",
+                virtual_names.join(", ")
+            ))
+        }
+    };
+
+    let mut targets: Vec<Ipv4Addr> = chosen.iter().filter_map(|i| i.broadcast).collect();
+    targets.dedup();
+    BroadcastPlan {
+        targets,
+        chosen: chosen.iter().map(|i| i.name.clone()).collect(),
+        warning,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iface(name: &str, ip: &str, kind: InterfaceKind) -> NetInterface {
+        NetInterface {
+            name: name.to_string(),
+            ip: ip.parse().unwrap(),
+            broadcast: None,
+            kind,
+            up: true,
+            index: Some(2),
+        }
+    }
+
+    /// An up interface as `list` would report it, classified by name.
+    fn reported(name: &str, ip: &str, broadcast: Option<&str>) -> NetInterface {
+        let ip: IpAddr = ip.parse().unwrap();
+        NetInterface {
+            kind: classify(name, ip.is_loopback(), false),
+            broadcast: broadcast.map(|b| b.parse().unwrap()),
+            ..iface(name, &ip.to_string(), InterfaceKind::Physical)
+        }
+    }
+
+    #[test]
+    fn names_are_classified_on_every_platform() {
+        use InterfaceKind::*;
+        let table = [
+            // Linux
+            ("lo", true, Loopback),
+            ("eth0", false, Physical),
+            ("enp3s0", false, Physical),
+            ("wlp2s0", false, Physical),
+            ("docker0", false, Virtual),
+            ("veth3f2a1b0", false, Virtual),
+            ("br-5c1d2e3f4a5b", false, Virtual),
+            ("virbr0", false, Virtual),
+            ("tun0", false, Virtual),
+            ("wg0", false, Virtual),
+            ("tailscale0", false, Virtual),
+            // macOS
+            ("lo0", true, Loopback),
+            ("en0", false, Physical),
+            ("en7", false, Physical),
+            ("utun3", false, Virtual),
+            ("awdl0", false, Virtual),
+            ("llw0", false, Virtual),
+            ("bridge100", false, Virtual),
+            ("anpi1", false, Virtual),
+            // Windows
+            ("Loopback Pseudo-Interface 1", true, Loopback),
+            ("Ethernet", false, Physical),
+            ("Wi-Fi", false, Physical),
+            ("vEthernet (WSL)", false, Virtual),
+            ("vEthernet (Default Switch)", false, Virtual),
+            ("Tailscale", false, Virtual),
+            ("VirtualBox Host-Only Network", false, Virtual),
+            ("VMware Network Adapter VMnet8", false, Virtual),
+            ("OpenVPN TAP-Windows6", false, Virtual),
+        ];
+        for (name, loopback, kind) in table {
+            assert_eq!(classify(name, loopback, false), kind, "{}", name);
+        }
+        // A point-to-point link is a tunnel whatever it is called.
+        assert_eq!(classify("eth1", false, true), Virtual);
+    }
+
+    #[test]
+    fn physical_interfaces_win_over_virtual_ones() {
+        let linux = [
+            reported("lo", "127.0.0.1", None),
+            reported("docker0", "172.17.0.1", Some("172.17.255.255")),
+            reported("enp3s0", "192.168.1.20", Some("192.168.1.255")),
+            reported("tailscale0", "100.101.102.103", None),
+            reported("wlp2s0", "10.0.0.7", Some("10.0.0.255")),
+            reported("wlp2s0", "fe80::1", None),
+        ];
+        let macos = [
+            reported("lo0", "127.0.0.1", None),
+            reported("utun3", "10.8.0.2", None),
+            reported("bridge100", "192.168.64.1", Some("192.168.64.255")),
+            reported("en0", "192.168.1.30", Some("192.168.1.255")),
+            // Self-assigned, so no DHCP server answered on it.
+            reported("en7", "169.254.3.4", Some("169.254.255.255")),
+        ];
+        let windows = [
+            reported("vEthernet (WSL)", "172.25.0.1", Some("172.25.15.255")),
+            reported("Wi-Fi", "192.168.1.40", Some("192.168.1.255")),
+            reported("Ethernet", "192.168.1.41", Some("192.168.1.255")),
+            reported("Loopback Pseudo-Interface 1", "127.0.0.1", None),
+        ];
+        let cases: [(&[NetInterface], &[&str], &[&str]); 3] = [
+            (
+                &linux,
+                &["enp3s0", "wlp2s0"],
+                &["192.168.1.255", "10.0.0.255"],
+            ),
+            (&macos, &["en0"], &["192.168.1.255"]),
+            // One broadcast for two adapters on the same subnet.
+            (&windows, &["Wi-Fi", "Ethernet"], &["192.168.1.255"]),
+        ];
+        for (interfaces, chosen, targets) in cases {
+            let plan = plan_broadcast(interfaces, None);
+            assert_eq!(plan.chosen, chosen);
+            let targets: Vec<Ipv4Addr> = targets.iter().map(|t| t.parse().unwrap()).collect();
+            assert_eq!(plan.targets, targets);
+            assert_eq!(plan.warning, None);
+        }
+
+        // Unless one is picked by name.
+        let plan = plan_broadcast(&linux, Some("docker0"));
+        assert_eq!(plan.chosen, ["docker0"]);
+        assert_eq!(plan.targets, [Ipv4Addr::new(172, 17, 255, 255)]);
+    }
+
+    #[test]
+    fn only_virtual_interfaces_are_warned_about() {
+        let mut interfaces = vec![
+            reported("lo0", "127.0.0.1", None),
+            reported("utun3", "10.8.0.2", None),
+            reported("docker0", "172.17.0.1", Some("172.17.255.255")),
+            reported("wg0", "10.9.0.2", None),
+        ];
+        interfaces[3].up = false;
+        let plan = plan_broadcast(&interfaces, None);
+        assert!(plan.targets.is_empty() && plan.chosen.is_empty());
+        assert_eq!(
+            plan.warning.as_deref(),
+            Some(
+                "Only virtual interfaces found (utun3, docker0); announcements may not reach any LAN peers. Use --interface to pick one."
+            )
+        );
+
+        let plan = plan_broadcast(&interfaces[..1], None);
+        assert_eq!(
+            plan.warning.as_deref(),
+            Some("No physical network interface found; peers on the LAN may not see you.")
+        );
+        let plan = plan_broadcast(&interfaces, Some("eth9"));
+        assert!(
+            plan.warning
+                .unwrap()
+                .starts_with("Interface 'eth9' not found")
+        );
+    }
+
+    #[test]
+    fn enumeration_finds_loopback() {
+        let all = list();
+        assert!(
+            all.iter()
+                .any(|i| i.kind == InterfaceKind::Loopback && i.ip.is_loopback()),
+            "{:?}",
+            all
+        );
+    }
+
+    #[test]
+    fn loopback_is_not_connectable() {
+        let all = vec![
+            iface("lo", "127.0.0.1", InterfaceKind::Loopback),
+            iface("eth0", "192.168.1.5", InterfaceKind::Physical),
+            iface("eth0", "fe80::1", InterfaceKind::Physical),
+            iface("wg0", "10.0.0.2", InterfaceKind::Virtual),
+        ];
+        let names = |ipv6| -> Vec<String> {
+            connectable(all.clone(), ipv6)
+                .iter()
+                .map(|i| i.ip.to_string())
+                .collect()
+        };
+        assert_eq!(names(false), ["192.168.1.5", "10.0.0.2"]);
+        assert_eq!(names(true), ["192.168.1.5", "fe80::1", "10.0.0.2"]);
+    }
+
+    #[test]
+    fn link_local_chat_address_carries_the_scope() {
+        let v6 = iface("eth0", "fe80::1", InterfaceKind::Physical);
+        assert_eq!(v6.chat_address(3001).to_string(), "[fe80::1%2]:3001");
+        let v4 = iface("eth0", "192.168.1.5", InterfaceKind::Physical);
+        assert_eq!(v4.chat_address(3001).to_string(), "192.168.1.5:3001");
+    }
+}
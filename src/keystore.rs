@@ -0,0 +1,675 @@
+use crate::identity;
+use crate::storage;
+use crate::vault;
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+const KEYSTORE_FILE: &str = "known_peers";
+
+/// Layout written by `save`. Version 1 had no header and only the
+/// fingerprint, IP and trust of each key; `parse` upgrades it.
+const VERSION: u32 = 2;
+const HEADER: &str = "# sandesh known_peers v";
+
+/// How often the keys heard in beacons are saved, so beacons every few
+/// seconds don't mean a write every few seconds; see `save_discoveries`.
+pub const DISCOVERY_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Most keys only heard in beacons that are kept, in the file and waiting
+/// to be saved. Past this the ones heard least recently go, so a host
+/// minting fresh keys can't grow either without end.
+const MAX_UNPINNED: usize = 256;
+
+/// Held by every `KeyStore` from load to drop, so the UI and the discovery
+/// thread never save over each other's changes.
+static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Keys heard in signed beacons since the last `save_discoveries`, with
+/// the first and last time each was heard.
+static DISCOVERED: Mutex<Option<HashMap<String, Heard>>> = Mutex::new(None);
+
+type Heard = (DateTime<Utc>, DateTime<Utc>);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Pin {
+    /// As printed by `identity::fingerprint`.
+    fingerprint: String,
+    /// Where the key was last seen; `None` for a key trusted before it
+    /// was ever seen.
+    ip: Option<IpAddr>,
+    trusted: bool,
+    /// False for a key only heard in beacons so far: its first chat still
+    /// counts as `PinStatus::New`.
+    pinned: bool,
+    history: History,
+}
+
+/// When a key was around, by the wall clock. Saved as RFC 3339, so it
+/// means the same after a restart, unlike an `Instant`. A clock that was
+/// set back can make a time lie in the future; `format_ago` shows those
+/// as just now, and `first_*` is moved back to the earliest time seen.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct History {
+    /// In a signed beacon; only from version 2 on.
+    pub first_discovered: Option<DateTime<Utc>>,
+    /// Saved at most every `DISCOVERY_SAVE_INTERVAL`.
+    pub last_discovered: Option<DateTime<Utc>>,
+    /// When the last chat with the key ended.
+    pub last_chatted: Option<DateTime<Utc>>,
+    /// Chats that got past the handshake and have ended.
+    pub total_sessions: u64,
+}
+
+impl History {
+    fn discovered(&mut self, now: DateTime<Utc>) {
+        if self.first_discovered.is_none_or(|first| now < first) {
+            self.first_discovered = Some(now);
+        }
+        self.last_discovered = Some(now);
+    }
+}
+
+/// `now - then` as "3 days ago", or "just now" for under a minute and for
+/// a time the clock has since gone back past.
+pub fn format_ago(then: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let secs = (now - then).num_seconds();
+    let (n, unit) = match secs {
+        ..60 => return "just now".to_string(),
+        60..3600 => (secs / 60, "minute"),
+        3600..86400 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+    format!("{} {}{} ago", n, unit, if n == 1 { "" } else { "s" })
+}
+
+/// What the keystore knew about a peer's identity key when it connected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PinStatus {
+    /// Pinned with `trust`.
+    Trusted,
+    /// Seen before and pinned on first use.
+    Known,
+    /// Never seen; now pinned.
+    New,
+    /// This IP presented a different key last time. The new key is not
+    /// pinned until the user runs `trust` on it.
+    Changed { previous: String },
+}
+
+/// Identity keys of peers, persisted to `~/.sandesh/known_peers` under a
+/// `# sandesh known_peers v2` header, as one `<fingerprint> <ip|-> [trusted]
+/// [unpinned] [first-seen=<time>] [last-seen=<time>] [last-chat=<time>]
+/// [chats=<n>]` line per key. Keys are pinned on first use and compared by
+/// IP on later connections; keys heard in beacons are kept too, for their
+/// history, but aren't pinned until a chat, and only up to `MAX_UNPINNED`.
+pub struct KeyStore {
+    pins: Vec<Pin>,
+    path: Option<PathBuf>,
+    /// The file exists but couldn't be read (keystore locked, damaged, or
+    /// from a newer build), so it must not be overwritten with what little
+    /// is known.
+    unreadable: bool,
+    _lock: MutexGuard<'static, ()>,
+}
+
+/// The layout version of a keystore file and its keys. `None` for a
+/// version newer than this build knows. Malformed lines are skipped.
+fn parse(contents: &str) -> Option<(u32, Vec<Pin>)> {
+    let version = match contents.lines().next().and_then(|l| l.strip_prefix(HEADER)) {
+        Some(version) => version.trim().parse().ok()?,
+        None => 1,
+    };
+    if version > VERSION {
+        return None;
+    }
+    let time = |value: &str| {
+        DateTime::parse_from_rfc3339(value)
+            .ok()
+            .map(|t| t.with_timezone(&Utc))
+    };
+    let mut pins = Vec::new();
+    for line in contents.lines().filter(|l| !l.starts_with('#')) {
+        let mut fields = line.split_whitespace();
+        let Some(fingerprint) = fields.next().and_then(identity::normalize_fingerprint) else {
+            continue;
+        };
+        let mut pin = Pin {
+            fingerprint,
+            ip: fields.next().and_then(|ip| ip.parse().ok()),
+            trusted: false,
+            pinned: true,
+            history: History::default(),
+        };
+        for field in fields {
+            match field.split_once('=') {
+                None if field == "trusted" => pin.trusted = true,
+                None if field == "unpinned" && version >= 2 => pin.pinned = false,
+                Some(("first-seen", t)) => pin.history.first_discovered = time(t),
+                Some(("last-seen", t)) => pin.history.last_discovered = time(t),
+                Some(("last-chat", t)) => pin.history.last_chatted = time(t),
+                Some(("chats", n)) => pin.history.total_sessions = n.parse().unwrap_or(0),
+                _ => {}
+            }
+        }
+        pins.push(pin);
+    }
+    // Version 1 -> 2: every key in the file was pinned, and nothing is
+    // known of its history.
+    Some((version, pins))
+}
+
+fn render(pins: &[Pin]) -> String {
+    let time = |t: DateTime<Utc>| t.to_rfc3339_opts(SecondsFormat::Secs, true);
+    let mut contents = format!("{}{}\n", HEADER, VERSION);
+    for pin in pins {
+        let ip = pin.ip.map_or("-".to_string(), |ip| ip.to_string());
+        contents.push_str(&format!("{} {}", pin.fingerprint, ip));
+        if pin.trusted {
+            contents.push_str(" trusted");
+        }
+        if !pin.pinned {
+            contents.push_str(" unpinned");
+        }
+        let history = &pin.history;
+        for (key, at) in [
+            ("first-seen", history.first_discovered),
+            ("last-seen", history.last_discovered),
+            ("last-chat", history.last_chatted),
+        ] {
+            if let Some(at) = at {
+                contents.push_str(&format!(" {}={}", key, time(at)));
+            }
+        }
+        if history.total_sessions > 0 {
+            contents.push_str(&format!(" chats={}", history.total_sessions));
+        }
+        contents.push('\n');
+    }
+    contents
+}
+
+/// Notes that a signed beacon from `fingerprint` was heard. Only kept in
+/// memory, so the discovery listener never waits on the disk; a background
+/// thread writes it down with `save_discoveries`.
+pub fn record_discovery(fingerprint: &str) {
+    let mut heard = DISCOVERED.lock().unwrap();
+    note_discovery(
+        heard.get_or_insert_with(HashMap::new),
+        fingerprint,
+        Utc::now(),
+    );
+}
+
+fn note_discovery(heard: &mut HashMap<String, Heard>, fingerprint: &str, now: DateTime<Utc>) {
+    if let Some((first, last)) = heard.get_mut(fingerprint) {
+        *first = (*first).min(now);
+        *last = now;
+        return;
+    }
+    if heard.len() >= MAX_UNPINNED
+        && let Some(oldest) = heard
+            .iter()
+            .min_by_key(|(_, (_, last))| *last)
+            .map(|(fingerprint, _)| fingerprint.clone())
+    {
+        heard.remove(&oldest);
+    }
+    heard.insert(fingerprint.to_string(), (now, now));
+}
+
+/// Writes down the keys `record_discovery` noted since the last call.
+pub fn save_discoveries() -> io::Result<()> {
+    let heard = DISCOVERED.lock().unwrap().take().unwrap_or_default();
+    if heard.is_empty() {
+        return Ok(());
+    }
+    let mut store = KeyStore::load();
+    store.discovered_all(&heard);
+    store.save()
+}
+
+impl KeyStore {
+    /// Loads the keystore, sealing it if it was written before encryption
+    /// was turned on and upgrading it if it has an older layout. A missing
+    /// or unreadable file gives an empty store.
+    /// Keys heard in beacons but not saved yet are included.
+    pub fn load() -> Self {
+        let mut store = Self::load_from(storage::data_file(KEYSTORE_FILE));
+        if let Some(heard) = DISCOVERED.lock().unwrap().as_ref() {
+            store.discovered_all(heard);
+        }
+        store
+    }
+
+    fn load_from(path: Option<PathBuf>) -> Self {
+        let lock = FILE_LOCK
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let read = path.as_ref().map(|p| vault::read(p));
+        let mut unreadable = matches!(&read, Some(Err(e)) if e.kind() != io::ErrorKind::NotFound);
+
+        let mut pins = Vec::new();
+        let mut outdated = false;
+        if let Some(Ok(contents)) = read {
+            match parse(&contents) {
+                Some((version, parsed)) => {
+                    pins = parsed;
+                    outdated = version < VERSION;
+                }
+                None => unreadable = true,
+            }
+        }
+
+        let store = KeyStore {
+            pins,
+            path,
+            unreadable,
+            _lock: lock,
+        };
+        if outdated {
+            let _ = store.save();
+        }
+        store
+    }
+
+    /// Checks a connecting peer's key and records where it was seen. A
+    /// changed key leaves the store untouched.
+    pub fn check(&mut self, ip: IpAddr, fingerprint: &str) -> io::Result<PinStatus> {
+        let fingerprint = fingerprint.to_string();
+        if let Some(index) = self.pins.iter().position(|p| p.fingerprint == fingerprint) {
+            // A key only heard in beacons under some other address can't
+            // vouch for this one, as a pinned key can.
+            if !self.pins[index].pinned
+                && let Some(pin) = self.pins.iter().find(|p| p.ip == Some(ip))
+            {
+                return Ok(PinStatus::Changed {
+                    previous: pin.fingerprint.clone(),
+                });
+            }
+            // A key can move address; the IP it left is no longer its.
+            for pin in &mut self.pins {
+                if pin.ip == Some(ip) && pin.fingerprint != fingerprint {
+                    pin.ip = None;
+                }
+            }
+            let pin = &mut self.pins[index];
+            pin.ip = Some(ip);
+            let status = if pin.trusted {
+                PinStatus::Trusted
+            } else if pin.pinned {
+                PinStatus::Known
+            } else {
+                PinStatus::New
+            };
+            pin.pinned = true;
+            self.save()?;
+            return Ok(status);
+        }
+
+        if let Some(pin) = self.pins.iter().find(|p| p.ip == Some(ip)) {
+            return Ok(PinStatus::Changed {
+                previous: pin.fingerprint.clone(),
+            });
+        }
+
+        self.pins.push(Pin {
+            fingerprint,
+            ip: Some(ip),
+            trusted: false,
+            pinned: true,
+            history: History::default(),
+        });
+        self.save()?;
+        Ok(PinStatus::New)
+    }
+
+    /// The trusted key last seen at `ip`, if any: a request from there is
+    /// accepted without asking, as long as the peer presents that key.
+    pub fn trusted_at(&self, ip: IpAddr) -> Option<String> {
+        self.pins
+            .iter()
+            .find(|p| p.trusted && p.ip == Some(ip))
+            .map(|p| p.fingerprint.clone())
+    }
+
+    /// Trusted keys and where each was last seen.
+    pub fn trusted(&self) -> Vec<(String, Option<IpAddr>)> {
+        self.pins
+            .iter()
+            .filter(|p| p.trusted)
+            .map(|p| (p.fingerprint.clone(), p.ip))
+            .collect()
+    }
+
+    /// What is known of when `fingerprint` was around, and whether it is
+    /// trusted.
+    pub fn history(&self, fingerprint: &str) -> Option<(&History, bool)> {
+        self.pins
+            .iter()
+            .find(|p| p.fingerprint == fingerprint)
+            .map(|p| (&p.history, p.trusted))
+    }
+
+    /// Every key and its history, heard in beacons or not.
+    pub fn histories(&self) -> impl Iterator<Item = (&str, &History)> {
+        self.pins
+            .iter()
+            .map(|p| (p.fingerprint.as_str(), &p.history))
+    }
+
+    /// The key last chatted with from `ip` and its history, for a request
+    /// from there; it only applies if the peer presents that key.
+    pub fn history_at(&self, ip: IpAddr) -> Option<(&str, &History)> {
+        self.pins
+            .iter()
+            .find(|p| p.pinned && p.ip == Some(ip))
+            .map(|p| (p.fingerprint.as_str(), &p.history))
+    }
+
+    /// Marks a key as trusted, adding it if it hasn't been seen yet. Returns
+    /// false if it already was trusted.
+    pub fn trust(&mut self, fingerprint: &str) -> io::Result<bool> {
+        match self.pins.iter_mut().find(|p| p.fingerprint == fingerprint) {
+            Some(pin) if pin.trusted => return Ok(false),
+            Some(pin) => {
+                pin.trusted = true;
+                pin.pinned = true;
+            }
+            None => self.pins.push(Pin {
+                fingerprint: fingerprint.to_string(),
+                ip: None,
+                trusted: true,
+                pinned: true,
+                history: History::default(),
+            }),
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Takes the trust back; the key stays pinned. Returns false if it
+    /// wasn't trusted.
+    pub fn untrust(&mut self, fingerprint: &str) -> io::Result<bool> {
+        let Some(pin) = self
+            .pins
+            .iter_mut()
+            .find(|p| p.fingerprint == fingerprint && p.trusted)
+        else {
+            return Ok(false);
+        };
+        pin.trusted = false;
+        // A key trusted before it was ever chatted with pins nothing, and
+        // is only kept if beacons gave it a history.
+        if pin.ip.is_none() && pin.history.total_sessions == 0 {
+            pin.pinned = false;
+        }
+        self.pins
+            .retain(|p| p.pinned || p.history.first_discovered.is_some());
+        self.save()?;
+        Ok(true)
+    }
+
+    fn discovered(&mut self, fingerprint: &str, now: DateTime<Utc>) {
+        match self.pins.iter_mut().find(|p| p.fingerprint == fingerprint) {
+            Some(pin) => pin.history.discovered(now),
+            None => {
+                let mut history = History::default();
+                history.discovered(now);
+                self.pins.push(Pin {
+                    fingerprint: fingerprint.to_string(),
+                    ip: None,
+                    trusted: false,
+                    pinned: false,
+                    history,
+                });
+            }
+        }
+    }
+
+    /// Adds what `record_discovery` noted, then drops the keys only heard
+    /// in beacons beyond `MAX_UNPINNED`, least recently heard first.
+    fn discovered_all(&mut self, heard: &HashMap<String, Heard>) {
+        for (fingerprint, &(first, last)) in heard {
+            self.discovered(fingerprint, first);
+            self.discovered(fingerprint, last);
+        }
+        let mut unpinned: Vec<(Option<DateTime<Utc>>, String)> = self
+            .pins
+            .iter()
+            .filter(|p| !p.pinned)
+            .map(|p| (p.history.last_discovered, p.fingerprint.clone()))
+            .collect();
+        let Some(excess) = unpinned.len().checked_sub(MAX_UNPINNED) else {
+            return;
+        };
+        unpinned.sort_unstable();
+        unpinned.truncate(excess);
+        self.pins
+            .retain(|p| p.pinned || !unpinned.iter().any(|(_, f)| *f == p.fingerprint));
+    }
+
+    /// Counts a chat with `fingerprint` that just ended. Called once per
+    /// chat, after `check` pinned the key.
+    pub fn record_chat(&mut self, fingerprint: &str) -> io::Result<()> {
+        if let Some(pin) = self.pins.iter_mut().find(|p| p.fingerprint == fingerprint) {
+            pin.history.last_chatted = Some(Utc::now());
+            pin.history.total_sessions += 1;
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if self.unreadable {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the known peers file couldn't be read, so it was left as it is",
+            ));
+        }
+        let Some(path) = &self.path else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no home directory to store known peers in",
+            ));
+        };
+        vault::write(path, &render(&self.pins))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+    use std::fs;
+
+    const A: &str = "aaaa-bbbb-cccc-dddd-eeee-ffff-0000-1111";
+    const B: &str = "2222-3333-4444-5555-6666-7777-8888-9999";
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    /// A keystore file of its own for each test.
+    fn scratch_file(test: &str, contents: &str) -> PathBuf {
+        let path = scratch_dir(&format!("keystore-{}", test)).join("known_peers");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn version_1_files_are_upgraded_in_place() {
+        let path = scratch_file(
+            "upgrade",
+            &format!("{} 10.0.0.5 trusted\n{} -\nnot a key\n", A, B),
+        );
+        let store = KeyStore::load_from(Some(path.clone()));
+        assert!(!store.unreadable);
+        assert_eq!(
+            store.trusted(),
+            vec![(A.to_string(), Some("10.0.0.5".parse().unwrap()))]
+        );
+        assert!(
+            store
+                .pins
+                .iter()
+                .all(|p| p.pinned && p.history == History::default())
+        );
+        drop(store);
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!(
+                "# sandesh known_peers v2\n{} 10.0.0.5 trusted\n{} -\n",
+                A, B
+            )
+        );
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn version_2_round_trips_history() {
+        let history = History {
+            first_discovered: Some(at("2026-01-02T03:04:05Z")),
+            last_discovered: Some(at("2026-02-03T04:05:06Z")),
+            last_chatted: Some(at("2026-02-01T00:00:00Z")),
+            total_sessions: 7,
+        };
+        let pins = vec![
+            Pin {
+                fingerprint: A.to_string(),
+                ip: Some("fe80::1".parse().unwrap()),
+                trusted: true,
+                pinned: true,
+                history: history.clone(),
+            },
+            Pin {
+                fingerprint: B.to_string(),
+                ip: None,
+                trusted: false,
+                pinned: false,
+                history: History {
+                    last_chatted: None,
+                    total_sessions: 0,
+                    ..history
+                },
+            },
+        ];
+        let contents = render(&pins);
+        assert!(contents.contains(" first-seen=2026-01-02T03:04:05Z"));
+        assert_eq!(parse(&contents), Some((2, pins)));
+    }
+
+    #[test]
+    fn newer_versions_are_left_alone() {
+        let contents = format!("# sandesh known_peers v3\n{} 10.0.0.5\n", A);
+        assert_eq!(parse(&contents), None);
+
+        let path = scratch_file("newer", &contents);
+        let mut store = KeyStore::load_from(Some(path.clone()));
+        assert!(store.unreadable);
+        assert!(store.trust(B).is_err());
+        drop(store);
+        assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn bad_fields_are_dropped_not_the_key() {
+        let contents = format!(
+            "# sandesh known_peers v2\n{} - first-seen=yesterday chats=lots last-chat=2026-01-01T00:00:00+05:30\n",
+            A
+        );
+        let (_, pins) = parse(&contents).unwrap();
+        assert_eq!(pins[0].history.first_discovered, None);
+        assert_eq!(pins[0].history.total_sessions, 0);
+        assert_eq!(
+            pins[0].history.last_chatted,
+            Some(at("2025-12-31T18:30:00Z"))
+        );
+    }
+
+    #[test]
+    fn discovery_copes_with_the_clock_going_back() {
+        let mut history = History::default();
+        history.discovered(at("2026-03-01T12:00:00Z"));
+        history.discovered(at("2026-02-01T12:00:00Z"));
+        assert_eq!(history.first_discovered, Some(at("2026-02-01T12:00:00Z")));
+        assert_eq!(history.last_discovered, Some(at("2026-02-01T12:00:00Z")));
+    }
+
+    #[test]
+    fn ages_read_naturally() {
+        let now = at("2026-03-10T12:00:00Z");
+        assert_eq!(format_ago(at("2026-03-10T11:59:30Z"), now), "just now");
+        assert_eq!(format_ago(at("2026-03-10T11:59:00Z"), now), "1 minute ago");
+        assert_eq!(format_ago(at("2026-03-10T09:00:00Z"), now), "3 hours ago");
+        assert_eq!(format_ago(at("2026-03-04T11:00:00Z"), now), "6 days ago");
+        // From before the clock was set back.
+        assert_eq!(format_ago(at("2026-03-11T12:00:00Z"), now), "just now");
+    }
+
+    #[test]
+    fn a_key_heard_in_beacons_is_new_at_its_first_chat() {
+        let path = scratch_file("beacons", "");
+        let mut store = KeyStore::load_from(Some(path.clone()));
+        let ip = "10.0.0.9".parse().unwrap();
+        store.discovered(A, at("2026-03-01T12:00:00Z"));
+        assert!(store.history_at(ip).is_none());
+
+        assert_eq!(store.check(ip, A).unwrap(), PinStatus::New);
+        assert_eq!(store.check(ip, A).unwrap(), PinStatus::Known);
+        store.record_chat(A).unwrap();
+        let (fingerprint, history) = store.history_at(ip).unwrap();
+        assert_eq!(fingerprint, A);
+        assert_eq!(history.total_sessions, 1);
+        assert_eq!(history.first_discovered, Some(at("2026-03-01T12:00:00Z")));
+        drop(store);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn keys_only_heard_in_beacons_are_capped() {
+        let start = at("2026-03-01T12:00:00Z");
+        let mut heard = HashMap::new();
+        for n in 0..MAX_UNPINNED * 2 {
+            let now = start + chrono::Duration::seconds(n as i64);
+            note_discovery(&mut heard, &format!("key-{}", n), now);
+        }
+        assert_eq!(heard.len(), MAX_UNPINNED);
+        assert!(!heard.contains_key("key-0"), "the least recent goes first");
+        assert!(heard.contains_key(&format!("key-{}", MAX_UNPINNED * 2 - 1)));
+
+        let path = scratch_file("flood", "");
+        let mut store = KeyStore::load_from(Some(path.clone()));
+        store.check("10.0.0.9".parse().unwrap(), A).unwrap();
+        store.discovered(B, at("2026-02-01T12:00:00Z"));
+        store.discovered_all(&heard);
+        assert_eq!(store.pins.len(), MAX_UNPINNED + 1);
+        assert!(store.history(A).is_some(), "pinned keys always stay");
+        assert!(store.history(B).is_none());
+        drop(store);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn untrusting_keeps_keys_with_a_history() {
+        let path = scratch_file("untrust", "");
+        let mut store = KeyStore::load_from(Some(path.clone()));
+        store.discovered(A, at("2026-03-01T12:00:00Z"));
+        store.trust(A).unwrap();
+        store.trust(B).unwrap();
+        assert!(store.untrust(A).unwrap());
+        assert!(store.untrust(B).unwrap());
+        assert!(store.history(A).is_some_and(|(_, trusted)| !trusted));
+        assert!(store.history(B).is_none());
+        drop(store);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,79 @@
+//! Sandesh: serverless, end-to-end encrypted chat between peers on a LAN.
+//!
+//! The `sandesh` binary is a terminal front end over this crate. Programs
+//! that want to talk to Sandesh peers themselves, such as GUIs or bots,
+//! can use [`Session`]:
+//!
+//! ```no_run
+//! # fn main() -> Result<(), sandesh::crypto::CryptoError> {
+//! sandesh::identity::init()?;
+//! let mut session = sandesh::connect("192.168.1.20:3001", None)?;
+//! println!("Verification code: {}", session.verification_code());
+//! session.send("hello")?;
+//! println!("peer: {}", session.recv()?);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! `crypto` and `protocol` hold the handshake and wire format, `network`
+//! discovery and the chat listener, and `state` the table of peers seen on
+//! the network. If the user encrypted their keys, `vault::unlock` has to
+//! be called with their passphrase before `identity::init`.
+
+mod argon2;
+pub mod crypto;
+pub mod identity;
+pub mod keystore;
+pub mod network;
+pub mod protocol;
+mod session;
+pub mod state;
+pub mod storage;
+#[cfg(test)]
+mod test_util;
+pub mod transfer;
+pub mod vault;
+
+// The terminal front end. Public so the binary can reach it, but not part
+// of the library's API.
+#[doc(hidden)]
+pub mod aliases;
+#[doc(hidden)]
+pub mod announce;
+#[doc(hidden)]
+pub mod blocklist;
+#[doc(hidden)]
+pub mod chat;
+#[doc(hidden)]
+pub mod clipboard;
+#[doc(hidden)]
+pub mod completion;
+#[doc(hidden)]
+pub mod filter;
+#[doc(hidden)]
+pub mod interfaces;
+#[doc(hidden)]
+pub mod outbox;
+#[doc(hidden)]
+pub mod pad;
+#[doc(hidden)]
+pub mod pipe;
+#[doc(hidden)]
+pub mod resources;
+#[doc(hidden)]
+pub mod shout;
+#[doc(hidden)]
+pub mod static_peers;
+#[doc(hidden)]
+pub mod tags;
+#[doc(hidden)]
+pub mod terminal;
+#[doc(hidden)]
+pub mod transcript;
+#[doc(hidden)]
+#[cfg(feature = "update-check")]
+pub mod update;
+#[doc(hidden)]
+pub mod watches;
+
+pub use session::{Session, connect};
@@ -1,87 +1,634 @@
-mod chat;
-mod crypto;
-mod network;
-mod state;
-
+#[cfg(feature = "update-check")]
+use sandesh::update;
+use sandesh::{
+    aliases, announce, blocklist, chat, completion, crypto, filter, identity, interfaces, keystore,
+    network, outbox, pipe, protocol, resources, shout, state, static_peers, tags, terminal,
+    transcript, transfer, vault, watches,
+};
+use std::collections::HashMap;
 use std::io::{self, Write};
-use std::net::UdpSocket;
-use std::sync::mpsc;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, mpsc};
 use std::time::Duration;
 
+use chrono::{DateTime, Local, NaiveDate, Utc};
 use colored::*;
 use crossterm::{
     cursor,
-    event::{self, Event, KeyCode},
+    event::{self, Event, KeyCode, KeyModifiers},
     execute,
-    terminal::{
-        Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen, SetTitle, disable_raw_mode,
-        enable_raw_mode,
-    },
+    terminal::{Clear, ClearType, SetTitle},
 };
+use terminal::TerminalGuard;
+
+/// TCP port chats are accepted on, and assumed for peers given without one.
+const DEFAULT_PORT: u16 = 3001;
+
+const USAGE: &str = "Usage: sandesh [--port <n>] [--interface <name>] [--nick <name>] [--discovery broadcast|multicast] [--ipv6] [--log] [--connect <ip[:port]> [--on-connect \"send <message>\"]]";
+
+const PIPE_USAGE: &str = "Usage: sandesh push <ip[:port]|alias> <file>\n       sandesh pull --accept-from <fingerprint> --out <dir> [--port <n>]";
+
+/// Wrong passphrases allowed at startup before giving up.
+const PASSPHRASE_ATTEMPTS: u32 = 3;
+
+/// Peers heard from this recently are shown as fresh. Beacons go out every
+/// 5 seconds, so an older one has missed at least one, and after 15 it is
+/// dropped from the list.
+const FRESH_PEER: Duration = Duration::from_secs(6);
+
+/// Addresses in the order the last `find-quick` numbered them, for
+/// `connect <n>`.
+static LAST_LISTING: Mutex<Vec<SocketAddr>> = Mutex::new(Vec::new());
+
+#[derive(Default)]
+struct Args {
+    port: Option<u16>,
+    connect: Option<String>,
+    initial_message: Option<String>,
+    interface: Option<String>,
+    nickname: Option<String>,
+    discovery: network::DiscoveryMode,
+    ipv6: bool,
+    log: bool,
+    #[cfg(feature = "update-check")]
+    check_updates: bool,
+    #[cfg(feature = "update-check")]
+    update_url: Option<String>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args::default();
+    let mut iter = std::env::args().skip(1);
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--connect" => {
+                let target = iter.next().ok_or("--connect needs an address")?;
+                args.connect = Some(network::with_port(&target, DEFAULT_PORT));
+            }
+            "--on-connect" => {
+                let action = iter.next().ok_or("--on-connect needs an action")?;
+                let message = action
+                    .strip_prefix("send ")
+                    .filter(|m| !m.trim().is_empty())
+                    .ok_or("--on-connect only supports \"send <message>\"")?;
+                args.initial_message = Some(message.to_string());
+            }
+            "--port" => {
+                let port = iter.next().ok_or("--port needs a port number")?;
+                args.port = Some(
+                    port.parse()
+                        .map_err(|_| format!("Invalid port: {}", port))?,
+                );
+            }
+            "--interface" => {
+                args.interface = Some(iter.next().ok_or("--interface needs an interface name")?);
+            }
+            "--nick" => {
+                let name = iter.next().ok_or("--nick needs a nickname")?;
+                if !protocol::valid_nickname(&name) {
+                    return Err(format!(
+                        "Nicknames are up to {} bytes of printable text.",
+                        protocol::MAX_NICKNAME_LEN
+                    ));
+                }
+                args.nickname = Some(name);
+            }
+            "--discovery" => {
+                args.discovery = match iter.next().as_deref() {
+                    Some("broadcast") => network::DiscoveryMode::Broadcast,
+                    Some("multicast") => network::DiscoveryMode::Multicast,
+                    _ => return Err("--discovery needs broadcast or multicast".to_string()),
+                };
+            }
+            "--ipv6" => args.ipv6 = true,
+            "--log" => args.log = true,
+            #[cfg(feature = "update-check")]
+            "--check-updates" => args.check_updates = true,
+            #[cfg(feature = "update-check")]
+            "--update-url" => {
+                args.update_url = Some(iter.next().ok_or("--update-url needs a URL")?);
+                args.check_updates = true;
+            }
+            other => return Err(format!("Unknown argument: {}", other)),
+        }
+    }
+
+    if args.initial_message.is_some() && args.connect.is_none() {
+        return Err("--on-connect requires --connect".to_string());
+    }
+    Ok(args)
+}
+
+/// A file to send or receive without the chat window.
+enum Pipe {
+    Push {
+        target: String,
+        file: PathBuf,
+    },
+    Pull {
+        port: u16,
+        fingerprint: String,
+        out: PathBuf,
+    },
+}
+
+fn parse_pipe_args() -> Result<Pipe, String> {
+    let mut iter = std::env::args().skip(1);
+    match iter.next().as_deref() {
+        Some("push") => {
+            let target = iter.next().ok_or("push needs a peer and a file")?;
+            let file = iter.next().ok_or("push needs a file")?;
+            if let Some(other) = iter.next() {
+                return Err(format!("Unknown argument: {}", other));
+            }
+            Ok(Pipe::Push {
+                target,
+                file: file.into(),
+            })
+        }
+        _ => {
+            let mut port = DEFAULT_PORT;
+            let mut fingerprint = None;
+            let mut out = None;
+            while let Some(arg) = iter.next() {
+                match arg.as_str() {
+                    "--accept-from" => {
+                        let key = iter.next().ok_or("--accept-from needs a fingerprint")?;
+                        fingerprint = Some(
+                            identity::normalize_fingerprint(&key)
+                                .ok_or_else(|| format!("Not a fingerprint: {}", key))?,
+                        );
+                    }
+                    "--out" => {
+                        out = Some(PathBuf::from(iter.next().ok_or("--out needs a folder")?))
+                    }
+                    "--port" => {
+                        let n = iter.next().ok_or("--port needs a port number")?;
+                        port = n.parse().map_err(|_| format!("Invalid port: {}", n))?;
+                    }
+                    other => return Err(format!("Unknown argument: {}", other)),
+                }
+            }
+            Ok(Pipe::Pull {
+                port,
+                fingerprint: fingerprint.ok_or("pull needs --accept-from")?,
+                out: out.ok_or("pull needs --out")?,
+            })
+        }
+    }
+}
+
+/// `sandesh push` and `sandesh pull`. Returns the exit code: 0 once the
+/// file is through, 1 if it isn't, 2 for bad arguments. The pulled file's
+/// path is printed on stdout, for scripts.
+fn run_pipe() -> i32 {
+    let pipe = match parse_pipe_args() {
+        Ok(pipe) => pipe,
+        Err(e) => {
+            eprintln!("{}\n{}", e, PIPE_USAGE);
+            return 2;
+        }
+    };
+    if let Err(e) = open_vault_quietly() {
+        eprintln!("Cannot open the keystore: {}", e);
+        return 1;
+    }
+    if let Err(e) = identity::init() {
+        eprintln!("Cannot load the identity key: {}", e);
+        return 1;
+    }
+    transfer::load_settings();
+    aliases::load();
+
+    let result = match pipe {
+        Pipe::Push { target, file } => {
+            let addr = match aliases::target_named(&target) {
+                Some(aliases::AliasTarget::Addr(addr)) => addr.to_string(),
+                Some(aliases::AliasTarget::Ip(ip)) => {
+                    network::with_port(&ip.to_string(), DEFAULT_PORT)
+                }
+                None => network::with_port(&target, DEFAULT_PORT),
+            };
+            pipe::push(&addr, &file)
+        }
+        Pipe::Pull {
+            port,
+            fingerprint,
+            out,
+        } => pipe::pull(port, &fingerprint, &out).map(|path| println!("{}", path.display())),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
 
-const PORT: u16 = 3001;
+/// Sets up multicast and IPv6 discovery as asked on the command line. A
+/// multicast group or IPv6 that can't be used is reported and left out,
+/// so discovery still works the usual way.
+fn discovery_sockets(
+    socket: std::net::UdpSocket,
+    args: &Args,
+    announce_settings: &announce::SharedAnnounce,
+) -> network::DiscoverySockets {
+    let interface = announce_settings
+        .lock()
+        .unwrap()
+        .interface()
+        .map(str::to_string);
+    let mut mode = args.discovery;
+    if mode == network::DiscoveryMode::Multicast
+        && let Err(e) = network::join_multicast(&socket, interface.as_deref())
+    {
+        println!(
+            "{} Cannot join multicast group {} ({}); discovering by broadcast instead.",
+            "Warning:".yellow().bold(),
+            protocol::MULTICAST_GROUP,
+            e
+        );
+        mode = network::DiscoveryMode::Broadcast;
+    }
+    let v6 = if args.ipv6 {
+        match network::bind_discovery_socket_v6(interface.as_deref()) {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                println!(
+                    "{} Cannot use IPv6 ({}); discovering and chatting over IPv4 only.",
+                    "Warning:".yellow().bold(),
+                    e
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+    network::DiscoverySockets {
+        v4: socket,
+        v6,
+        mode,
+    }
+}
+
+/// Asks for the keystore passphrase if the keys are encrypted, or on the
+/// first run whether to encrypt them at all.
+fn open_vault() -> io::Result<()> {
+    let cancelled = || io::Error::new(io::ErrorKind::Interrupted, "cancelled");
+    match vault::status()? {
+        vault::Status::Plaintext => Ok(()),
+        vault::Status::Encrypted => {
+            for attempt in 1..=PASSPHRASE_ATTEMPTS {
+                let passphrase =
+                    terminal::read_secret("Keystore passphrase: ")?.ok_or_else(cancelled)?;
+                match vault::unlock(&passphrase) {
+                    Err(e)
+                        if e.kind() == io::ErrorKind::PermissionDenied
+                            && attempt < PASSPHRASE_ATTEMPTS =>
+                    {
+                        println!("{}", e);
+                    }
+                    result => return result,
+                }
+            }
+            unreachable!("the last attempt returns")
+        }
+        vault::Status::Unset => {
+            println!("Your identity key and known peers can be encrypted with a passphrase.");
+            let passphrase = terminal::read_secret(
+                "New keystore passphrase (Enter to keep them unencrypted): ",
+            )?
+            .ok_or_else(cancelled)?;
+            if passphrase.is_empty() {
+                println!("Keys are stored unencrypted. Delete ~/.sandesh/vault to be asked again.");
+                return vault::set_up(None);
+            }
+            let repeated = terminal::read_secret("Repeat it: ")?.ok_or_else(cancelled)?;
+            if *repeated != *passphrase {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the passphrases don't match",
+                ));
+            }
+            vault::set_up(Some(&passphrase))
+        }
+    }
+}
+
+/// Opens the keystore for `push` and `pull` without asking anything:
+/// encrypted keys take their passphrase from `SANDESH_PASSPHRASE`, and on
+/// a first run the question is left for the chat window.
+fn open_vault_quietly() -> io::Result<()> {
+    if vault::status()? != vault::Status::Encrypted {
+        return Ok(());
+    }
+    let passphrase = std::env::var("SANDESH_PASSPHRASE").map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "the keys are encrypted; set SANDESH_PASSPHRASE",
+        )
+    })?;
+    vault::unlock(&passphrase)
+}
 
 fn main() -> std::io::Result<()> {
+    match std::env::args().nth(1).as_deref() {
+        // Hidden: prints the wire-format spec for other implementations.
+        Some("protocol-spec") => {
+            println!("{}", protocol::describe());
+            return Ok(());
+        }
+        Some("push" | "pull") => std::process::exit(run_pipe()),
+        _ => {}
+    }
+    terminal::install_handlers();
+
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    transcript::set_enabled(args.log);
+
+    if let Err(e) = open_vault() {
+        eprintln!("Cannot open the keystore: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = identity::init() {
+        eprintln!("Cannot load the identity key: {}", e);
+        std::process::exit(1);
+    }
+    // Seals a known-peers file left from before encryption was turned on.
+    keystore::KeyStore::load();
+
     execute!(io::stdout(), SetTitle("Sandesh P2P"))?;
-    let socket = UdpSocket::bind(format!("0.0.0.0:{}", PORT)).expect("couldn't bind");
-    socket.set_broadcast(true).expect("set_broadcast failed");
+    let socket = network::bind_discovery_socket()?;
+    let tcp_port = args.port.unwrap_or(DEFAULT_PORT);
 
     let known_peers = state::init_peers();
+    let mut tag_book = tags::TagBook::load();
+    let mut watch_list = watches::WatchList::load();
+    let mut presence = watches::PresenceTracker::new();
     let (tx, rx) = mpsc::channel();
-    network::start_background_tasks(socket, known_peers.clone(), PORT, tx);
+    let announce_settings = announce::AnnounceSettings::load();
+    {
+        let mut settings = announce_settings.lock().unwrap();
+        settings.interface_override = args.interface.clone();
+        settings.nickname_override = args.nickname.clone();
+        crypto::set_nickname(settings.nickname());
+    }
+    let sockets = discovery_sockets(socket, &args, &announce_settings);
+    let resources = Arc::new(resources::Resources::new());
+    blocklist::load();
+    filter::load();
+    transfer::load_settings();
+    static_peers::load();
+    aliases::load();
+    let background = match network::start_background_tasks(
+        sockets,
+        known_peers.clone(),
+        tcp_port,
+        tx,
+        announce_settings.clone(),
+        resources.clone(),
+    ) {
+        Ok(tasks) => tasks,
+        Err(e) => {
+            eprintln!("Cannot accept chats on port {}: {}", tcp_port, e);
+            std::process::exit(1);
+        }
+    };
+    let discovery_stats = background.stats().clone();
+
+    #[cfg(feature = "update-check")]
+    let update_notice = args.check_updates.then(|| {
+        let url = args.update_url.clone();
+        update::spawn_check(url.unwrap_or_else(|| update::DEFAULT_URL.to_string()))
+    });
 
     clear_screen();
     print_banner();
+    if discovery_stats.tcp_port() != tcp_port {
+        println!(
+            "{} port {} is in use; accepting chats on port {} instead.",
+            "Note:".yellow().bold(),
+            tcp_port,
+            discovery_stats.tcp_port()
+        );
+    }
+    warn_about_interfaces(&announce_settings);
+
+    let first_chat = match &args.connect {
+        Some(target) => chat::auto_connect(target, args.initial_message.as_deref())?,
+        None => None,
+    };
 
-    enable_raw_mode()?;
-    print_prompt("");
+    let _raw = TerminalGuard::raw()?;
+    // Open chats; the prompt is tab 0, in front whenever no chat is.
+    let mut tabs = chat::Tabs::new();
+    match first_chat {
+        Some(session) => tabs.open(session)?,
+        None => print_prompt("", &announce_settings),
+    }
 
     let mut input_buffer = String::new();
 
     let mut command_history: Vec<String> = Vec::new();
     let mut history_index: usize = 0;
+    let mut cycle: Option<Cycle> = None;
+    let mut rejected_seen: u64 = 0;
+    let mut rate_limited_seen: u64 = 0;
+    // An incoming request waiting for y/n. Others stay queued meanwhile.
+    let mut pending: Option<chat::PendingRequest> = None;
 
-    loop {
-        if let Ok(stream) = rx.try_recv() {
-            disable_raw_mode()?;
-            chat::handle_incoming_request(stream)?;
-            enable_raw_mode()?;
-            print_prompt(&input_buffer);
+    let exit_code = loop {
+        if !tabs.at_home() {
+            if tabs.run(&known_peers)? {
+                redraw_prompt(&input_buffer, pending.as_ref(), &announce_settings)?;
+            }
+            continue;
+        }
+        for notice in tabs.tick(&known_peers)? {
+            print!("\r\n{} {}", "*".cyan().bold(), notice);
+            redraw_prompt(&input_buffer, pending.as_ref(), &announce_settings)?;
+        }
+
+        #[cfg(feature = "update-check")]
+        if let Some(notice) = &update_notice
+            && notice.try_recv().is_ok()
+            && let Some(release) = update::latest()
+        {
+            print!("\r\n{}", update::notice(release).dimmed());
+            redraw_prompt(&input_buffer, pending.as_ref(), &announce_settings)?;
+        }
+
+        for shout in shout::take_received() {
+            print!(
+                "\x07\r\n{} {}",
+                "*".magenta().bold(),
+                shout.line().magenta()
+            );
+            redraw_prompt(&input_buffer, pending.as_ref(), &announce_settings)?;
+        }
+
+        let rejected = resources.rejected();
+        if rejected > rejected_seen {
+            print!(
+                "\r\n{}",
+                format!(
+                    "Turned away {} incoming request(s): request limit ({}) reached.",
+                    rejected - rejected_seen,
+                    resources.request_limit()
+                )
+                .yellow()
+            );
+            rejected_seen = rejected;
+            redraw_prompt(&input_buffer, pending.as_ref(), &announce_settings)?;
+        }
+
+        let rate_limited = resources.rate_limited();
+        if rate_limited > rate_limited_seen {
+            print!(
+                "\r\n{}",
+                format!(
+                    "Turned away {} incoming connection(s) from a peer that made more than {} in a minute.",
+                    rate_limited - rate_limited_seen,
+                    resources.request_rate()
+                )
+                .yellow()
+            );
+            rate_limited_seen = rate_limited;
+            redraw_prompt(&input_buffer, pending.as_ref(), &announce_settings)?;
+        }
+
+        for addr in presence.arrivals(&known_peers) {
+            let peers = known_peers.read().unwrap();
+            let Some(watch) = peers
+                .get(&addr)
+                .and_then(|info| watch_list.matching(addr.ip(), info, &tag_book))
+            else {
+                continue;
+            };
+            drop(peers);
+            print!(
+                "\x07\r\n{} {}{} is online (watching {})",
+                "*".yellow().bold(),
+                addr.to_string().bold(),
+                format_tags(&tag_book, addr.ip()),
+                watch.target
+            );
+            if watch.then_connect {
+                print!("\r\n");
+                let cooked = TerminalGuard::cooked()?;
+                let session = chat::initiate_connection(&addr.to_string(), None)?;
+                drop(cooked);
+                if let Some(session) = session {
+                    tabs.open(session)?;
+                    break;
+                }
+            }
+            redraw_prompt(&input_buffer, pending.as_ref(), &announce_settings)?;
+        }
+        if !tabs.at_home() {
+            continue;
+        }
+
+        if let Some(request) = pending.take_if(|r| r.is_expired()) {
+            request.expire();
+            print_prompt(&input_buffer, &announce_settings);
+        }
+
+        if pending.is_none()
+            && let Ok(stream) = rx.try_recv()
+        {
+            resources.request_taken();
+            if let Ok(request) = chat::PendingRequest::new(stream, &known_peers) {
+                if let Some(fingerprint) = request.trusted_key() {
+                    match request.auto_accept(&fingerprint)? {
+                        Some(session) => tabs.open(session)?,
+                        None => print_prompt(&input_buffer, &announce_settings),
+                    }
+                } else {
+                    request.show()?;
+                    pending = Some(request);
+                }
+            }
         }
 
         if event::poll(Duration::from_millis(100))?
             && let Event::Key(key) = event::read()?
         {
+            if let Some(request) = pending.as_mut()
+                && !terminal::is_interrupt(&key)
+            {
+                match key.code {
+                    KeyCode::Char(c) => {
+                        request.answer.push(c);
+                        print!("{}", c);
+                        io::stdout().flush()?;
+                    }
+                    KeyCode::Backspace => {
+                        print!("{}", terminal::erase_last(&mut request.answer));
+                        io::stdout().flush()?;
+                    }
+                    KeyCode::Enter => {
+                        let request = pending.take().expect("a request is pending");
+                        match chat::answer_incoming_request(request)? {
+                            Some(session) => tabs.open(session)?,
+                            None => print_prompt(&input_buffer, &announce_settings),
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            if key.code != KeyCode::Tab {
+                cycle = None;
+            }
             match key.code {
+                _ if terminal::is_interrupt(&key) => {
+                    print!("\r\nShutting down...\r\n");
+                    break 130;
+                }
+                KeyCode::Left | KeyCode::Right
+                    if key.modifiers.contains(KeyModifiers::CONTROL) && !tabs.is_empty() =>
+                {
+                    tabs.step(key.code == KeyCode::Right)?;
+                }
                 KeyCode::Char(c) => {
                     input_buffer.push(c);
                     print!("{}", c);
                     io::stdout().flush()?;
                 }
-                KeyCode::Backspace => {
-                    if input_buffer.pop().is_some() {
-                        print!("\x08 \x08");
-                        io::stdout().flush()?;
-                    }
+                KeyCode::Backspace if !input_buffer.is_empty() => {
+                    print!("{}", terminal::erase_last(&mut input_buffer));
+                    io::stdout().flush()?;
                 }
-                KeyCode::Up => {
-                    if !command_history.is_empty() && history_index > 0 {
-                        history_index -= 1;
-                        input_buffer = command_history[history_index].clone();
-                        print_prompt_clean(&input_buffer);
-                    }
+                KeyCode::Tab => {
+                    complete(&mut input_buffer, &mut cycle, &known_peers, &tag_book);
+                    print_prompt_clean(&input_buffer, &announce_settings);
+                }
+                KeyCode::Up if !command_history.is_empty() && history_index > 0 => {
+                    history_index -= 1;
+                    input_buffer = command_history[history_index].clone();
+                    print_prompt_clean(&input_buffer, &announce_settings);
                 }
-                KeyCode::Down => {
-                    if history_index < command_history.len() {
-                        history_index += 1;
+                KeyCode::Down if history_index < command_history.len() => {
+                    history_index += 1;
 
-                        if history_index == command_history.len() {
-                            input_buffer.clear();
-                        } else {
-                            input_buffer = command_history[history_index].clone();
-                        }
-                        print_prompt_clean(&input_buffer);
+                    if history_index == command_history.len() {
+                        input_buffer.clear();
+                    } else {
+                        input_buffer = command_history[history_index].clone();
                     }
+                    print_prompt_clean(&input_buffer, &announce_settings);
                 }
                 KeyCode::Enter => {
                     println!("\r");
@@ -95,30 +642,132 @@ fn main() -> std::io::Result<()> {
 
                     input_buffer.clear();
 
-                    disable_raw_mode()?;
-                    handle_command(&command_line, &known_peers)?;
-                    enable_raw_mode()?;
+                    if command_line.split_whitespace().next() == Some("exit") {
+                        print!("Shutting down...\r\n");
+                        break 0;
+                    }
 
-                    print_prompt("");
+                    let cooked = TerminalGuard::cooked()?;
+                    let (command, args) =
+                        command_line.split_once(' ').unwrap_or((&command_line, ""));
+                    match command {
+                        "host" => handle_host_command(args, &rx, &known_peers, &resources)?,
+                        "connect" => {
+                            if let Some(session) = handle_connect_command(args, &known_peers)? {
+                                tabs.open(session)?;
+                            }
+                        }
+                        "switch" => handle_switch_command(args, &mut tabs)?,
+                        _ => handle_command(
+                            &command_line,
+                            &known_peers,
+                            &mut tag_book,
+                            &discovery_stats,
+                            &announce_settings,
+                            &resources,
+                            &mut watch_list,
+                        )?,
+                    }
+                    drop(cooked);
+
+                    if tabs.at_home() {
+                        print_prompt("", &announce_settings);
+                    }
                 }
                 _ => {}
             }
         }
+    };
+
+    background.shutdown();
+    let _ = keystore::save_discoveries();
+    drop(_raw);
+    terminal::restore();
+    std::process::exit(exit_code);
+}
+
+/// Puts back whichever prompt was showing after other output: the question
+/// for a pending request, or the command line.
+fn redraw_prompt(
+    input_buffer: &str,
+    pending: Option<&chat::PendingRequest>,
+    announce: &announce::SharedAnnounce,
+) -> io::Result<()> {
+    match pending {
+        Some(request) => request.show(),
+        None => {
+            print_prompt(input_buffer, announce);
+            Ok(())
+        }
+    }
+}
+
+/// Matches a Tab press found for the word starting at `start`; further
+/// presses step through them.
+struct Cycle {
+    start: usize,
+    matches: Vec<String>,
+    index: usize,
+}
+
+/// Completes the last word of `input_buffer`. A single match is filled in
+/// with a space after it; several are listed under the prompt and filled
+/// in one per press, starting with the first.
+fn complete(
+    input_buffer: &mut String,
+    cycle: &mut Option<Cycle>,
+    known_peers: &state::PeerMap,
+    tag_book: &tags::TagBook,
+) {
+    if let Some(cycle) = cycle {
+        cycle.index = (cycle.index + 1) % cycle.matches.len();
+        input_buffer.truncate(cycle.start);
+        input_buffer.push_str(&cycle.matches[cycle.index]);
+        return;
+    }
+    let peer_words = completion::peer_words(known_peers);
+    let tags = tag_book.all_tags();
+    let (start, matches) = completion::candidates(input_buffer, &peer_words, &tags);
+    match matches.as_slice() {
+        [] => {}
+        [word] => {
+            input_buffer.truncate(start);
+            input_buffer.push_str(word);
+            input_buffer.push(' ');
+        }
+        several => {
+            print!("\r\n{}\r\n", several.join("  ").dimmed());
+            input_buffer.truncate(start);
+            input_buffer.push_str(&several[0]);
+            *cycle = Some(Cycle {
+                start,
+                matches,
+                index: 0,
+            });
+        }
     }
 }
 
-fn print_prompt_clean(text: &str) {
+fn print_prompt_clean(text: &str, announce: &announce::SharedAnnounce) {
     print!("\r");
     execute!(
         io::stdout(),
         crossterm::terminal::Clear(crossterm::terminal::ClearType::UntilNewLine)
     )
     .unwrap();
-    print!("{} {}", "SANDESH >> ".green().bold(), text);
+    print!("{} {}", prompt_label(announce), text);
     io::stdout().flush().unwrap();
 }
 
-fn handle_command(input: &str, known_peers: &state::PeerMap) -> io::Result<()> {
+fn handle_command(
+    input: &str,
+    known_peers: &state::PeerMap,
+    tag_book: &mut tags::TagBook,
+    discovery_stats: &network::DiscoveryStats,
+    announce_settings: &announce::SharedAnnounce,
+    resources: &resources::Resources,
+    watch_list: &mut watches::WatchList,
+) -> io::Result<()> {
     let parts: Vec<&str> = input.split_whitespace().collect();
     if parts.is_empty() {
         return Ok(());
@@ -128,106 +777,1463 @@ fn handle_command(input: &str, known_peers: &state::PeerMap) -> io::Result<()> {
     let args = &parts[1..];
 
     match command {
-        "find" => {
-            monitor_peers(known_peers)?;
-        }
-        "find-quick" => {
-            let peers = known_peers.lock().unwrap();
-            println!("{}", "--- Known Peers ---".yellow());
-            if peers.is_empty() {
-                println!("No peers found yet.");
-            } else {
-                for (peer, _) in peers.iter() {
-                    println!(" - {}", peer);
+        "find" => match parse_tag_filter(args) {
+            Some(tag) => monitor_peers(known_peers, tag_book, tag)?,
+            None => println!("Usage: find [--tag <tag>]"),
+        },
+        "find-quick" => match parse_tag_filter(args) {
+            Some(tag) => {
+                let peers = known_peers.read().unwrap();
+                println!("{}", "--- Known Peers ---".yellow());
+                let mut matching: Vec<_> = peers
+                    .iter()
+                    .filter(|(peer, _)| tag.is_none_or(|t| tag_book.has_tag(peer.ip(), t)))
+                    .collect();
+                matching.sort_by_key(|(addr, _)| **addr);
+                let unanswered: Vec<_> = static_peers::unanswered(&peers)
+                    .into_iter()
+                    .filter(|peer| tag.is_none_or(|t| tag_book.has_tag(peer.ip(), t)))
+                    .collect();
+                if matching.is_empty() && unanswered.is_empty() {
+                    println!("No peers found yet.");
+                } else {
+                    let chats = last_chats();
+                    for (n, (peer, info)) in matching.iter().enumerate() {
+                        println!(
+                            " {}. {} {}{}{}",
+                            n + 1,
+                            format_peer(peer, info),
+                            last_heard(info),
+                            format_last_chat(&chats, info),
+                            format_tags(tag_book, peer.ip())
+                        );
+                    }
+                    for (n, peer) in unanswered.iter().enumerate() {
+                        println!(
+                            " {}. {}{}",
+                            matching.len() + n + 1,
+                            format_unanswered(peer),
+                            format_tags(tag_book, peer.ip())
+                        );
+                    }
                 }
+                *LAST_LISTING.lock().unwrap() = matching
+                    .iter()
+                    .map(|(addr, _)| **addr)
+                    .chain(unanswered)
+                    .collect();
+                println!("{}", "-------------------".yellow());
             }
-            println!("{}", "-------------------".yellow());
+            None => println!("Usage: find-quick [--tag <tag>]"),
+        },
+        "peer" => handle_peer_command(args, known_peers, tag_book),
+        "history" => handle_history_command(args, known_peers),
+        "block" | "unblock" | "blocklist" => {
+            handle_block_command(command, args, known_peers, tag_book)
         }
-        "connect" => {
-            if args.is_empty() {
-                println!("Usage: connect <IP:PORT>");
-            } else {
-                let target = if args[0].contains(':') {
-                    args[0].to_string()
+        "add-peer" | "remove-peer" => handle_static_peer_command(command, args),
+        "alias" => handle_alias_command(args),
+        "shout" => handle_shout_command(input, known_peers),
+        "announce" => handle_announce_command(args, announce_settings),
+        "trust" => handle_trust_command(args),
+        "outbox" => handle_outbox_command(args),
+        "watch" | "unwatch" | "watches" => {
+            handle_watch_command(command, args, watch_list, known_peers, tag_book)
+        }
+        "status" => print_status(known_peers, announce_settings, discovery_stats),
+        "whoami" => print_whoami(announce_settings, discovery_stats),
+        "netstats" => {
+            println!("{}", "--- Discovery ---".yellow());
+            println!(
+                "Announcements received: {}",
+                discovery_stats.announcements()
+            );
+            println!("Other datagrams:        {}", discovery_stats.ignored());
+            println!("Rejected beacons:       {}", discovery_stats.rejected());
+            println!(
+                "Probes answered:        {}",
+                discovery_stats.probes_answered()
+            );
+            match discovery_stats.os_drops() {
+                Some(0) => println!("Dropped by the OS:      0"),
+                Some(n) => println!(
+                    "Dropped by the OS:      {}",
+                    format!("{} (receive buffer overflowed)", n).red()
+                ),
+                None => println!("Dropped by the OS:      not reported on this platform"),
+            }
+            match discovery_stats.recv_buffer() {
+                Some(size) => println!("Receive buffer:         {} bytes", size),
+                None => println!("Receive buffer:         unknown"),
+            }
+            println!("{}", "-----------------".yellow());
+        }
+        "set" => match args {
+            ["outgoing-filter", "on"] => match filter::set_enabled(true) {
+                Ok(()) => {
+                    println!("Outgoing filter on: messages that look like secrets are held back.")
+                }
+                Err(e) => println!("Outgoing filter on for now, but couldn't save it: {}", e),
+            },
+            ["outgoing-filter", "off"] => match filter::set_enabled(false) {
+                Ok(()) => println!("Outgoing filter off."),
+                Err(e) => println!("Outgoing filter off for now, but couldn't save it: {}", e),
+            },
+            ["outgoing-filter", "add", pattern @ ..] if !pattern.is_empty() => {
+                match filter::add_pattern(&pattern.join(" ")) {
+                    Ok(()) => println!("Pattern added."),
+                    Err(e) => println!("Couldn't add the pattern: {}", e),
+                }
+            }
+            ["outgoing-filter", "remove", category @ ..] if !category.is_empty() => {
+                match filter::remove_pattern(&category.join(" ")) {
+                    Ok(true) => println!("Pattern removed."),
+                    Ok(false) => println!("No pattern you added has that category."),
+                    Err(e) => println!("Couldn't remove the pattern: {}", e),
+                }
+            }
+            ["outgoing-filter", "list"] => {
+                println!(
+                    "Outgoing filter {}.",
+                    if filter::is_enabled() { "on" } else { "off" }
+                );
+                for (category, regex, added) in filter::patterns() {
+                    let origin = if added { "added" } else { "built-in" };
+                    println!("  {} ({}): {}", category, origin, regex.dimmed());
+                }
+            }
+            ["cipher", "auto"] => {
+                crypto::set_cipher_suite(None);
+                println!("Cipher auto: ChaCha20-Poly1305 unless the peer only allows AES-256-GCM.");
+            }
+            ["cipher", name @ ("chacha20" | "aes-gcm")] => {
+                let suite = if *name == "aes-gcm" {
+                    protocol::CipherSuite::Aes256Gcm
                 } else {
-                    format!("{}:{}", args[0], PORT)
+                    protocol::CipherSuite::ChaCha20Poly1305
                 };
-                chat::initiate_connection(&target)?;
+                crypto::set_cipher_suite(Some(suite));
+                println!(
+                    "Cipher {}: peers that don't allow it can't connect.",
+                    suite.name()
+                );
+            }
+            ["padding", "on"] => {
+                crypto::set_padding(true);
+                println!(
+                    "Padding on: messages are sent in {}-byte steps.",
+                    protocol::PAD_BUCKET
+                );
+            }
+            ["padding", "off"] => {
+                crypto::set_padding(false);
+                println!("Padding off: frame sizes match message lengths.");
+            }
+            ["compression", "on"] => {
+                crypto::set_compression(true);
+                println!(
+                    "Compression on: long messages and file chunks are deflated when it helps."
+                );
+            }
+            ["compression", "off"] => {
+                crypto::set_compression(false);
+                println!("Compression off.");
+            }
+            ["shouts", "on"] => {
+                shout::set_accepting(true);
+                println!("Shouts from peers are shown as they arrive.");
+            }
+            ["shouts", "off"] => {
+                shout::set_accepting(false);
+                println!("Shouts off: peers are told you don't take them.");
+            }
+            ["request-limit", n] => match n.parse::<usize>() {
+                Ok(limit) if limit > 0 => {
+                    resources.set_request_limit(limit);
+                    println!("Up to {} incoming requests can wait for an answer.", limit);
+                }
+                _ => println!("Usage: set request-limit <n> (at least 1)"),
+            },
+            ["request-rate", n] => match n.parse::<usize>() {
+                Ok(rate) if rate > 0 => {
+                    resources.set_request_rate(rate);
+                    println!("Each address can connect up to {} times a minute.", rate);
+                }
+                _ => println!("Usage: set request-rate <n> (at least 1)"),
+            },
+            ["request-timeout", secs] => match secs.parse::<u64>() {
+                Ok(secs) if secs > 0 => {
+                    chat::set_request_timeout(Duration::from_secs(secs));
+                    println!(
+                        "Unanswered requests are rejected after {}s.",
+                        chat::request_timeout().as_secs()
+                    );
+                }
+                _ => println!("Usage: set request-timeout <seconds>"),
+            },
+            ["downloads"] => print_download_settings(),
+            ["downloads", "per-peer", on @ ("on" | "off")] => {
+                match transfer::update_settings(|s| s.per_peer = *on == "on") {
+                    Ok(()) => print_download_settings(),
+                    Err(e) => println!("{} {}", "Could not save the setting:".red(), e),
+                }
+            }
+            ["downloads", "collisions", policy] => match transfer::Collision::parse(policy) {
+                Some(policy) => match transfer::update_settings(|s| s.collision = policy) {
+                    Ok(()) => print_download_settings(),
+                    Err(e) => println!("{} {}", "Could not save the setting:".red(), e),
+                },
+                None => println!("Usage: set downloads collisions rename|overwrite|reject"),
+            },
+            ["downloads", "default"] => match transfer::update_settings(|s| s.dir = None) {
+                Ok(()) => print_download_settings(),
+                Err(e) => println!("{} {}", "Could not save the setting:".red(), e),
+            },
+            ["downloads", path @ ..] => {
+                let path = path.join(" ");
+                match transfer::check_downloads_dir(Path::new(&path)) {
+                    Ok(dir) => match transfer::update_settings(|s| s.dir = Some(dir)) {
+                        Ok(()) => print_download_settings(),
+                        Err(e) => println!("{} {}", "Could not save the setting:".red(), e),
+                    },
+                    Err(e) => println!("{} {}: {}", "Can't save files in".red(), path, e),
+                }
+            }
+            ["rekey-after", frames, minutes] => {
+                match (frames.parse::<u64>(), minutes.parse::<u64>()) {
+                    (Ok(frames), Ok(minutes)) if frames > 0 && minutes > 0 => {
+                        crypto::set_rekey_policy(
+                            frames,
+                            Duration::from_secs(minutes.saturating_mul(60)),
+                        );
+                        println!(
+                            "Chats switch to fresh keys every {} messages or {} minutes.",
+                            frames, minutes
+                        );
+                    }
+                    _ => println!("Usage: set rekey-after <messages> <minutes> (both at least 1)"),
+                }
+            }
+            _ => println!(
+                "Usage: set outgoing-filter on|off|list|add <category>: <regex>|remove <category> | set cipher auto|chacha20|aes-gcm | set padding on|off | set compression on|off | set shouts on|off | set request-limit <n> | set request-rate <n> | set request-timeout <seconds> | set rekey-after <messages> <minutes> | set downloads [<path>|default|per-peer on|off|collisions rename|overwrite|reject]"
+            ),
+        },
+        "resources" => {
+            println!("{}", "--- Resources ---".yellow());
+            println!(
+                "Pending requests: {}/{}",
+                resources.pending_requests(),
+                resources.request_limit()
+            );
+            println!("Turned away:      {}", resources.rejected());
+            println!(
+                "Rate limited:     {} (over {} a minute from one address)",
+                resources.rate_limited(),
+                resources.request_rate()
+            );
+            if let Some(n) = resources::thread_count() {
+                println!("Threads:          {}", n);
             }
+            println!("{}", "-----------------".yellow());
         }
         "cls" | "clear" => {
             clear_screen();
             print_banner();
         }
         "help" => {
-            println!("  find              - Live monitor of active peers");
-            println!("  find-quick        - List known peers");
-            println!("  connect <ip:port> - Request chat");
-            println!("  cls | clear       - Clear screen");
-            println!("  exit              - Close application");
-        }
-        "exit" => {
-            println!("Shutting down...");
-            std::process::exit(0);
+            println!("  find [--tag <tag>]         - Live monitor of active peers");
+            println!("  find-quick [--tag <tag>]   - List known peers");
+            println!("  peer tag|untag <ip> <tag>  - Add or remove a tag on a peer");
+            println!("  peer tags                  - List tagged peers");
+            println!("  peer info <peer>           - When a peer was seen and chatted with");
+            println!(
+                "  history verify <peer>      - Check a peer's transcript hasn't been tampered with"
+            );
+            println!(
+                "  connect <ip[:port]>        - Request chat (port defaults to {})",
+                DEFAULT_PORT
+            );
+            println!("  connect <nickname>         - Request chat with a discovered peer by name");
+            println!(
+                "  connect <n>                - Request chat with peer n of the last find-quick"
+            );
+            println!("  connect <ip> --pass        - Require a passphrase agreed on out of band");
+            println!("  switch [n]                 - Bring chat tab n to the front, or list them");
+            println!("  host <room>                - Open a group room; requests ask to join it");
+            println!("  shout <message>            - Send one message to every known peer");
+            println!(
+                "  trust [add] <fingerprint>  - Pin a peer's key as verified; its requests are auto-accepted"
+            );
+            println!("  trust remove <fingerprint> - Stop trusting a key");
+            println!(
+                "  trust list                 - List trusted keys and where they were last seen"
+            );
+            println!(
+                "  outbox [clear]             - Messages kept for peers a chat dropped, or drop them"
+            );
+            println!("  block | unblock <ip>       - Turn a peer's requests away without asking");
+            println!("  block | unblock --tag <t>  - The same for every peer tagged t");
+            println!("  blocklist                  - List blocked peers");
+            println!(
+                "  alias <ip[:port]> <name>   - Your own name for a peer, shown over its nickname"
+            );
+            println!("  alias --remove <name>      - Forget an alias (alias --list shows them)");
+            println!("  add-peer <ip[:port]>       - Keep probing a peer beacons don't reach");
+            println!("  remove-peer <ip[:port]>    - Stop probing a peer added by address");
+            println!(
+                "  watch <target>             - Alert when a peer comes online (--then connect to dial it)"
+            );
+            println!(
+                "  watches | unwatch <target> - List or remove watches (target: ip, #tag, fingerprint, name)"
+            );
+            println!("  announce scope all|list    - Broadcast, or announce only to listed peers");
+            println!("  announce to|drop <ip>      - Edit the announce list");
+            println!(
+                "  announce interface <name>  - Broadcast on one interface (auto: all physical)"
+            );
+            println!("  announce name <nickname>   - Name shown to peers next to your address");
+            println!(
+                "  whoami                     - Addresses peers can connect to, and your identity"
+            );
+            println!("  status                     - Announce scope and interfaces in use");
+            println!("  netstats                   - Discovery packet counters");
+            println!("  set outgoing-filter on|off - Hold back messages that look like secrets");
+            println!(
+                "  set outgoing-filter add <category>: <regex> | remove <category> | list - Its patterns"
+            );
+            println!(
+                "  set cipher <suite>         - auto (default), chacha20 or aes-gcm for new chats"
+            );
+            println!(
+                "  set padding on|off         - Hide message lengths in size buckets (default on)"
+            );
+            println!(
+                "  set compression on|off     - Deflate what you send when it shrinks (default off)"
+            );
+            println!("  set shouts on|off          - Show or turn away peers' shouts (default on)");
+            println!("  set request-limit <n>      - Max incoming requests waiting for an answer");
+            println!(
+                "  set request-rate <n>       - Max connections per address per minute (default 10)"
+            );
+            println!(
+                "  set request-timeout <s>    - Reject unanswered requests after s seconds (default 20)"
+            );
+            println!(
+                "  set rekey-after <n> <min>  - Refresh chat keys after n messages or min minutes"
+            );
+            println!(
+                "  set downloads [<path>]     - Where accepted files go (default ~/.sandesh/downloads)"
+            );
+            println!(
+                "  set downloads per-peer on|off | collisions rename|overwrite|reject - Folders, taken names"
+            );
+            println!("  resources                  - Pending requests against their limit");
+            #[cfg(feature = "update-check")]
+            println!("  changelog                  - Show notes for a newer release");
+            println!("  cls | clear                - Clear screen");
+            println!("  exit                       - Close application");
+            println!();
+            println!("{}", USAGE);
+            println!(
+                "  --port <n>                 - Accept chats on this port instead of {}",
+                DEFAULT_PORT
+            );
         }
+        #[cfg(feature = "update-check")]
+        "changelog" => update::print_changelog(),
         _ => println!("Unknown command."),
     }
     Ok(())
 }
 
-fn print_prompt(current_input: &str) {
-    print!("\r{} {}", "\nSANDESH >> ".green().bold(), current_input);
+fn handle_peer_command(args: &[&str], known_peers: &state::PeerMap, tag_book: &mut tags::TagBook) {
+    match args {
+        ["info", target] => print_peer_info(target, known_peers),
+        ["tag", peer, tag] | ["untag", peer, tag] => {
+            let Some(ip) = network::parse_peer_ip(peer) else {
+                println!("Not an IP address: {}", peer);
+                return;
+            };
+            if let Err(e) = tags::validate_tag(tag) {
+                println!("Invalid tag '{}': {}", tag, e);
+                return;
+            }
+            let result = if args[0] == "tag" {
+                tag_book.add(ip, tag)
+            } else {
+                tag_book.remove(ip, tag)
+            };
+            match result {
+                Ok(true) => println!("{}{}", ip, format_tags(tag_book, ip)),
+                Ok(false) => println!("Nothing to change."),
+                Err(e) => println!("{} {}", "Could not save tags:".red(), e),
+            }
+        }
+        ["tags"] => {
+            let mut any = false;
+            for (ip, set) in tag_book.entries() {
+                any = true;
+                let list: Vec<&str> = set.iter().map(String::as_str).collect();
+                println!(" - {} {}", ip, list.join(", "));
+            }
+            if !any {
+                println!("No tagged peers.");
+            }
+        }
+        _ => println!(
+            "Usage: peer info <peer|fingerprint> | peer tag <ip> <tag> | peer untag <ip> <tag> | peer tags"
+        ),
+    }
+}
+
+fn handle_history_command(args: &[&str], known_peers: &state::PeerMap) {
+    match args {
+        ["verify", target] => {
+            let Some(ip) = history_ip(target, known_peers) else {
+                return;
+            };
+            match transcript::verify(ip) {
+                Ok(Some(integrity)) if integrity.is_intact() => println!("{}.", integrity),
+                Ok(Some(integrity)) => println!("{} {}.", "WARNING:".red().bold(), integrity),
+                Ok(None) => println!(
+                    "Transcripts are only chained while the keystore is encrypted, so there is nothing to verify."
+                ),
+                Err(e) => println!("Cannot verify the history of {}: {}", target, e),
+            }
+        }
+        ["view", target] | ["view", target, _] => {
+            let date = match args.get(2) {
+                Some(date) => match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+                    Ok(date) => Some(date),
+                    Err(_) => {
+                        println!("Dates are written YYYY-MM-DD, e.g. 2026-10-14.");
+                        return;
+                    }
+                },
+                None => None,
+            };
+            let Some(ip) = history_ip(target, known_peers) else {
+                return;
+            };
+            if let Err(e) = chat::view_history(ip, target, date) {
+                if e.kind() == io::ErrorKind::NotFound {
+                    println!("No history of {}: {}", target, e);
+                } else {
+                    println!("Cannot show the history of {}: {}", target, e);
+                }
+            }
+        }
+        _ => println!("Usage: history view <peer> [YYYY-MM-DD] | history verify <peer>"),
+    }
+}
+
+/// The IP a peer's transcript is kept under. `None`, having said why, if
+/// `target` doesn't name one.
+fn history_ip(target: &str, known_peers: &state::PeerMap) -> Option<IpAddr> {
+    let addr = resolve_target(target, known_peers)?;
+    match addr.parse::<SocketAddr>() {
+        Ok(addr) => Some(addr.ip()),
+        Err(_) => {
+            println!("Unknown peer: {}", target);
+            None
+        }
+    }
+}
+
+/// `peer info`: what the keystore knows of when a key was around. A peer
+/// given by name or address stands for the key its beacons are signed
+/// with, or else the one it last chatted with.
+fn print_peer_info(target: &str, known_peers: &state::PeerMap) {
+    let fingerprint = identity::normalize_fingerprint(target).or_else(|| {
+        let addr: SocketAddr = resolve_target(target, known_peers)?.parse().ok()?;
+        let peers = known_peers.read().unwrap();
+        let heard = peers
+            .iter()
+            .find(|(a, _)| a.ip() == addr.ip())
+            .and_then(|(_, info)| info.identity.as_ref().map(identity::fingerprint));
+        drop(peers);
+        heard.or_else(|| {
+            keystore::KeyStore::load()
+                .history_at(addr.ip())
+                .map(|(fingerprint, _)| fingerprint.to_string())
+        })
+    });
+    let Some(fingerprint) = fingerprint else {
+        println!("Nothing is known of {}.", target);
+        return;
+    };
+
+    let keystore = keystore::KeyStore::load();
+    let Some((history, trusted)) = keystore.history(&fingerprint) else {
+        println!("Nothing is known of {}.", fingerprint);
+        return;
+    };
+    let now = Utc::now();
+    let when = |at: Option<DateTime<Utc>>| match at {
+        Some(at) => format!(
+            "{} ({})",
+            at.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+            keystore::format_ago(at, now)
+        ),
+        None => "never".to_string(),
+    };
+    println!("{}", fingerprint.bold());
+    println!("  First discovered: {}", when(history.first_discovered));
+    println!("  Last discovered:  {}", when(history.last_discovered));
+    println!("  Last chatted:     {}", when(history.last_chatted));
+    println!("  Chats:            {}", history.total_sessions);
+    println!("  Trusted:          {}", if trusted { "yes" } else { "no" });
+}
+
+/// The time each key's last chat ended, for `find` to show.
+fn last_chats() -> HashMap<String, DateTime<Utc>> {
+    keystore::KeyStore::load()
+        .histories()
+        .filter_map(|(fingerprint, history)| Some((fingerprint.to_string(), history.last_chatted?)))
+        .collect()
+}
+
+/// " chatted 6 days ago" if we ever chatted with the key the peer's
+/// beacons are signed with.
+fn format_last_chat(chats: &HashMap<String, DateTime<Utc>>, info: &state::PeerInfo) -> String {
+    let chatted = info
+        .identity
+        .as_ref()
+        .and_then(|key| chats.get(&identity::fingerprint(key)));
+    match chatted {
+        Some(at) => format!(
+            " {}",
+            format!("chatted {}", keystore::format_ago(*at, Utc::now())).dimmed()
+        ),
+        None => String::new(),
+    }
+}
+
+fn handle_block_command(
+    command: &str,
+    args: &[&str],
+    known_peers: &state::PeerMap,
+    tag_book: &tags::TagBook,
+) {
+    let ips: Vec<IpAddr> = match (command, args) {
+        ("blocklist", []) => {
+            let blocked = blocklist::blocked();
+            if blocked.is_empty() {
+                println!("No peers are blocked.");
+            }
+            for ip in blocked {
+                println!(" - {}", ip);
+            }
+            return;
+        }
+        ("block" | "unblock", ["--tag", tag]) => {
+            let tagged: Vec<IpAddr> = tag_book
+                .entries()
+                .filter(|(_, set)| set.contains(*tag))
+                .map(|(ip, _)| *ip)
+                .collect();
+            if tagged.is_empty() {
+                println!("No peer is tagged {}.", tag);
+            }
+            tagged
+        }
+        ("block" | "unblock", [target]) => match network::parse_peer_ip(target) {
+            Some(ip) => vec![ip],
+            None => {
+                println!("Not an IP address: {}", target);
+                return;
+            }
+        },
+        _ => {
+            println!(
+                "Usage: block <ip> | block --tag <tag> | unblock <ip> | unblock --tag <tag> | blocklist"
+            );
+            return;
+        }
+    };
+    for ip in ips {
+        block_or_unblock(command == "block", ip, known_peers);
+    }
+}
+
+fn block_or_unblock(block: bool, ip: IpAddr, known_peers: &state::PeerMap) {
+    if block {
+        match blocklist::block(ip) {
+            Ok(added) => {
+                // Drop it from the peer list now rather than when it
+                // would have expired.
+                known_peers
+                    .write()
+                    .unwrap()
+                    .retain(|addr, _| addr.ip() != ip);
+                if added {
+                    println!("Blocked {}.", ip);
+                } else {
+                    println!("{} is already blocked.", ip);
+                }
+            }
+            Err(e) => println!("{} {}", "Could not save the blocklist:".red(), e),
+        }
+    } else {
+        match blocklist::unblock(ip) {
+            Ok(true) => println!("Unblocked {}.", ip),
+            Ok(false) => println!("{} is not blocked.", ip),
+            Err(e) => println!("{} {}", "Could not save the blocklist:".red(), e),
+        }
+    }
+}
+
+/// `trust [add] <fingerprint>`, `trust remove <fingerprint>` and `trust
+/// list`.
+fn handle_trust_command(args: &[&str]) {
+    let (add, fingerprint) = match args {
+        ["list"] => {
+            let trusted = keystore::KeyStore::load().trusted();
+            if trusted.is_empty() {
+                println!("No keys are trusted.");
+            }
+            for (fingerprint, ip) in trusted {
+                match ip {
+                    Some(ip) => println!(" - {} (last seen at {})", fingerprint, ip),
+                    None => println!(" - {} (not seen yet)", fingerprint),
+                }
+            }
+            return;
+        }
+        ["add", fingerprint] | [fingerprint] => (true, fingerprint),
+        ["remove", fingerprint] => (false, fingerprint),
+        _ => {
+            println!("Usage: trust [add] <fingerprint> | trust remove <fingerprint> | trust list");
+            return;
+        }
+    };
+    let Some(fingerprint) = identity::normalize_fingerprint(fingerprint) else {
+        println!("Not a fingerprint: {}", fingerprint);
+        return;
+    };
+
+    let mut keystore = keystore::KeyStore::load();
+    let saved = if add {
+        keystore.trust(&fingerprint)
+    } else {
+        keystore.untrust(&fingerprint)
+    };
+    match (add, saved) {
+        (true, Ok(true)) => println!("Trusted {}.", fingerprint),
+        (true, Ok(false)) => println!("{} is already trusted.", fingerprint),
+        (false, Ok(true)) => println!("No longer trusting {}.", fingerprint),
+        (false, Ok(false)) => println!("{} is not trusted.", fingerprint),
+        (_, Err(e)) => println!("{} {}", "Could not save known peers:".red(), e),
+    }
+}
+
+/// `outbox` lists the messages waiting for each peer, `outbox clear`
+/// drops them.
+fn handle_outbox_command(args: &[&str]) {
+    match args {
+        [] => match outbox::pending() {
+            Ok(pending) if pending.is_empty() => println!("No messages are waiting to be sent."),
+            Ok(pending) => {
+                for (fingerprint, n) in pending {
+                    println!(
+                        " - {}: {} message(s), sent when you next chat",
+                        fingerprint, n
+                    );
+                }
+            }
+            Err(e) => println!("{} {}", "Could not read the outbox:".red(), e),
+        },
+        ["clear"] => match outbox::clear() {
+            Ok(0) => println!("No messages are waiting to be sent."),
+            Ok(n) => println!("Dropped {} message(s).", n),
+            Err(e) => println!("{} {}", "Could not clear the outbox:".red(), e),
+        },
+        _ => println!("Usage: outbox [clear]"),
+    }
+}
+
+fn handle_static_peer_command(command: &str, args: &[&str]) {
+    let [target] = args else {
+        println!("Usage: {} <ip[:port]>", command);
+        return;
+    };
+    let Ok(addr) = network::with_port(target, DEFAULT_PORT).parse::<SocketAddr>() else {
+        println!("Not an IP address: {}", target);
+        return;
+    };
+
+    let result = if command == "add-peer" {
+        static_peers::add(addr)
+    } else {
+        static_peers::remove(addr)
+    };
+    match (command, result) {
+        ("add-peer", Ok(true)) => {
+            println!(
+                "Added {}. It shows in find once it answers, within a few seconds.",
+                addr
+            )
+        }
+        ("add-peer", Ok(false)) => println!("{} is already a static peer.", addr),
+        (_, Ok(true)) => println!("Removed {}.", addr),
+        (_, Ok(false)) => println!("{} is not a static peer.", addr),
+        (_, Err(e)) => println!("{} {}", "Could not save static peers:".red(), e),
+    }
+}
+
+/// `connect <target> [--pass]`. Handled by the main loop rather than
+/// `handle_command`, since the chat it opens becomes one of its tabs.
+fn handle_connect_command(
+    args: &str,
+    known_peers: &state::PeerMap,
+) -> io::Result<Option<chat::ChatSession>> {
+    match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [target] => match resolve_target(target, known_peers) {
+            Some(addr) => chat::initiate_connection(&addr, None),
+            None => Ok(None),
+        },
+        [target, "--pass"] => {
+            if let Some(addr) = resolve_target(target, known_peers)
+                && let Some(passphrase) = chat::ask_passphrase()?
+            {
+                return chat::initiate_connection(&addr, Some(&passphrase));
+            }
+            Ok(None)
+        }
+        _ => {
+            println!("Usage: connect <IP[:PORT]|nickname|n> [--pass]");
+            Ok(None)
+        }
+    }
+}
+
+/// `switch [n]`: brings chat tab `n` to the front, or lists the tabs.
+fn handle_switch_command(args: &str, tabs: &mut chat::Tabs) -> io::Result<()> {
+    if tabs.is_empty() {
+        println!("No chats open.");
+        return Ok(());
+    }
+    match args.trim() {
+        "" => println!("{}", tabs.list()),
+        arg => match arg.parse() {
+            Ok(0) => {}
+            Ok(n) if tabs.switch_to(n)? => {}
+            _ => println!("Usage: switch <n> (see 'switch' for the open chats)"),
+        },
+    }
+    Ok(())
+}
+
+/// `host <room>`. Handled by the main loop rather than `handle_command`,
+/// since the room takes over the incoming requests it would otherwise ask
+/// about.
+fn handle_host_command(
+    args: &str,
+    requests: &mpsc::Receiver<TcpStream>,
+    peers: &state::PeerMap,
+    resources: &resources::Resources,
+) -> io::Result<()> {
+    let name = args.split_whitespace().collect::<Vec<_>>().join(" ");
+    if !protocol::valid_nickname(&name) {
+        println!(
+            "Usage: host <room name> (at most {} bytes)",
+            protocol::MAX_NICKNAME_LEN
+        );
+        return Ok(());
+    }
+    chat::host_room(&name, requests, peers, resources)
+}
+
+fn handle_shout_command(input: &str, peers: &state::PeerMap) {
+    let text = input.trim_start()["shout".len()..].trim();
+    if text.is_empty() {
+        println!("Usage: shout <message>");
+        return;
+    }
+    if text.len() > protocol::MAX_MESSAGE_LEN {
+        println!(
+            "Message too long (limit {} bytes).",
+            protocol::MAX_MESSAGE_LEN
+        );
+        return;
+    }
+    let names: HashMap<SocketAddr, Option<String>> = peers
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(addr, info)| (*addr, info.nickname.clone()))
+        .collect();
+    if names.is_empty() {
+        println!("No peers to shout to.");
+        return;
+    }
+    println!("Shouting to {} peer(s)...", names.len());
+    for (addr, result) in shout::send_all(peers, text) {
+        let peer = match aliases::name_for(&addr).or_else(|| names.get(&addr).cloned().flatten()) {
+            Some(name) => format!("{} ({})", name, addr),
+            None => addr.to_string(),
+        };
+        match result {
+            Ok(()) => println!("  {} {} {}", terminal::bullet(), peer, "delivered".green()),
+            Err(e) => println!(
+                "  {} {} {}",
+                terminal::bullet(),
+                peer,
+                format!("failed: {}", e).red()
+            ),
+        }
+    }
+}
+
+fn handle_alias_command(args: &[&str]) {
+    match args {
+        ["--list"] => {
+            let all = aliases::list();
+            if all.is_empty() {
+                println!("No aliases.");
+            }
+            for (target, name) in all {
+                println!(" - {} {}", name.bold(), target);
+            }
+        }
+        ["--remove", name @ ..] if !name.is_empty() => {
+            let name = name.join(" ");
+            match aliases::remove(&name) {
+                Ok(removed) if removed.is_empty() => println!("No alias is called {}.", name),
+                Ok(removed) => {
+                    for target in removed {
+                        println!("{} is no longer called {}.", target, name);
+                    }
+                }
+                Err(e) => println!("{} {}", "Could not save aliases:".red(), e),
+            }
+        }
+        [target, name @ ..] if !name.is_empty() && !target.starts_with("--") => {
+            let Ok(target) = target.parse::<aliases::AliasTarget>() else {
+                println!("Not an IP address: {}", target);
+                return;
+            };
+            let name = name.join(" ");
+            if !protocol::valid_nickname(&name) {
+                println!(
+                    "Aliases are up to {} bytes, without control characters.",
+                    protocol::MAX_NICKNAME_LEN
+                );
+                return;
+            }
+            // Names have to be unique for `connect <alias>` to know which
+            // peer is meant.
+            if let Some(other) = aliases::target_named(&name)
+                && other != target
+            {
+                println!("{} is already called {}.", other, name);
+                return;
+            }
+            match aliases::set(target, &name) {
+                Ok(()) => println!("{} is now called {}.", target, name.bold()),
+                Err(e) => println!("{} {}", "Could not save aliases:".red(), e),
+            }
+        }
+        _ => println!("Usage: alias <ip[:port]> <name> | alias --remove <name> | alias --list"),
+    }
+}
+
+fn handle_watch_command(
+    command: &str,
+    args: &[&str],
+    watch_list: &mut watches::WatchList,
+    known_peers: &state::PeerMap,
+    tag_book: &tags::TagBook,
+) {
+    let (target, then_connect) = match (command, args) {
+        ("watches", []) => {
+            let mut any = false;
+            for watch in watch_list.iter() {
+                any = true;
+                let action = if watch.then_connect {
+                    " (then connect)"
+                } else {
+                    ""
+                };
+                println!(" - {}{}", watch.target, action);
+            }
+            if !any {
+                println!("Not watching any peers.");
+            }
+            return;
+        }
+        ("watch", [target]) | ("unwatch", [target]) => (*target, false),
+        ("watch", [target, "--then", "connect"]) => (*target, true),
+        _ => {
+            println!(
+                "Usage: watch <ip|#tag|fingerprint|name> [--then connect] | unwatch <ip|#tag|fingerprint|name> | watches"
+            );
+            return;
+        }
+    };
+
+    let target = match network::parse_peer_ip(target) {
+        Some(ip) => watches::WatchTarget::Ip(ip),
+        None => match watches::WatchTarget::parse(target) {
+            Ok(target) => target,
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        },
+    };
+
+    if command == "watch" {
+        // Alerts fire on arrival only, so say so if it's here already.
+        let online: Vec<String> = known_peers
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(addr, info)| target.matches(addr.ip(), info, tag_book))
+            .map(|(addr, _)| addr.to_string())
+            .collect();
+        let shown = target.to_string();
+        match watch_list.add(target, then_connect) {
+            Ok(()) if online.is_empty() => println!(
+                "Watching {}. You'll be alerted when it comes online.",
+                shown
+            ),
+            Ok(()) => println!("Watching {}. Already online: {}", shown, online.join(", ")),
+            Err(e) => println!("{} {}", "Could not save watches:".red(), e),
+        }
+    } else {
+        match watch_list.remove(&target) {
+            Ok(true) => println!("No longer watching {}.", target),
+            Ok(false) => println!("Not watching {}.", target),
+            Err(e) => println!("{} {}", "Could not save watches:".red(), e),
+        }
+    }
+}
+
+fn handle_announce_command(args: &[&str], announce_settings: &announce::SharedAnnounce) {
+    let mut settings = announce_settings.lock().unwrap();
+    let changed = match args {
+        [] | ["status"] => false,
+        ["scope", "all"] => {
+            settings.scope = announce::AnnounceScope::All;
+            true
+        }
+        ["scope", "list"] => {
+            settings.scope = announce::AnnounceScope::List;
+            true
+        }
+        ["interface", "auto"] => {
+            settings.interface = None;
+            true
+        }
+        ["interface", name] => {
+            settings.interface = Some(name.to_string());
+            true
+        }
+        ["name", "none"] => {
+            settings.nickname = None;
+            true
+        }
+        ["name", words @ ..] if !words.is_empty() => {
+            let name = words.join(" ");
+            if !protocol::valid_nickname(&name) {
+                println!(
+                    "Nicknames are up to {} bytes of printable text.",
+                    protocol::MAX_NICKNAME_LEN
+                );
+                return;
+            }
+            settings.nickname = Some(name);
+            true
+        }
+        ["to", peer] | ["drop", peer] => {
+            let Some(ip) = network::parse_peer_ip(peer) else {
+                println!("Not an IP address: {}", peer);
+                return;
+            };
+            if args[0] == "to" && !settings.targets.contains(&ip) {
+                settings.targets.push(ip);
+            } else if args[0] == "drop" {
+                settings.targets.retain(|t| *t != ip);
+            }
+            true
+        }
+        _ => {
+            println!(
+                "Usage: announce [status] | announce scope all|list | announce to|drop <ip> | announce interface <name>|auto | announce name <nickname>|none"
+            );
+            return;
+        }
+    };
+
+    if changed && let Err(e) = settings.save() {
+        println!("{} {}", "Could not save announce settings:".red(), e);
+    }
+    crypto::set_nickname(settings.nickname());
+
+    match settings.scope {
+        announce::AnnounceScope::All => println!("Announcing to: everyone on the LAN"),
+        announce::AnnounceScope::List => println!("Announcing to: listed peers only"),
+    }
+    if settings.targets.is_empty() {
+        println!("List: (empty)");
+    } else {
+        let list: Vec<String> = settings.targets.iter().map(|ip| ip.to_string()).collect();
+        println!("List: {}", list.join(", "));
+    }
+    println!("Interface: {}", settings.interface().unwrap_or("auto"));
+    println!("Nickname: {}", settings.nickname().unwrap_or("(none)"));
+}
+
+/// Shown once at startup: announcing into a VPN tunnel or nowhere at all
+/// otherwise just looks like an empty peer list.
+fn warn_about_interfaces(announce_settings: &announce::SharedAnnounce) {
+    let settings = announce_settings.lock().unwrap();
+    if settings.scope != announce::AnnounceScope::All {
+        return;
+    }
+    let plan = interfaces::plan_broadcast(&interfaces::list(), settings.interface());
+    if let Some(warning) = plan.warning {
+        println!("{} {}", "Warning:".yellow().bold(), warning);
+    }
+}
+
+fn print_status(
+    known_peers: &state::PeerMap,
+    announce_settings: &announce::SharedAnnounce,
+    discovery_stats: &network::DiscoveryStats,
+) {
+    let settings = announce_settings.lock().unwrap();
+    let all = interfaces::list();
+    let plan = interfaces::plan_broadcast(&all, settings.interface());
+
+    println!("{}", "--- Status ---".yellow());
+    println!("Known peers: {}", known_peers.read().unwrap().len());
+    println!(
+        "Identity: {}",
+        identity::fingerprint(&identity::public_key())
+    );
+    println!("Instance: {:016x}", network::instance_id());
+    println!("Chat port: {}", discovery_stats.tcp_port());
+    match discovery_stats.listen_port() {
+        Some(port) if port == protocol::DISCOVERY_PORT => println!("Discovery port: {}", port),
+        Some(port) => println!(
+            "Discovery port: {} {}",
+            port,
+            format!(
+                "(fallback, {} could not be shared)",
+                protocol::DISCOVERY_PORT
+            )
+            .yellow()
+        ),
+        None => println!(
+            "Discovery port: {}",
+            "none, no discovery port could be bound: other peers are not heard".red()
+        ),
+    }
+    println!("Discovery: {}", discovery_stats.mode());
+    if discovery_stats.ipv6() {
+        println!("IPv6: on, multicasting to {}", protocol::MULTICAST_GROUP_V6);
+    }
+    println!("Announce scope: {}", settings.scope);
+    match settings.scope {
+        announce::AnnounceScope::All
+            if discovery_stats.mode() == network::DiscoveryMode::Multicast =>
+        {
+            println!("Multicasting to: {}", protocol::MULTICAST_GROUP)
+        }
+        announce::AnnounceScope::All if plan.targets.is_empty() => {
+            println!("Broadcasting to: {} (fallback)", protocol::BROADCAST_ADDR)
+        }
+        announce::AnnounceScope::All => {
+            let targets: Vec<String> = plan.targets.iter().map(|ip| ip.to_string()).collect();
+            println!("Broadcasting to: {}", targets.join(", "));
+        }
+        announce::AnnounceScope::List => {
+            println!("Unicasting to: {} listed peer(s)", settings.targets.len())
+        }
+    }
+    println!("Interfaces:");
+    for iface in &all {
+        let kind = match iface.kind {
+            interfaces::InterfaceKind::Loopback => "loopback",
+            interfaces::InterfaceKind::Virtual => "virtual",
+            interfaces::InterfaceKind::Physical => "physical",
+        };
+        let mut notes = vec![kind];
+        if !iface.up {
+            notes.push("down");
+        }
+        if iface.is_link_local() {
+            notes.push("link-local");
+        }
+        let line = format!(" - {} {} ({})", iface.name, iface.ip, notes.join(", "));
+        if plan.chosen.contains(&iface.name) && iface.broadcast.is_some() {
+            println!("{} {}", line.green(), "<- announcing".green());
+        } else {
+            println!("{}", line.dimmed());
+        }
+    }
+    if let Some(warning) = &plan.warning {
+        println!("{} {}", "Warning:".yellow().bold(), warning);
+    }
+    println!("{}", "--------------".yellow());
+}
+
+fn print_download_settings() {
+    let settings = transfer::settings();
+    let dir = match &settings.dir {
+        Some(dir) => dir.display().to_string(),
+        None => "~/.sandesh/downloads".to_string(),
+    };
+    println!("Files you accept are saved in {}.", dir);
+    if settings.per_peer {
+        println!("Each peer's files go in a folder named after it.");
+    }
+    println!(
+        "A name that's already taken: {}.",
+        match settings.collision {
+            transfer::Collision::Rename => "the file is saved under a numbered name",
+            transfer::Collision::Overwrite => "the offer warns, and accepting replaces the file",
+            transfer::Collision::Reject => "the offer is declined",
+        }
+    );
+}
+
+/// `whoami`: what to tell a friend so they can connect, one address per
+/// line to copy. IPv6 ones are listed only while chats are accepted over
+/// IPv6.
+fn print_whoami(
+    announce_settings: &announce::SharedAnnounce,
+    discovery_stats: &network::DiscoveryStats,
+) {
+    let port = discovery_stats.tcp_port();
+    let addresses = interfaces::connectable(interfaces::list(), discovery_stats.ipv6());
+
+    println!("{}", "--- Who am I ---".yellow());
+    if let Some(name) = announce_settings.lock().unwrap().nickname() {
+        println!("Nickname: {}", name);
+    }
+    println!(
+        "Identity: {}",
+        identity::fingerprint(&identity::public_key())
+    );
+    if addresses.is_empty() {
+        println!(
+            "No network address besides loopback; only this host can connect, at 127.0.0.1:{}.",
+            port
+        );
+    } else {
+        println!("Peers can connect to you at:");
+        for iface in &addresses {
+            let mut notes = vec![iface.name.as_str()];
+            if iface.kind == interfaces::InterfaceKind::Virtual {
+                notes.push("virtual");
+            }
+            if iface.is_link_local() {
+                notes.push("link-local");
+            }
+            let line = format!("  {}", iface.chat_address(port));
+            let notes = format!("({})", notes.join(", "));
+            if iface.kind == interfaces::InterfaceKind::Physical {
+                println!("{} {}", line.bold(), notes.dimmed());
+            } else {
+                println!("{} {}", line, notes.dimmed());
+            }
+        }
+    }
+    println!("They type `connect <address>`, and can check the identity with you once connected.");
+    println!("{}", "-----------------".yellow());
+}
+
+/// What `connect` should dial: the peer one of our aliases names, the
+/// peer at that position in the last `find-quick` list, the address of the one discovered peer with that
+/// nickname, or else `target` itself as a host. `None`, with the peers to
+/// pick from listed, if several share the nickname or none has it but
+/// some have one close to it.
+fn resolve_target(target: &str, known_peers: &state::PeerMap) -> Option<String> {
+    if target.parse::<SocketAddr>().is_ok() || target.parse::<IpAddr>().is_ok() {
+        return Some(network::with_port(target, DEFAULT_PORT));
+    }
+    if let Some(alias) = aliases::target_named(target) {
+        return Some(match alias {
+            aliases::AliasTarget::Addr(addr) => addr.to_string(),
+            // The port the peer announces, if exactly one is seen there.
+            aliases::AliasTarget::Ip(ip) => {
+                let peers = known_peers.read().unwrap();
+                let mut at_ip = peers.keys().filter(|addr| addr.ip() == ip);
+                match (at_ip.next(), at_ip.next()) {
+                    (Some(addr), None) => addr.to_string(),
+                    _ => network::with_port(&ip.to_string(), DEFAULT_PORT),
+                }
+            }
+        });
+    }
+    if let Ok(n) = target.parse::<usize>() {
+        let listing = LAST_LISTING.lock().unwrap();
+        return match n.checked_sub(1).and_then(|i| listing.get(i)) {
+            Some(addr) => Some(addr.to_string()),
+            None => {
+                println!("No peer {} in the last find-quick list.", n);
+                None
+            }
+        };
+    }
+    let peers = known_peers.read().unwrap();
+    match state::peers_named(&peers, target).as_slice() {
+        // Close to someone's nickname is more likely a typo than a host
+        // name, so nothing is dialed.
+        [] => match state::peers_named_like(&peers, target).as_slice() {
+            [] => Some(network::with_port(target, DEFAULT_PORT)),
+            close => {
+                println!("No peer is called {}. Did you mean:", target.bold());
+                for addr in close {
+                    println!(" - {}", format_peer(addr, &peers[addr]));
+                }
+                None
+            }
+        },
+        [addr] => Some(addr.to_string()),
+        several => {
+            println!("Several peers are called {}:", target.bold());
+            for addr in several {
+                println!(" - {}", format_peer(addr, &peers[addr]));
+            }
+            println!("Connect to one of them by address.");
+            None
+        }
+    }
+}
+
+/// `[]` means no filter, `["--tag", t]` filters on `t`; anything else is a
+/// usage error and yields `None`.
+fn parse_tag_filter<'a>(args: &[&'a str]) -> Option<Option<&'a str>> {
+    match args {
+        [] => Some(None),
+        ["--tag", tag] => Some(Some(tag)),
+        _ => None,
+    }
+}
+
+fn format_tags(tag_book: &tags::TagBook, ip: IpAddr) -> String {
+    let tags: Vec<String> = tag_book.tags_for(ip).map(|t| format!("#{}", t)).collect();
+    if tags.is_empty() {
+        String::new()
+    } else {
+        format!(" {}", tags.join(" ").dimmed())
+    }
+}
+
+fn print_prompt(current_input: &str, announce: &announce::SharedAnnounce) {
+    print!("\r\n{} {}", prompt_label(announce), current_input);
     io::stdout().flush().unwrap();
 }
 
-fn monitor_peers(shared_peers: &state::PeerMap) -> io::Result<()> {
-    enable_raw_mode()?;
+/// The prompt itself, marked while announcements are limited to a list so
+/// being semi-hidden is never forgotten.
+fn prompt_label(announce: &announce::SharedAnnounce) -> String {
+    let settings = announce.lock().unwrap();
+    match settings.scope {
+        announce::AnnounceScope::All => "SANDESH >> ".green().bold().to_string(),
+        announce::AnnounceScope::List => format!(
+            "{}{}{} ",
+            "SANDESH ".green().bold(),
+            "[announce: list]".yellow(),
+            " >>".green().bold()
+        ),
+    }
+}
+
+fn monitor_peers(
+    shared_peers: &state::PeerMap,
+    tag_book: &tags::TagBook,
+    initial_tag: Option<&str>,
+) -> io::Result<()> {
+    let all_tags = tag_book.all_tags();
+    let mut tag_filter = initial_tag.map(str::to_string);
+    // Chats can't end while this is up, so once is enough; holding the
+    // keystore instead would stall the discovery thread.
+    let chats = last_chats();
+
+    let screen = TerminalGuard::fullscreen()?;
     let mut stdout = io::stdout();
 
-    execute!(stdout, EnterAlternateScreen, cursor::Show)?;
-    execute!(stdout, cursor::MoveTo(0, 0))?;
-    println!("(Press 'q' or 'Esc' to return to menu)\r");
-    println!("{}\r", "Scanning for Peers...".yellow());
+    execute!(stdout, cursor::Show, cursor::MoveTo(0, 0))?;
+    println!("(Press 'q' or 'Esc' to return to menu, 't' to cycle tags)\r");
+    println!("\r");
     println!("{}\r", "---------------------------------".dimmed());
 
+    let mut shown: Vec<String> = Vec::new();
     loop {
         if event::poll(Duration::from_millis(100))?
             && let Event::Key(key) = event::read()?
         {
             match key.code {
                 KeyCode::Char('q') | KeyCode::Esc => break,
+                _ if terminal::is_interrupt(&key) => break,
+                KeyCode::Char('t') => tag_filter = next_tag(&all_tags, tag_filter.as_deref()),
                 _ => {}
             }
         }
 
-        let current_peers = shared_peers.lock().unwrap();
+        let mut lines = vec![match &tag_filter {
+            Some(tag) => format!("Scanning for Peers tagged #{}...", tag)
+                .yellow()
+                .to_string(),
+            None => "Scanning for Peers...".yellow().to_string(),
+        }];
 
-        execute!(
-            stdout,
-            cursor::MoveTo(0, 3),
-            Clear(ClearType::FromCursorDown)
-        )?;
+        let current_peers = shared_peers.read().unwrap();
 
-        if current_peers.is_empty() {
-            println!("{}\r", "Waiting for signals...".italic().dimmed());
+        let unanswered: Vec<_> = static_peers::unanswered(&current_peers)
+            .into_iter()
+            .filter(|addr| {
+                tag_filter
+                    .as_deref()
+                    .is_none_or(|t| tag_book.has_tag(addr.ip(), t))
+            })
+            .collect();
+        if current_peers.is_empty() && unanswered.is_empty() {
+            lines.push("Waiting for signals...".italic().dimmed().to_string());
         } else {
-            let mut sorted_peers: Vec<_> = current_peers.keys().collect();
-            sorted_peers.sort();
+            let mut sorted_peers: Vec<_> = current_peers
+                .iter()
+                .filter(|(addr, _)| {
+                    tag_filter
+                        .as_deref()
+                        .is_none_or(|t| tag_book.has_tag(addr.ip(), t))
+                })
+                .collect();
+            sorted_peers.sort_by_key(|(addr, _)| **addr);
 
-            for peer in sorted_peers {
-                println!("{} {}\r", "•".green(), peer);
+            for (peer, info) in sorted_peers {
+                let features = info.features.letters();
+                let tags = format_tags(tag_book, peer.ip());
+                let bullet = freshness(info, terminal::bullet());
+                let age = format!("{}{}", last_heard(info), format_last_chat(&chats, info));
+                let peer = format_peer(peer, info);
+                lines.push(if features.is_empty() {
+                    format!("{} {} {}{}", bullet, peer, age, tags)
+                } else {
+                    format!("{} {} {} [{}]{}", bullet, peer, age, features.cyan(), tags)
+                });
+            }
+            for addr in unanswered {
+                let tags = format_tags(tag_book, addr.ip());
+                lines.push(format!(
+                    "{} {}{}",
+                    terminal::bullet().dimmed(),
+                    format_unanswered(&addr),
+                    tags
+                ));
             }
         }
-
         drop(current_peers);
-        stdout.flush()?;
+
+        // Ages tick once a second; redrawing only then keeps the list
+        // from flickering on every poll.
+        if lines != shown {
+            execute!(stdout, cursor::MoveTo(0, 1), Clear(ClearType::CurrentLine))?;
+            print!("{}", lines[0]);
+            execute!(
+                stdout,
+                cursor::MoveTo(0, 3),
+                Clear(ClearType::FromCursorDown)
+            )?;
+            for line in &lines[1..] {
+                print!("{}\r\n", line);
+            }
+            stdout.flush()?;
+            shown = lines;
+        }
     }
 
-    execute!(stdout, LeaveAlternateScreen, cursor::Show)?;
-    disable_raw_mode()?;
+    drop(screen);
     Ok(())
 }
 
+/// `nickname (ip:port)`, with our alias in place of the nickname if we gave
+/// the peer one, or just the address for peers without either, marked if it
+/// was added with `add-peer`, then the short fingerprint of the
+/// key the peer's beacons are signed with (`unverified` for a legacy peer)
+/// and the status they announce, if any.
+fn format_peer(addr: &SocketAddr, info: &state::PeerInfo) -> String {
+    let fingerprint = info.fingerprint_label();
+    let name = aliases::name_for(addr).or_else(|| info.name());
+    let listed = if static_peers::is_static(addr) {
+        format!(" {}", "(static)".cyan())
+    } else {
+        String::new()
+    };
+    let peer = match name {
+        Some(name) => format!(
+            "{} ({}){} {}",
+            name.bold(),
+            addr,
+            listed,
+            fingerprint.dimmed()
+        ),
+        None => format!("{}{} {}", addr, listed, fingerprint.dimmed()),
+    };
+    match info.status {
+        Some(status @ protocol::PeerStatus::Available) => {
+            format!("{} {} {}", peer, terminal::dash(), status.name().green())
+        }
+        Some(status @ protocol::PeerStatus::Busy) => {
+            format!("{} {} {}", peer, terminal::dash(), status.name().yellow())
+        }
+        None => peer,
+    }
+}
+
+/// `(Ns ago)`, coloured by `freshness`.
+fn last_heard(info: &state::PeerInfo) -> ColoredString {
+    freshness(
+        info,
+        format!("({}s ago)", info.last_seen.elapsed().as_secs()),
+    )
+}
+
+/// `text` in green if the peer was heard from within `FRESH_PEER`, else
+/// in yellow, as it nears being dropped.
+fn freshness(info: &state::PeerInfo, text: impl Into<String>) -> ColoredString {
+    let text = text.into();
+    if info.last_seen.elapsed() < FRESH_PEER {
+        text.green()
+    } else {
+        text.yellow()
+    }
+}
+
+/// A static peer that hasn't answered its probes, so there's nothing to
+/// show but the address it was added with and our alias for it.
+fn format_unanswered(addr: &SocketAddr) -> String {
+    let peer = match aliases::name_for(addr) {
+        Some(name) => format!("{} ({})", name.bold(), addr),
+        None => addr.to_string(),
+    };
+    format!(
+        "{} {} {} {}",
+        peer,
+        "(static)".cyan(),
+        terminal::dash(),
+        "not answering".dimmed()
+    )
+}
+
+/// The filter after `current` when cycling with 't': each tag in turn,
+/// then back to showing everyone.
+fn next_tag(all_tags: &[&str], current: Option<&str>) -> Option<String> {
+    let next = match current {
+        None => all_tags.first(),
+        Some(tag) => match all_tags.iter().position(|t| *t == tag) {
+            Some(i) => all_tags.get(i + 1),
+            None => all_tags.first(),
+        },
+    };
+    next.map(|t| t.to_string())
+}
+
 fn clear_screen() {
     execute!(io::stdout(), Clear(ClearType::All), cursor::MoveTo(0, 0)).unwrap();
 }
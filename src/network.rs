@@ -1,69 +1,1264 @@
-use crate::state::PeerMap;
-use std::net::{TcpListener, TcpStream, UdpSocket};
+use crate::announce::{AnnounceScope, AnnounceSettings, SharedAnnounce};
+use crate::blocklist;
+use crate::chat;
+use crate::identity;
+use crate::interfaces::{self, InterfaceKind};
+use crate::keystore;
+use crate::protocol::{
+    self, ANNOUNCE_MAX_SKEW, Announce, Features, PeerStatus, SIGNAL_HOLD, SIGNAL_REJECT, Signer,
+};
+use crate::resources::Resources;
+use crate::shout;
+use crate::state::{PeerInfo, PeerMap};
+use crate::static_peers;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc::Sender;
-use std::thread;
-use std::time::{Duration, Instant};
-
-const BROADCAST_ADDR: &str = "255.255.255.255";
-const PROTOCOL_MSG: &[u8] = b"HELLO_P2P";
+use std::sync::{Arc, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 const PEER_TIMEOUT: Duration = Duration::from_secs(15);
 const BROADCAST_INTERVAL: Duration = Duration::from_secs(5);
 
+// Room for bursts of announcements while the listener waits on the peer
+// map lock. The OS may clamp this (net.core.rmem_max on Linux).
+const DISCOVERY_RECV_BUFFER: usize = 1024 * 1024;
+
+// Anyone on the LAN can send beacons claiming any number of ports, so the
+// peer map is capped per IP, which leaves room for several instances on
+// one host, and overall.
+const MAX_PEERS_PER_IP: usize = 16;
+const MAX_PEERS: usize = 1024;
+
+// A silent peer is probed after KEEPALIVE_IDLE and declared dead after
+// KEEPALIVE_RETRIES unanswered probes, i.e. roughly 25 seconds.
+const KEEPALIVE_IDLE: Duration = Duration::from_secs(10);
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+const KEEPALIVE_RETRIES: u32 = 3;
+
+// Ports tried after the configured chat port when it's taken, before
+// letting the OS pick one.
+const CHAT_FALLBACK_PORTS: u16 = 3;
+
+// A send that cannot hand its bytes to the kernel for this long means the
+// peer stopped reading; the session is treated as lost.
+const WRITE_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Hops a multicast beacon may take, so it reaches nearby subnets where
+// the routers forward multicast; the default of 1 never leaves the LAN.
+const MULTICAST_TTL: u32 = 4;
+
+// How long a background thread waiting on a socket or sleeping may take
+// to notice `BackgroundTasks::shutdown`.
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// How beacons reach other instances, picked with `--discovery`. Beacons
+/// are heard either way, so a multicast instance still lists broadcasting
+/// peers; only its own beacons need peers that joined the group.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DiscoveryMode {
+    #[default]
+    Broadcast,
+    /// To `protocol::MULTICAST_GROUP`, which gets through networks that
+    /// block broadcast.
+    Multicast,
+}
+
+impl fmt::Display for DiscoveryMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryMode::Broadcast => write!(f, "broadcast"),
+            DiscoveryMode::Multicast => write!(f, "multicast"),
+        }
+    }
+}
+
+/// Random id for this run, sent in every beacon so instances sharing an IP
+/// (or one instance seen under several addresses) can be told apart.
+pub fn instance_id() -> u64 {
+    static ID: OnceLock<u64> = OnceLock::new();
+    *ID.get_or_init(rand::random)
+}
+
+/// Binds the discovery socket with SO_REUSEADDR (and SO_REUSEPORT where
+/// the OS needs it for sharing), so every instance on the host hears
+/// broadcasts on the discovery port. If the port can't be shared, the
+/// next fallback port is tried. Failing all of them, an ephemeral port is
+/// used: this instance can still announce, but won't hear anyone.
+pub fn bind_discovery_socket() -> io::Result<UdpSocket> {
+    bind_discovery_port(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
+
+/// The IPv6 discovery socket for `--ipv6`, bound like the IPv4 one and
+/// joined to `protocol::MULTICAST_GROUP_V6`, as IPv6 has no broadcast, on
+/// the interface named `only` or every physical one with IPv6. Failing
+/// those, the group is joined on the default interface; an error means it
+/// can't be joined at all.
+pub fn bind_discovery_socket_v6(only: Option<&str>) -> io::Result<UdpSocket> {
+    let socket = bind_discovery_port(IpAddr::V6(Ipv6Addr::UNSPECIFIED))?;
+    let joined = ipv6_interfaces(only)
+        .iter()
+        .filter(|&&index| {
+            socket
+                .join_multicast_v6(&protocol::MULTICAST_GROUP_V6, index)
+                .is_ok()
+        })
+        .count();
+    if joined == 0 {
+        socket.join_multicast_v6(&protocol::MULTICAST_GROUP_V6, 0)?;
+    }
+    Ok(socket)
+}
+
+fn bind_discovery_port(ip: IpAddr) -> io::Result<UdpSocket> {
+    for port in protocol::discovery_ports() {
+        if let Ok(socket) = bind_shared_udp(SocketAddr::new(ip, port)) {
+            return Ok(socket);
+        }
+    }
+    bind_shared_udp(SocketAddr::new(ip, 0))
+}
+
+fn bind_shared_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    // Linux delivers broadcasts to every SO_REUSEADDR socket; BSDs need
+    // SO_REUSEPORT for the bind itself to succeed.
+    #[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+    socket.set_reuse_port(true)?;
+    if addr.is_ipv4() {
+        socket.set_broadcast(true)?;
+    } else {
+        // IPv4 beacons belong to the IPv4 socket.
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Joins the discovery socket to `protocol::MULTICAST_GROUP` on the
+/// interface named `only`, or on every discovery candidate, or failing
+/// those on whichever interface the routing table picks. An error means
+/// the group can't be used at all.
+pub fn join_multicast(socket: &UdpSocket, only: Option<&str>) -> io::Result<()> {
+    socket.set_multicast_ttl_v4(MULTICAST_TTL)?;
+    let joined = multicast_interfaces(only)
+        .iter()
+        .filter(|addr| {
+            socket
+                .join_multicast_v4(&protocol::MULTICAST_GROUP, addr)
+                .is_ok()
+        })
+        .count();
+    if joined == 0 {
+        socket.join_multicast_v4(&protocol::MULTICAST_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    }
+    Ok(())
+}
+
+/// IPv4 addresses of the interfaces multicast beacons go out on; the
+/// multicast counterpart of `interfaces::plan_broadcast`.
+fn multicast_interfaces(only: Option<&str>) -> Vec<Ipv4Addr> {
+    interfaces::list()
+        .into_iter()
+        .filter(|i| match only {
+            Some(name) => i.name == name,
+            None => i.is_discovery_candidate(),
+        })
+        .filter_map(|i| match i.ip {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        })
+        .collect()
+}
+
+/// Indexes of the interfaces IPv6 beacons go out on and are heard on:
+/// the one named `only`, or every physical one that is up with an IPv6
+/// address.
+fn ipv6_interfaces(only: Option<&str>) -> Vec<u32> {
+    let mut indexes: Vec<u32> = interfaces::list()
+        .into_iter()
+        .filter(|i| i.ip.is_ipv6())
+        .filter(|i| match only {
+            Some(name) => i.name == name,
+            None => i.up && i.kind == InterfaceKind::Physical,
+        })
+        .filter_map(|i| i.index)
+        .collect();
+    indexes.sort_unstable();
+    indexes.dedup();
+    indexes
+}
+
+/// Binds the chat listener on `port`, or if that's taken (say, by an
+/// instance that didn't exit cleanly) on one of the next few ports, and
+/// failing those on a port the OS picks. Beacons carry the port actually
+/// bound, so peers still find us.
+fn bind_chat_listener(port: u16) -> io::Result<TcpListener> {
+    let candidates = (0..=CHAT_FALLBACK_PORTS)
+        .filter_map(|offset| port.checked_add(offset))
+        .chain([0]);
+    let mut in_use = None;
+    for candidate in candidates {
+        match TcpListener::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, candidate))) {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => in_use = Some(e),
+            Err(e) => return Err(e),
+        }
+    }
+    Err(in_use.expect("at least one port was tried"))
+}
+
+/// The IPv6 chat listener for `--ipv6`, on the port the IPv4 one got.
+/// It takes IPv6 connections only: IPv4 ones reaching a dual-stack socket
+/// would show up as IPv4-mapped addresses, which the blocklist and pinned
+/// keys wouldn't match.
+fn bind_chat_listener_v6(port: u16) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(true)?;
+    // As std's `TcpListener::bind` does, so a restart can take the port
+    // back while old connections linger in TIME_WAIT.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// `target` with `port` if it has none. An IPv6 address with a port is
+/// written in brackets, `[fe80::1%2]:3001`, so a bare one, all colons, gets
+/// the brackets added.
+pub fn with_port(target: &str, port: u16) -> String {
+    let bare_ipv6 = target
+        .split_once('%')
+        .map_or(target, |(ip, _)| ip)
+        .parse::<Ipv6Addr>()
+        .is_ok();
+    if bare_ipv6 {
+        format!("[{}]:{}", target, port)
+    } else if target.starts_with('[') && target.ends_with(']') {
+        format!("{}:{}", target, port)
+    } else if target.contains(':') {
+        target.to_string()
+    } else {
+        format!("{}:{}", target, port)
+    }
+}
+
+/// Accepts either a bare IP or an `ip:port` as printed by `find`.
+pub fn parse_peer_ip(arg: &str) -> Option<IpAddr> {
+    arg.parse::<IpAddr>()
+        .ok()
+        .or_else(|| arg.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// Prepares a chat session socket so that a peer that vanished without
+/// closing the connection is detected: TCP keepalive turns a dead path into
+/// a `TimedOut` read error, and the write timeout bounds how long a send can
+/// sit on a send buffer that never drains.
+pub fn configure_session_socket(stream: &TcpStream) -> io::Result<()> {
+    let keepalive = TcpKeepalive::new().with_time(KEEPALIVE_IDLE);
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    let keepalive = keepalive.with_interval(KEEPALIVE_INTERVAL);
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    let keepalive = keepalive.with_retries(KEEPALIVE_RETRIES);
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    stream.set_write_timeout(Some(WRITE_STALL_TIMEOUT))
+}
+
+/// Blocks until `stream` has something to read, it is closed, or `timeout`
+/// passes. Where that can't be asked of the OS, it just sleeps.
+pub fn wait_readable(stream: &TcpStream, timeout: Duration) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+        use std::os::fd::AsFd;
+
+        let mut fds = [PollFd::new(stream.as_fd(), PollFlags::POLLIN)];
+        match poll(
+            &mut fds,
+            PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX),
+        ) {
+            Ok(_) | Err(nix::errno::Errno::EINTR) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = stream;
+        thread::sleep(timeout);
+        Ok(())
+    }
+}
+
+/// Counters for the discovery socket, shown by `netstats`.
+pub struct DiscoveryStats {
+    announcements: AtomicU64,
+    ignored: AtomicU64,
+    rejected: AtomicU64,
+    probes_answered: AtomicU64,
+    recv_buffer: Option<usize>,
+    listen_port: Option<u16>,
+    tcp_port: u16,
+    /// Sent in our beacons; see `instance_id`.
+    instance: u64,
+    mode: DiscoveryMode,
+    ipv6: bool,
+    #[cfg(target_os = "linux")]
+    socket_inode: Option<u64>,
+}
+
+impl DiscoveryStats {
+    fn new(
+        socket: &UdpSocket,
+        tcp_port: u16,
+        instance: u64,
+        mode: DiscoveryMode,
+        ipv6: bool,
+    ) -> Self {
+        DiscoveryStats {
+            announcements: AtomicU64::new(0),
+            ignored: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            probes_answered: AtomicU64::new(0),
+            recv_buffer: SockRef::from(socket).recv_buffer_size().ok(),
+            listen_port: socket.local_addr().ok().map(|a| a.port()),
+            tcp_port,
+            instance,
+            mode,
+            ipv6,
+            #[cfg(target_os = "linux")]
+            socket_inode: linux::socket_inode(socket),
+        }
+    }
+
+    /// Probes from peers that added us by address, answered with a beacon.
+    pub fn probes_answered(&self) -> u64 {
+        self.probes_answered.load(Ordering::Relaxed)
+    }
+
+    pub fn announcements(&self) -> u64 {
+        self.announcements.load(Ordering::Relaxed)
+    }
+
+    /// Datagrams that arrived on the discovery port but were not announcements.
+    pub fn ignored(&self) -> u64 {
+        self.ignored.load(Ordering::Relaxed)
+    }
+
+    /// Announcements dropped for being unsigned, forged or stale.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// The discovery port this instance hears beacons on, or `None` if it
+    /// couldn't bind any of them.
+    pub fn listen_port(&self) -> Option<u16> {
+        self.listen_port
+            .filter(|p| protocol::discovery_ports().any(|d| d == *p))
+    }
+
+    /// The TCP port advertised in beacons.
+    pub fn tcp_port(&self) -> u16 {
+        self.tcp_port
+    }
+
+    pub fn mode(&self) -> DiscoveryMode {
+        self.mode
+    }
+
+    /// Whether peers are also discovered and chats accepted over IPv6.
+    pub fn ipv6(&self) -> bool {
+        self.ipv6
+    }
+
+    /// Receive buffer size the OS actually granted.
+    pub fn recv_buffer(&self) -> Option<usize> {
+        self.recv_buffer
+    }
+
+    /// Datagrams the kernel discarded because the receive buffer was full,
+    /// where the platform exposes that count.
+    pub fn os_drops(&self) -> Option<u64> {
+        #[cfg(target_os = "linux")]
+        {
+            self.socket_inode.and_then(linux::udp_drops)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            None
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs;
+    use std::net::UdpSocket;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    pub fn socket_inode(socket: &UdpSocket) -> Option<u64> {
+        let fd = socket.as_raw_fd();
+        fs::metadata(format!("/proc/self/fd/{}", fd))
+            .ok()
+            .map(|m| m.ino())
+    }
+
+    /// Looks the socket up by inode in /proc/net/udp{,6} and returns the
+    /// trailing `drops` column.
+    pub fn udp_drops(inode: u64) -> Option<u64> {
+        ["/proc/net/udp", "/proc/net/udp6"].iter().find_map(|path| {
+            let table = fs::read_to_string(path).ok()?;
+            table.lines().skip(1).find_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let line_inode: u64 = fields.get(9)?.parse().ok()?;
+                if line_inode != inode {
+                    return None;
+                }
+                fields.last()?.parse().ok()
+            })
+        })
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// The peer map entry a beacon makes, if any. Anyone can send a beacon
+/// from any address, so a signed one is only listed while fresh enough
+/// that a recorded one can't be replayed for long. A bare `HELLO_P2P`, all
+/// that builds from before nicknames send, is listed as an unverified
+/// placeholder, since there is nothing in it to forge.
+fn listed_info(datagram: &[u8], announce: Announce, now: Instant) -> Option<PeerInfo> {
+    if datagram == protocol::ANNOUNCE_MAGIC {
+        return Some(PeerInfo::legacy(now));
+    }
+    let signer = announce.signer.filter(|s| is_fresh(s.timestamp))?;
+    Some(PeerInfo {
+        last_seen: now,
+        features: announce.features,
+        instance: announce.instance,
+        nickname: announce.nickname,
+        status: announce.status,
+        identity: Some(signer.identity),
+        signed_at: signer.timestamp,
+    })
+}
+
+fn is_fresh(timestamp: u64) -> bool {
+    unix_time().abs_diff(timestamp) <= ANNOUNCE_MAX_SKEW
+}
+
+/// Named so the threads are recognisable in debuggers and `top -H`.
+fn spawn_named(name: &str, f: impl FnOnce() + Send + 'static) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name(name.to_string())
+        .spawn(f)
+        .expect("failed to spawn background thread")
+}
+
+/// Sleeps for `duration`, cut short by a shutdown. False once stopped.
+fn pause(stop: &AtomicBool, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while !stop.load(Ordering::Relaxed) {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return true;
+        }
+        thread::sleep(left.min(STOP_POLL));
+    }
+    false
+}
+
+/// What `start_background_tasks` discovers peers with.
+pub struct DiscoverySockets {
+    /// From `bind_discovery_socket`. With `DiscoveryMode::Multicast` it
+    /// must already have joined the group; see `join_multicast`.
+    pub v4: UdpSocket,
+    /// From `bind_discovery_socket_v6`, with `--ipv6`. Chats are then
+    /// accepted over IPv6 as well.
+    pub v6: Option<UdpSocket>,
+    pub mode: DiscoveryMode,
+}
+
+/// The threads `start_background_tasks` runs. Dropping it stops them, as
+/// `shutdown` does.
+pub struct BackgroundTasks {
+    stats: Arc<DiscoveryStats>,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl BackgroundTasks {
+    pub fn stats(&self) -> &Arc<DiscoveryStats> {
+        &self.stats
+    }
+
+    /// Stops discovery and the chat listeners and waits for their threads
+    /// to finish, which closes their sockets. Chats already open carry on.
+    pub fn shutdown(mut self) {
+        self.stop_threads();
+    }
+
+    fn stop_threads(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for BackgroundTasks {
+    fn drop(&mut self) {
+        self.stop_threads();
+    }
+}
+
+/// Starts discovery and accepts chats on `tcp_port`, or a fallback port if
+/// it's taken; `DiscoveryStats::tcp_port` has the one in use. Fails only if
+/// no port can be bound, so the caller can report it before the UI starts.
 pub fn start_background_tasks(
-    socket: UdpSocket,
+    sockets: DiscoverySockets,
     peers: PeerMap,
-    port: u16,
+    tcp_port: u16,
     conn_sender: Sender<TcpStream>,
-) {
-    let socket_listener = socket.try_clone().expect("failed to clone into listener");
+    announce_settings: SharedAnnounce,
+    resources: Arc<Resources>,
+) -> io::Result<BackgroundTasks> {
+    start_instance(
+        sockets,
+        peers,
+        tcp_port,
+        conn_sender,
+        announce_settings,
+        resources,
+        instance_id(),
+    )
+}
+
+/// `start_background_tasks` as the instance `instance`, which tests pick
+/// so two can run in one process.
+fn start_instance(
+    sockets: DiscoverySockets,
+    peers: PeerMap,
+    tcp_port: u16,
+    conn_sender: Sender<TcpStream>,
+    announce_settings: SharedAnnounce,
+    resources: Arc<Resources>,
+    instance: u64,
+) -> io::Result<BackgroundTasks> {
+    let DiscoverySockets {
+        v4: socket,
+        v6,
+        mode,
+    } = sockets;
+    let listener = bind_chat_listener(tcp_port)?;
+    let tcp_port = listener.local_addr()?.port();
+    // Beacons carry one port, so IPv6 chats have to come in on the same.
+    let listener_v6 = match v6 {
+        Some(_) => Some(bind_chat_listener_v6(tcp_port)?),
+        None => None,
+    };
+    let _ = SockRef::from(&socket).set_recv_buffer_size(DISCOVERY_RECV_BUFFER);
+    let stats = Arc::new(DiscoveryStats::new(
+        &socket,
+        tcp_port,
+        instance,
+        mode,
+        v6.is_some(),
+    ));
+
     let socket_broadcaster = socket
         .try_clone()
         .expect("failed to clone into broadcaster");
+    let broadcaster_v6 = v6.as_ref().map(|socket| {
+        socket
+            .try_clone()
+            .expect("failed to clone into broadcaster")
+    });
     let peers_cleanup = peers.clone();
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut threads = Vec::new();
+
+    let (listen_peers, listen_stats) = (peers.clone(), stats.clone());
+    let (listen_settings, listen_stop) = (announce_settings.clone(), stop.clone());
+    threads.push(spawn_named("discovery-listen", move || {
+        listen_for_beacons(
+            socket,
+            listen_peers,
+            listen_stats,
+            listen_settings,
+            listen_stop,
+        )
+    }));
+    if let Some(socket) = v6 {
+        let _ = SockRef::from(&socket).set_recv_buffer_size(DISCOVERY_RECV_BUFFER);
+        let (listen_stats, listen_settings) = (stats.clone(), announce_settings.clone());
+        let listen_stop = stop.clone();
+        threads.push(spawn_named("discovery-listen-v6", move || {
+            listen_for_beacons(socket, peers, listen_stats, listen_settings, listen_stop)
+        }));
+    }
 
-    thread::spawn(move || {
-        let mut buffer = [0u8; 1024];
+    let announce_stop = stop.clone();
+    threads.push(spawn_named("discovery-announce", move || {
         loop {
-            match socket_listener.recv_from(&mut buffer) {
-                Ok((size, source_addr)) => {
-                    if &buffer[..size] == PROTOCOL_MSG {
-                        let mut p = peers.lock().unwrap();
-                        p.insert(source_addr, Instant::now());
+            // Re-read every interval so scope and nickname changes, and
+            // interfaces coming and going (VPN up/down, Wi-Fi roaming),
+            // apply without a restart.
+            let (announce, hosts, only) = {
+                let settings = announce_settings.lock().unwrap();
+                let announce = encode_beacon(&settings, tcp_port, instance);
+                let hosts =
+                    settings.destinations(mode, broadcaster_v6.is_some(), &interfaces::list());
+                (announce, hosts, settings.interface().map(str::to_string))
+            };
+            for host in hosts {
+                match (host, &broadcaster_v6) {
+                    (IpAddr::V4(group), _) if group.is_multicast() => {
+                        let via = multicast_interfaces(only.as_deref());
+                        multicast_beacon_v4(&socket_broadcaster, &announce, host, &via);
                     }
+                    (IpAddr::V4(_), _) => send_beacon(&socket_broadcaster, &announce, host),
+                    (IpAddr::V6(group), Some(socket)) if group.is_multicast() => {
+                        let via = ipv6_interfaces(only.as_deref());
+                        multicast_beacon_v6(socket, &announce, host, &via);
+                    }
+                    (IpAddr::V6(_), Some(socket)) => send_beacon(socket, &announce, host),
+                    // A listed IPv6 peer without `--ipv6` can't be reached.
+                    (IpAddr::V6(_), None) => {}
+                }
+            }
+            // Static peers are asked for their beacons whatever our scope,
+            // since a probe says nothing about us.
+            let probe = protocol::encode_probe();
+            for addr in static_peers::list() {
+                if blocklist::is_blocked(addr.ip()) {
+                    continue;
+                }
+                match (addr.ip(), &broadcaster_v6) {
+                    (IpAddr::V4(_), _) => send_beacon(&socket_broadcaster, &probe, addr.ip()),
+                    (IpAddr::V6(_), Some(socket)) => send_beacon(socket, &probe, addr.ip()),
+                    (IpAddr::V6(_), None) => {}
                 }
-                Err(_) => { /* Ignore errors in background to avoid spamming UI */ }
+            }
+            if !pause(&announce_stop, BROADCAST_INTERVAL) {
+                break;
             }
         }
-    });
+    }));
 
-    thread::spawn(move || {
-        loop {
-            let target = format!("{}:{}", BROADCAST_ADDR, port);
-            let _ = socket_broadcaster.send_to(PROTOCOL_MSG, &target);
-            thread::sleep(BROADCAST_INTERVAL);
+    let expiry_stop = stop.clone();
+    threads.push(spawn_named("peer-expiry", move || {
+        // The keys the listener heard are saved from here, off its thread.
+        let mut saved_at = Instant::now();
+        while pause(&expiry_stop, Duration::from_secs(2)) {
+            let mut p = peers_cleanup.write().unwrap();
+            p.retain(|_, info| info.last_seen.elapsed() < PEER_TIMEOUT);
+            drop(p);
+            if saved_at.elapsed() >= keystore::DISCOVERY_SAVE_INTERVAL {
+                let _ = keystore::save_discoveries();
+                saved_at = Instant::now();
+            }
         }
-    });
+    }));
 
-    thread::spawn(move || {
-        loop {
-            thread::sleep(Duration::from_secs(2));
-            let mut p = peers_cleanup.lock().unwrap();
-            p.retain(|_, last_seen| last_seen.elapsed() < PEER_TIMEOUT);
+    if let Some(listener) = listener_v6 {
+        let (conn_sender, resources) = (conn_sender.clone(), resources.clone());
+        let accept_stop = stop.clone();
+        threads.push(spawn_named("tcp-accept-v6", move || {
+            accept_chats(listener, conn_sender, resources, accept_stop)
+        }));
+    }
+    let accept_stop = stop.clone();
+    threads.push(spawn_named("tcp-accept", move || {
+        accept_chats(listener, conn_sender, resources, accept_stop)
+    }));
+
+    Ok(BackgroundTasks {
+        stats,
+        stop,
+        threads,
+    })
+}
+
+/// Our signed beacon, as it stands with the current settings.
+fn encode_beacon(settings: &AnnounceSettings, tcp_port: u16, instance: u64) -> Vec<u8> {
+    Announce {
+        features: Features::LOCAL,
+        tcp_port: Some(tcp_port),
+        instance: Some(instance),
+        nickname: settings.nickname().map(str::to_string),
+        status: Some(if chat::chat_open() {
+            PeerStatus::Busy
+        } else {
+            PeerStatus::Available
+        }),
+        signer: Some(Signer {
+            identity: identity::public_key(),
+            timestamp: unix_time(),
+        }),
+    }
+    .encode(identity::sign)
+}
+
+/// Adds the peers whose beacons arrive on `socket` to `peers`, and answers
+/// probes from peers that added us by address.
+fn listen_for_beacons(
+    socket: UdpSocket,
+    peers: PeerMap,
+    stats: Arc<DiscoveryStats>,
+    announce_settings: SharedAnnounce,
+    stop: Arc<AtomicBool>,
+) {
+    let mut buffer = [0u8; 1024];
+    let _ = socket.set_read_timeout(Some(STOP_POLL));
+    while !stop.load(Ordering::Relaxed) {
+        match socket.recv_from(&mut buffer) {
+            Ok((size, source_addr)) => {
+                if protocol::is_probe(&buffer[..size]) {
+                    // Answered only where our beacons would go anyway, so
+                    // probing doesn't find an instance limited to a list.
+                    let settings = announce_settings.lock().unwrap();
+                    if !blocklist::is_blocked(source_addr.ip())
+                        && (settings.scope == AnnounceScope::All
+                            || settings.targets.contains(&source_addr.ip()))
+                    {
+                        let beacon = encode_beacon(&settings, stats.tcp_port, stats.instance);
+                        if socket.send_to(&beacon, source_addr).is_ok() {
+                            stats.probes_answered.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    continue;
+                }
+                // Parse and build the entry before locking so the lock
+                // is held only for the insert.
+                let Some(announce) = Announce::decode(&buffer[..size]) else {
+                    stats.ignored.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                };
+                stats.announcements.fetch_add(1, Ordering::Relaxed);
+                // Our own beacons come back to us, through loopback or
+                // any of several NICs. Matching on the instance id
+                // instead of our addresses skips them whatever path
+                // they took, while other instances on this host, which
+                // have their own ids, are still listed.
+                if announce.instance == Some(stats.instance)
+                    || blocklist::is_blocked(source_addr.ip())
+                {
+                    continue;
+                }
+                // Builds that don't advertise a TCP port listen on the
+                // port they send from.
+                let addr = SocketAddr::new(
+                    source_addr.ip(),
+                    announce.tcp_port.unwrap_or(source_addr.port()),
+                );
+                let Some(info) = listed_info(&buffer[..size], announce, Instant::now()) else {
+                    stats.rejected.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                };
+                if let Some(identity) = &info.identity {
+                    keystore::record_discovery(&identity::fingerprint(identity));
+                }
+                list_peer(&mut peers.write().unwrap(), addr, info);
+            }
+            // Timeouts land here too, to check for a shutdown.
+            Err(_) => { /* Ignore errors in background to avoid spamming UI */ }
         }
-    });
+    }
+}
+
+/// Puts the peer a beacon came from in `peers`, making room if its IP or
+/// the whole map is at its cap by dropping the entry heard from least
+/// recently, at that IP if it is the IP that is full.
+fn list_peer(peers: &mut HashMap<SocketAddr, PeerInfo>, addr: SocketAddr, info: PeerInfo) {
+    // Anyone can send a bare beacon, so it never displaces a peer that
+    // signed its own.
+    let displaces = |p: &PeerInfo| info.identity.is_some() || p.identity.is_none();
+    if peers.get(&addr).is_some_and(|p| !displaces(p)) {
+        return;
+    }
+    // One entry per instance: a peer that moved address replaces its old
+    // entry instead of showing up twice, unless the beacon is no newer
+    // than the one the entry came from (a copy over another NIC, or a
+    // replay).
+    if let Some(instance) = info.instance {
+        if peers.iter().any(|(a, p)| {
+            *a != addr && p.instance == Some(instance) && p.signed_at >= info.signed_at
+        }) {
+            return;
+        }
+        peers.retain(|a, p| *a == addr || p.instance != Some(instance));
+    }
+    if !peers.contains_key(&addr) {
+        let at_ip = peers.keys().filter(|a| a.ip() == addr.ip()).count();
+        if at_ip >= MAX_PEERS_PER_IP || peers.len() >= MAX_PEERS {
+            let same_ip = at_ip >= MAX_PEERS_PER_IP;
+            let stalest = peers
+                .iter()
+                .filter(|(a, p)| (!same_ip || a.ip() == addr.ip()) && displaces(p))
+                .min_by_key(|(_, p)| p.last_seen)
+                .map(|(a, _)| *a);
+            let Some(stalest) = stalest else {
+                return;
+            };
+            peers.remove(&stalest);
+        }
+    }
+    peers.insert(addr, info);
+}
 
-    thread::spawn(move || {
-        let listener =
-            TcpListener::bind(format!("0.0.0.0:{}", port)).expect("Could not bind TCP listener");
+/// Sends `beacon` to `host` on every discovery port.
+fn send_beacon(socket: &UdpSocket, beacon: &[u8], host: IpAddr) {
+    for port in protocol::discovery_ports() {
+        let _ = socket.send_to(beacon, SocketAddr::new(host, port));
+    }
+}
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(s) => {
+/// Sends `beacon` to an IPv4 multicast `group` out of each interface in
+/// `via` in turn, or if there are none, out of whichever one the routing
+/// table picks.
+fn multicast_beacon_v4(socket: &UdpSocket, beacon: &[u8], group: IpAddr, via: &[Ipv4Addr]) {
+    for addr in via {
+        let _ = SockRef::from(socket).set_multicast_if_v4(addr);
+        send_beacon(socket, beacon, group);
+    }
+    if via.is_empty() {
+        send_beacon(socket, beacon, group);
+    }
+}
+
+/// The IPv6 counterpart of `multicast_beacon_v4`, with interface indexes.
+fn multicast_beacon_v6(socket: &UdpSocket, beacon: &[u8], group: IpAddr, via: &[u32]) {
+    for &index in via {
+        let _ = SockRef::from(socket).set_multicast_if_v6(index);
+        send_beacon(socket, beacon, group);
+    }
+    if via.is_empty() {
+        let _ = SockRef::from(socket).set_multicast_if_v6(0);
+        send_beacon(socket, beacon, group);
+    }
+}
+
+/// Queues the chat requests arriving on `listener` for the UI thread.
+fn accept_chats(
+    listener: TcpListener,
+    conn_sender: Sender<TcpStream>,
+    resources: Arc<Resources>,
+    stop: Arc<AtomicBool>,
+) {
+    // Polled, since a blocking accept can't be interrupted portably.
+    if let Err(e) = listener.set_nonblocking(true) {
+        eprintln!("Cannot accept chats: {}", e);
+        return;
+    }
+    while !stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((mut s, _)) => {
+                // Accepted sockets inherit non-blocking mode on some
+                // platforms; chats expect blocking ones.
+                if s.set_nonblocking(false).is_err() {
+                    continue;
+                }
+                // Blocked peers are turned away before taking a slot
+                // in the request queue, so they can't crowd it.
+                if s.peer_addr().is_ok_and(|a| blocklist::is_blocked(a.ip())) {
+                    let _ = s.write_all(&[SIGNAL_REJECT]);
+                    continue;
+                }
+                // Shouts count too: they are printed at the prompt.
+                if s.peer_addr().is_ok_and(|a| !resources.allow_from(a.ip())) {
+                    let _ = s.write_all(&[SIGNAL_REJECT]);
+                    continue;
+                }
+                let Some(mut s) = shout::take_if_shout(s) else {
+                    continue;
+                };
+                if resources.try_queue_request() {
+                    // Nobody can answer while a chat is in front, so the
+                    // peer is told to keep waiting past its usual timeout.
+                    if chat::chat_open()
+                        && let Ok(addr) = s.peer_addr()
+                        && s.write_all(&[SIGNAL_HOLD]).is_ok()
+                    {
+                        chat::note_held(addr);
+                    }
                     let _ = conn_sender.send(s);
+                } else {
+                    let _ = s.write_all(&[SIGNAL_REJECT]);
                 }
-                Err(e) => eprintln!("Connection failed: {}", e),
             }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(STOP_POLL),
+            Err(e) => eprintln!("Connection failed: {}", e),
         }
-    });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{self, CryptoError};
+    use crate::protocol::ANNOUNCE_MAGIC;
+    use crate::state::LEGACY_NAME;
+    use crate::test_util::connected;
+
+    #[test]
+    fn bare_beacon_is_listed_unverified() {
+        let announce = Announce::decode(ANNOUNCE_MAGIC).unwrap();
+        assert_eq!(announce.tcp_port, None);
+        assert_eq!(announce.signer, None);
+
+        let info = listed_info(ANNOUNCE_MAGIC, announce, Instant::now()).unwrap();
+        assert_eq!(info.identity, None);
+        assert_eq!(info.name().as_deref(), Some(LEGACY_NAME));
+        assert_eq!(info.fingerprint_label(), "unverified");
+        // Nothing to look it up by but its address.
+        assert_eq!(info.nickname, None);
+    }
+
+    #[test]
+    fn other_unsigned_beacons_are_dropped() {
+        let unsigned = Announce {
+            features: Features::default(),
+            tcp_port: Some(3000),
+            instance: Some(7),
+            nickname: Some("mallory".to_string()),
+            status: None,
+            signer: None,
+        };
+        let payload = unsigned.encode(|_| unreachable!("nothing to sign with"));
+        let decoded = Announce::decode(&payload).unwrap();
+        assert!(listed_info(&payload, decoded, Instant::now()).is_none());
+        // The magic and a feature byte is no longer a bare beacon.
+        let mut with_features = ANNOUNCE_MAGIC.to_vec();
+        with_features.push(0);
+        let decoded = Announce::decode(&with_features).unwrap();
+        assert!(listed_info(&with_features, decoded, Instant::now()).is_none());
+    }
+
+    /// A signed beacon from instance `instance`, listening on `tcp_port`.
+    fn beacon(instance: u64, tcp_port: u16) -> Vec<u8> {
+        Announce {
+            features: Features::LOCAL,
+            tcp_port: Some(tcp_port),
+            instance: Some(instance),
+            nickname: None,
+            status: None,
+            signer: Some(Signer {
+                identity: identity::public_key(),
+                timestamp: unix_time(),
+            }),
+        }
+        .encode(identity::sign)
+    }
+
+    #[test]
+    fn a_flood_of_beacons_is_kept_up_with_and_the_peer_map_stays_bounded() {
+        use crate::announce::AnnounceSettings;
+        use std::sync::Mutex;
+        const BURST: u64 = 50;
+        const BURSTS: u64 = 4;
+
+        identity::init_for_tests();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let _ = SockRef::from(&socket).set_recv_buffer_size(DISCOVERY_RECV_BUFFER);
+        let to = socket.local_addr().unwrap();
+        let stats = Arc::new(DiscoveryStats::new(
+            &socket,
+            0,
+            1,
+            DiscoveryMode::Broadcast,
+            false,
+        ));
+        let peers = PeerMap::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        let settings = Arc::new(Mutex::new(AnnounceSettings {
+            scope: AnnounceScope::List,
+            targets: Vec::new(),
+            interface: None,
+            interface_override: None,
+            nickname: None,
+            nickname_override: None,
+        }));
+        let listening = {
+            let (peers, stats, stop) = (peers.clone(), stats.clone(), stop.clone());
+            thread::spawn(move || listen_for_beacons(socket, peers, stats, settings, stop))
+        };
+
+        // Every beacon from one host, each claiming another instance and
+        // port, signed up front so each burst goes out back to back.
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for burst in 0..BURSTS {
+            let beacons: Vec<Vec<u8>> = (0..BURST)
+                .map(|n| {
+                    let n = 2 + burst * BURST + n;
+                    beacon(n, 10_000 + n as u16)
+                })
+                .collect();
+            for beacon in &beacons {
+                sender.send_to(beacon, to).unwrap();
+            }
+            let sent = (burst + 1) * BURST;
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while stats.announcements.load(Ordering::Relaxed) < sent && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert_eq!(stats.announcements.load(Ordering::Relaxed), sent);
+            assert_eq!(stats.rejected.load(Ordering::Relaxed), 0);
+            assert!(peers.read().unwrap().len() <= MAX_PEERS_PER_IP);
+        }
+        let newest = peers.read().unwrap().values().map(|p| p.instance).max();
+        assert_eq!(
+            newest,
+            Some(Some(1 + BURSTS * BURST)),
+            "the latest are listed"
+        );
+
+        stop.store(true, Ordering::Relaxed);
+        listening.join().unwrap();
+    }
+
+    #[test]
+    fn the_peer_map_makes_room_from_the_stalest_entry() {
+        identity::init_for_tests();
+        let start = Instant::now();
+        let signed = |instance: u64, secs: u64| PeerInfo {
+            instance: Some(instance),
+            identity: Some(identity::public_key()),
+            ..PeerInfo::legacy(start + Duration::from_secs(secs))
+        };
+        let host =
+            |n: usize, port: u16| SocketAddr::new(Ipv4Addr::new(10, 0, 0, n as u8).into(), port);
+
+        let mut peers = HashMap::new();
+        for n in 0..MAX_PEERS / MAX_PEERS_PER_IP {
+            for port in 0..MAX_PEERS_PER_IP {
+                let instance = (n * MAX_PEERS_PER_IP + port) as u64;
+                list_peer(&mut peers, host(n, port as u16), signed(instance, instance));
+            }
+        }
+        assert_eq!(peers.len(), MAX_PEERS);
+
+        // A full IP gives up its own stalest entry, not another host's.
+        list_peer(&mut peers, host(5, 999), signed(5000, 5000));
+        assert_eq!(peers.len(), MAX_PEERS);
+        assert!(peers.contains_key(&host(0, 0)));
+        assert!(!peers.contains_key(&host(5, 0)));
+
+        // A new host takes the stalest entry anywhere.
+        let newcomer = SocketAddr::new(Ipv4Addr::new(10, 0, 1, 1).into(), 3000);
+        list_peer(&mut peers, newcomer, signed(5001, 5001));
+        assert_eq!(peers.len(), MAX_PEERS);
+        assert!(peers.contains_key(&newcomer));
+        assert!(!peers.contains_key(&host(0, 0)));
+
+        // A bare beacon has no signed entry to push out.
+        let bare = SocketAddr::new(Ipv4Addr::new(10, 0, 1, 2).into(), 3000);
+        list_peer(&mut peers, bare, PeerInfo::legacy(start));
+        assert!(!peers.contains_key(&bare));
+        assert_eq!(peers.len(), MAX_PEERS);
+    }
+
+    #[test]
+    fn ports_are_added_for_both_families() {
+        assert_eq!(with_port("192.168.1.5", 3001), "192.168.1.5:3001");
+        assert_eq!(with_port("192.168.1.5:4000", 3001), "192.168.1.5:4000");
+        assert_eq!(with_port("fe80::1", 3001), "[fe80::1]:3001");
+        assert_eq!(with_port("fe80::1%2", 3001), "[fe80::1%2]:3001");
+        assert_eq!(with_port("[fe80::1]", 3001), "[fe80::1]:3001");
+        assert_eq!(with_port("[fe80::1]:4000", 3001), "[fe80::1]:4000");
+        assert_eq!(with_port("laptop.local", 3001), "laptop.local:3001");
+        for target in ["192.168.1.5", "::1", "[::1]", "[::1]:4000"] {
+            assert!(
+                with_port(target, 3001).parse::<SocketAddr>().is_ok(),
+                "{}",
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn peer_ips_parse_with_or_without_a_port() {
+        let v4: IpAddr = "192.168.1.5".parse().unwrap();
+        let v6: IpAddr = "fe80::1".parse().unwrap();
+        assert_eq!(parse_peer_ip("192.168.1.5"), Some(v4));
+        assert_eq!(parse_peer_ip("192.168.1.5:3001"), Some(v4));
+        assert_eq!(parse_peer_ip("fe80::1"), Some(v6));
+        assert_eq!(parse_peer_ip("[fe80::1]:3001"), Some(v6));
+        assert_eq!(parse_peer_ip("[fe80::1]"), None);
+        assert_eq!(parse_peer_ip("laptop"), None);
+    }
+
+    // Dials from other loopback addresses, which only Linux routes
+    // without setup.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn blocked_addresses_never_reach_the_prompt() {
+        use std::io::Read;
+        use std::sync::mpsc;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, requests) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let accepting = {
+            let stop = stop.clone();
+            thread::spawn(move || accept_chats(listener, sender, Arc::new(Resources::new()), stop))
+        };
+        blocklist::block_for_tests("127.0.0.2".parse().unwrap());
+        let dial = |from: &str| {
+            let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+            let from: SocketAddr = from.parse().unwrap();
+            socket.bind(&from.into()).unwrap();
+            socket.connect(&addr.into()).unwrap();
+            TcpStream::from(socket)
+        };
+
+        let mut blocked = dial("127.0.0.2:0");
+        let mut signal = [0u8; 1];
+        blocked.read_exact(&mut signal).unwrap();
+        assert_eq!(signal[0], SIGNAL_REJECT);
+
+        let allowed = dial("127.0.0.3:0");
+        let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(request.peer_addr().unwrap(), allowed.local_addr().unwrap());
+        assert!(requests.try_recv().is_err());
+
+        stop.store(true, Ordering::Relaxed);
+        accepting.join().unwrap();
+    }
+
+    #[test]
+    fn a_peer_that_stops_reading_is_caught_within_the_stall_timeout() {
+        let ((mut a, mut a_hs), (_b, _)) = connected();
+        configure_session_socket(&a).unwrap();
+        assert!(SockRef::from(&a).keepalive().unwrap());
+        assert_eq!(a.write_timeout().unwrap(), Some(WRITE_STALL_TIMEOUT));
+        // Small buffers fill sooner; the other end never reads.
+        SockRef::from(&a).set_send_buffer_size(4096).unwrap();
+
+        // Varied, so compression doesn't shrink the frames.
+        let mut seed = 1u32;
+        let text: String = (0..3000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                char::from(b'a' + (seed >> 16) as u8 % 26)
+            })
+            .collect();
+        let filling = Instant::now();
+        let (err, waited) = loop {
+            let started = Instant::now();
+            match crypto::encrypt_and_send(&mut a, &mut a_hs.cipher, &text) {
+                Ok(()) => assert!(filling.elapsed() < Duration::from_secs(30)),
+                Err(e) => break (e, started.elapsed()),
+            }
+        };
+        assert!(matches!(err, CryptoError::Stalled), "{}", err);
+        assert!(
+            waited <= WRITE_STALL_TIMEOUT + Duration::from_secs(1),
+            "{:?}",
+            waited
+        );
+        assert!(a_hs.cipher.last_written().is_some());
+    }
+
+    /// Two instances in one process, each announcing only to 127.0.0.1
+    /// from a discovery port of its own, as two instances on one host do
+    /// when the port can't be shared.
+    #[test]
+    fn two_instances_on_one_host_find_and_reach_each_other() {
+        use crate::announce::AnnounceSettings;
+        use crate::protocol::SIGNAL_ACCEPT;
+        use std::io::Read;
+        use std::sync::{Mutex, mpsc};
+
+        identity::init_for_tests();
+        // Plain binds, so a port someone else shares is skipped.
+        let mut ports = protocol::discovery_ports()
+            .filter_map(|port| UdpSocket::bind(("127.0.0.1", port)).ok());
+        let start = |instance: u64, socket: UdpSocket| {
+            let peers = PeerMap::default();
+            let (sender, requests) = mpsc::channel();
+            let settings = Arc::new(Mutex::new(AnnounceSettings {
+                scope: AnnounceScope::List,
+                targets: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+                interface: None,
+                interface_override: None,
+                nickname: None,
+                nickname_override: None,
+            }));
+            let sockets = DiscoverySockets {
+                v4: socket,
+                v6: None,
+                mode: DiscoveryMode::Broadcast,
+            };
+            let tasks = start_instance(
+                sockets,
+                peers.clone(),
+                0,
+                sender,
+                settings,
+                Arc::new(Resources::new()),
+                instance,
+            )
+            .unwrap();
+            (tasks, peers, requests)
+        };
+        let (ids, sockets) = ([0x0a, 0x0b], [ports.next(), ports.next()]);
+        let [Some(a_socket), Some(b_socket)] = sockets else {
+            panic!("two discovery ports should be free on 127.0.0.1");
+        };
+        let (a, a_peers, _) = start(ids[0], a_socket);
+        let (b, b_peers, b_requests) = start(ids[1], b_socket);
+
+        let instances = |peers: &PeerMap| -> Vec<(SocketAddr, Option<u64>)> {
+            let peers = peers.read().unwrap();
+            peers.iter().map(|(addr, p)| (*addr, p.instance)).collect()
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while (instances(&a_peers).is_empty() || instances(&b_peers).is_empty())
+            && Instant::now() < deadline
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let [(b_addr, b_instance)] = instances(&a_peers)[..] else {
+            panic!("a should list b alone: {:?}", instances(&a_peers));
+        };
+        assert_eq!(b_instance, Some(ids[1]));
+        assert_eq!(b_addr.port(), b.stats().tcp_port);
+        let [(a_addr, a_instance)] = instances(&b_peers)[..] else {
+            panic!("b should list a alone: {:?}", instances(&b_peers));
+        };
+        assert_eq!(a_instance, Some(ids[0]));
+        assert_eq!(a_addr.port(), a.stats().tcp_port);
+
+        // a dials b where it found it, b accepts, and they shake hands.
+        let dialing = thread::spawn(move || {
+            let mut stream = TcpStream::connect(b_addr).unwrap();
+            let mut answer = [0u8; 1];
+            stream.read_exact(&mut answer).unwrap();
+            assert_eq!(answer[0], SIGNAL_ACCEPT);
+            crypto::perform_handshake(&stream, crypto::Role::Initiator, None).unwrap()
+        });
+        let mut request = b_requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        request.write_all(&[SIGNAL_ACCEPT]).unwrap();
+        let answering = crypto::perform_handshake(&request, crypto::Role::Responder, None).unwrap();
+        let dialed = dialing.join().unwrap();
+        assert_eq!(dialed.sas, answering.sas);
+
+        a.shutdown();
+        b.shutdown();
+    }
 }
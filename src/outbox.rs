@@ -0,0 +1,117 @@
+//! Chat messages the peer never confirmed before the connection dropped,
+//! kept until the next chat with the same identity key and sent first
+//! there. Persisted to `~/.sandesh/outbox`, sealed like the known peers
+//! file, as one `<fingerprint> <message>` line per message in the order
+//! they were typed; backslashes and line breaks in a message are escaped.
+//! A message that arrived but whose ack was lost goes out twice.
+
+use crate::storage;
+use crate::vault;
+use std::io;
+use std::path::PathBuf;
+
+const OUTBOX_FILE: &str = "outbox";
+
+fn path() -> io::Result<PathBuf> {
+    storage::data_file(OUTBOX_FILE).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "no home directory to keep unsent messages in",
+        )
+    })
+}
+
+/// Every queued message with its peer's fingerprint. A missing file is an
+/// empty outbox; one that can't be read is an error, so it isn't
+/// overwritten.
+fn load() -> io::Result<Vec<(String, String)>> {
+    let contents = match vault::read(&path()?) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(contents
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(fingerprint, message)| (fingerprint.to_string(), unescape(message)))
+        .collect())
+}
+
+fn save(entries: &[(String, String)]) -> io::Result<()> {
+    let contents: String = entries
+        .iter()
+        .map(|(fingerprint, message)| format!("{} {}\n", fingerprint, escape(message)))
+        .collect();
+    vault::write(&path()?, &contents)
+}
+
+/// Adds `messages` for the peer with `fingerprint`, after any it already
+/// has waiting.
+pub fn queue(fingerprint: &str, messages: &[String]) -> io::Result<()> {
+    let mut entries = load()?;
+    entries.extend(
+        messages
+            .iter()
+            .map(|message| (fingerprint.to_string(), message.clone())),
+    );
+    save(&entries)
+}
+
+/// Removes and returns the messages waiting for `fingerprint`, oldest
+/// first.
+pub fn take(fingerprint: &str) -> io::Result<Vec<String>> {
+    let (taken, kept): (Vec<_>, Vec<_>) = load()?
+        .into_iter()
+        .partition(|(queued_for, _)| queued_for == fingerprint);
+    if !taken.is_empty() {
+        save(&kept)?;
+    }
+    Ok(taken.into_iter().map(|(_, message)| message).collect())
+}
+
+/// How many messages wait for each peer, by fingerprint, in the order the
+/// first of them was queued.
+pub fn pending() -> io::Result<Vec<(String, usize)>> {
+    let mut counts: Vec<(String, usize)> = Vec::new();
+    for (fingerprint, _) in load()? {
+        match counts.iter_mut().find(|(f, _)| *f == fingerprint) {
+            Some((_, n)) => *n += 1,
+            None => counts.push((fingerprint, 1)),
+        }
+    }
+    Ok(counts)
+}
+
+/// Drops every queued message. Returns how many there were.
+pub fn clear() -> io::Result<usize> {
+    let dropped = load()?.len();
+    if dropped > 0 {
+        save(&[])?;
+    }
+    Ok(dropped)
+}
+
+fn escape(message: &str) -> String {
+    message
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(line: &str) -> String {
+    let mut message = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            message.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => message.push('\n'),
+            Some('r') => message.push('\r'),
+            Some(other) => message.push(other),
+            None => message.push('\\'),
+        }
+    }
+    message
+}
@@ -0,0 +1,209 @@
+//! The notes pad shared by the two sides of a chat. Either side can add a
+//! line, or rewrite or remove one, and sends the line as it now stands;
+//! see `protocol::PadEdit`. Edits carry Lamport stamps, so both sides end
+//! up with the same lines in the same order whatever order the edits
+//! arrive in. Nothing is kept once the chat's tab is closed, unless it is
+//! saved with `/pad save`.
+
+use crate::crypto::Role;
+use crate::protocol::{MAX_PAD_LINES, PadEdit, PadStamp};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+
+pub struct Pad {
+    side: u8,
+    /// Above every clock this side has sent or seen.
+    clock: u64,
+    /// Each line's latest version and text, in pad order. Removed lines
+    /// stay, empty, so an older edit arriving late can't bring them back.
+    lines: BTreeMap<PadStamp, (PadStamp, String)>,
+}
+
+impl Pad {
+    pub fn new(role: Role) -> Self {
+        Pad {
+            side: match role {
+                Role::Initiator => 0,
+                Role::Responder => 1,
+            },
+            clock: 0,
+            lines: BTreeMap::new(),
+        }
+    }
+
+    fn stamp(&mut self) -> PadStamp {
+        self.clock += 1;
+        PadStamp {
+            clock: self.clock,
+            side: self.side,
+        }
+    }
+
+    /// Adds `text` as a new line and returns the edit to send. `None` once
+    /// the pad is full.
+    pub fn add(&mut self, text: &str) -> Option<PadEdit> {
+        if self.lines.len() >= MAX_PAD_LINES {
+            return None;
+        }
+        let line = self.stamp();
+        self.lines.insert(line, (line, text.to_string()));
+        Some(PadEdit {
+            line,
+            version: line,
+            text: text.to_string(),
+        })
+    }
+
+    /// Replaces the text of line `n`, counted from 1 as `lines` shows them;
+    /// empty text removes it. Returns the edit to send, or `None` if there
+    /// is no such line.
+    pub fn edit(&mut self, n: usize, text: &str) -> Option<PadEdit> {
+        let line = *self
+            .lines
+            .iter()
+            .filter(|(_, (_, text))| !text.is_empty())
+            .nth(n.checked_sub(1)?)?
+            .0;
+        let version = self.stamp();
+        self.lines.insert(line, (version, text.to_string()));
+        Some(PadEdit {
+            line,
+            version,
+            text: text.to_string(),
+        })
+    }
+
+    /// Takes an edit from the peer, unless this side already has a later
+    /// version of the line. Returns whether the pad changed.
+    pub fn apply(&mut self, edit: PadEdit) -> bool {
+        self.clock = self.clock.max(edit.version.clock);
+        match self.lines.get(&edit.line) {
+            Some((version, _)) if *version >= edit.version => false,
+            None if self.lines.len() >= MAX_PAD_LINES => false,
+            _ => {
+                self.lines.insert(edit.line, (edit.version, edit.text));
+                true
+            }
+        }
+    }
+
+    /// The lines that haven't been removed, in order.
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        self.lines
+            .values()
+            .map(|(_, text)| text.as_str())
+            .filter(|text| !text.is_empty())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines().next().is_none()
+    }
+
+    /// Writes the lines to a new file at `path`, one per line. An existing
+    /// file is left alone.
+    pub fn save(&self, path: &Path) -> io::Result<usize> {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        let mut count = 0;
+        for line in self.lines() {
+            writeln!(file, "{}", line)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{self, CipherState, Incoming};
+    use crate::test_util::connected;
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn lines(pad: &Pad) -> Vec<&str> {
+        pad.lines().collect()
+    }
+
+    #[test]
+    fn concurrent_edits_converge_whatever_the_order() {
+        let mut a = Pad::new(Role::Initiator);
+        let mut b = Pad::new(Role::Responder);
+        let first = a.add("ip 10.0.0.7").unwrap();
+        b.apply(first.clone());
+        // Both rewrite the same line, and each adds one, before hearing
+        // from the other.
+        let a_edit = a.edit(1, "ip 10.0.0.8").unwrap();
+        let b_edit = b.edit(1, "ip 10.0.0.9").unwrap();
+        let a_add = a.add("ping it").unwrap();
+        let b_add = b.add("restart sshd").unwrap();
+
+        for edit in [b_add, b_edit] {
+            a.apply(edit);
+        }
+        for edit in [a_edit, a_add, first] {
+            b.apply(edit);
+        }
+        assert_eq!(lines(&a), lines(&b));
+        // Same clock, so the responder's edit wins the tie.
+        assert_eq!(lines(&a), ["ip 10.0.0.9", "ping it", "restart sshd"]);
+    }
+
+    #[test]
+    fn removed_lines_stay_removed() {
+        let mut a = Pad::new(Role::Initiator);
+        let mut b = Pad::new(Role::Responder);
+        let add = a.add("temp").unwrap();
+        let remove = a.edit(1, "").unwrap();
+        assert!(a.is_empty());
+        assert!(b.apply(remove));
+        assert!(!b.apply(add));
+        assert!(b.is_empty());
+        assert!(a.edit(1, "again").is_none());
+    }
+
+    /// Applies every pad frame that arrives until `count` have.
+    fn receive(stream: &mut TcpStream, cipher: &mut CipherState, pad: &mut Pad, count: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut received = 0;
+        while received < count {
+            match crypto::receive_and_decrypt(stream, cipher).unwrap() {
+                Some(Incoming::Pad(edit)) => {
+                    pad.apply(edit);
+                    received += 1;
+                }
+                Some(_) => {}
+                None => {
+                    assert!(Instant::now() < deadline, "pad frames didn't arrive");
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn loopback_sessions_converge() {
+        let ((mut a, a_hs), (mut b, b_hs)) = connected();
+        let (mut a_cipher, mut b_cipher) = (a_hs.cipher, b_hs.cipher);
+        let mut a_pad = Pad::new(Role::Initiator);
+        let mut b_pad = Pad::new(Role::Responder);
+
+        // Interleaved adds, each side sending before reading the other's.
+        for n in 0..5 {
+            let edit = a_pad.add(&format!("a{}", n)).unwrap();
+            crypto::send_pad(&mut a, &mut a_cipher, &edit).unwrap();
+            let edit = b_pad.add(&format!("b{}", n)).unwrap();
+            crypto::send_pad(&mut b, &mut b_cipher, &edit).unwrap();
+        }
+        let edit = a_pad.edit(2, "a1, edited").unwrap();
+        crypto::send_pad(&mut a, &mut a_cipher, &edit).unwrap();
+        receive(&mut b, &mut b_cipher, &mut b_pad, 6);
+        receive(&mut a, &mut a_cipher, &mut a_pad, 5);
+
+        assert_eq!(lines(&a_pad), lines(&b_pad));
+        assert_eq!(a_pad.lines().count(), 10);
+        assert!(lines(&b_pad).contains(&"a1, edited"));
+    }
+}
@@ -0,0 +1,136 @@
+//! `sandesh push` and `sandesh pull`: one file over one encrypted session,
+//! with no chat window, for scripts and cron jobs. Progress and errors go
+//! to stderr, and the exit code says whether the file made it.
+
+use crate::keystore::{KeyStore, PinStatus};
+use crate::transfer::{self, Sending};
+use crate::{Session, connect};
+use std::io::IsTerminal;
+use std::net::{Ipv4Addr, TcpListener};
+use std::path::{Path, PathBuf};
+
+/// Characters in the progress bar between its brackets.
+const BAR_WIDTH: usize = 30;
+
+/// Dials `addr`, checks the key it presents against the one pinned for
+/// it, and sends `path`. A changed key stops it before anything is sent.
+pub fn push(addr: &str, path: &Path) -> Result<(), String> {
+    let mut file =
+        Sending::open(path).map_err(|e| format!("Cannot send {}: {}", path.display(), e))?;
+    let mut session =
+        connect(addr, None).map_err(|e| format!("Cannot connect to {}: {}", addr, e))?;
+    let fingerprint = session.peer_fingerprint();
+    let ip = session.peer_addr().map_err(|e| e.to_string())?.ip();
+    match KeyStore::load().check(ip, &fingerprint) {
+        Ok(PinStatus::Changed { previous }) => {
+            return Err(format!(
+                "The identity key of {} has changed (pinned {}, now {}). If the change is \
+                 expected, run 'trust <fingerprint>' at the prompt.",
+                addr, previous, fingerprint
+            ));
+        }
+        Ok(PinStatus::New) => eprintln!("New peer identity: {} (pinned)", fingerprint),
+        Ok(_) => eprintln!("Peer identity: {}", fingerprint),
+        Err(e) => eprintln!("Could not save known peers: {}", e),
+    }
+
+    let mut bar = Progress::new(&file.name, file.size);
+    let taken = session.send_file(&mut file, |file| bar.show(file.sent()));
+    bar.finish();
+    match taken {
+        Ok(true) => {
+            let _ = session.close();
+            eprintln!("Sent {}.", file.name);
+            Ok(())
+        }
+        Ok(false) => Err(format!("{} declined {}.", addr, file.name)),
+        Err(e) => Err(format!("Sending {} failed: {}", file.name, e)),
+    }
+}
+
+/// Waits on `port` for the peer with key `fingerprint` to push a file, and
+/// saves it in `folder`. Anyone else is turned away and the wait goes on.
+pub fn pull(port: u16, fingerprint: &str, folder: &Path) -> Result<PathBuf, String> {
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))
+        .map_err(|e| format!("Cannot listen on port {}: {}", port, e))?;
+    eprintln!("Waiting for {} on port {}...", fingerprint, port);
+    loop {
+        let (stream, addr) = listener
+            .accept()
+            .map_err(|e| format!("Cannot accept connections: {}", e))?;
+        let mut session = match Session::accept(stream, None) {
+            Ok(session) => session,
+            Err(e) => {
+                eprintln!("Handshake with {} failed: {}", addr, e);
+                continue;
+            }
+        };
+        if session.peer_fingerprint() != fingerprint {
+            eprintln!(
+                "Turned away {}, which presented {}.",
+                addr,
+                session.peer_fingerprint()
+            );
+            continue;
+        }
+        eprintln!("Connected to {}.", addr);
+
+        let mut bar: Option<Progress> = None;
+        let saved = session.receive_file(folder, |file| {
+            bar.get_or_insert_with(|| Progress::new(&file.name, file.size))
+                .show(file.received())
+        });
+        if let Some(bar) = bar {
+            bar.finish();
+        }
+        let _ = session.close();
+        return saved.map_err(|e| format!("Receiving failed: {}", e));
+    }
+}
+
+/// A bar redrawn in place on stderr as a file goes through. Nothing is
+/// drawn when stderr isn't a terminal, so logs don't fill up with it.
+struct Progress {
+    name: String,
+    total: u64,
+    shown: Option<u128>,
+    enabled: bool,
+}
+
+impl Progress {
+    fn new(name: &str, total: u64) -> Self {
+        Progress {
+            name: name.to_string(),
+            total,
+            shown: None,
+            enabled: std::io::stderr().is_terminal(),
+        }
+    }
+
+    /// Redraws the bar if `done` bytes moved it on a percent.
+    fn show(&mut self, done: u64) {
+        let percent = match self.total {
+            0 => 100,
+            total => u128::from(done) * 100 / u128::from(total),
+        };
+        if !self.enabled || self.shown == Some(percent) {
+            return;
+        }
+        self.shown = Some(percent);
+        let filled = BAR_WIDTH * percent as usize / 100;
+        eprint!(
+            "\r{} [{}{}] {:>3}% of {}",
+            self.name,
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled),
+            percent,
+            transfer::format_size(self.total)
+        );
+    }
+
+    fn finish(self) {
+        if self.shown.is_some() {
+            eprintln!();
+        }
+    }
+}
@@ -0,0 +1,988 @@
+//! The Sandesh wire format. Every byte that goes over UDP or TCP is laid out
+//! by the types and constants here, and `describe` renders them as a
+//! machine-readable spec for other implementations.
+
+use byteorder::{BigEndian, ByteOrder};
+use ed25519_dalek::{Signature, VerifyingKey};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+/// Bumped on any incompatible change to a layout below.
+pub const PROTOCOL_VERSION: u8 = 27;
+
+// --- Discovery (UDP) ---
+
+pub const BROADCAST_ADDR: &str = "255.255.255.255";
+/// Administratively scoped group beacons go to with `--discovery
+/// multicast`, on the same ports as broadcasts.
+pub const MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 42, 98);
+/// Link-local group IPv6 beacons go to with `--ipv6`, on the same ports,
+/// in place of broadcast, which IPv6 doesn't have.
+pub const MULTICAST_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x7361, 0x6e64);
+pub const ANNOUNCE_MAGIC: &[u8] = b"HELLO_P2P";
+
+/// UDP port beacons are sent to and heard on. Instances on one host share
+/// it; one that can't bind it takes the next free fallback port instead,
+/// and beacons go to all of them so it is still heard.
+pub const DISCOVERY_PORT: u16 = 3001;
+pub const DISCOVERY_FALLBACK_PORTS: u16 = 3;
+
+pub fn discovery_ports() -> impl Iterator<Item = u16> {
+    DISCOVERY_PORT..=DISCOVERY_PORT + DISCOVERY_FALLBACK_PORTS
+}
+
+/// Capabilities a peer advertises in its discovery beacon, and again in
+/// `MSG_FEATURES` at the start of a chat. The beacon's are only a hint for
+/// the peer list; a chat session goes by the ones the peer sent it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Features(u8);
+
+impl Features {
+    pub const FILE_TRANSFER: Features = Features(1 << 0);
+    pub const GROUPS: Features = Features(1 << 1);
+    pub const POST_QUANTUM: Features = Features(1 << 2);
+    /// Deletes messages when the other side asks; see `MSG_EXPIRE`.
+    pub const DISAPPEARING: Features = Features(1 << 3);
+
+    /// What this build supports.
+    pub const LOCAL: Features =
+        Features(Features::FILE_TRANSFER.0 | Features::GROUPS.0 | Features::DISAPPEARING.0);
+
+    const NAMED: [(Features, char, &'static str); 4] = [
+        (Features::FILE_TRANSFER, 'F', "file_transfer"),
+        (Features::GROUPS, 'G', "groups"),
+        (Features::POST_QUANTUM, 'Q', "post_quantum"),
+        (Features::DISAPPEARING, 'D', "disappearing"),
+    ];
+
+    /// Unknown bits are kept as-is so newer peers survive a round trip.
+    pub fn from_bits(bits: u8) -> Self {
+        Features(bits)
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Short form for the peer list, e.g. "FQ". Empty when nothing known is set.
+    pub fn letters(self) -> String {
+        Features::NAMED
+            .iter()
+            .filter(|(feature, _, _)| self.contains(*feature))
+            .map(|(_, letter, _)| *letter)
+            .collect()
+    }
+}
+
+/// Discovery beacon: `ANNOUNCE_MAGIC`, one feature byte, the `u16` BE TCP
+/// port the sender accepts chats on, a `u64` BE instance id that tells
+/// apart instances sharing an IP, a nickname as one length byte and that
+/// many bytes of UTF-8 (length 0 for none), a `PeerStatus` byte, and the
+/// signer: a `u64` BE
+/// Unix timestamp in seconds, the sender's Ed25519 identity key and its
+/// signature over every byte before the signature. Older builds stop
+/// after the magic, the feature byte or the nickname; missing fields
+/// decode as `None`, and so does a nickname that fails `valid_nickname`
+/// and a signer whose signature doesn't verify. Bytes past the last field
+/// are ignored so the beacon can grow.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Announce {
+    pub features: Features,
+    pub tcp_port: Option<u16>,
+    pub instance: Option<u64>,
+    pub nickname: Option<String>,
+    /// `None` from builds that don't send one, or for a value this build
+    /// doesn't know.
+    pub status: Option<PeerStatus>,
+    pub signer: Option<Signer>,
+}
+
+/// Whether a peer can take a chat right now, as its beacons say.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerStatus {
+    Available = 0,
+    /// In a chat; a request waits on hold until it ends.
+    Busy = 1,
+}
+
+impl PeerStatus {
+    pub fn from_byte(byte: u8) -> Option<PeerStatus> {
+        match byte {
+            0 => Some(PeerStatus::Available),
+            1 => Some(PeerStatus::Busy),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PeerStatus::Available => "available",
+            PeerStatus::Busy => "busy",
+        }
+    }
+}
+
+/// Who sent a beacon and when, as vouched for by its signature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Signer {
+    pub identity: VerifyingKey,
+    pub timestamp: u64,
+}
+
+/// How far a beacon's timestamp may be from the receiver's clock, in
+/// seconds, before it is dropped as a replay.
+pub const ANNOUNCE_MAX_SKEW: u64 = 30;
+
+const SIGNATURE_LEN: usize = 64;
+
+pub const MAX_NICKNAME_LEN: usize = 32;
+
+/// Nicknames are printed straight to the terminal, so control characters
+/// (escape sequences included) are never accepted.
+pub fn valid_nickname(name: &str) -> bool {
+    !name.trim().is_empty() && name.len() <= MAX_NICKNAME_LEN && !name.chars().any(char::is_control)
+}
+
+/// Body of `MSG_NICKNAME`: the name as one length byte and UTF-8, as in
+/// beacons, or a lone zero byte for none.
+pub fn encode_nickname(name: Option<&str>) -> Vec<u8> {
+    let name = name.filter(|n| valid_nickname(n)).unwrap_or("");
+    let mut body = vec![name.len() as u8];
+    body.extend_from_slice(name.as_bytes());
+    body
+}
+
+/// `None` for no nickname, and for one that fails `valid_nickname`.
+pub fn decode_nickname(body: &[u8]) -> Option<String> {
+    let (&len, name) = body.split_first()?;
+    let name = std::str::from_utf8(name.get(..len as usize)?).ok()?;
+    valid_nickname(name).then(|| name.to_string())
+}
+
+impl Announce {
+    /// Later fields are only written after the ones before them, as the
+    /// layout is positional. `sign` is given the bytes to sign with the
+    /// key in `signer`, and is only called if there is one.
+    pub fn encode(&self, sign: impl FnOnce(&[u8]) -> Signature) -> Vec<u8> {
+        let mut payload = ANNOUNCE_MAGIC.to_vec();
+        payload.push(self.features.bits());
+        if let Some(port) = self.tcp_port {
+            payload.extend_from_slice(&port.to_be_bytes());
+            if let Some(instance) = self.instance {
+                payload.extend_from_slice(&instance.to_be_bytes());
+                let name = self.nickname.as_deref().filter(|n| valid_nickname(n));
+                if name.is_some() || self.status.is_some() || self.signer.is_some() {
+                    let name = name.unwrap_or_default();
+                    payload.push(name.len() as u8);
+                    payload.extend_from_slice(name.as_bytes());
+                    payload.push(self.status.unwrap_or(PeerStatus::Available) as u8);
+                }
+                if let Some(signer) = &self.signer {
+                    payload.extend_from_slice(&signer.timestamp.to_be_bytes());
+                    payload.extend_from_slice(signer.identity.as_bytes());
+                    let signature = sign(&payload);
+                    payload.extend_from_slice(&signature.to_bytes());
+                }
+            }
+        }
+        payload
+    }
+
+    pub fn decode(payload: &[u8]) -> Option<Announce> {
+        let rest = payload.strip_prefix(ANNOUNCE_MAGIC)?;
+        let nickname = rest.get(11).and_then(|&len| {
+            let name = std::str::from_utf8(rest.get(12..12 + len as usize)?).ok()?;
+            valid_nickname(name).then(|| name.to_string())
+        });
+        let status_at = rest.get(11).map(|&len| 12 + len as usize);
+        let status = status_at
+            .and_then(|at| rest.get(at))
+            .and_then(|&byte| PeerStatus::from_byte(byte));
+        let signer = status_at.and_then(|at| decode_signer(payload, ANNOUNCE_MAGIC.len() + at + 1));
+        Some(Announce {
+            features: Features::from_bits(rest.first().copied().unwrap_or(0)),
+            tcp_port: rest.get(1..3).map(BigEndian::read_u16),
+            instance: rest.get(3..11).map(BigEndian::read_u64),
+            nickname,
+            status,
+            signer,
+        })
+    }
+}
+
+/// The signer fields starting at `start`, if they are all there and the
+/// signature over everything before it checks out.
+fn decode_signer(payload: &[u8], start: usize) -> Option<Signer> {
+    let signature_at = start + 8 + IDENTITY_KEY_LEN;
+    let signature = payload.get(signature_at..signature_at + SIGNATURE_LEN)?;
+    let signature = Signature::from_bytes(signature.try_into().ok()?);
+    let identity =
+        VerifyingKey::from_bytes(payload[start + 8..signature_at].try_into().ok()?).ok()?;
+    identity
+        .verify_strict(&payload[..signature_at], &signature)
+        .ok()?;
+    Some(Signer {
+        identity,
+        timestamp: BigEndian::read_u64(&payload[start..start + 8]),
+    })
+}
+
+/// Asks a peer added by address for its beacon, which it sends back to
+/// where the probe came from. Zero-padded to `PROBE_LEN`, more than any
+/// beacon takes, so answering can't be used to amplify traffic.
+pub const PROBE_MAGIC: &[u8] = b"SANDESH_PROBE";
+pub const PROBE_LEN: usize = 256;
+
+pub fn encode_probe() -> Vec<u8> {
+    let mut probe = PROBE_MAGIC.to_vec();
+    probe.resize(PROBE_LEN, 0);
+    probe
+}
+
+/// Shorter datagrams aren't answered, whatever they start with.
+pub fn is_probe(datagram: &[u8]) -> bool {
+    datagram.len() >= PROBE_LEN && datagram.starts_with(PROBE_MAGIC)
+}
+
+// --- Connection request (TCP) ---
+
+/// Single byte the acceptor sends after the user answers the prompt.
+pub const SIGNAL_ACCEPT: u8 = b'Y';
+pub const SIGNAL_REJECT: u8 = b'N';
+/// Sent ahead of the answer when the request arrived while a chat was in
+/// front: it waits in the queue, and the answer follows once the user is
+/// back at the prompt.
+pub const SIGNAL_HOLD: u8 = b'W';
+/// Sent by the connecting side straight after connecting, instead of
+/// waiting for an answer, to deliver one shout rather than open a chat.
+/// The acceptor answers at once without asking its user; after
+/// `SIGNAL_ACCEPT` come the handshake and a single `MSG_SHOUT` frame, and
+/// the acceptor closes the connection once it has read it.
+pub const SIGNAL_SHOUT: u8 = b'S';
+/// How long the acceptor waits for `SIGNAL_SHOUT` before taking a new
+/// connection for a chat request.
+pub const SHOUT_WAIT: Duration = Duration::from_millis(200);
+
+/// How long the connecting side waits for that byte. The acceptor's prompt
+/// rejects on its own before then (see `chat::set_request_timeout`).
+pub const ANSWER_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long it waits for the answer instead once put on hold.
+pub const HOLD_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// --- Handshake (TCP) ---
+
+/// Both sides first send `HANDSHAKE_MAGIC` and `PROTOCOL_VERSION` as a
+/// single byte and check the peer's, so other programs and builds with a
+/// different wire format are turned away before any keys are exchanged.
+/// Next the side that connected offers its cipher suites and the other
+/// picks one (see `CipherSuite`). Then they run that suite's Noise
+/// pattern, the side that connected as initiator, with the magic, version,
+/// offer and pick as the prologue. Each handshake message goes out with
+/// the same `u32` length prefix as a chat frame.
+pub const HANDSHAKE_MAGIC: &[u8] = b"SNDSH";
+
+/// AEADs the handshake and chat frames can run with. Each id is a single
+/// bit: the initiator sends one byte with the bits of every suite it
+/// accepts, and the responder answers with the id of the first suite in
+/// `ALL` that both accept, or `SUITE_NONE` before hanging up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CipherSuite {
+    ChaCha20Poly1305 = 1 << 0,
+    Aes256Gcm = 1 << 1,
+}
+
+pub const SUITE_NONE: u8 = 0;
+
+impl CipherSuite {
+    /// In order of preference.
+    pub const ALL: [CipherSuite; 2] = [CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm];
+
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<CipherSuite> {
+        CipherSuite::ALL.into_iter().find(|suite| suite.id() == id)
+    }
+
+    /// The preferred suite among those set in both bitmasks.
+    pub fn pick(offered: u8, accepted: u8) -> Option<CipherSuite> {
+        CipherSuite::ALL
+            .into_iter()
+            .find(|suite| offered & accepted & suite.id() != 0)
+    }
+
+    /// XX sends both static keys encrypted and proves possession of each.
+    /// A side's static key is the X25519 form of its Ed25519 identity key,
+    /// and the payload of the second (responder) and third (initiator)
+    /// messages is the Ed25519 key itself, which has to convert to the
+    /// static key the handshake authenticated. The first message has no
+    /// payload.
+    pub fn noise_params(self) -> &'static str {
+        match self {
+            CipherSuite::ChaCha20Poly1305 => "Noise_XXpsk3_25519_ChaChaPoly_SHA256",
+            CipherSuite::Aes256Gcm => "Noise_XXpsk3_25519_AESGCM_SHA256",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CipherSuite::ChaCha20Poly1305 => "ChaCha20-Poly1305",
+            CipherSuite::Aes256Gcm => "AES-256-GCM",
+        }
+    }
+}
+pub const IDENTITY_KEY_LEN: usize = 32;
+
+/// The Noise pre-shared key is `SHA-256(PASSPHRASE_LABEL || passphrase)`,
+/// or of the label alone when there is no passphrase; the passphrase itself
+/// is never sent.
+pub const PASSPHRASE_LABEL: &[u8] = b"sandesh-v1 passphrase";
+
+/// Key confirmation, the last handshake step: each side's first transport
+/// message is a frame of type `MSG_CONFIRM` with `CONFIRM_LABEL` as its
+/// body, and each checks the peer's. The initiator has no other proof that
+/// the responder mixed in the same pre-shared key, i.e. the same
+/// passphrase.
+pub const CONFIRM_LABEL: &[u8] = b"sandesh-v1 key confirmation";
+
+/// Label hashed, together with the Noise handshake hash, into the short
+/// authentication string users compare.
+pub const SAS_LABEL: &[u8] = b"sandesh-v1 sas";
+
+// --- Encrypted frames (TCP) ---
+
+pub const FRAME_LEN_PREFIX: usize = 4;
+/// Poly1305 tag appended to every ciphertext.
+pub const TAG_LEN: usize = 16;
+/// Largest frame body accepted: Noise's maximum message length. This also
+/// bounds what a peer can make us allocate before anything is checked.
+pub const MAX_FRAME_LEN: usize = 65535;
+/// Type byte and `u16` big-endian body length ahead of every body.
+pub const PLAINTEXT_HEADER_LEN: usize = 3;
+/// Longest message text that still fits in a frame.
+pub const MAX_MESSAGE_LEN: usize = MAX_FRAME_LEN - TAG_LEN - PLAINTEXT_HEADER_LEN;
+/// Set in the type byte when the body is raw DEFLATE (RFC 1951) of the
+/// real one, which inflates to at most `MAX_MESSAGE_LEN` bytes. Senders
+/// only compress when it makes the body shorter; never on the handshake
+/// frames.
+pub const FLAG_COMPRESSED: u8 = 0x80;
+/// With padding on, plaintexts are zero-filled up to a multiple of this, or
+/// to the largest that fits in a frame, so the frame length only gives away
+/// a message's size to the nearest bucket.
+pub const PAD_BUCKET: usize = 64;
+
+/// First byte of every frame's plaintext, saying what the rest is.
+pub const MSG_CHAT: u8 = 0;
+/// Rekeying: the initiator sends `MSG_REKEY_INIT` with a fresh X25519
+/// public key, the responder answers `MSG_REKEY_ACK` with its own and
+/// switches its sending key, and the initiator switches its receiving key,
+/// sends an empty `MSG_REKEY_DONE` and switches its sending key. Nonce
+/// counters carry on across the switch. If both sides start at once, the
+/// side that was the Noise initiator ignores the peer's INIT.
+pub const MSG_REKEY_INIT: u8 = 1;
+pub const MSG_REKEY_ACK: u8 = 2;
+pub const MSG_REKEY_DONE: u8 = 3;
+/// Only valid as the first frame in each direction; see `CONFIRM_LABEL`.
+pub const MSG_CONFIRM: u8 = 4;
+/// File transfer; see `FileMessage`.
+pub const MSG_FILE_OFFER: u8 = 5;
+pub const MSG_FILE_ACCEPT: u8 = 6;
+pub const MSG_FILE_CHUNK: u8 = 7;
+pub const MSG_FILE_REFUSE: u8 = 8;
+pub const MSG_FILE_ABORT: u8 = 9;
+/// Only valid as the second frame in each direction, right after
+/// `MSG_CONFIRM`; see `encode_nickname`.
+pub const MSG_NICKNAME: u8 = 10;
+/// Group rooms; see `RoomMessage`.
+pub const MSG_ROOM_RELAY: u8 = 11;
+pub const MSG_ROOM_NOTICE: u8 = 12;
+pub const MSG_ROOM_MEMBERS: u8 = 13;
+/// Empty; see `HEARTBEAT_INTERVAL`.
+pub const MSG_PING: u8 = 14;
+/// UTF-8 text; only valid as the first frame after `SIGNAL_SHOUT`.
+pub const MSG_SHOUT: u8 = 15;
+/// Types from here up to `FLAG_COMPRESSED` are hints: a receiver that
+/// doesn't know one skips it, so new hints don't need a version bump.
+pub const MSG_HINT_MIN: u8 = 0x40;
+/// One byte, 1 while the sender is typing a message and 0 once it
+/// stopped. Set when the input line fills, resent every `TYPING_REFRESH`
+/// while typing goes on, and cleared after `TYPING_IDLE` without a key
+/// press; at most one frame per `TYPING_MIN_GAP`. The receiver forgets
+/// it after `TYPING_TIMEOUT`, or when the message arrives.
+pub const MSG_TYPING: u8 = MSG_HINT_MIN;
+pub const TYPING_IDLE: Duration = Duration::from_secs(3);
+pub const TYPING_MIN_GAP: Duration = Duration::from_secs(2);
+pub const TYPING_REFRESH: Duration = Duration::from_secs(5);
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(8);
+/// u64 count of `MSG_CHAT` frames received so far, sent back after each
+/// batch of them. Like nonces, message ids aren't sent: the n-th chat
+/// frame in a direction has id n - 1, so this confirms every id below it.
+/// A hint, but since chats now expect it, it came with a version bump.
+pub const MSG_ACK: u8 = MSG_HINT_MIN + 1;
+/// How long a sent message may go unconfirmed before the chat window
+/// flags it.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+/// u64 count of chat frames the user has had on screen, counted like
+/// `MSG_ACK`. Sent only while the window has focus and shows the latest
+/// line, and not at all with `/receipts off`, so a peer can't tell a
+/// missing receipt from one withheld.
+pub const MSG_READ: u8 = MSG_HINT_MIN + 2;
+/// Empty. Sent by the side that closes a chat just before it hangs up, so
+/// the other side can tell the peer left from a dropped connection.
+/// Nothing follows it in either direction.
+pub const MSG_BYE: u8 = MSG_HINT_MIN + 3;
+/// Disappearing messages: a `u32` BE time to live in seconds, 0 for off,
+/// then `EXPIRE_PROPOSE` or `EXPIRE_AGREE`. A side that changes its TTL
+/// proposes it; the peer takes it for both directions and agrees, so the
+/// proposer can tell a peer that deletes from one that ignores the hint.
+/// An agreement is never answered. Only proposed to a peer whose
+/// `MSG_FEATURES` has `Features::DISAPPEARING`, or that sent none; the
+/// proposer stops waiting for an agreement after `ACK_TIMEOUT`.
+pub const MSG_EXPIRE: u8 = MSG_HINT_MIN + 4;
+pub const EXPIRE_PROPOSE: u8 = 0;
+pub const EXPIRE_AGREE: u8 = 1;
+/// A line of the shared notes pad; see `PadEdit`. A build that doesn't
+/// know it keeps no pad, so the sender's pad stays its own.
+pub const MSG_PAD: u8 = MSG_HINT_MIN + 5;
+/// Longest pad line, in bytes.
+pub const MAX_PAD_LINE_LEN: usize = 1024;
+/// Lines a pad holds, removed ones included; lines added past this are
+/// dropped on both sides.
+pub const MAX_PAD_LINES: usize = 256;
+/// One byte, the sender's `Features`. Both sides send it as their first
+/// frame after the handshake; a build that predates it sends none.
+pub const MSG_FEATURES: u8 = MSG_HINT_MIN + 6;
+/// Chat windows send `MSG_PING` this often. Once a peer has pinged, a
+/// silence of `HEARTBEAT_TIMEOUT` with no frame of any kind means it is
+/// gone, even if TCP never said so. Peers that never ping, such as
+/// library sessions, aren't timed out.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(20);
+/// Rekeyed keys are HKDF-SHA256 over the X25519 output with salt
+/// `KDF_SALT` and info `REKEY_INFO` followed by both rekey keys, lowest
+/// first. The first `SESSION_KEY_LEN` bytes key the frames sent by the side
+/// with the lower rekey key, the rest the other direction.
+pub const REKEY_INFO: &[u8] = b"sandesh-v1 rekey";
+pub const KDF_SALT: &[u8] = b"sandesh-v1 handshake";
+pub const REKEY_KEY_LEN: usize = 32;
+pub const SESSION_KEY_LEN: usize = 32;
+
+/// A chat frame: `u32` big-endian length of the ciphertext, then a Noise
+/// transport message. Noise seals it with the negotiated `CipherSuite`
+/// under a nonce counted per direction and never sent, so a replayed, dropped or
+/// reordered frame fails to authenticate. The plaintext is a message type
+/// byte (`MSG_*`), the body length as `u16` big-endian, the UTF-8 text or
+/// control payload, and any padding (see `PAD_BUCKET`), which the receiver
+/// drops. The body may be compressed; see `FLAG_COMPRESSED`.
+pub struct Frame {
+    pub ciphertext: Vec<u8>,
+}
+
+impl Frame {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = Frame::length_prefix(self.ciphertext.len()).to_vec();
+        frame.extend_from_slice(&self.ciphertext);
+        frame
+    }
+
+    /// The prefix for a body of `body_len` bytes. Noise transport messages
+    /// have no associated data, but a prefix that was altered moves the end
+    /// of the message, so the tag no longer matches either way.
+    pub fn length_prefix(body_len: usize) -> [u8; FRAME_LEN_PREFIX] {
+        let mut prefix = [0u8; FRAME_LEN_PREFIX];
+        BigEndian::write_u32(&mut prefix, body_len as u32);
+        prefix
+    }
+
+    /// Reads the body length out of a length prefix, rejecting lengths too
+    /// short to hold a tag, or over `MAX_FRAME_LEN`.
+    pub fn body_len(prefix: [u8; FRAME_LEN_PREFIX]) -> io::Result<usize> {
+        let len = BigEndian::read_u32(&prefix) as usize;
+        if len < TAG_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Message too short",
+            ));
+        }
+        if len > MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Message too long",
+            ));
+        }
+        Ok(len)
+    }
+}
+
+pub const MAX_FILE_NAME_LEN: usize = 255;
+/// File data per `MSG_FILE_CHUNK`, well under `MAX_MESSAGE_LEN`.
+pub const FILE_CHUNK_LEN: usize = 16 * 1024;
+/// SHA-256 of the whole file, sent with the offer.
+pub const FILE_DIGEST_LEN: usize = 32;
+
+/// File transfer frames. Each side can send one file and receive one at a
+/// time, independently of each other. The sender offers the file's size,
+/// SHA-256 and name, and sends nothing more until the receiver accepts,
+/// naming the offset to start from so an interrupted download can be
+/// resumed. The data then follows in order, each chunk tagged with its
+/// offset, and the transfer is complete once `size` bytes have arrived and
+/// hash to the offered digest. Either side can stop it: the receiver with
+/// `Refuse` (which also declines an offer), the sender with `Abort`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileMessage {
+    /// `u64` BE size, the SHA-256 of the file, then the UTF-8 file name,
+    /// at most `MAX_FILE_NAME_LEN` bytes and without directories.
+    Offer {
+        size: u64,
+        sha256: [u8; FILE_DIGEST_LEN],
+        name: String,
+    },
+    /// `u64` BE offset the sender starts from: 0, or where the receiver's
+    /// interrupted download of the same file ended.
+    Accept {
+        offset: u64,
+    },
+    /// `u64` BE offset of the data, then the data.
+    Chunk {
+        offset: u64,
+        data: Vec<u8>,
+    },
+    Refuse,
+    Abort,
+}
+
+impl FileMessage {
+    pub fn kind(&self) -> u8 {
+        match self {
+            FileMessage::Offer { .. } => MSG_FILE_OFFER,
+            FileMessage::Accept { .. } => MSG_FILE_ACCEPT,
+            FileMessage::Chunk { .. } => MSG_FILE_CHUNK,
+            FileMessage::Refuse => MSG_FILE_REFUSE,
+            FileMessage::Abort => MSG_FILE_ABORT,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            FileMessage::Offer { size, sha256, name } => {
+                [&size.to_be_bytes(), sha256.as_slice(), name.as_bytes()].concat()
+            }
+            FileMessage::Accept { offset } => offset.to_be_bytes().to_vec(),
+            FileMessage::Chunk { offset, data } => {
+                [&offset.to_be_bytes(), data.as_slice()].concat()
+            }
+            FileMessage::Refuse | FileMessage::Abort => Vec::new(),
+        }
+    }
+
+    /// `None` for a kind that isn't a file frame or a body that doesn't fit
+    /// its layout.
+    pub fn decode(kind: u8, body: &[u8]) -> Option<FileMessage> {
+        match kind {
+            MSG_FILE_OFFER => {
+                let name = std::str::from_utf8(body.get(8 + FILE_DIGEST_LEN..)?).ok()?;
+                if name.is_empty() || name.len() > MAX_FILE_NAME_LEN {
+                    return None;
+                }
+                Some(FileMessage::Offer {
+                    size: BigEndian::read_u64(&body[..8]),
+                    sha256: body[8..8 + FILE_DIGEST_LEN].try_into().ok()?,
+                    name: name.to_string(),
+                })
+            }
+            MSG_FILE_CHUNK => Some(FileMessage::Chunk {
+                offset: BigEndian::read_u64(body.get(..8)?),
+                data: body[8..].to_vec(),
+            }),
+            MSG_FILE_ACCEPT => Some(FileMessage::Accept {
+                offset: BigEndian::read_u64(body.get(..8)?),
+            }),
+            MSG_FILE_REFUSE => Some(FileMessage::Refuse),
+            MSG_FILE_ABORT => Some(FileMessage::Abort),
+            _ => None,
+        }
+    }
+}
+
+/// Longest member name in a room frame. Hosts tell members with the same
+/// nickname apart by adding their address, so this is more than
+/// `MAX_NICKNAME_LEN`.
+pub const MAX_MEMBER_NAME_LEN: usize = 255;
+
+/// Frames the host of a room sends its members. Each member holds an
+/// ordinary chat with the host, who sends its own messages as `MSG_CHAT`
+/// and passes every member's on to the others as `Relay`. Members send
+/// only chat frames; room frames from them are ignored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RoomMessage {
+    /// `u8` length and the sender's name, then the UTF-8 text.
+    Relay { from: String, text: String },
+    /// UTF-8 text from the host itself, such as who joined or left.
+    Notice(String),
+    /// The room name, then each member's name, one per line. Sent on
+    /// joining and whenever someone joins or leaves.
+    Members { room: String, names: Vec<String> },
+}
+
+impl RoomMessage {
+    pub fn kind(&self) -> u8 {
+        match self {
+            RoomMessage::Relay { .. } => MSG_ROOM_RELAY,
+            RoomMessage::Notice(_) => MSG_ROOM_NOTICE,
+            RoomMessage::Members { .. } => MSG_ROOM_MEMBERS,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            RoomMessage::Relay { from, text } => {
+                [&[from.len() as u8], from.as_bytes(), text.as_bytes()].concat()
+            }
+            RoomMessage::Notice(text) => text.as_bytes().to_vec(),
+            RoomMessage::Members { room, names } => {
+                let mut lines = vec![room.as_str()];
+                lines.extend(names.iter().map(String::as_str));
+                lines.join("\n").into_bytes()
+            }
+        }
+    }
+
+    /// `None` for a kind that isn't a room frame, a body that doesn't fit
+    /// its layout, or names that aren't fit to print.
+    pub fn decode(kind: u8, body: &[u8]) -> Option<RoomMessage> {
+        let name = |bytes: &[u8]| {
+            let name = std::str::from_utf8(bytes).ok()?;
+            valid_member_name(name).then(|| name.to_string())
+        };
+        match kind {
+            MSG_ROOM_RELAY => {
+                let len = *body.first()? as usize;
+                Some(RoomMessage::Relay {
+                    from: name(body.get(1..1 + len)?)?,
+                    text: String::from_utf8(body[1 + len..].to_vec()).ok()?,
+                })
+            }
+            MSG_ROOM_NOTICE => Some(RoomMessage::Notice(String::from_utf8(body.to_vec()).ok()?)),
+            MSG_ROOM_MEMBERS => {
+                let mut lines = body.split(|b| *b == b'\n');
+                Some(RoomMessage::Members {
+                    room: name(lines.next()?)?,
+                    names: lines.map(name).collect::<Option<_>>()?,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Orders pad lines and edits the same way on both sides: a Lamport clock,
+/// ties broken by the side that made it, 0 for the Noise initiator and 1
+/// for the responder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PadStamp {
+    pub clock: u64,
+    pub side: u8,
+}
+
+/// A pad line as the sender now has it: `u64` BE clock and `u8` side of
+/// `line`, the same of `version`, then the UTF-8 text. `line` names the
+/// line by when it was added, and puts it in order; `version` says when
+/// this text was written, and the latest version of a line wins. Empty
+/// text removes the line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PadEdit {
+    pub line: PadStamp,
+    pub version: PadStamp,
+    pub text: String,
+}
+
+impl PadEdit {
+    const HEADER_LEN: usize = 2 * 9;
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(Self::HEADER_LEN + self.text.len());
+        for stamp in [self.line, self.version] {
+            body.extend_from_slice(&stamp.clock.to_be_bytes());
+            body.push(stamp.side);
+        }
+        body.extend_from_slice(self.text.as_bytes());
+        body
+    }
+
+    /// `None` for a body that doesn't fit the layout, or text that isn't a
+    /// `valid_pad_line`.
+    pub fn decode(body: &[u8]) -> Option<PadEdit> {
+        let stamp = |bytes: &[u8]| PadStamp {
+            clock: BigEndian::read_u64(&bytes[..8]),
+            side: bytes[8],
+        };
+        let header = body.get(..Self::HEADER_LEN)?;
+        let text = std::str::from_utf8(&body[Self::HEADER_LEN..]).ok()?;
+        (text.is_empty() || valid_pad_line(text)).then(|| PadEdit {
+            line: stamp(&header[..9]),
+            version: stamp(&header[9..]),
+            text: text.to_string(),
+        })
+    }
+}
+
+/// A single printable line of at most `MAX_PAD_LINE_LEN` bytes.
+pub fn valid_pad_line(text: &str) -> bool {
+    !text.trim().is_empty() && text.len() <= MAX_PAD_LINE_LEN && !text.chars().any(char::is_control)
+}
+
+/// Like `valid_nickname`, up to `MAX_MEMBER_NAME_LEN` bytes.
+pub fn valid_member_name(name: &str) -> bool {
+    !name.trim().is_empty()
+        && name.len() <= MAX_MEMBER_NAME_LEN
+        && !name.chars().any(char::is_control)
+}
+
+/// Machine-readable description of the wire format as JSON, generated from
+/// the constants above.
+pub fn describe() -> String {
+    let suites: Vec<String> = CipherSuite::ALL
+        .iter()
+        .map(|suite| {
+            format!(
+                "{{\"id\": {}, \"name\": \"{}\", \"noise_params\": \"{}\"}}",
+                suite.id(),
+                suite.name(),
+                suite.noise_params()
+            )
+        })
+        .collect();
+    let feature_bits: Vec<String> = Features::NAMED
+        .iter()
+        .map(|(feature, letter, name)| {
+            format!(
+                "{{\"name\": \"{}\", \"bit\": {}, \"letter\": \"{}\"}}",
+                name,
+                feature.bits().trailing_zeros(),
+                letter
+            )
+        })
+        .collect();
+
+    format!(
+        r#"{{
+  "protocol": "sandesh",
+  "version": {version},
+  "discovery": {{
+    "transport": "udp",
+    "broadcast_addr": "{broadcast}",
+    "multicast_group": "{multicast}",
+    "multicast_group_v6": "{multicast_v6}",
+    "ports": [{discovery_ports}],
+    "fields": [
+      {{"name": "magic", "size": {magic_len}, "value": "{magic}"}},
+      {{"name": "features", "size": 1, "optional": true}},
+      {{"name": "tcp_port", "size": 2, "encoding": "u16_be", "optional": true}},
+      {{"name": "instance_id", "size": 8, "encoding": "u64_be", "optional": true}},
+      {{"name": "nickname", "size": "1 + n", "encoding": "u8 length || utf8", "max_len": {max_nickname}, "control_chars": false, "empty": "no nickname", "optional": true}},
+      {{"name": "status", "size": 1, "values": {{"0": "available", "1": "busy"}}, "unknown": "no status", "optional": true}},
+      {{"name": "timestamp", "size": 8, "encoding": "u64_be unix seconds", "max_skew": {max_skew}, "optional": true}},
+      {{"name": "identity_key", "size": {identity_len}, "encoding": "ed25519 public key", "optional": true}},
+      {{"name": "signature", "size": {signature_len}, "encoding": "ed25519", "covers": "every byte before it", "optional": true}}
+    ],
+    "unsigned_or_stale": "ignored",
+    "bare_magic": "listed as an unverified legacy peer, never over a signed entry",
+    "feature_bits": [{feature_bits}],
+    "trailing_bytes": "ignored",
+    "probe": {{"magic": "{probe_magic}", "size": {probe_len}, "padding": "zeros", "shorter": "ignored", "answer": "current beacon, unicast to the probe's source address", "answered_when": "the receiver would announce to that address anyway"}}
+  }},
+  "connect": {{
+    "transport": "tcp",
+    "acceptor_sends": {{"size": 1, "accept": {accept}, "reject": {reject}, "hold": {hold}, "after_hold": "accept or reject follows"}},
+    "initiator_waits_secs": {answer_timeout},
+    "initiator_waits_on_hold_secs": {hold_timeout},
+    "shout": {{"initiator_sends": {shout}, "when": "right after connecting", "acceptor_waits_ms": {shout_wait}, "answer": "accept or reject, without a prompt", "then": "handshake, one shout frame, acceptor closes"}}
+  }},
+  "handshake": {{
+    "preamble": [
+      {{"name": "magic", "size": {hs_magic_len}, "direction": "both", "value": "{hs_magic}", "on_mismatch": "close"}},
+      {{"name": "protocol_version", "size": 1, "direction": "both", "value": {version}, "on_mismatch": "close"}}
+    ],
+    "cipher_suite": {{"offer": {{"direction": "initiator", "size": 1, "encoding": "bitmask of accepted suite ids"}}, "pick": {{"direction": "responder", "size": 1, "value": "id of the first suite both accept", "none": {suite_none}, "on_none": "close"}}, "suites": [{suites}]}},
+    "noise": {{"protocol": "noise_params of the picked suite", "initiator": "connecting side", "prologue": "magic || protocol_version || offer || pick", "framing": "u32_be length || noise message", "static_key": "x25519 form of the ed25519 identity key", "psk": {{"position": 3, "value": "sha256(\"{pass_label}\" [|| passphrase])"}}, "payloads": [{{"message": 1, "body": "empty"}}, {{"message": 2, "body": "responder ed25519_identity_key", "size": {identity_len}}}, {{"message": 3, "body": "initiator ed25519_identity_key", "size": {identity_len}}}], "on_identity_mismatch": "close"}},
+    "key_confirmation": {{"direction": "both", "frame": {{"type": {msg_confirm}, "body": "\"{confirm_label}\"", "first": true}}, "on_mismatch": "close"}},
+    "nickname": {{"direction": "both", "frame": {{"type": {msg_nickname}, "body": "u8 length || utf8", "second": true}}, "max_len": {max_nickname}, "empty": "no nickname", "on_invalid": "treated as no nickname"}},
+    "sas": {{"hash": "sha256", "input": "\"{sas_label}\" || noise_handshake_hash", "display": "first 8 bytes as u64_be mod 10^15, three groups of 5 digits"}}
+  }},
+  "frame": {{
+    "fields": [
+      {{"name": "length", "size": {prefix_len}, "encoding": "u32_be", "covers": ["ciphertext"], "min": {tag_len}, "max": {max_frame}}},
+      {{"name": "ciphertext", "size": "length", "aead": "noise transport (picked suite)", "aad": "none", "tag_len": {tag_len}, "plaintext": "type_u8 || u16_be body_len || body || zero padding", "padding": {{"optional": true, "to_multiple_of": {pad_bucket}, "capped_at": "max_frame - tag_len"}}}}
+    ],
+    "compression": {{"flag": {flag_compressed}, "in": "type byte", "body": "raw deflate (rfc 1951)", "max_inflated": {max_message}, "optional": true, "handshake_frames": false}},
+    "hints": {{"types": "{msg_hint_min}..{flag_compressed}", "unknown": "ignored"}},
+    "nonce": {{"transmitted": false, "counter": "noise, per direction, starts at 0 and carries on across rekeys", "on_decrypt_failure": "close"}},
+    "types": [
+      {{"value": {msg_chat}, "name": "chat", "body": "utf8 text"}},
+      {{"value": {msg_init}, "name": "rekey_init", "body": "x25519_public_key", "sent_by": "initiator"}},
+      {{"value": {msg_ack}, "name": "rekey_ack", "body": "x25519_public_key", "sent_by": "responder", "then": "responder switches sending key"}},
+      {{"value": {msg_done}, "name": "rekey_done", "body": "empty", "sent_by": "initiator", "then": "initiator switches sending key"}},
+      {{"value": {msg_confirm}, "name": "confirm", "body": "\"{confirm_label}\"", "sent_by": "both", "only": "first frame in each direction"}},
+      {{"value": {msg_file_offer}, "name": "file_offer", "body": "u64_be size || sha256 of file || utf8 file name", "digest_len": {file_digest_len}, "max_name_len": {max_file_name}, "sent_by": "file sender"}},
+      {{"value": {msg_file_accept}, "name": "file_accept", "body": "u64_be offset to start from", "sent_by": "file receiver", "resume": "offset is where an interrupted download of the same name, size and sha256 from this peer ended, else 0"}},
+      {{"value": {msg_file_chunk}, "name": "file_chunk", "body": "u64_be offset || data", "max_data_len": {file_chunk_len}, "sent_by": "file sender", "after": "file_accept", "order": "ascending offsets, complete at size", "on_digest_mismatch": "discard file"}},
+      {{"value": {msg_file_refuse}, "name": "file_refuse", "body": "empty", "sent_by": "file receiver", "then": "declines the offer or stops the transfer"}},
+      {{"value": {msg_file_abort}, "name": "file_abort", "body": "empty", "sent_by": "file sender", "then": "stops the transfer"}},
+      {{"value": {msg_nickname}, "name": "nickname", "body": "u8 length || utf8", "sent_by": "both", "only": "second frame in each direction"}},
+      {{"value": {msg_room_relay}, "name": "room_relay", "body": "u8 length || sender name || utf8 text", "sent_by": "room host"}},
+      {{"value": {msg_room_notice}, "name": "room_notice", "body": "utf8 text", "sent_by": "room host"}},
+      {{"value": {msg_room_members}, "name": "room_members", "body": "room name, then member names, newline separated", "sent_by": "room host", "when": "on joining and on every join or leave"}},
+      {{"value": {msg_ping}, "name": "ping", "body": "empty", "sent_by": "both", "every_secs": {heartbeat_interval}}},
+      {{"value": {msg_shout}, "name": "shout", "body": "utf8", "sent_by": "initiator", "only": "first frame after a shout handshake"}},
+      {{"value": {msg_typing}, "name": "typing", "body": "u8 1 = typing, 0 = stopped", "sent_by": "both", "hint": true, "idle_secs": {typing_idle}, "min_gap_secs": {typing_gap}, "refresh_secs": {typing_refresh}, "expires_secs": {typing_timeout}}},
+      {{"value": {msg_chat_ack}, "name": "ack", "body": "u64 chat frames received so far", "sent_by": "both", "hint": true, "ids": "implicit, counted from 0 per direction", "flag_after_secs": {ack_timeout}}},
+      {{"value": {msg_read}, "name": "read", "body": "u64 chat frames shown to the user so far", "sent_by": "both", "hint": true, "optional": true}},
+      {{"value": {msg_bye}, "name": "bye", "body": "empty", "sent_by": "the side closing the chat", "hint": true, "then": "both sides stop sending and close"}},
+      {{"value": {msg_expire}, "name": "expire", "body": "u32_be ttl_secs (0 = off) || u8 {expire_propose} = propose, {expire_agree} = agree", "sent_by": "both", "hint": true, "on_propose": "take the ttl for both directions, answer agree", "on_agree": "not answered", "requires_feature": "disappearing", "answer_within_secs": {ack_timeout}}},
+      {{"value": {msg_pad}, "name": "pad", "body": "u64_be line_clock || u8 line_side || u64_be version_clock || u8 version_side || utf8 text", "sent_by": "both", "hint": true, "sides": {{"initiator": 0, "responder": 1}}, "order": "lines by (line_clock, line_side)", "merge": "highest (version_clock, version_side) wins", "clock": "lamport: above every clock sent or received", "empty_text": "removes the line", "max_line_len": {max_pad_line}, "control_chars": false, "max_lines": {max_pad_lines}}},
+      {{"value": {msg_features}, "name": "features", "body": "u8 feature bits", "sent_by": "both", "hint": true, "only": "first frame after the handshake", "optional": true}}
+    ],
+    "heartbeat": {{"interval_secs": {heartbeat_interval}, "timeout_secs": {heartbeat_timeout}, "timeout_applies": "after the peer's first ping", "silence": "no frame of any kind"}},
+    "rooms": {{"members": "each holds an ordinary chat with the host", "host_messages": "chat", "member_messages": "chat, relayed by the host to the others as room_relay", "max_name_len": {max_member_name}, "control_chars": false, "from_members": "room frames ignored"}},
+    "rekey": {{"kdf": "hkdf_sha256", "ikm": "x25519_shared_secret", "salt": "{kdf_salt}", "info": "\"{rekey_info}\" || lower_rekey_key || higher_rekey_key", "size": {okm_len}, "keys": [{{"sent_by": "lower_rekey_key", "bytes": "0..{session_key_len}"}}, {{"sent_by": "higher_rekey_key", "bytes": "{session_key_len}..{okm_len}"}}], "replaces": "noise cipher key for that direction", "simultaneous": "noise initiator ignores the peer's rekey_init"}}
+  }}
+}}"#,
+        version = PROTOCOL_VERSION,
+        broadcast = BROADCAST_ADDR,
+        multicast = MULTICAST_GROUP,
+        multicast_v6 = MULTICAST_GROUP_V6,
+        discovery_ports = discovery_ports()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        magic_len = ANNOUNCE_MAGIC.len(),
+        magic = String::from_utf8_lossy(ANNOUNCE_MAGIC),
+        feature_bits = feature_bits.join(", "),
+        probe_magic = String::from_utf8_lossy(PROBE_MAGIC),
+        probe_len = PROBE_LEN,
+        accept = SIGNAL_ACCEPT,
+        reject = SIGNAL_REJECT,
+        hold = SIGNAL_HOLD,
+        shout = SIGNAL_SHOUT,
+        shout_wait = SHOUT_WAIT.as_millis(),
+        answer_timeout = ANSWER_TIMEOUT.as_secs(),
+        hold_timeout = HOLD_TIMEOUT.as_secs(),
+        hs_magic_len = HANDSHAKE_MAGIC.len(),
+        hs_magic = String::from_utf8_lossy(HANDSHAKE_MAGIC),
+        identity_len = IDENTITY_KEY_LEN,
+        suite_none = SUITE_NONE,
+        suites = suites.join(", "),
+        kdf_salt = String::from_utf8_lossy(KDF_SALT),
+        session_key_len = SESSION_KEY_LEN,
+        okm_len = 2 * SESSION_KEY_LEN,
+        sas_label = String::from_utf8_lossy(SAS_LABEL),
+        pass_label = String::from_utf8_lossy(PASSPHRASE_LABEL),
+        confirm_label = String::from_utf8_lossy(CONFIRM_LABEL),
+        prefix_len = FRAME_LEN_PREFIX,
+        tag_len = TAG_LEN,
+        max_nickname = MAX_NICKNAME_LEN,
+        max_skew = ANNOUNCE_MAX_SKEW,
+        signature_len = SIGNATURE_LEN,
+        msg_chat = MSG_CHAT,
+        flag_compressed = FLAG_COMPRESSED,
+        max_message = MAX_MESSAGE_LEN,
+        msg_init = MSG_REKEY_INIT,
+        msg_ack = MSG_REKEY_ACK,
+        msg_done = MSG_REKEY_DONE,
+        msg_confirm = MSG_CONFIRM,
+        msg_file_offer = MSG_FILE_OFFER,
+        msg_file_accept = MSG_FILE_ACCEPT,
+        msg_file_chunk = MSG_FILE_CHUNK,
+        msg_file_refuse = MSG_FILE_REFUSE,
+        msg_file_abort = MSG_FILE_ABORT,
+        msg_nickname = MSG_NICKNAME,
+        msg_room_relay = MSG_ROOM_RELAY,
+        msg_room_notice = MSG_ROOM_NOTICE,
+        msg_room_members = MSG_ROOM_MEMBERS,
+        msg_ping = MSG_PING,
+        msg_shout = MSG_SHOUT,
+        msg_hint_min = MSG_HINT_MIN,
+        msg_typing = MSG_TYPING,
+        msg_chat_ack = MSG_ACK,
+        msg_read = MSG_READ,
+        msg_bye = MSG_BYE,
+        msg_expire = MSG_EXPIRE,
+        expire_propose = EXPIRE_PROPOSE,
+        expire_agree = EXPIRE_AGREE,
+        msg_pad = MSG_PAD,
+        max_pad_line = MAX_PAD_LINE_LEN,
+        max_pad_lines = MAX_PAD_LINES,
+        msg_features = MSG_FEATURES,
+        ack_timeout = ACK_TIMEOUT.as_secs(),
+        typing_idle = TYPING_IDLE.as_secs(),
+        typing_gap = TYPING_MIN_GAP.as_secs(),
+        typing_refresh = TYPING_REFRESH.as_secs(),
+        typing_timeout = TYPING_TIMEOUT.as_secs(),
+        heartbeat_interval = HEARTBEAT_INTERVAL.as_secs(),
+        heartbeat_timeout = HEARTBEAT_TIMEOUT.as_secs(),
+        max_member_name = MAX_MEMBER_NAME_LEN,
+        max_file_name = MAX_FILE_NAME_LEN,
+        file_chunk_len = FILE_CHUNK_LEN,
+        file_digest_len = FILE_DIGEST_LEN,
+        rekey_info = String::from_utf8_lossy(REKEY_INFO),
+        max_frame = MAX_FRAME_LEN,
+        pad_bucket = PAD_BUCKET,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Other implementations are written against the spec, so any change
+    /// to the wire format has to show up as a change to the checked-in
+    /// copy too. `cargo run -- protocol-spec > tests/protocol-spec.json`
+    /// regenerates it.
+    #[test]
+    fn spec_matches_the_checked_in_copy() {
+        assert_eq!(
+            format!("{}\n", describe()),
+            include_str!("../tests/protocol-spec.json")
+        );
+    }
+
+    #[test]
+    fn features_round_trip_through_their_bits() {
+        for features in [
+            Features::default(),
+            Features::FILE_TRANSFER,
+            Features::LOCAL,
+            Features::from_bits(Features::LOCAL.bits() | Features::POST_QUANTUM.bits()),
+        ] {
+            assert_eq!(Features::from_bits(features.bits()), features);
+        }
+        assert_eq!(Features::LOCAL.letters(), "FGD");
+        assert_eq!(Features::default().letters(), "");
+    }
+
+    #[test]
+    fn unknown_feature_bits_survive_but_are_ignored() {
+        let newer = Features::from_bits(0b1010_0001);
+        assert_eq!(newer.bits(), 0b1010_0001);
+        assert!(newer.contains(Features::FILE_TRANSFER));
+        assert!(!newer.contains(Features::GROUPS));
+        assert_eq!(newer.letters(), "F");
+
+        let announce = Announce {
+            features: newer,
+            tcp_port: None,
+            instance: None,
+            nickname: None,
+            status: None,
+            signer: None,
+        };
+        let decoded = Announce::decode(&announce.encode(|_| unreachable!())).unwrap();
+        assert_eq!(decoded.features.bits(), 0b1010_0001);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+const DEFAULT_REQUEST_LIMIT: usize = 4;
+const DEFAULT_REQUEST_RATE: usize = 10;
+/// The window `request_rate` counts connections over.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Bounds the incoming chat requests waiting for a y/n answer. While a chat
+/// is open they are put on hold until it ends, so without a cap every dial
+/// attempt would sit on an open socket until then.
+pub struct Resources {
+    pending_requests: AtomicUsize,
+    request_limit: AtomicUsize,
+    rejected: AtomicU64,
+    /// Connections per address in the current window, with when it began.
+    recent: Mutex<HashMap<IpAddr, (Instant, usize)>>,
+    request_rate: AtomicUsize,
+    rate_limited: AtomicU64,
+}
+
+impl Default for Resources {
+    fn default() -> Self {
+        Resources::new()
+    }
+}
+
+impl Resources {
+    pub fn new() -> Self {
+        Resources {
+            pending_requests: AtomicUsize::new(0),
+            request_limit: AtomicUsize::new(DEFAULT_REQUEST_LIMIT),
+            rejected: AtomicU64::new(0),
+            recent: Mutex::new(HashMap::new()),
+            request_rate: AtomicUsize::new(DEFAULT_REQUEST_RATE),
+            rate_limited: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a connection from `ip`. Returns false, and counts it as rate
+    /// limited, once the address has made `request_rate` of them within
+    /// `RATE_WINDOW`, so one peer can't keep the prompt asking.
+    pub fn allow_from(&self, ip: IpAddr) -> bool {
+        self.allow_from_at(ip, Instant::now())
+    }
+
+    fn allow_from_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut recent = self.recent.lock().unwrap();
+        recent.retain(|_, (since, _)| now.duration_since(*since) < RATE_WINDOW);
+        let (_, count) = recent.entry(ip).or_insert((now, 0));
+        *count += 1;
+        let allowed = *count <= self.request_rate();
+        if !allowed {
+            self.rate_limited.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    /// Reserves a slot for a new incoming request. Returns false, and counts
+    /// the rejection, when the limit is reached.
+    pub fn try_queue_request(&self) -> bool {
+        let limit = self.request_limit();
+        let queued = self
+            .pending_requests
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < limit).then_some(n + 1)
+            })
+            .is_ok();
+        if !queued {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        queued
+    }
+
+    /// Releases the slot of a request that has been taken off the queue.
+    pub fn request_taken(&self) {
+        let _ = self
+            .pending_requests
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+    }
+
+    pub fn pending_requests(&self) -> usize {
+        self.pending_requests.load(Ordering::SeqCst)
+    }
+
+    pub fn request_limit(&self) -> usize {
+        self.request_limit.load(Ordering::Relaxed)
+    }
+
+    pub fn set_request_limit(&self, limit: usize) {
+        self.request_limit.store(limit, Ordering::Relaxed);
+    }
+
+    /// Requests turned away because the limit was reached, since startup.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Connections allowed per address per minute.
+    pub fn request_rate(&self) -> usize {
+        self.request_rate.load(Ordering::Relaxed)
+    }
+
+    pub fn set_request_rate(&self, rate: usize) {
+        self.request_rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// Connections turned away by `allow_from`, since startup.
+    pub fn rate_limited(&self) -> u64 {
+        self.rate_limited.load(Ordering::Relaxed)
+    }
+}
+
+/// Threads in this process, where the platform reports it.
+pub fn thread_count() -> Option<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix("Threads:"))
+            .and_then(|n| n.trim().parse().ok())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_burst_past_the_rate_is_dropped() {
+        let resources = Resources::new();
+        resources.set_request_rate(3);
+        let ip: IpAddr = "192.168.1.5".parse().unwrap();
+        let other: IpAddr = "192.168.1.6".parse().unwrap();
+        let start = Instant::now();
+        let burst: Vec<bool> = (0..5).map(|_| resources.allow_from_at(ip, start)).collect();
+        assert_eq!(burst, [true, true, true, false, false]);
+        assert_eq!(resources.rate_limited(), 2);
+        assert!(resources.allow_from_at(other, start));
+
+        let later = start + RATE_WINDOW;
+        assert!(resources.allow_from_at(ip, later));
+        assert_eq!(resources.rate_limited(), 2);
+    }
+
+    #[test]
+    fn requests_past_the_limit_are_rejected() {
+        let resources = Resources::new();
+        resources.set_request_limit(2);
+        assert!(resources.try_queue_request());
+        assert!(resources.try_queue_request());
+        assert!(!resources.try_queue_request());
+        assert_eq!(resources.rejected(), 1);
+        resources.request_taken();
+        assert!(resources.try_queue_request());
+        assert_eq!(resources.pending_requests(), 2);
+    }
+}
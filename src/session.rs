@@ -0,0 +1,365 @@
+use crate::crypto::{self, CipherState, CryptoError, Handshake, Incoming, Role};
+use crate::identity;
+use crate::network;
+use crate::protocol::{
+    ACK_TIMEOUT, ANSWER_TIMEOUT, CipherSuite, FileMessage, HOLD_TIMEOUT, SIGNAL_ACCEPT,
+    SIGNAL_HOLD, SIGNAL_REJECT,
+};
+use crate::transfer::{Collision, Receiving, Sending};
+use ed25519_dalek::VerifyingKey;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// An open, encrypted chat with one peer: the TCP stream plus the Noise
+/// transport and its counters. Rekeys happen on their own as messages
+/// are sent and received, and every message received is acknowledged so
+/// the peer's chat window can mark it delivered. File offers from the
+/// peer are declined unless `receive_file` is waiting for one.
+///
+/// `identity::init` has to have been called before one is opened.
+pub struct Session {
+    stream: TcpStream,
+    cipher: CipherState,
+    peer_identity: VerifyingKey,
+    sas: String,
+    suite: CipherSuite,
+    peer_nickname: Option<String>,
+    /// Chat frames received, sent back as acks; see `protocol::MSG_ACK`.
+    received: u64,
+}
+
+/// Dials a peer, waits for its user to accept and runs the handshake as
+/// the initiator. With a `passphrase`, the peer must have accepted with
+/// the same one. If the peer is in another chat, this waits up to
+/// `protocol::HOLD_TIMEOUT` for it to end.
+pub fn connect(addr: impl ToSocketAddrs, passphrase: Option<&str>) -> Result<Session, CryptoError> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(ANSWER_TIMEOUT))?;
+    let mut answer = [0u8; 1];
+    stream.read_exact(&mut answer)?;
+    if answer[0] == SIGNAL_HOLD {
+        stream.set_read_timeout(Some(HOLD_TIMEOUT))?;
+        stream.read_exact(&mut answer)?;
+    }
+    stream.set_read_timeout(None)?;
+    if answer[0] != SIGNAL_ACCEPT {
+        return Err(CryptoError::Io(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "Peer rejected the connection",
+        )));
+    }
+    Session::start(stream, Role::Initiator, passphrase)
+}
+
+impl Session {
+    /// Accepts a connection taken from the chat listener and runs the
+    /// handshake as the responder.
+    pub fn accept(mut stream: TcpStream, passphrase: Option<&str>) -> Result<Session, CryptoError> {
+        stream.write_all(&[SIGNAL_ACCEPT])?;
+        Session::start(stream, Role::Responder, passphrase)
+    }
+
+    /// Turns down a connection taken from the chat listener.
+    pub fn reject(mut stream: TcpStream) -> io::Result<()> {
+        stream.write_all(&[SIGNAL_REJECT])
+    }
+
+    fn start(
+        stream: TcpStream,
+        role: Role,
+        passphrase: Option<&str>,
+    ) -> Result<Session, CryptoError> {
+        network::configure_session_socket(&stream)?;
+        let Handshake {
+            cipher,
+            peer_identity,
+            sas,
+            suite,
+            peer_nickname,
+        } = crypto::perform_handshake(&stream, role, passphrase)?;
+        stream.set_nonblocking(true)?;
+        Ok(Session {
+            stream,
+            cipher,
+            peer_identity,
+            sas,
+            suite,
+            peer_nickname,
+            received: 0,
+        })
+    }
+
+    pub fn send(&mut self, text: &str) -> Result<(), CryptoError> {
+        self.rekey_if_due()?;
+        crypto::encrypt_and_send(&mut self.stream, &mut self.cipher, text)
+    }
+
+    /// Waits for the peer's next message.
+    pub fn recv(&mut self) -> Result<String, CryptoError> {
+        loop {
+            if let Some(text) = self.try_recv()? {
+                return Ok(text);
+            }
+            self.wait(None)?;
+        }
+    }
+
+    /// The peer's next message if one has fully arrived, without waiting.
+    pub fn try_recv(&mut self) -> Result<Option<String>, CryptoError> {
+        loop {
+            match self.next_frame()? {
+                None => return Ok(None),
+                Some(Incoming::Chat(text)) if !text.is_empty() => return Ok(Some(text)),
+                Some(Incoming::File(FileMessage::Offer { .. })) => crypto::send_file_message(
+                    &mut self.stream,
+                    &mut self.cipher,
+                    &FileMessage::Refuse,
+                )?,
+                Some(_) => {}
+            }
+        }
+    }
+
+    /// Offers `file` and, once the peer accepts, sends it from wherever
+    /// their interrupted download of it stopped, calling `progress` after
+    /// each chunk. Returns whether the peer took it: false if they
+    /// declined or stopped it, or found it didn't match the offer. Chat
+    /// messages that arrive meanwhile are acknowledged but dropped.
+    pub fn send_file(
+        &mut self,
+        file: &mut Sending,
+        mut progress: impl FnMut(&Sending),
+    ) -> Result<bool, CryptoError> {
+        let offer = FileMessage::Offer {
+            size: file.size,
+            sha256: file.sha256,
+            name: file.name.clone(),
+        };
+        crypto::send_file_message(&mut self.stream, &mut self.cipher, &offer)?;
+        while !file.is_accepted() {
+            match self.next_frame()? {
+                Some(Incoming::File(FileMessage::Accept { offset })) => {
+                    if let Err(e) = file.accept(offset) {
+                        self.send_file_message(FileMessage::Abort)?;
+                        return Err(e.into());
+                    }
+                }
+                Some(Incoming::File(FileMessage::Refuse)) => return Ok(false),
+                Some(Incoming::Bye) => return Err(CryptoError::Disconnected),
+                Some(_) => {}
+                None => {
+                    self.wait(None)?;
+                }
+            }
+        }
+        progress(file);
+
+        loop {
+            while let Some(incoming) = self.next_frame()? {
+                match incoming {
+                    Incoming::File(FileMessage::Refuse) => return Ok(false),
+                    Incoming::Bye => return Err(CryptoError::Disconnected),
+                    _ => {}
+                }
+            }
+            let (offset, data) = match file.next_chunk() {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(e) => {
+                    self.send_file_message(FileMessage::Abort)?;
+                    return Err(e.into());
+                }
+            };
+            self.send_file_message(FileMessage::Chunk { offset, data })?;
+            progress(file);
+        }
+
+        // The receiver checks the file once it has all of it, and refuses
+        // it then if it doesn't match. A chat window says nothing when it
+        // does, so a quiet `ACK_TIMEOUT` counts as taken.
+        let deadline = Instant::now() + ACK_TIMEOUT;
+        loop {
+            match self.next_frame() {
+                Ok(Some(Incoming::File(FileMessage::Refuse))) => return Ok(false),
+                Ok(Some(Incoming::Bye)) | Err(CryptoError::Disconnected) => return Ok(true),
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    match self.wait(Some(left)) {
+                        Ok(true) => {}
+                        Ok(false) | Err(CryptoError::Disconnected) => return Ok(true),
+                        Err(e) => return Err(e),
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Waits for the peer to offer a file, accepts it into `folder`,
+    /// calling `progress` as it arrives, and returns where it was saved
+    /// once it matches the offer. A file that doesn't, or whose name is
+    /// taken when the collision policy rejects it, is refused. Chat
+    /// messages that arrive meanwhile are acknowledged but dropped.
+    pub fn receive_file(
+        &mut self,
+        folder: &Path,
+        mut progress: impl FnMut(&Receiving),
+    ) -> Result<PathBuf, CryptoError> {
+        let peer = self.peer_fingerprint();
+        let mut file = loop {
+            match self.next_frame()? {
+                Some(Incoming::File(FileMessage::Offer { size, sha256, name })) => {
+                    break Receiving::offered_into(&name, size, sha256, &peer, folder);
+                }
+                Some(Incoming::Bye) => return Err(CryptoError::Disconnected),
+                Some(_) => {}
+                None => {
+                    self.wait(None)?;
+                }
+            }
+        };
+        let accepted = match file.collides() {
+            Some(Collision::Reject) => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", file.name),
+            )),
+            _ => file.accept(),
+        };
+        match accepted {
+            Ok(offset) => self.send_file_message(FileMessage::Accept { offset })?,
+            Err(e) => {
+                self.send_file_message(FileMessage::Refuse)?;
+                return Err(e.into());
+            }
+        }
+        progress(&file);
+
+        while !file.is_complete() {
+            let incoming = match self.next_frame() {
+                Ok(Some(incoming)) => incoming,
+                Ok(None) => match self.wait(None) {
+                    Ok(_) => continue,
+                    Err(e) => {
+                        let _ = file.suspend();
+                        return Err(e);
+                    }
+                },
+                Err(e) => {
+                    let _ = file.suspend();
+                    return Err(e);
+                }
+            };
+            match incoming {
+                Incoming::File(FileMessage::Chunk { offset, data }) => {
+                    if let Err(e) = file.write_chunk(offset, &data) {
+                        self.send_file_message(FileMessage::Refuse)?;
+                        return Err(e.into());
+                    }
+                    progress(&file);
+                }
+                Incoming::File(FileMessage::Abort) => {
+                    return Err(CryptoError::Io(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "Peer stopped sending the file",
+                    )));
+                }
+                Incoming::Bye => {
+                    let _ = file.suspend();
+                    return Err(CryptoError::Disconnected);
+                }
+                _ => {}
+            }
+        }
+        match file.finish() {
+            Ok(path) => Ok(path),
+            Err(e) => {
+                self.send_file_message(FileMessage::Refuse)?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Tells the peer the chat is over, so it doesn't wait for more.
+    pub fn close(mut self) -> Result<(), CryptoError> {
+        crypto::send_bye(&mut self.stream, &mut self.cipher)
+    }
+
+    /// Short authentication string to compare with the peer out of band;
+    /// see `crypto::compute_sas`.
+    pub fn verification_code(&self) -> &str {
+        &self.sas
+    }
+
+    pub fn peer_identity(&self) -> &VerifyingKey {
+        &self.peer_identity
+    }
+
+    pub fn peer_fingerprint(&self) -> String {
+        identity::fingerprint(&self.peer_identity)
+    }
+
+    /// The name the peer sent during the handshake, if it has one. Set
+    /// ours with `crypto::set_nickname`.
+    pub fn peer_nickname(&self) -> Option<&str> {
+        self.peer_nickname.as_deref()
+    }
+
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.suite
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// The next frame if one has fully arrived, after acknowledging it if
+    /// it is a chat message.
+    fn next_frame(&mut self) -> Result<Option<Incoming>, CryptoError> {
+        self.rekey_if_due()?;
+        let incoming = crypto::receive_and_decrypt(&mut self.stream, &mut self.cipher);
+        if matches!(
+            incoming,
+            Ok(Some(Incoming::Chat(_))) | Err(CryptoError::InvalidUtf8)
+        ) {
+            self.received += 1;
+            crypto::send_ack(&mut self.stream, &mut self.cipher, self.received)?;
+        }
+        incoming
+    }
+
+    /// Blocks until more of a frame arrives, then goes back to the
+    /// non-blocking reads the frame layer expects. Returns false if
+    /// `timeout` passed first.
+    fn wait(&mut self, timeout: Option<Duration>) -> Result<bool, CryptoError> {
+        self.stream.set_nonblocking(false)?;
+        self.stream.set_read_timeout(timeout)?;
+        let waited = self.stream.peek(&mut [0u8; 1]);
+        self.stream.set_read_timeout(None)?;
+        self.stream.set_nonblocking(true)?;
+        match waited {
+            Ok(0) => Err(CryptoError::Disconnected),
+            Ok(_) => Ok(true),
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn send_file_message(&mut self, message: FileMessage) -> Result<(), CryptoError> {
+        self.rekey_if_due()?;
+        crypto::send_file_message(&mut self.stream, &mut self.cipher, &message)
+    }
+
+    fn rekey_if_due(&mut self) -> Result<(), CryptoError> {
+        if self.cipher.rekey_due() {
+            crypto::start_rekey(&mut self.stream, &mut self.cipher)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+//! One-off announcements to every known peer, sent with `shout`. Each
+//! peer gets its own short connection: `SIGNAL_SHOUT`, the handshake, one
+//! `MSG_SHOUT` frame, and the receiver hangs up once it has read it.
+
+use crate::aliases;
+use crate::crypto::{self, CryptoError};
+use crate::keystore::{KeyStore, PinStatus};
+use crate::network;
+use crate::protocol::{SHOUT_WAIT, SIGNAL_ACCEPT, SIGNAL_REJECT, SIGNAL_SHOUT};
+use crate::state::PeerMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long each step of delivering a shout may take, on either side.
+const SHOUT_TIMEOUT: Duration = Duration::from_secs(5);
+const RECEIVE_POLL: Duration = Duration::from_millis(10);
+
+// On until the user turns them away with `set shouts off`.
+static ACCEPTING: AtomicBool = AtomicBool::new(true);
+/// Shouts received since the prompt or chat window last looked.
+static RECEIVED: Mutex<Vec<Shout>> = Mutex::new(Vec::new());
+
+pub struct Shout {
+    pub from: SocketAddr,
+    /// Our alias for the sender, else the nickname it sent.
+    pub name: Option<String>,
+    pub text: String,
+    /// Whether the sender's identity key differs from the one pinned for
+    /// its IP.
+    pub key_changed: bool,
+}
+
+impl Shout {
+    /// `name (ip): text`, flagged if the key changed. The port is one the
+    /// sender picked for this connection, so it says nothing.
+    pub fn line(&self) -> String {
+        let from = match &self.name {
+            Some(name) => format!("{} ({})", name, self.from.ip()),
+            None => self.from.ip().to_string(),
+        };
+        let warning = if self.key_changed {
+            " [identity key changed]"
+        } else {
+            ""
+        };
+        format!("Shout from {}{}: {}", from, warning, self.text)
+    }
+}
+
+pub fn set_accepting(accepting: bool) {
+    ACCEPTING.store(accepting, Ordering::Relaxed);
+}
+
+pub fn is_accepting() -> bool {
+    ACCEPTING.load(Ordering::Relaxed)
+}
+
+/// Shouts received since the last call, oldest first.
+pub fn take_received() -> Vec<Shout> {
+    std::mem::take(&mut *RECEIVED.lock().unwrap())
+}
+
+/// Shouts `text` to every peer in `peers` at once and returns how it went
+/// for each, sorted by address.
+pub fn send_all(peers: &PeerMap, text: &str) -> Vec<(SocketAddr, Result<(), CryptoError>)> {
+    let mut addrs: Vec<SocketAddr> = peers.read().unwrap().keys().copied().collect();
+    addrs.sort();
+    thread::scope(|scope| {
+        let sends: Vec<_> = addrs
+            .iter()
+            .map(|addr| scope.spawn(move || send(*addr, text)))
+            .collect();
+        addrs
+            .iter()
+            .zip(sends)
+            .map(|(addr, send)| {
+                let result = send
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("shout thread panicked").into()));
+                (*addr, result)
+            })
+            .collect()
+    })
+}
+
+fn send(addr: SocketAddr, text: &str) -> Result<(), CryptoError> {
+    let mut stream = TcpStream::connect_timeout(&addr, SHOUT_TIMEOUT)?;
+    stream.write_all(&[SIGNAL_SHOUT])?;
+    stream.set_read_timeout(Some(SHOUT_TIMEOUT))?;
+    let mut answer = [0u8; 1];
+    stream.read_exact(&mut answer)?;
+    if answer[0] != SIGNAL_ACCEPT {
+        return Err(CryptoError::Io(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "Peer doesn't take shouts",
+        )));
+    }
+    network::configure_session_socket(&stream)?;
+    let handshake = crypto::perform_handshake(&stream, crypto::Role::Initiator, None)?;
+    let mut cipher = handshake.cipher;
+    crypto::send_shout(&mut stream, &mut cipher, text)?;
+    // The receiver hangs up once it has read the shout, which is the only
+    // sign it arrived.
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(SHOUT_TIMEOUT))?;
+    match stream.read(&mut [0u8; 1]) {
+        Ok(0) => Ok(()),
+        Ok(_) => Err(CryptoError::MalformedFrame("Unexpected reply to a shout")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Takes a connection the chat listener just accepted if it carries a
+/// shout, answering it on a thread of its own. Anything else is handed
+/// back as a chat request.
+pub fn take_if_shout(stream: TcpStream) -> Option<TcpStream> {
+    let mut first = [0u8; 1];
+    let shout = stream.set_read_timeout(Some(SHOUT_WAIT)).is_ok()
+        && matches!(stream.peek(&mut first), Ok(1))
+        && first[0] == SIGNAL_SHOUT;
+    if !shout {
+        return stream.set_read_timeout(None).is_ok().then_some(stream);
+    }
+    let _ = thread::Builder::new()
+        .name("shout".to_string())
+        .spawn(move || {
+            let _ = receive(stream);
+        });
+    None
+}
+
+fn receive(mut stream: TcpStream) -> Result<(), CryptoError> {
+    stream.read_exact(&mut [0u8; 1])?;
+    if !is_accepting() {
+        stream.write_all(&[SIGNAL_REJECT])?;
+        return Ok(());
+    }
+    stream.write_all(&[SIGNAL_ACCEPT])?;
+    network::configure_session_socket(&stream)?;
+    let handshake = crypto::perform_handshake(&stream, crypto::Role::Responder, None)?;
+    let from = stream.peer_addr()?;
+    let fingerprint = handshake.peer_fingerprint();
+    let mut cipher = handshake.cipher;
+    stream.set_nonblocking(true)?;
+    let deadline = Instant::now() + SHOUT_TIMEOUT;
+    let text = loop {
+        match crypto::receive_and_decrypt(&mut stream, &mut cipher)? {
+            // Shouts arrive unasked, so they print nothing but text.
+            Some(crypto::Incoming::Shout(text)) => {
+                break text.chars().filter(|c| !c.is_control()).collect::<String>();
+            }
+            Some(_) => return Err(CryptoError::MalformedFrame("Expected a shout")),
+            None if Instant::now() >= deadline => {
+                return Err(io::Error::from(io::ErrorKind::TimedOut).into());
+            }
+            None => thread::sleep(RECEIVE_POLL),
+        }
+    };
+    let pin = KeyStore::load().check(from.ip(), &fingerprint);
+    RECEIVED.lock().unwrap().push(Shout {
+        from,
+        name: aliases::name_for(&from).or(handshake.peer_nickname),
+        text,
+        key_changed: matches!(pin, Ok(PinStatus::Changed { .. })),
+    });
+    Ok(())
+}
@@ -1,10 +1,219 @@
+use crate::identity;
+use crate::protocol::{Features, PeerStatus};
+use ed25519_dalek::VerifyingKey;
 use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
 use std::time::Instant;
 
-pub type PeerMap = Arc<Mutex<HashMap<SocketAddr, Instant>>>;
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    pub last_seen: Instant,
+    pub features: Features,
+    /// Random id the peer's instance picked at startup, if it sends one.
+    pub instance: Option<u64>,
+    /// Name the peer chose to announce, already checked to be printable.
+    pub nickname: Option<String>,
+    pub status: Option<PeerStatus>,
+    /// Identity key the peer's beacons are signed with; `None` for a
+    /// legacy peer, whose bare `HELLO_P2P` can't vouch for anything.
+    pub identity: Option<VerifyingKey>,
+    /// Timestamp of the newest beacon taken from the peer, so an older one
+    /// replayed from elsewhere can't move its entry.
+    pub signed_at: u64,
+}
+
+/// What a legacy peer is listed as, having announced no name.
+pub const LEGACY_NAME: &str = "legacy peer";
+
+impl PeerInfo {
+    /// The placeholder entry for a bare `HELLO_P2P` beacon, from a build
+    /// that predates everything after the magic.
+    pub fn legacy(last_seen: Instant) -> Self {
+        PeerInfo {
+            last_seen,
+            features: Features::default(),
+            instance: None,
+            nickname: None,
+            status: None,
+            identity: None,
+            signed_at: 0,
+        }
+    }
+
+    /// The announced nickname, or `LEGACY_NAME` for a legacy peer.
+    pub fn name(&self) -> Option<String> {
+        match (&self.nickname, self.identity) {
+            (Some(name), _) => Some(name.clone()),
+            (None, None) => Some(LEGACY_NAME.to_string()),
+            (None, Some(_)) => None,
+        }
+    }
+
+    /// The short fingerprint of the key the beacons are signed with, or
+    /// `unverified` for a legacy peer.
+    pub fn fingerprint_label(&self) -> String {
+        self.identity
+            .as_ref()
+            .map_or("unverified".to_string(), identity::short_fingerprint)
+    }
+}
+
+/// Keyed by IP and the TCP port the peer accepts chats on, so the key is
+/// what `connect` needs. Only the discovery threads write to it.
+pub type PeerMap = Arc<RwLock<HashMap<SocketAddr, PeerInfo>>>;
+
+/// Addresses of the peers announcing `nickname`, ignoring case, sorted so
+/// they list the same way `find` does.
+pub fn peers_named(peers: &HashMap<SocketAddr, PeerInfo>, nickname: &str) -> Vec<SocketAddr> {
+    let wanted = nickname.to_lowercase();
+    let mut matches: Vec<SocketAddr> = peers
+        .iter()
+        .filter(|(_, info)| {
+            info.nickname
+                .as_ref()
+                .is_some_and(|n| n.to_lowercase() == wanted)
+        })
+        .map(|(addr, _)| *addr)
+        .collect();
+    matches.sort();
+    matches
+}
+
+/// Addresses of the peers whose nickname is close to `nickname`, ignoring
+/// case: one contains the other, or it's a typo or two away. For suggesting
+/// when `peers_named` finds nobody.
+pub fn peers_named_like(peers: &HashMap<SocketAddr, PeerInfo>, nickname: &str) -> Vec<SocketAddr> {
+    let wanted = nickname.to_lowercase();
+    let mut matches: Vec<SocketAddr> = peers
+        .iter()
+        .filter(|(_, info)| {
+            info.nickname.as_ref().is_some_and(|n| {
+                let n = n.to_lowercase();
+                n.contains(&wanted) || wanted.contains(&n) || edit_distance(&n, &wanted) <= 2
+            })
+        })
+        .map(|(addr, _)| *addr)
+        .collect();
+    matches.sort();
+    matches
+}
+
+/// Levenshtein distance, over characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The nickname announced from `ip`, if every peer seen there announces
+/// the same one. Chat requests come from another port than the announced
+/// one, so the IP is all there is to go on.
+pub fn nickname_at(peers: &HashMap<SocketAddr, PeerInfo>, ip: IpAddr) -> Option<String> {
+    let mut names = peers
+        .iter()
+        .filter(|(addr, _)| addr.ip() == ip)
+        .map(|(_, info)| info.nickname.as_deref());
+    let first = names.next()??;
+    names
+        .all(|name| name == Some(first))
+        .then(|| first.to_string())
+}
 
 pub fn init_peers() -> PeerMap {
-    Arc::new(Mutex::new(HashMap::new()))
+    Arc::new(RwLock::new(HashMap::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Peers as discovery would have listed them, by address and nickname.
+    fn peer_map(peers: &[(&str, Option<&str>)]) -> HashMap<SocketAddr, PeerInfo> {
+        peers
+            .iter()
+            .map(|(addr, name)| {
+                let info = PeerInfo {
+                    nickname: name.map(str::to_string),
+                    ..PeerInfo::legacy(Instant::now())
+                };
+                (addr.parse().unwrap(), info)
+            })
+            .collect()
+    }
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn nicknames_resolve_to_their_peers() {
+        let peers = peer_map(&[
+            ("192.168.1.34:3000", Some("alice")),
+            ("192.168.1.40:3000", Some("bob")),
+            ("192.168.1.41:3000", None),
+        ]);
+        assert_eq!(peers_named(&peers, "alice"), addrs(&["192.168.1.34:3000"]));
+        assert_eq!(peers_named(&peers, "BOB"), addrs(&["192.168.1.40:3000"]));
+        // Left for `connect` to try as a host name.
+        assert!(peers_named(&peers, "carol").is_empty());
+        assert!(peers_named_like(&peers, "carol").is_empty());
+    }
+
+    #[test]
+    fn shared_nicknames_list_every_peer_in_order() {
+        let peers = peer_map(&[
+            ("10.0.0.9:3000", Some("lab")),
+            ("10.0.0.2:3001", Some("Lab")),
+            ("10.0.0.2:3000", Some("lab")),
+            ("10.0.0.5:3000", Some("desk")),
+        ]);
+        assert_eq!(
+            peers_named(&peers, "lab"),
+            addrs(&["10.0.0.2:3000", "10.0.0.2:3001", "10.0.0.9:3000"])
+        );
+    }
+
+    #[test]
+    fn near_misses_are_suggested() {
+        let peers = peer_map(&[
+            ("10.0.0.1:3000", Some("alice")),
+            ("10.0.0.2:3000", Some("alicia")),
+            ("10.0.0.3:3000", Some("bob")),
+        ]);
+        assert!(peers_named(&peers, "bbo").is_empty());
+        assert_eq!(peers_named_like(&peers, "bbo"), addrs(&["10.0.0.3:3000"]));
+        assert_eq!(
+            peers_named_like(&peers, "ali"),
+            addrs(&["10.0.0.1:3000", "10.0.0.2:3000"])
+        );
+    }
+
+    #[test]
+    fn requests_are_named_only_when_the_ip_agrees() {
+        let peers = peer_map(&[
+            ("10.0.0.1:3000", Some("alice")),
+            ("10.0.0.1:3001", Some("alice")),
+            ("10.0.0.2:3000", Some("bob")),
+            ("10.0.0.2:3001", Some("eve")),
+            ("10.0.0.3:3000", None),
+        ]);
+        let ip = |ip: &str| ip.parse().unwrap();
+        assert_eq!(
+            nickname_at(&peers, ip("10.0.0.1")).as_deref(),
+            Some("alice")
+        );
+        assert_eq!(nickname_at(&peers, ip("10.0.0.2")), None);
+        assert_eq!(nickname_at(&peers, ip("10.0.0.3")), None);
+        assert_eq!(nickname_at(&peers, ip("10.0.0.4")), None);
+    }
 }
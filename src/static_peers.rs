@@ -0,0 +1,129 @@
+use crate::state::PeerInfo;
+use crate::storage;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+const STATIC_PEERS_FILE: &str = "static_peers";
+
+/// Peers added by address with `add-peer`, for networks their beacons
+/// don't cross. The discovery thread probes each one, and a peer that
+/// answers is listed like any other until it stops answering. Persisted
+/// to `~/.sandesh/static_peers` as one `ip:port` per line. Global because
+/// the network threads and the peer views all read it.
+static STATIC: Mutex<BTreeSet<SocketAddr>> = Mutex::new(BTreeSet::new());
+
+/// Loads the static peers at startup. A missing or unreadable file leaves
+/// the list empty; malformed lines are skipped.
+pub fn load() {
+    let contents = storage::data_file(STATIC_PEERS_FILE).and_then(|p| fs::read_to_string(p).ok());
+    *STATIC.lock().unwrap() = parse(contents.as_deref().unwrap_or_default());
+}
+
+fn parse(contents: &str) -> BTreeSet<SocketAddr> {
+    contents
+        .lines()
+        .filter_map(|line| line.trim().parse().ok())
+        .collect()
+}
+
+fn render(addrs: &BTreeSet<SocketAddr>) -> String {
+    addrs.iter().map(|addr| format!("{}\n", addr)).collect()
+}
+
+pub fn is_static(addr: &SocketAddr) -> bool {
+    STATIC.lock().unwrap().contains(addr)
+}
+
+/// Returns false if the address was already listed.
+pub fn add(addr: SocketAddr) -> io::Result<bool> {
+    let mut addrs = STATIC.lock().unwrap();
+    let added = addrs.insert(addr);
+    if added {
+        save(&addrs)?;
+    }
+    Ok(added)
+}
+
+/// Returns false if the address was not listed.
+pub fn remove(addr: SocketAddr) -> io::Result<bool> {
+    let mut addrs = STATIC.lock().unwrap();
+    let removed = addrs.remove(&addr);
+    if removed {
+        save(&addrs)?;
+    }
+    Ok(removed)
+}
+
+/// Every static peer, sorted.
+pub fn list() -> Vec<SocketAddr> {
+    STATIC.lock().unwrap().iter().copied().collect()
+}
+
+/// The static peers with no entry in `peers`: those that haven't answered
+/// a probe yet, or stopped answering.
+pub fn unanswered(peers: &HashMap<SocketAddr, PeerInfo>) -> Vec<SocketAddr> {
+    missing(&STATIC.lock().unwrap(), peers)
+}
+
+fn missing(addrs: &BTreeSet<SocketAddr>, peers: &HashMap<SocketAddr, PeerInfo>) -> Vec<SocketAddr> {
+    addrs
+        .iter()
+        .filter(|addr| !peers.contains_key(addr))
+        .copied()
+        .collect()
+}
+
+fn save(addrs: &BTreeSet<SocketAddr>) -> io::Result<()> {
+    let path = storage::data_file(STATIC_PEERS_FILE).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "no home directory to store static peers in",
+        )
+    })?;
+    storage::write_atomic(&path, &render(addrs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn file_lists_addresses_of_both_families() {
+        let addrs =
+            parse("10.8.0.2:3000\n  [fd00::2]:3000  \n\nnot an address\n10.8.0.2\n10.8.0.2:3000\n");
+        let expected: BTreeSet<SocketAddr> = ["10.8.0.2:3000", "[fd00::2]:3000"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        // Junk and a missing port are skipped, and repeats listed once.
+        assert_eq!(addrs, expected);
+        assert_eq!(render(&addrs), "10.8.0.2:3000\n[fd00::2]:3000\n");
+        assert_eq!(parse(&render(&addrs)), addrs);
+        assert!(parse("").is_empty());
+    }
+
+    #[test]
+    fn only_peers_that_never_answered_are_listed_apart() {
+        let addrs = parse("10.8.0.2:3000\n10.8.0.3:3000\n[fd00::2]:3000\n");
+        let answered = PeerInfo {
+            nickname: Some("vpn".to_string()),
+            ..PeerInfo::legacy(Instant::now())
+        };
+        let mut peers = HashMap::new();
+        peers.insert("10.8.0.3:3000".parse().unwrap(), answered.clone());
+        // Discovered on the LAN, not through the list.
+        peers.insert("192.168.1.5:3000".parse().unwrap(), answered);
+        assert_eq!(
+            missing(&addrs, &peers),
+            [
+                "10.8.0.2:3000".parse().unwrap(),
+                "[fd00::2]:3000".parse().unwrap()
+            ]
+        );
+        assert!(missing(&BTreeSet::new(), &peers).is_empty());
+    }
+}
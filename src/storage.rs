@@ -0,0 +1,48 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const DATA_DIR: &str = ".sandesh";
+
+/// `~/.sandesh`, or `None` when no home directory can be determined.
+pub fn data_dir() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(DATA_DIR))
+}
+
+/// Path of a file inside the data directory.
+pub fn data_file(name: &str) -> Option<PathBuf> {
+    data_dir().map(|dir| dir.join(name))
+}
+
+/// Replaces `path` by writing a sibling temp file and renaming it over the
+/// original, so a crash mid-write never leaves a truncated file behind.
+/// The temp file is named after the process, so instances saving the same
+/// file at once don't write into each other's.
+pub fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = temp_path(path);
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+/// Like `write_atomic`, but the file is readable by the owner only (on
+/// Unix). For key material.
+pub fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = temp_path(path);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&tmp)?.write_all(contents.as_bytes())?;
+    fs::rename(&tmp, path)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    path.with_extension(format!("{}.tmp", std::process::id()))
+}
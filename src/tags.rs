@@ -0,0 +1,128 @@
+use crate::storage;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+const TAGS_FILE: &str = "tags";
+const MAX_TAG_LEN: usize = 16;
+
+/// User-assigned tags per peer IP, persisted to `~/.sandesh/tags` as one
+/// `<ip> <tag>,<tag>` line per peer. Keyed by IP rather than address so a
+/// peer keeps its tags when it announces from a different port.
+pub struct TagBook {
+    tags: BTreeMap<IpAddr, BTreeSet<String>>,
+    path: Option<PathBuf>,
+}
+
+impl TagBook {
+    /// Loads the tag file. A missing or unreadable file gives an empty book;
+    /// malformed lines are skipped.
+    pub fn load() -> Self {
+        let path = storage::data_file(TAGS_FILE);
+        let mut tags = BTreeMap::new();
+
+        if let Some(contents) = path.as_ref().and_then(|p| fs::read_to_string(p).ok()) {
+            for line in contents.lines() {
+                let Some((ip, list)) = line.split_once(' ') else {
+                    continue;
+                };
+                let Ok(ip) = ip.parse::<IpAddr>() else {
+                    continue;
+                };
+                let set: BTreeSet<String> = list
+                    .split(',')
+                    .filter(|t| validate_tag(t).is_ok())
+                    .map(str::to_string)
+                    .collect();
+                if !set.is_empty() {
+                    tags.insert(ip, set);
+                }
+            }
+        }
+
+        TagBook { tags, path }
+    }
+
+    /// A book with no tags that is never saved, so tests never touch
+    /// `~/.sandesh`.
+    #[cfg(test)]
+    pub(crate) fn empty() -> Self {
+        TagBook {
+            tags: BTreeMap::new(),
+            path: None,
+        }
+    }
+
+    /// Returns false if the peer already had the tag.
+    pub fn add(&mut self, ip: IpAddr, tag: &str) -> io::Result<bool> {
+        let added = self.tags.entry(ip).or_default().insert(tag.to_string());
+        if added {
+            self.save()?;
+        }
+        Ok(added)
+    }
+
+    /// Returns false if the peer did not have the tag.
+    pub fn remove(&mut self, ip: IpAddr, tag: &str) -> io::Result<bool> {
+        let Some(set) = self.tags.get_mut(&ip) else {
+            return Ok(false);
+        };
+        let removed = set.remove(tag);
+        if set.is_empty() {
+            self.tags.remove(&ip);
+        }
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    pub fn tags_for(&self, ip: IpAddr) -> impl Iterator<Item = &str> {
+        self.tags.get(&ip).into_iter().flatten().map(String::as_str)
+    }
+
+    pub fn has_tag(&self, ip: IpAddr, tag: &str) -> bool {
+        self.tags.get(&ip).is_some_and(|set| set.contains(tag))
+    }
+
+    /// Every tag in use, sorted and deduplicated.
+    pub fn all_tags(&self) -> Vec<&str> {
+        let all: BTreeSet<&str> = self.tags.values().flatten().map(String::as_str).collect();
+        all.into_iter().collect()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&IpAddr, &BTreeSet<String>)> {
+        self.tags.iter()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no home directory to store tags in",
+            ));
+        };
+        let mut contents = String::new();
+        for (ip, set) in &self.tags {
+            let list: Vec<&str> = set.iter().map(String::as_str).collect();
+            contents.push_str(&format!("{} {}\n", ip, list.join(",")));
+        }
+        storage::write_atomic(path, &contents)
+    }
+}
+
+/// Tags are short identifiers: 1-16 characters of `a-z`, `0-9`, `-`, `_`.
+pub fn validate_tag(tag: &str) -> Result<(), &'static str> {
+    if tag.is_empty() || tag.len() > MAX_TAG_LEN {
+        return Err("tags must be 1-16 characters long");
+    }
+    if !tag
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+    {
+        return Err("tags may only contain a-z, 0-9, '-' and '_'");
+    }
+    Ok(())
+}
@@ -0,0 +1,491 @@
+use crossterm::{
+    cursor,
+    event::{
+        self, DisableFocusChange, DisableMouseCapture, EnableFocusChange, EnableMouseCapture,
+        Event, KeyCode, KeyEvent, KeyModifiers,
+    },
+    execute,
+    terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode},
+};
+#[cfg(unix)]
+use std::io::IsTerminal;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::panic;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock, TryLockError};
+use std::time::Duration;
+use unicode_width::UnicodeWidthChar;
+use zeroize::Zeroizing;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Mode {
+    raw: bool,
+    alternate_screen: bool,
+    mouse: bool,
+}
+
+const NORMAL: Mode = Mode {
+    raw: false,
+    alternate_screen: false,
+    mouse: false,
+};
+
+impl Mode {
+    /// Full-screen views hear when the window gains or loses focus. Line
+    /// input would get the reports as stray text, so only raw mode does.
+    fn focus_events(self) -> bool {
+        self.raw && self.alternate_screen
+    }
+}
+
+/// Modes requested by live guards, innermost last. The terminal is always
+/// in the mode of the last entry, or `NORMAL` when there is none.
+struct Stack(Vec<(u64, Mode)>);
+
+impl Stack {
+    fn current(&self) -> Mode {
+        self.0.last().map_or(NORMAL, |(_, mode)| *mode)
+    }
+
+    /// Puts guard `id`'s mode on top, returning the steps that get the
+    /// terminal there. Registered before the steps are taken, so a failed
+    /// transition is still undone when the guard drops.
+    fn push(&mut self, id: u64, mode: Mode) -> Vec<Step> {
+        let before = self.current();
+        self.0.push((id, mode));
+        steps(before, mode)
+    }
+
+    /// Takes guard `id` out, returning the steps to the mode of whichever
+    /// guard is on top now. None if it wasn't on top.
+    fn remove(&mut self, id: u64) -> Vec<Step> {
+        let before = self.current();
+        self.0.retain(|(guard, _)| *guard != id);
+        steps(before, self.current())
+    }
+
+    /// Forgets every guard, returning the steps back to `NORMAL`.
+    fn clear(&mut self) -> Vec<Step> {
+        let before = self.current();
+        self.0.clear();
+        steps(before, NORMAL)
+    }
+}
+
+static STACK: Mutex<Stack> = Mutex::new(Stack(Vec::new()));
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// One change to the terminal's mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Step {
+    DisableFocusChange,
+    DisableMouseCapture,
+    /// Also shows the cursor, which views may have hidden.
+    LeaveAlternateScreen,
+    EnableRawMode,
+    DisableRawMode,
+    EnterAlternateScreen,
+    EnableMouseCapture,
+    EnableFocusChange,
+}
+
+/// Owns a terminal mode for as long as it lives. Guards nest: a new guard
+/// switches the terminal to its mode, and dropping it restores whatever
+/// the remaining guards ask for, so early returns, `?` and panics all
+/// unwind to a usable terminal. Entering a mode that is already active is
+/// a no-op, which keeps the alternate screen from being entered twice.
+pub struct TerminalGuard {
+    id: u64,
+}
+
+impl TerminalGuard {
+    /// Raw mode on the main screen, for the prompt's key-by-key input.
+    pub fn raw() -> io::Result<Self> {
+        Self::enter(Mode {
+            raw: true,
+            alternate_screen: false,
+            mouse: false,
+        })
+    }
+
+    /// Raw mode on the alternate screen, for full-screen views.
+    pub fn fullscreen() -> io::Result<Self> {
+        Self::enter(Mode {
+            raw: true,
+            alternate_screen: true,
+            mouse: false,
+        })
+    }
+
+    /// A full-screen view that also gets mouse events, for scrolling with
+    /// the wheel. Most terminals then only select text with Shift held.
+    pub fn fullscreen_with_mouse() -> io::Result<Self> {
+        Self::enter(Mode {
+            raw: true,
+            alternate_screen: true,
+            mouse: true,
+        })
+    }
+
+    /// The alternate screen with line-buffered input, for status output
+    /// before a full-screen view takes over.
+    pub fn alternate_screen() -> io::Result<Self> {
+        Self::enter(Mode {
+            raw: false,
+            alternate_screen: true,
+            mouse: false,
+        })
+    }
+
+    /// Plain line-buffered terminal, for `println!` output and `read_line`
+    /// prompts issued from inside a raw-mode loop.
+    pub fn cooked() -> io::Result<Self> {
+        Self::enter(NORMAL)
+    }
+
+    fn enter(mode: Mode) -> io::Result<Self> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let mut stack = STACK.lock().unwrap();
+        take(stack.push(id, mode))?;
+        Ok(TerminalGuard { id })
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let mut stack = STACK.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = take(stack.remove(self.id));
+    }
+}
+
+/// Puts the terminal back to normal whatever guards are live, for paths
+/// that won't unwind them: the panic hook, Ctrl+C and `exit`. The guards
+/// are forgotten, so dropping them afterwards changes nothing.
+pub fn restore() {
+    let mut stack = match STACK.try_lock() {
+        Ok(stack) => stack,
+        Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        // Mid-transition on another thread: undo everything to be safe.
+        Err(TryLockError::WouldBlock) => {
+            let everything = Mode {
+                raw: true,
+                alternate_screen: true,
+                mouse: true,
+            };
+            let _ = take(steps(everything, NORMAL));
+            return;
+        }
+    };
+    let _ = take(stack.clear());
+}
+
+/// Restores the terminal before a panic message is printed, and on Ctrl+C
+/// outside raw mode (raw mode delivers Ctrl+C as a key press instead), so
+/// neither leaves the shell unusable.
+pub fn install_handlers() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore();
+        default_hook(info);
+    }));
+    let _ = ctrlc::set_handler(|| {
+        restore();
+        std::process::exit(130);
+    });
+}
+
+/// Ctrl+C as read in raw mode.
+pub fn is_interrupt(key: &KeyEvent) -> bool {
+    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL)
+}
+
+/// Drops the last character of a line being typed in place and returns
+/// what erases it on screen. Wide characters (CJK, most emoji) take two
+/// cells and combining marks none, so it backs over as many cells as the
+/// character covered.
+pub fn erase_last(line: &mut String) -> String {
+    let removed = line.pop().and_then(|c| c.width()).unwrap_or(0);
+    "\x08 \x08".repeat(removed)
+}
+
+/// Waits up to `timeout` for terminal input, like `event::poll`, but also
+/// returns (with `false`) as soon as another thread calls `wake_input`.
+pub fn poll_input(timeout: Duration) -> io::Result<bool> {
+    #[cfg(unix)]
+    if let Some((waker, _)) = wake_pair().filter(|_| io::stdin().is_terminal()) {
+        use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+        use std::io::Read;
+        use std::os::fd::AsFd;
+
+        // Crossterm may hold input it already read off the descriptor.
+        if event::poll(Duration::ZERO)? {
+            return Ok(true);
+        }
+        let stdin = io::stdin();
+        let mut fds = [
+            PollFd::new(stdin.as_fd(), PollFlags::POLLIN),
+            PollFd::new(waker.as_fd(), PollFlags::POLLIN),
+        ];
+        match poll(
+            &mut fds,
+            PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX),
+        ) {
+            Ok(_) | Err(nix::errno::Errno::EINTR) => {}
+            Err(e) => return Err(e.into()),
+        }
+        while matches!((&*waker).read(&mut [0; 64]), Ok(n) if n > 0) {}
+        return event::poll(Duration::ZERO);
+    }
+    event::poll(timeout)
+}
+
+/// Cuts short a `poll_input` in progress, or the next one, so the caller
+/// gets to look at whatever the waking thread left for it.
+pub fn wake_input() {
+    #[cfg(unix)]
+    if let Some((_, wake)) = wake_pair() {
+        // A full socket means a wakeup is already pending.
+        let _ = (&*wake).write(&[1]);
+    }
+}
+
+#[cfg(unix)]
+fn wake_pair() -> Option<&'static (UnixStream, UnixStream)> {
+    static PAIR: OnceLock<Option<(UnixStream, UnixStream)>> = OnceLock::new();
+    PAIR.get_or_init(|| {
+        let (waker, wake) = UnixStream::pair().ok()?;
+        waker.set_nonblocking(true).ok()?;
+        wake.set_nonblocking(true).ok()?;
+        Some((waker, wake))
+    })
+    .as_ref()
+}
+
+/// Only the transitions that differ between `from` and `to`, what was on
+/// in `from` turned off first and in the reverse order it goes on.
+fn steps(from: Mode, to: Mode) -> Vec<Step> {
+    let mut steps = Vec::new();
+    if from.focus_events() && !to.focus_events() {
+        steps.push(Step::DisableFocusChange);
+    }
+    if from.mouse && !to.mouse {
+        steps.push(Step::DisableMouseCapture);
+    }
+    if from.alternate_screen && !to.alternate_screen {
+        steps.push(Step::LeaveAlternateScreen);
+    }
+    if from.raw != to.raw {
+        steps.push(if to.raw {
+            Step::EnableRawMode
+        } else {
+            Step::DisableRawMode
+        });
+    }
+    if !from.alternate_screen && to.alternate_screen {
+        steps.push(Step::EnterAlternateScreen);
+    }
+    if !from.mouse && to.mouse {
+        steps.push(Step::EnableMouseCapture);
+    }
+    if !from.focus_events() && to.focus_events() {
+        steps.push(Step::EnableFocusChange);
+    }
+    steps
+}
+
+/// Makes the changes to the real terminal, stopping at the first that
+/// fails.
+fn take(steps: Vec<Step>) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    for step in steps {
+        match step {
+            Step::DisableFocusChange => execute!(stdout, DisableFocusChange)?,
+            Step::DisableMouseCapture => execute!(stdout, DisableMouseCapture)?,
+            Step::LeaveAlternateScreen => execute!(stdout, LeaveAlternateScreen, cursor::Show)?,
+            Step::EnableRawMode => enable_raw_mode()?,
+            Step::DisableRawMode => disable_raw_mode()?,
+            Step::EnterAlternateScreen => execute!(stdout, EnterAlternateScreen)?,
+            Step::EnableMouseCapture => execute!(stdout, EnableMouseCapture)?,
+            Step::EnableFocusChange => execute!(stdout, EnableFocusChange)?,
+        }
+    }
+    Ok(())
+}
+
+/// Reads a line without echoing it, for passphrases. `None` if the user
+/// cancelled with Esc or Ctrl+C.
+pub fn read_secret(prompt: &str) -> io::Result<Option<Zeroizing<String>>> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let _raw = TerminalGuard::raw()?;
+    // Reserved up front so typing doesn't reallocate and leave copies of
+    // the secret behind.
+    let mut secret = Zeroizing::new(String::with_capacity(256));
+    loop {
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        match key.code {
+            KeyCode::Enter => break,
+            KeyCode::Esc => {
+                print!("\r\n");
+                return Ok(None);
+            }
+            _ if is_interrupt(&key) => {
+                print!("\r\n");
+                return Ok(None);
+            }
+            KeyCode::Backspace => {
+                secret.pop();
+            }
+            KeyCode::Char(c) => secret.push(c),
+            _ => {}
+        }
+    }
+    print!("\r\n");
+    Ok(Some(secret))
+}
+
+/// Whether the terminal can be expected to show non-ASCII characters.
+/// Unix terminals say so through the locale; Rust writes to Windows
+/// consoles as UTF-16, which they always render.
+pub fn supports_unicode() -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    *SUPPORTED.get_or_init(|| {
+        if cfg!(windows) {
+            return true;
+        }
+        // The first of these that is set decides, as for the C library.
+        ["LC_ALL", "LC_CTYPE", "LANG"]
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|value| !value.is_empty())
+            .is_some_and(|locale| {
+                let locale = locale.to_ascii_lowercase();
+                locale.contains("utf-8") || locale.contains("utf8")
+            })
+    })
+}
+
+/// Separator before a trailing note, falling back to ASCII where Unicode
+/// may not render.
+pub fn dash() -> &'static str {
+    if supports_unicode() { "\u{2014}" } else { "-" }
+}
+
+/// List bullet, falling back to ASCII where Unicode may not render.
+pub fn bullet() -> &'static str {
+    if supports_unicode() { "\u{2022}" } else { "*" }
+}
+
+/// Trailing ellipsis, falling back to ASCII where Unicode may not render.
+pub fn ellipsis() -> &'static str {
+    if supports_unicode() {
+        "\u{2026}"
+    } else {
+        "..."
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mode, NORMAL, Stack, Step::*, erase_last};
+
+    const RAW: Mode = Mode {
+        raw: true,
+        alternate_screen: false,
+        mouse: false,
+    };
+    const FULLSCREEN: Mode = Mode {
+        raw: true,
+        alternate_screen: true,
+        mouse: false,
+    };
+    const MOUSE: Mode = Mode {
+        raw: true,
+        alternate_screen: true,
+        mouse: true,
+    };
+
+    #[test]
+    fn nested_guards_unwind_in_reverse() {
+        let mut stack = Stack(Vec::new());
+        assert_eq!(stack.push(0, RAW), [EnableRawMode]);
+        assert_eq!(
+            stack.push(1, FULLSCREEN),
+            [EnterAlternateScreen, EnableFocusChange]
+        );
+        assert_eq!(stack.push(2, MOUSE), [EnableMouseCapture]);
+
+        assert_eq!(stack.remove(2), [DisableMouseCapture]);
+        assert_eq!(stack.remove(1), [DisableFocusChange, LeaveAlternateScreen]);
+        assert_eq!(stack.remove(0), [DisableRawMode]);
+        assert!(stack.current() == NORMAL);
+    }
+
+    #[test]
+    fn guards_dropped_out_of_order_wait_for_the_top() {
+        let mut stack = Stack(Vec::new());
+        stack.push(0, RAW);
+        stack.push(1, FULLSCREEN);
+        stack.push(2, MOUSE);
+        // Still under the mouse guard, so nothing changes yet.
+        assert_eq!(stack.remove(1), []);
+        assert_eq!(
+            stack.remove(2),
+            [
+                DisableFocusChange,
+                DisableMouseCapture,
+                LeaveAlternateScreen
+            ]
+        );
+        assert!(stack.current() == RAW);
+    }
+
+    #[test]
+    fn entering_the_current_mode_changes_nothing() {
+        let mut stack = Stack(Vec::new());
+        stack.push(0, FULLSCREEN);
+        assert_eq!(stack.push(1, FULLSCREEN), []);
+        assert_eq!(stack.remove(1), []);
+        // A cooked guard inside a raw one drops out of raw mode and back.
+        let mut stack = Stack(Vec::new());
+        stack.push(0, RAW);
+        assert_eq!(stack.push(1, NORMAL), [DisableRawMode]);
+        assert_eq!(stack.remove(1), [EnableRawMode]);
+    }
+
+    #[test]
+    fn restoring_undoes_everything_once() {
+        let mut stack = Stack(Vec::new());
+        stack.push(0, RAW);
+        stack.push(1, MOUSE);
+        assert_eq!(
+            stack.clear(),
+            [
+                DisableFocusChange,
+                DisableMouseCapture,
+                LeaveAlternateScreen,
+                DisableRawMode
+            ]
+        );
+        // Guards dropped afterwards find nothing left to undo.
+        assert_eq!(stack.remove(1), []);
+        assert_eq!(stack.remove(0), []);
+    }
+
+    #[test]
+    fn backspace_erases_the_cells_a_character_covered() {
+        let mut line = "a本e\u{301}🎉".to_string();
+        assert_eq!(erase_last(&mut line), "\x08 \x08".repeat(2));
+        assert_eq!(line, "a本e\u{301}");
+        assert_eq!(erase_last(&mut line), "", "combining marks take no cell");
+        assert_eq!(erase_last(&mut line), "\x08 \x08");
+        assert_eq!(erase_last(&mut line), "\x08 \x08".repeat(2));
+        assert_eq!(erase_last(&mut line), "\x08 \x08");
+        assert_eq!(line, "");
+        assert_eq!(erase_last(&mut line), "");
+    }
+}
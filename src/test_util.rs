@@ -0,0 +1,51 @@
+//! Fixtures shared by the unit tests of several modules.
+
+use crate::crypto::{self, CipherState, CryptoError, Handshake, Incoming, Role};
+use crate::identity;
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// An empty directory of its own for each test.
+pub(crate) fn scratch_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sandesh-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Both ends of a loopback chat that finished its handshake, initiator
+/// first, non-blocking as the chat window keeps them.
+pub(crate) fn connected() -> ((TcpStream, Handshake), (TcpStream, Handshake)) {
+    identity::init_for_tests();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let initiator = thread::spawn(move || {
+        let stream = TcpStream::connect(addr).unwrap();
+        let handshake = crypto::perform_handshake(&stream, Role::Initiator, None).unwrap();
+        (stream, handshake)
+    });
+    let (stream, _) = listener.accept().unwrap();
+    let handshake = crypto::perform_handshake(&stream, Role::Responder, None).unwrap();
+    let (other, other_handshake) = initiator.join().unwrap();
+    stream.set_nonblocking(true).unwrap();
+    other.set_nonblocking(true).unwrap();
+    ((other, other_handshake), (stream, handshake))
+}
+
+/// The next frame on one end of `connected`, waiting for it to arrive.
+pub(crate) fn receive(
+    stream: &mut TcpStream,
+    state: &mut CipherState,
+) -> Result<Incoming, CryptoError> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        if let Some(incoming) = crypto::receive_and_decrypt(stream, state)? {
+            return Ok(incoming);
+        }
+        assert!(Instant::now() < deadline, "no frame arrived");
+        thread::sleep(Duration::from_millis(1));
+    }
+}
@@ -0,0 +1,798 @@
+use crate::identity::{decode_hex, encode_hex};
+use crate::storage;
+use crate::vault;
+use blake2::Blake2bMac;
+use blake2::digest::consts::U16;
+use blake2::digest::{KeyInit, Mac};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, Utc};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use zeroize::Zeroizing;
+
+// Off unless started with `--log`; `/log on` turns it on for one chat.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+const HISTORY_DIR: &str = "history";
+const HEAD_SUFFIX: &str = "head";
+const MARKS_SUFFIX: &str = "marks";
+const TAG_LEN: usize = 16;
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%SZ";
+const TIMESTAMP_LEN: usize = "YYYY-MM-DD HH:MM:SSZ".len();
+
+/// Where each open log's chain ends, shared so two chats with the same
+/// address extend one chain rather than forking it.
+static CHAINS: Mutex<Vec<(PathBuf, Chain)>> = Mutex::new(Vec::new());
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// A peer's plaintext chat log, `~/.sandesh/history/<peer ip>.log`. Only
+/// lines that were decrypted (or typed) here end up in it, never anything
+/// from the wire.
+///
+/// While the keystore is encrypted, each line also carries a MAC over the
+/// previous line's MAC and its own text, under `vault::history_key`, and a
+/// sealed `<peer ip>.head` file records how many lines are chained and the
+/// last MAC. Changing, inserting or removing a line breaks the chain from
+/// there on, and cutting lines off the end leaves fewer than the head
+/// says; see `verify`.
+pub struct Transcript {
+    file: File,
+    path: PathBuf,
+    key: Option<Zeroizing<[u8; 32]>>,
+    integrity: Option<Integrity>,
+}
+
+impl Transcript {
+    /// Opens the peer's log for appending, creating it (readable by the
+    /// owner only, on Unix) if this is the first chat with them. A chained
+    /// log is checked first.
+    pub fn open(peer: IpAddr) -> io::Result<Self> {
+        let path = log_path(peer)?;
+        fs::create_dir_all(path.parent().expect("logs are kept in a folder"))?;
+        let key = vault::history_key();
+        let integrity = match &key {
+            Some(key) => {
+                let (integrity, chain) = check_file(&path, key)?;
+                let mut chains = CHAINS.lock().unwrap();
+                chains.retain(|(open, _)| *open != path);
+                chains.push((path.clone(), chain));
+                Some(integrity)
+            }
+            None => None,
+        };
+
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = options.open(&path)?;
+        Ok(Transcript {
+            file,
+            path,
+            key,
+            integrity,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// What checking the chain found when the log was opened; `None` if it
+    /// isn't chained.
+    pub fn integrity(&self) -> Option<&Integrity> {
+        self.integrity.as_ref()
+    }
+
+    /// Appends `speaker: text` with a UTC timestamp, and returns where the
+    /// line starts in the log, which is how `Marks` know it. Each line goes
+    /// to the file in a single unbuffered write, so a crash loses nothing
+    /// already recorded. Control characters are escaped so a message can't
+    /// forge extra lines.
+    pub fn record(&mut self, speaker: &str, text: &str) -> io::Result<u64> {
+        let record = format!("{} {}: {}", timestamp(), speaker, escape_controls(text));
+        let Some(key) = &self.key else {
+            return self.append(&format!("{}\n", record));
+        };
+
+        let mut chains = CHAINS.lock().unwrap();
+        let chain = match chains.iter_mut().find(|(open, _)| *open == self.path) {
+            Some((_, chain)) => chain,
+            None => {
+                chains.push((self.path.clone(), Chain::default()));
+                &mut chains.last_mut().expect("just pushed").1
+            }
+        };
+        let tag = chain_tag(key, &chain.tag, &record);
+        let start = self.append(&format!("{}\t{}\n", record, encode_hex(&tag)))?;
+        chain.records += 1;
+        chain.tag = tag;
+        vault::write(&head_path(&self.path), &chain.render())?;
+        Ok(start)
+    }
+
+    /// Writes `line` at the end of the log and returns where it starts.
+    fn append(&mut self, line: &str) -> io::Result<u64> {
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+        Ok(self.file.metadata()?.len() - line.len() as u64)
+    }
+}
+
+/// Bookmarks and private notes on lines of a peer's log, kept beside it in
+/// `<peer ip>.marks`, sealed like the head file while the keystore is
+/// encrypted. They never leave this machine. A line is known by where it
+/// starts in the log, which only ever grows, so the marks stay put as
+/// later chats add to it.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Marks {
+    bookmarks: BTreeSet<u64>,
+    notes: BTreeMap<u64, String>,
+}
+
+impl Marks {
+    /// The marks on the log kept for `peer`; none if there is no file yet.
+    pub fn load(peer: IpAddr) -> io::Result<Self> {
+        Marks::load_from(&marks_path(&log_path(peer)?))
+    }
+
+    fn load_from(path: &Path) -> io::Result<Self> {
+        match vault::read(path) {
+            Ok(contents) => Ok(Marks::parse(&contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Marks::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, peer: IpAddr) -> io::Result<()> {
+        self.save_to(&marks_path(&log_path(peer)?))
+    }
+
+    fn save_to(&self, path: &Path) -> io::Result<()> {
+        vault::write(path, &self.render())
+    }
+
+    pub fn is_bookmarked(&self, line: u64) -> bool {
+        self.bookmarks.contains(&line)
+    }
+
+    /// Bookmarks the line starting at `line`, or takes the bookmark off if
+    /// it had one. Returns whether it is bookmarked now.
+    pub fn toggle(&mut self, line: u64) -> bool {
+        if self.bookmarks.remove(&line) {
+            return false;
+        }
+        self.bookmarks.insert(line);
+        true
+    }
+
+    /// Where each bookmarked line starts, in the order they were logged.
+    pub fn bookmarks(&self) -> impl Iterator<Item = u64> + '_ {
+        self.bookmarks.iter().copied()
+    }
+
+    /// The first bookmark logged after `line`, or with none after it (or
+    /// no `line`) the first of all, so repeated jumps go round them.
+    pub fn next_bookmark(&self, line: Option<u64>) -> Option<u64> {
+        line.and_then(|line| self.bookmarks.range(line + 1..).next())
+            .or_else(|| self.bookmarks.first())
+            .copied()
+    }
+
+    pub fn note(&self, line: u64) -> Option<&str> {
+        self.notes.get(&line).map(String::as_str)
+    }
+
+    /// Puts `text` under the line, in place of any note it had; empty
+    /// `text` removes the note.
+    pub fn set_note(&mut self, line: u64, text: &str) {
+        if text.is_empty() {
+            self.notes.remove(&line);
+        } else {
+            self.notes.insert(line, escape_controls(text));
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut contents = String::new();
+        for line in &self.bookmarks {
+            contents.push_str(&format!("bookmark {}\n", line));
+        }
+        for (line, text) in &self.notes {
+            contents.push_str(&format!("note {} {}\n", line, text));
+        }
+        contents
+    }
+
+    /// Lines that don't parse are skipped rather than losing the rest.
+    fn parse(contents: &str) -> Marks {
+        let mut marks = Marks::default();
+        for line in contents.lines() {
+            let mut fields = line.splitn(3, ' ');
+            match (fields.next(), fields.next().and_then(|n| n.parse().ok())) {
+                (Some("bookmark"), Some(at)) => {
+                    marks.bookmarks.insert(at);
+                }
+                (Some("note"), Some(at)) => {
+                    if let Some(text) = fields.next().filter(|text| !text.is_empty()) {
+                        marks.notes.insert(at, text.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        marks
+    }
+}
+
+/// What checking a chained transcript found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Integrity {
+    /// Every chained line checks out. The `unchained` lines before them
+    /// were written before the keystore was encrypted, so nothing vouches
+    /// for those.
+    Verified { records: usize, unchained: usize },
+    /// The line with this number, counted from 1, was changed, or a line
+    /// just before it was removed or inserted.
+    BrokenAt { line: usize },
+    /// The log ends after `records` of the `expected` lines chained into it.
+    Truncated { records: usize, expected: usize },
+    /// The lines check out, but the head file is gone, so lines cut off
+    /// the end would go unnoticed.
+    NoHead { records: usize },
+}
+
+impl Integrity {
+    pub fn is_intact(&self) -> bool {
+        matches!(self, Integrity::Verified { .. })
+    }
+}
+
+impl fmt::Display for Integrity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Integrity::Verified {
+                records,
+                unchained: 0,
+            } => write!(
+                f,
+                "History integrity verified ({} records)",
+                count(*records)
+            ),
+            Integrity::Verified { records, unchained } => write!(
+                f,
+                "History integrity verified ({} records; {} older ones predate encryption and can't be checked)",
+                count(*records),
+                count(*unchained)
+            ),
+            Integrity::BrokenAt { line } => write!(
+                f,
+                "History was tampered with: line {} was changed, or one just before it removed or inserted",
+                count(*line)
+            ),
+            Integrity::Truncated { records, expected } => write!(
+                f,
+                "History was cut short: it ends after {} of {} records",
+                count(*records),
+                count(*expected)
+            ),
+            Integrity::NoHead { records } => write!(
+                f,
+                "History records check out ({}), but its head file is missing, so lines cut off the end can't be detected",
+                count(*records)
+            ),
+        }
+    }
+}
+
+/// Checks the chain of the log kept for `peer`. `None` if the keystore
+/// isn't encrypted, so there is no chain to check.
+pub fn verify(peer: IpAddr) -> io::Result<Option<Integrity>> {
+    let Some(key) = vault::history_key() else {
+        return Ok(None);
+    };
+    let path = log_path(peer)?;
+    if !path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no transcript at {}", path.display()),
+        ));
+    }
+    check_file(&path, &key).map(|(integrity, _)| Some(integrity))
+}
+
+/// A peer's log opened for reading a few lines at a time. Only where each
+/// line starts is kept, so a long history costs eight bytes a line until
+/// it is read.
+pub struct Reader {
+    file: BufReader<File>,
+    starts: Vec<u64>,
+}
+
+/// One line of a log.
+pub struct Entry {
+    /// `None` if the line doesn't start with a timestamp.
+    pub at: Option<DateTime<Utc>>,
+    /// `You` or `They`.
+    pub speaker: String,
+    /// As recorded, with control characters still escaped.
+    pub text: String,
+}
+
+impl Reader {
+    pub fn open(peer: IpAddr) -> io::Result<Self> {
+        Reader::index(File::open(log_path(peer)?)?)
+    }
+
+    fn index(file: File) -> io::Result<Self> {
+        let mut file = BufReader::new(file);
+        let mut starts = vec![0];
+        let mut at = 0;
+        loop {
+            let buf = file.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            for (i, byte) in buf.iter().enumerate() {
+                if *byte == b'\n' {
+                    starts.push(at + i as u64 + 1);
+                }
+            }
+            let read = buf.len();
+            at += read as u64;
+            file.consume(read);
+        }
+        // Past the final newline, or in an empty file, no line starts.
+        if starts.last() == Some(&at) {
+            starts.pop();
+        }
+        Ok(Reader { file, starts })
+    }
+
+    pub fn len(&self) -> usize {
+        self.starts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.starts.is_empty()
+    }
+
+    /// Where line `n` starts in the log.
+    pub fn start(&self, n: usize) -> u64 {
+        self.starts[n]
+    }
+
+    /// The number of the line starting at byte `start`, if one does.
+    pub fn line_at(&self, start: u64) -> Option<usize> {
+        self.starts.binary_search(&start).ok()
+    }
+
+    /// Line `n`, counted from 0.
+    pub fn entry(&mut self, n: usize) -> io::Result<Entry> {
+        self.file.seek(SeekFrom::Start(self.starts[n]))?;
+        let mut line = String::new();
+        self.file.read_line(&mut line)?;
+        Ok(Entry::parse(line.trim_end_matches('\n')))
+    }
+
+    /// The first line timestamped on `date` or later, in local time.
+    /// Lines are in the order they were written, so this is a binary
+    /// search; lines without a timestamp count as earlier.
+    pub fn first_on(&mut self, date: NaiveDate) -> io::Result<Option<usize>> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            let on_or_after = self
+                .entry(mid)?
+                .at
+                .is_some_and(|at| at.with_timezone(&Local).date_naive() >= date);
+            if on_or_after {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        Ok((low < self.len()).then_some(low))
+    }
+}
+
+impl Entry {
+    fn parse(line: &str) -> Entry {
+        let record = line.split_once('\t').map_or(line, |(record, _)| record);
+        let stamped = record
+            .get(..TIMESTAMP_LEN)
+            .and_then(|stamp| NaiveDateTime::parse_from_str(stamp, TIMESTAMP_FORMAT).ok())
+            .zip(record.get(TIMESTAMP_LEN + 1..));
+        let (at, rest) = match stamped {
+            Some((at, rest)) => (Some(at.and_utc()), rest),
+            None => (None, record),
+        };
+        let (speaker, text) = rest.split_once(": ").unwrap_or(("", rest));
+        Entry {
+            at,
+            speaker: speaker.to_string(),
+            text: text.to_string(),
+        }
+    }
+}
+
+fn log_path(peer: IpAddr) -> io::Result<PathBuf> {
+    let dir = storage::data_file(HISTORY_DIR).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "no home directory to keep transcripts in",
+        )
+    })?;
+    // IPv6 colons aren't allowed in file names everywhere.
+    Ok(dir.join(format!("{}.log", peer.to_string().replace(':', "_"))))
+}
+
+fn head_path(log: &Path) -> PathBuf {
+    log.with_extension(HEAD_SUFFIX)
+}
+
+fn marks_path(log: &Path) -> PathBuf {
+    log.with_extension(MARKS_SUFFIX)
+}
+
+/// `text` with control characters escaped, so it stays on one line.
+fn escape_controls(text: &str) -> String {
+    let mut clean = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_control() {
+            clean.extend(c.escape_default());
+        } else {
+            clean.push(c);
+        }
+    }
+    clean
+}
+
+/// Where a chain ends: how many lines are in it and the last one's MAC.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Chain {
+    records: usize,
+    tag: [u8; TAG_LEN],
+}
+
+impl Chain {
+    fn render(&self) -> String {
+        format!("records {} {}\n", self.records, encode_hex(&self.tag))
+    }
+
+    fn parse(contents: &str) -> Option<Chain> {
+        let fields: Vec<&str> = contents.split_whitespace().collect();
+        let ["records", records, tag] = fields.as_slice() else {
+            return None;
+        };
+        Some(Chain {
+            records: records.parse().ok()?,
+            tag: decode_hex(tag)?.try_into().ok()?,
+        })
+    }
+}
+
+/// Checks the log at `path` against its head file, and returns where its
+/// chain ends so new lines carry on from there. A missing log is an empty
+/// one.
+fn check_file(path: &Path, key: &[u8; 32]) -> io::Result<(Integrity, Chain)> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let head = match vault::read(&head_path(path)) {
+        Ok(head) => Some(Chain::parse(&head).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a valid head file", head_path(path).display()),
+            )
+        })?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    Ok(check(&contents, key, head))
+}
+
+fn check(contents: &str, key: &[u8; 32], head: Option<Chain>) -> (Integrity, Chain) {
+    let mut chain = Chain::default();
+    let mut unchained = 0;
+    let mut broken = None;
+    // What the chain was `head.records` lines in, to compare with the head.
+    let mut at_head = (head.map(|head| head.records) == Some(0)).then_some(chain);
+    for (n, line) in contents.lines().enumerate() {
+        let Some((record, tag)) = line.split_once('\t') else {
+            if chain.records == 0 {
+                unchained += 1;
+            } else {
+                broken.get_or_insert(n + 1);
+            }
+            continue;
+        };
+        let tag: [u8; TAG_LEN] = decode_hex(tag)
+            .and_then(|tag| tag.try_into().ok())
+            .unwrap_or_default();
+        if tag != chain_tag(key, &chain.tag, record) {
+            broken.get_or_insert(n + 1);
+        }
+        chain.records += 1;
+        chain.tag = tag;
+        if head.map(|head| head.records) == Some(chain.records) {
+            at_head = Some(chain);
+        }
+    }
+
+    let integrity = match (broken, head) {
+        (Some(line), _) => Integrity::BrokenAt { line },
+        (None, None) if chain.records > 0 => Integrity::NoHead {
+            records: chain.records,
+        },
+        (None, Some(head)) if at_head.is_none() => Integrity::Truncated {
+            records: chain.records,
+            expected: head.records,
+        },
+        // Lines that verify can't end where the head says with another
+        // MAC, short of the head belonging to another log.
+        (None, Some(head)) if at_head != Some(head) => Integrity::BrokenAt {
+            line: unchained + head.records,
+        },
+        _ => Integrity::Verified {
+            records: chain.records,
+            unchained,
+        },
+    };
+    (integrity, chain)
+}
+
+fn chain_tag(key: &[u8; 32], previous: &[u8; TAG_LEN], record: &str) -> [u8; TAG_LEN] {
+    let mut mac =
+        <Blake2bMac<U16> as KeyInit>::new_from_slice(key).expect("32-byte keys are valid");
+    mac.update(previous);
+    mac.update(record.as_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+/// `1204` as `1,204`.
+fn count(n: usize) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+/// `YYYY-MM-DD HH:MM:SSZ` in UTC.
+fn timestamp() -> String {
+    Utc::now().format(TIMESTAMP_FORMAT).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+
+    const KEY: [u8; 32] = [3; 32];
+
+    /// A chained log of `n` lines, with the head it would have.
+    fn chained(n: usize) -> (Vec<String>, Chain) {
+        let mut chain = Chain::default();
+        let lines = (0..n)
+            .map(|i| {
+                let record = format!("2026-10-15 10:00:{:02}Z you: line {}", i, i);
+                chain.tag = chain_tag(&KEY, &chain.tag, &record);
+                chain.records += 1;
+                format!("{}\t{}", record, encode_hex(&chain.tag))
+            })
+            .collect();
+        (lines, chain)
+    }
+
+    fn check_lines(lines: &[String], head: Option<Chain>) -> Integrity {
+        check(&lines.join("\n"), &KEY, head).0
+    }
+
+    #[test]
+    fn untouched_log_verifies() {
+        let (mut lines, head) = chained(1204);
+        lines.insert(0, "2026-01-01 09:00:00Z you: from before".to_string());
+        let integrity = check_lines(&lines, Some(head));
+        assert_eq!(
+            integrity,
+            Integrity::Verified {
+                records: 1204,
+                unchained: 1
+            }
+        );
+        assert!(integrity.to_string().contains("1,204 records"));
+    }
+
+    #[test]
+    fn edited_middle_record_is_found() {
+        let (mut lines, head) = chained(10);
+        lines[4] = lines[4].replace("line 4", "line four");
+        assert_eq!(
+            check_lines(&lines, Some(head)),
+            Integrity::BrokenAt { line: 5 }
+        );
+    }
+
+    #[test]
+    fn removed_and_inserted_records_are_found() {
+        let (lines, head) = chained(10);
+        let mut removed = lines.clone();
+        removed.remove(6);
+        assert_eq!(
+            check_lines(&removed, Some(head)),
+            Integrity::BrokenAt { line: 7 }
+        );
+
+        let mut inserted = lines.clone();
+        inserted.insert(3, "2026-10-15 10:00:02Z peer: never said".to_string());
+        assert_eq!(
+            check_lines(&inserted, Some(head)),
+            Integrity::BrokenAt { line: 4 }
+        );
+        // A copy of a real line, MAC and all, doesn't fit anywhere else.
+        let mut replayed = lines;
+        replayed.insert(8, replayed[2].clone());
+        assert_eq!(
+            check_lines(&replayed, Some(head)),
+            Integrity::BrokenAt { line: 9 }
+        );
+    }
+
+    #[test]
+    fn truncation_is_found() {
+        let (mut lines, head) = chained(10);
+        lines.truncate(7);
+        assert_eq!(
+            check_lines(&lines, Some(head)),
+            Integrity::Truncated {
+                records: 7,
+                expected: 10
+            }
+        );
+        assert_eq!(check_lines(&lines, None), Integrity::NoHead { records: 7 });
+    }
+
+    #[test]
+    fn chain_from_another_key_is_rejected() {
+        let (lines, head) = chained(3);
+        let (integrity, _) = check(&lines.join("\n"), &[4; 32], Some(head));
+        assert_eq!(integrity, Integrity::BrokenAt { line: 1 });
+    }
+
+    fn reader_of(name: &str, contents: &str) -> Reader {
+        let dir = scratch_dir(&format!("reader-{}", name));
+        fs::write(dir.join("peer.log"), contents).unwrap();
+        let reader = Reader::index(File::open(dir.join("peer.log")).unwrap()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        reader
+    }
+
+    #[test]
+    fn reader_indexes_lines_with_or_without_a_final_newline() {
+        let (lines, _) = chained(3);
+        let log = lines.join("\n");
+        assert_eq!(reader_of("bare", &log).len(), 3);
+        let mut reader = reader_of("ended", &format!("{}\n", log));
+        assert_eq!(reader.len(), 3);
+        let last = reader.entry(2).unwrap();
+        assert_eq!(last.speaker, "you");
+        assert_eq!(last.text, "line 2");
+        assert_eq!(last.at.unwrap().to_rfc3339(), "2026-10-15T10:00:02+00:00");
+        assert!(reader_of("empty", "").is_empty());
+    }
+
+    #[test]
+    fn unstamped_lines_are_kept_whole() {
+        let mut reader = reader_of("odd", "not a record: at all\n");
+        let entry = reader.entry(0).unwrap();
+        assert!(entry.at.is_none());
+        assert_eq!(entry.speaker, "not a record");
+        assert_eq!(entry.text, "at all");
+    }
+
+    #[test]
+    fn reader_finds_lines_by_where_they_start() {
+        let reader = reader_of("starts", "first\nsecond\nthird\n");
+        assert_eq!(reader.start(2), 13);
+        assert_eq!(reader.line_at(13), Some(2));
+        assert_eq!(reader.line_at(0), Some(0));
+        // Inside a line, or past the end.
+        assert_eq!(reader.line_at(7), None);
+        assert_eq!(reader.line_at(19), None);
+    }
+
+    #[test]
+    fn marks_survive_a_save_and_load() {
+        let dir = scratch_dir("marks");
+        let path = dir.join("peer.marks");
+        assert_eq!(Marks::load_from(&path).unwrap(), Marks::default());
+
+        let mut marks = Marks::default();
+        assert!(marks.toggle(120));
+        assert!(marks.toggle(0));
+        marks.set_note(120, "the fix\nis here");
+        marks.set_note(64, "not bookmarked, still noted");
+        marks.save_to(&path).unwrap();
+
+        let loaded = Marks::load_from(&path).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(loaded, marks);
+        assert_eq!(loaded.bookmarks().collect::<Vec<_>>(), [0, 120]);
+        assert_eq!(loaded.note(120), Some("the fix\\nis here"));
+        assert_eq!(loaded.note(64), Some("not bookmarked, still noted"));
+        assert!(!loaded.is_bookmarked(64));
+    }
+
+    #[test]
+    fn marks_come_off_again() {
+        let mut marks = Marks::default();
+        marks.toggle(5);
+        marks.set_note(5, "gone soon");
+        assert!(!marks.toggle(5));
+        marks.set_note(5, "");
+        assert_eq!(marks, Marks::default());
+        assert_eq!(marks.render(), "");
+        // Junk in the file costs only its own line.
+        let parsed = Marks::parse("bookmark x\nnote 7\nstar 9\nbookmark 3\n");
+        assert_eq!(parsed.bookmarks().collect::<Vec<_>>(), [3]);
+        assert_eq!(parsed.note(7), None);
+    }
+
+    #[test]
+    fn bookmark_jumps_go_round_in_log_order() {
+        let mut marks = Marks::default();
+        assert_eq!(marks.next_bookmark(None), None);
+        for line in [300, 10, 95] {
+            marks.toggle(line);
+        }
+        let mut at = None;
+        let jumps: Vec<u64> = (0..4)
+            .map(|_| {
+                at = marks.next_bookmark(at);
+                at.unwrap()
+            })
+            .collect();
+        assert_eq!(jumps, [10, 95, 300, 10]);
+        // From an unmarked line, the next one down.
+        assert_eq!(marks.next_bookmark(Some(50)), Some(95));
+        assert_eq!(marks.next_bookmark(Some(301)), Some(10));
+    }
+
+    #[test]
+    fn first_on_finds_the_first_line_of_a_day() {
+        // Midday stamps, so the local date is the same in every zone
+        // within twelve hours of UTC.
+        let log = [
+            "2026-10-13 12:00:00Z You: monday",
+            "2026-10-14 11:00:00Z They: tuesday",
+            "2026-10-14 12:00:00Z You: still tuesday",
+            "2026-10-16 12:00:00Z They: thursday",
+        ]
+        .join("\n");
+        let mut reader = reader_of("days", &log);
+        let day = |d| NaiveDate::from_ymd_opt(2026, 10, d).unwrap();
+        assert_eq!(reader.first_on(day(1)).unwrap(), Some(0));
+        assert_eq!(reader.first_on(day(14)).unwrap(), Some(1));
+        assert_eq!(reader.first_on(day(15)).unwrap(), Some(3));
+        assert_eq!(reader.first_on(day(17)).unwrap(), None);
+    }
+}
@@ -0,0 +1,931 @@
+use crate::identity::{decode_hex, encode_hex};
+use crate::protocol::{FILE_CHUNK_LEN, FILE_DIGEST_LEN, MAX_FILE_NAME_LEN};
+use crate::storage;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const DOWNLOADS_DIR: &str = "downloads";
+const SETTINGS_FILE: &str = "download-settings";
+const PART_SUFFIX: &str = ".part";
+const RESUME_SUFFIX: &str = ".resume";
+/// How often an incoming file's `.resume` file is brought up to date.
+const CHECKPOINT_BYTES: u64 = 1024 * 1024;
+
+/// What happens to a received file whose name is already taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Collision {
+    /// Saved as `name (1)`, `name (2)`, ... instead.
+    Rename,
+    /// Replaces the file; the offer says so, and accepting confirms it.
+    Overwrite,
+    /// The offer is declined.
+    Reject,
+}
+
+impl Collision {
+    pub fn parse(name: &str) -> Option<Collision> {
+        match name {
+            "rename" => Some(Collision::Rename),
+            "overwrite" => Some(Collision::Overwrite),
+            "reject" => Some(Collision::Reject),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Collision::Rename => "rename",
+            Collision::Overwrite => "overwrite",
+            Collision::Reject => "reject",
+        }
+    }
+}
+
+/// Where received files go. Persisted to `~/.sandesh/download-settings`
+/// as optional `dir <path>`, `per-peer on` and `collisions <policy>`
+/// lines.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DownloadSettings {
+    /// `None` for `~/.sandesh/downloads`.
+    pub dir: Option<PathBuf>,
+    /// Whether each peer's files go in a folder of their own, named like
+    /// the chat tab.
+    pub per_peer: bool,
+    pub collision: Collision,
+}
+
+impl DownloadSettings {
+    const DEFAULT: DownloadSettings = DownloadSettings {
+        dir: None,
+        per_peer: false,
+        collision: Collision::Rename,
+    };
+
+    /// Unknown and malformed lines are skipped.
+    fn parse(contents: &str) -> DownloadSettings {
+        let mut settings = DownloadSettings::DEFAULT;
+        for line in contents.lines() {
+            match line.split_once(' ') {
+                Some(("dir", path)) if !path.is_empty() => settings.dir = Some(path.into()),
+                Some(("per-peer", on)) => settings.per_peer = on == "on",
+                Some(("collisions", policy)) => {
+                    if let Some(policy) = Collision::parse(policy) {
+                        settings.collision = policy;
+                    }
+                }
+                _ => {}
+            }
+        }
+        settings
+    }
+
+    fn render(&self) -> String {
+        let mut contents = String::new();
+        if let Some(dir) = &self.dir {
+            contents.push_str(&format!("dir {}\n", dir.display()));
+        }
+        if self.per_peer {
+            contents.push_str("per-peer on\n");
+        }
+        contents.push_str(&format!("collisions {}\n", self.collision.name()));
+        contents
+    }
+
+    /// The folder files from the peer shown as `peer_label` are saved in,
+    /// or `None` without a home directory to put the default one in.
+    fn folder(&self, peer_label: &str) -> Option<PathBuf> {
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => storage::data_file(DOWNLOADS_DIR)?,
+        };
+        Some(if self.per_peer {
+            dir.join(safe_name(peer_label))
+        } else {
+            dir
+        })
+    }
+}
+
+/// Global because chat tabs pick it up with each offer, and `set downloads`
+/// changes it at the prompt.
+static SETTINGS: Mutex<DownloadSettings> = Mutex::new(DownloadSettings::DEFAULT);
+
+/// Loads the download settings at startup. A missing or unreadable file
+/// leaves the defaults.
+pub fn load_settings() {
+    let contents = storage::data_file(SETTINGS_FILE).and_then(|p| fs::read_to_string(p).ok());
+    *SETTINGS.lock().unwrap() = DownloadSettings::parse(contents.as_deref().unwrap_or_default());
+}
+
+pub fn settings() -> DownloadSettings {
+    SETTINGS.lock().unwrap().clone()
+}
+
+/// Applies `change` to the settings and saves them.
+pub fn update_settings(change: impl FnOnce(&mut DownloadSettings)) -> io::Result<()> {
+    let mut settings = SETTINGS.lock().unwrap();
+    change(&mut settings);
+    let path = storage::data_file(SETTINGS_FILE).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "no home directory to store settings in",
+        )
+    })?;
+    storage::write_atomic(&path, &settings.render())
+}
+
+/// Checks that `path` is a directory files can be saved in, by creating
+/// and removing a file there, and returns it made absolute.
+pub fn check_downloads_dir(path: &Path) -> io::Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+    if !fs::metadata(&path)?.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a directory",
+        ));
+    }
+    let probe = path.join(format!(".sandesh-probe-{}", std::process::id()));
+    File::create(&probe)?;
+    fs::remove_file(&probe)?;
+    Ok(path)
+}
+
+/// Where a file being sent is read from: the file itself, or data held
+/// in memory such as a pasted image.
+trait Source: Read + Seek {}
+
+impl<T: Read + Seek> Source for T {}
+
+/// A file offered to the peer. It is read once to hash it for the offer,
+/// then again chunk by chunk once they accept.
+pub struct Sending {
+    source: Box<dyn Source>,
+    pub name: String,
+    pub size: u64,
+    pub sha256: [u8; FILE_DIGEST_LEN],
+    sent: u64,
+    accepted: bool,
+}
+
+impl Sending {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Not a regular file",
+            ));
+        }
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .filter(|name| name.len() <= MAX_FILE_NAME_LEN)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "File name missing or too long")
+            })?;
+        let mut hasher = Sha256::new();
+        io::copy(&mut (&mut file).take(metadata.len()), &mut hasher)?;
+        file.rewind()?;
+        Ok(Sending {
+            source: Box::new(file),
+            name,
+            size: metadata.len(),
+            sha256: hasher.finalize().into(),
+            sent: 0,
+            accepted: false,
+        })
+    }
+
+    /// Offers `data` under `name` without it being a file on disk.
+    pub fn from_bytes(name: String, data: Vec<u8>) -> Self {
+        Sending {
+            name,
+            size: data.len() as u64,
+            sha256: Sha256::digest(&data).into(),
+            source: Box::new(io::Cursor::new(data)),
+            sent: 0,
+            accepted: false,
+        }
+    }
+
+    /// Starts sending from `offset`, where the peer's interrupted download
+    /// of this file ended, or 0.
+    pub fn accept(&mut self, offset: u64) -> io::Result<()> {
+        if offset > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Peer asked to resume past the end of the file",
+            ));
+        }
+        self.source.seek(SeekFrom::Start(offset))?;
+        self.sent = offset;
+        self.accepted = true;
+        Ok(())
+    }
+
+    pub fn is_accepted(&self) -> bool {
+        self.accepted
+    }
+
+    /// The offset and data of the next chunk, or `None` once the whole file
+    /// has gone out. A file that shrank since it was offered fails here.
+    pub fn next_chunk(&mut self) -> io::Result<Option<(u64, Vec<u8>)>> {
+        let left = self.size - self.sent;
+        if left == 0 {
+            return Ok(None);
+        }
+        let mut data = vec![0u8; left.min(FILE_CHUNK_LEN as u64) as usize];
+        self.source.read_exact(&mut data)?;
+        let offset = self.sent;
+        self.sent += data.len() as u64;
+        Ok(Some((offset, data)))
+    }
+
+    pub fn progress(&self) -> String {
+        format!("Sending {} {}", self.name, percent(self.sent, self.size))
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+}
+
+/// A file the peer offered. Once accepted it is written to a `.part` file
+/// next to where it will be saved, and only renamed into place when
+/// complete and matching the offered SHA-256. A `.resume` file beside it
+/// records how far it got, so a download cut off by a disconnect can pick
+/// up where it stopped when the same peer offers the file again. Any other
+/// unfinished download is deleted when this is dropped.
+pub struct Receiving {
+    /// Already made safe to use as a file name.
+    pub name: String,
+    pub size: u64,
+    sha256: [u8; FILE_DIGEST_LEN],
+    /// Fingerprint of the sender, which interrupted downloads are kept
+    /// under.
+    peer: String,
+    /// Where it gets saved, from the settings when it was offered.
+    folder: Option<PathBuf>,
+    collision: Collision,
+    hasher: Sha256,
+    received: u64,
+    /// An interrupted download of a file by this name from this peer.
+    partial: Option<Partial>,
+    target: Option<Target>,
+}
+
+struct Target {
+    file: File,
+    part: PathBuf,
+    path: PathBuf,
+    resume: PathBuf,
+}
+
+/// What a `.resume` file says about an interrupted download.
+struct Partial {
+    path: PathBuf,
+    size: u64,
+    sha256: [u8; FILE_DIGEST_LEN],
+    received: u64,
+    /// SHA-256 of the first `received` bytes, to tell whether the `.part`
+    /// file was changed since.
+    prefix: [u8; FILE_DIGEST_LEN],
+}
+
+/// How an offer relates to an earlier, interrupted download.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resume {
+    /// Nothing to resume.
+    Fresh,
+    /// Accepting carries on from this many bytes.
+    From(u64),
+    /// The sender's file changed since (size or SHA-256 differ), so the
+    /// download starts over.
+    Changed,
+}
+
+impl Receiving {
+    /// An offer from the peer with fingerprint `peer`, shown as
+    /// `peer_label`, which names its folder if each peer gets one.
+    pub fn offered(
+        name: &str,
+        size: u64,
+        sha256: [u8; FILE_DIGEST_LEN],
+        peer: &str,
+        peer_label: &str,
+    ) -> Self {
+        let settings = settings();
+        let folder = settings.folder(peer_label);
+        Receiving::new(name, size, sha256, peer, folder, settings.collision)
+    }
+
+    /// An offer to be saved in `folder` rather than where the settings
+    /// say, though still under their collision policy.
+    pub fn offered_into(
+        name: &str,
+        size: u64,
+        sha256: [u8; FILE_DIGEST_LEN],
+        peer: &str,
+        folder: &Path,
+    ) -> Self {
+        let collision = settings().collision;
+        Receiving::new(name, size, sha256, peer, Some(folder.into()), collision)
+    }
+
+    fn new(
+        name: &str,
+        size: u64,
+        sha256: [u8; FILE_DIGEST_LEN],
+        peer: &str,
+        folder: Option<PathBuf>,
+        collision: Collision,
+    ) -> Self {
+        let name = safe_name(name);
+        Receiving {
+            partial: folder
+                .as_deref()
+                .and_then(|folder| find_partial(folder, peer, &name)),
+            name,
+            size,
+            sha256,
+            peer: peer.to_string(),
+            folder,
+            collision,
+            hasher: Sha256::new(),
+            received: 0,
+            target: None,
+        }
+    }
+
+    pub fn resume(&self) -> Resume {
+        match &self.partial {
+            None => Resume::Fresh,
+            Some(partial) if partial.size == self.size && partial.sha256 == self.sha256 => {
+                Resume::From(partial.received)
+            }
+            Some(_) => Resume::Changed,
+        }
+    }
+
+    /// Whether a file by this name is already where it would be saved,
+    /// and the collision policy decides what happens to it. Never for a
+    /// download being resumed.
+    pub fn collides(&self) -> Option<Collision> {
+        let folder = self.folder.as_ref()?;
+        (self.partial.is_none() && folder.join(&self.name).exists()).then_some(self.collision)
+    }
+
+    /// Where it is saved unless the name is taken.
+    pub fn folder(&self) -> Option<&Path> {
+        self.folder.as_deref()
+    }
+
+    pub fn is_accepted(&self) -> bool {
+        self.target.is_some()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.is_accepted() && self.received == self.size
+    }
+
+    /// Starts writing and returns the offset the sender should start
+    /// from. An interrupted download of the same file is carried on if
+    /// its `.part` file still holds what was received; otherwise the file
+    /// goes in the downloads folder under a name the collision policy
+    /// picks, and the `.part` file is started beside it.
+    pub fn accept(&mut self) -> io::Result<u64> {
+        let resume = self.resume();
+        if let Some(partial) = self.partial.take() {
+            let part = part_path(&partial.path);
+            if let Ok(mut file) = OpenOptions::new().read(true).write(true).open(&part) {
+                let offset = match resume {
+                    Resume::From(received) if self.rehash_prefix(&mut file, &partial)? => received,
+                    _ => 0,
+                };
+                file.set_len(offset)?;
+                file.seek(SeekFrom::Start(offset))?;
+                self.received = offset;
+                self.target = Some(Target {
+                    file,
+                    part,
+                    resume: resume_path(&partial.path),
+                    path: partial.path,
+                });
+                self.checkpoint()?;
+                return Ok(offset);
+            }
+            let _ = fs::remove_file(resume_path(&partial.path));
+        }
+
+        let dir = self.folder.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "no home directory to save files in",
+            )
+        })?;
+        fs::create_dir_all(dir)?;
+        let path = target_path(dir, &self.name, self.collision)?;
+        let part = part_path(&path);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&part)?;
+        self.target = Some(Target {
+            file,
+            part,
+            resume: resume_path(&path),
+            path,
+        });
+        self.checkpoint()?;
+        Ok(0)
+    }
+
+    /// Hashes what the `.part` file holds of `partial`, and keeps the
+    /// hasher if it matches what was recorded.
+    fn rehash_prefix(&mut self, file: &mut File, partial: &Partial) -> io::Result<bool> {
+        if file.metadata()?.len() < partial.received {
+            return Ok(false);
+        }
+        let mut hasher = Sha256::new();
+        io::copy(&mut (&mut *file).take(partial.received), &mut hasher)?;
+        let matches = hasher.clone().finalize().as_slice() == partial.prefix;
+        if matches {
+            self.hasher = hasher;
+        }
+        Ok(matches)
+    }
+
+    /// Appends a chunk, which has to start where the last one ended and
+    /// stay within the offered size.
+    pub fn write_chunk(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let Some(target) = self.target.as_mut() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "File data before the transfer was accepted",
+            ));
+        };
+        if offset != self.received || data.len() as u64 > self.size - self.received {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "File data out of order or past the offered size",
+            ));
+        }
+        target.file.write_all(data)?;
+        self.hasher.update(data);
+        let before = self.received / CHECKPOINT_BYTES;
+        self.received += data.len() as u64;
+        if self.received / CHECKPOINT_BYTES != before {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Records how far the download got in its `.resume` file. Data past
+    /// the recorded point, written before a crash, is cut off on resume.
+    fn checkpoint(&self) -> io::Result<()> {
+        let Some(target) = &self.target else {
+            return Ok(());
+        };
+        let prefix: [u8; FILE_DIGEST_LEN] = self.hasher.clone().finalize().into();
+        storage::write_private(
+            &target.resume,
+            &format!(
+                "peer {}\nname {}\nsize {}\nsha256 {}\nreceived {}\nprefix {}\n",
+                self.peer,
+                self.name,
+                self.size,
+                encode_hex(&self.sha256),
+                self.received,
+                encode_hex(&prefix)
+            ),
+        )
+    }
+
+    /// Keeps the unfinished download for resuming, after the connection
+    /// dropped rather than either side stopping the transfer.
+    pub fn suspend(mut self) -> io::Result<()> {
+        self.checkpoint()?;
+        self.target = None;
+        Ok(())
+    }
+
+    /// Checks the file against the offered SHA-256, then moves it into
+    /// place and returns where it went. A file that doesn't match is
+    /// deleted.
+    pub fn finish(mut self) -> io::Result<PathBuf> {
+        let target = self
+            .target
+            .take()
+            .expect("finish is only called on an accepted transfer");
+        let _ = fs::remove_file(&target.resume);
+        let digest: [u8; FILE_DIGEST_LEN] = std::mem::take(&mut self.hasher).finalize().into();
+        let saved = if digest == self.sha256 {
+            target
+                .file
+                .sync_all()
+                .and_then(|_| fs::rename(&target.part, &target.path))
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "SHA-256 doesn't match the offer; file discarded",
+            ))
+        };
+        if saved.is_err() {
+            let _ = fs::remove_file(&target.part);
+        }
+        saved.map(|_| target.path)
+    }
+
+    pub fn progress(&self) -> String {
+        format!(
+            "Receiving {} {}",
+            self.name,
+            percent(self.received, self.size)
+        )
+    }
+
+    /// How far it got, for telling the user about an interrupted transfer.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+}
+
+impl Drop for Receiving {
+    fn drop(&mut self) {
+        if let Some(target) = self.target.take() {
+            drop(target.file);
+            let _ = fs::remove_file(&target.part);
+            let _ = fs::remove_file(&target.resume);
+        }
+    }
+}
+
+/// The interrupted download of `name` from `peer`, if its `.resume` file
+/// is in `dir`. Unreadable ones are ignored.
+fn find_partial(dir: &Path, peer: &str, name: &str) -> Option<Partial> {
+    fs::read_dir(dir).ok()?.flatten().find_map(|entry| {
+        let resume = entry.path();
+        let path = resume.to_str()?.strip_suffix(RESUME_SUFFIX)?;
+        let contents = fs::read_to_string(&resume).ok()?;
+        let field = |key: &str| {
+            contents
+                .lines()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix(' '))
+        };
+        if field("peer")? != peer || field("name")? != name {
+            return None;
+        }
+        Some(Partial {
+            path: PathBuf::from(path),
+            size: field("size")?.parse().ok()?,
+            sha256: decode_hex(field("sha256")?)?.try_into().ok()?,
+            received: field("received")?.parse().ok()?,
+            prefix: decode_hex(field("prefix")?)?.try_into().ok()?,
+        })
+    })
+}
+
+/// Names Windows keeps for devices, with or without an extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// The peer picks the name, so only its last path component is kept, with
+/// control characters dropped, characters Windows can't store replaced,
+/// no leading dots (no hidden files, no `..`) and no trailing ones, which
+/// Windows drops. Device names such as `CON` or `nul.txt` get a leading
+/// `_`.
+fn safe_name(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = base
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+    let cleaned = cleaned
+        .trim()
+        .trim_start_matches('.')
+        .trim_end_matches(['.', ' ']);
+    let stem = cleaned.split('.').next().unwrap_or_default();
+    if cleaned.is_empty() {
+        "download".to_string()
+    } else if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem.trim_end()))
+    {
+        format!("_{}", cleaned)
+    } else {
+        cleaned.to_string()
+    }
+}
+
+/// Where a new download of `name` goes in `dir`, by the collision policy.
+/// The name is never one a download in progress is using.
+fn target_path(dir: &Path, name: &str, collision: Collision) -> io::Result<PathBuf> {
+    let path = dir.join(name);
+    let in_progress = part_path(&path).exists() || resume_path(&path).exists();
+    match collision {
+        Collision::Rename => Ok(free_path(dir, name)),
+        Collision::Overwrite if !in_progress => Ok(path),
+        Collision::Reject if !in_progress && !path.exists() => Ok(path),
+        _ => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} is already in {}", name, dir.display()),
+        )),
+    }
+}
+
+/// `name`, or `name (1)`, `name (2)`, ... before the extension, whichever
+/// is the first not already taken by a file or a download in progress.
+fn free_path(dir: &Path, name: &str) -> PathBuf {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    (0..)
+        .map(|n| match n {
+            0 => dir.join(name),
+            n => dir.join(format!("{} ({}){}", stem, n, extension)),
+        })
+        .find(|path| !path.exists() && !part_path(path).exists() && !resume_path(path).exists())
+        .expect("some numbered name is free")
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    with_suffix(path, PART_SUFFIX)
+}
+
+fn resume_path(path: &Path) -> PathBuf {
+    with_suffix(path, RESUME_SUFFIX)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut with = path.as_os_str().to_owned();
+    with.push(suffix);
+    PathBuf::from(with)
+}
+
+pub fn percent(done: u64, total: u64) -> String {
+    match total {
+        0 => "100%".to_string(),
+        total => format!("{}%", u128::from(done) * 100 / u128::from(total)),
+    }
+}
+
+/// Human-readable byte count, e.g. `3.4 MiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{self, CipherState, Incoming};
+    use crate::protocol::FileMessage;
+    use crate::test_util::{connected, receive, scratch_dir};
+    use std::net::TcpStream;
+
+    #[test]
+    fn safe_name_keeps_only_a_plain_file_name() {
+        assert_eq!(safe_name("../../etc/passwd"), "passwd");
+        assert_eq!(safe_name("C:\\Users\\me\\report.pdf"), "report.pdf");
+        assert_eq!(safe_name(".bashrc"), "bashrc");
+        assert_eq!(safe_name(".."), "download");
+        assert_eq!(safe_name(""), "download");
+        assert_eq!(safe_name("a\u{1b}[2Jb\n.txt"), "a[2Jb.txt");
+        assert_eq!(safe_name("what?<now>*.txt"), "what__now__.txt");
+        assert_eq!(safe_name("notes.txt. . "), "notes.txt");
+    }
+
+    #[test]
+    fn safe_name_escapes_windows_device_names() {
+        assert_eq!(safe_name("CON"), "_CON");
+        assert_eq!(safe_name("nul.txt"), "_nul.txt");
+        assert_eq!(safe_name("Com1.tar.gz"), "_Com1.tar.gz");
+        assert_eq!(safe_name("lpt9 .log"), "_lpt9 .log");
+        assert_eq!(safe_name("console.txt"), "console.txt");
+        assert_eq!(safe_name("COM10"), "COM10");
+    }
+
+    #[test]
+    fn rename_picks_the_first_free_numbered_name() {
+        let dir = scratch_dir("rename");
+        assert_eq!(
+            target_path(&dir, "a.txt", Collision::Rename).unwrap(),
+            dir.join("a.txt")
+        );
+        fs::write(dir.join("a.txt"), "old").unwrap();
+        fs::write(dir.join("a (1).txt.part"), "").unwrap();
+        assert_eq!(
+            target_path(&dir, "a.txt", Collision::Rename).unwrap(),
+            dir.join("a (2).txt")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn overwrite_takes_the_name_unless_a_download_is_using_it() {
+        let dir = scratch_dir("overwrite");
+        fs::write(dir.join("a.txt"), "old").unwrap();
+        assert_eq!(
+            target_path(&dir, "a.txt", Collision::Overwrite).unwrap(),
+            dir.join("a.txt")
+        );
+        fs::write(dir.join("a.txt.part"), "").unwrap();
+        let err = target_path(&dir, "a.txt", Collision::Overwrite).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reject_refuses_a_taken_name() {
+        let dir = scratch_dir("reject");
+        assert_eq!(
+            target_path(&dir, "a.txt", Collision::Reject).unwrap(),
+            dir.join("a.txt")
+        );
+        fs::write(dir.join("a.txt"), "old").unwrap();
+        let err = target_path(&dir, "a.txt", Collision::Reject).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "old");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn download_settings_round_trip() {
+        assert_eq!(DownloadSettings::parse(""), DownloadSettings::DEFAULT);
+        let settings = DownloadSettings {
+            dir: Some(PathBuf::from("/srv/in box")),
+            per_peer: true,
+            collision: Collision::Reject,
+        };
+        assert_eq!(DownloadSettings::parse(&settings.render()), settings);
+        assert_eq!(
+            DownloadSettings::parse("collisions sideways\nper-peer maybe\nbogus\n"),
+            DownloadSettings::DEFAULT
+        );
+    }
+
+    #[test]
+    fn per_peer_folders_are_named_safely() {
+        let settings = DownloadSettings {
+            dir: Some(PathBuf::from("/srv/in")),
+            per_peer: true,
+            collision: Collision::Rename,
+        };
+        assert_eq!(
+            settings.folder("../riya").unwrap(),
+            Path::new("/srv/in").join("riya")
+        );
+        assert_eq!(
+            settings.folder("fe80::1").unwrap(),
+            Path::new("/srv/in").join("fe80__1")
+        );
+    }
+
+    #[test]
+    fn downloads_dir_must_be_a_writable_directory() {
+        let dir = scratch_dir("check");
+        assert!(check_downloads_dir(&dir).unwrap().is_absolute());
+        assert!(check_downloads_dir(&dir.join("missing")).is_err());
+        fs::write(dir.join("file"), "").unwrap();
+        assert!(check_downloads_dir(&dir.join("file")).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// The next file frame on `stream`.
+    fn next_file_message(stream: &mut TcpStream, cipher: &mut CipherState) -> FileMessage {
+        match receive(stream, cipher).unwrap() {
+            Incoming::File(message) => message,
+            _ => panic!("expected a file frame"),
+        }
+    }
+
+    /// Offers `data` from `a` to `b`, which saves it in `dir`, and returns
+    /// both ends once the sender has the receiver's accept.
+    fn offer_and_accept(
+        data: &[u8],
+        dir: &Path,
+        (a, a_cipher): (&mut TcpStream, &mut CipherState),
+        (b, b_cipher): (&mut TcpStream, &mut CipherState),
+    ) -> (Sending, Receiving, Resume) {
+        let mut sending = Sending::from_bytes("notes.txt".to_string(), data.to_vec());
+        let offer = FileMessage::Offer {
+            size: sending.size,
+            sha256: sending.sha256,
+            name: sending.name.clone(),
+        };
+        crypto::send_file_message(a, a_cipher, &offer).unwrap();
+        let FileMessage::Offer { size, sha256, name } = next_file_message(b, b_cipher) else {
+            panic!("expected the offer");
+        };
+        let mut receiving = Receiving::offered_into(&name, size, sha256, "peer-fp", dir);
+        let resume = receiving.resume();
+        let offset = receiving.accept().unwrap();
+        crypto::send_file_message(b, b_cipher, &FileMessage::Accept { offset }).unwrap();
+        let FileMessage::Accept { offset } = next_file_message(a, a_cipher) else {
+            panic!("expected the accept");
+        };
+        sending.accept(offset).unwrap();
+        (sending, receiving, resume)
+    }
+
+    /// Sends up to `chunks` chunks from `a` and writes them at `b`.
+    fn pass_chunks(
+        chunks: usize,
+        sending: &mut Sending,
+        receiving: &mut Receiving,
+        (a, a_cipher): (&mut TcpStream, &mut CipherState),
+        (b, b_cipher): (&mut TcpStream, &mut CipherState),
+    ) {
+        for _ in 0..chunks {
+            let Some((offset, data)) = sending.next_chunk().unwrap() else {
+                return;
+            };
+            crypto::send_file_message(a, a_cipher, &FileMessage::Chunk { offset, data }).unwrap();
+            let FileMessage::Chunk { offset, data } = next_file_message(b, b_cipher) else {
+                panic!("expected a chunk");
+            };
+            receiving.write_chunk(offset, &data).unwrap();
+        }
+    }
+
+    #[test]
+    fn loopback_transfer_resumes_after_a_drop() {
+        let dir = scratch_dir("loopback-transfer");
+        let data: Vec<u8> = (0..FILE_CHUNK_LEN * 3 + 100)
+            .map(|n| (n * 31 % 251) as u8)
+            .collect();
+
+        let ((mut a, mut a_hs), (mut b, mut b_hs)) = connected();
+        let (mut sending, mut receiving, resume) = offer_and_accept(
+            &data,
+            &dir,
+            (&mut a, &mut a_hs.cipher),
+            (&mut b, &mut b_hs.cipher),
+        );
+        assert_eq!(resume, Resume::Fresh);
+        pass_chunks(
+            2,
+            &mut sending,
+            &mut receiving,
+            (&mut a, &mut a_hs.cipher),
+            (&mut b, &mut b_hs.cipher),
+        );
+        // The connection drops halfway.
+        receiving.suspend().unwrap();
+        drop((a, b));
+        let kept = FILE_CHUNK_LEN as u64 * 2;
+        assert_eq!(
+            fs::metadata(dir.join("notes.txt.part")).unwrap().len(),
+            kept
+        );
+
+        let ((mut a, mut a_hs), (mut b, mut b_hs)) = connected();
+        let (mut sending, mut receiving, resume) = offer_and_accept(
+            &data,
+            &dir,
+            (&mut a, &mut a_hs.cipher),
+            (&mut b, &mut b_hs.cipher),
+        );
+        assert_eq!(resume, Resume::From(kept));
+        assert_eq!(sending.sent(), kept);
+        pass_chunks(
+            usize::MAX,
+            &mut sending,
+            &mut receiving,
+            (&mut a, &mut a_hs.cipher),
+            (&mut b, &mut b_hs.cipher),
+        );
+        assert!(receiving.is_complete());
+        let saved = receiving.finish().unwrap();
+
+        assert_eq!(saved, dir.join("notes.txt"));
+        assert_eq!(fs::read(&saved).unwrap(), data);
+        let mut left: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["notes.txt"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+use colored::*;
+use std::sync::OnceLock;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+pub const DEFAULT_URL: &str =
+    "https://api.github.com/repos/AkshatRai07/p2p_messaging/releases/latest";
+
+const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+const FETCH_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub struct Release {
+    pub version: String,
+    pub notes: String,
+}
+
+static LATEST: OnceLock<Release> = OnceLock::new();
+
+/// Fetches the release document on a background thread. The receiver gets
+/// one message if a newer release than this build was found; failures are
+/// silent so a missing network never shows up at the prompt.
+pub fn spawn_check(url: String) -> Receiver<()> {
+    let (tx, rx) = mpsc::channel();
+    // A check that can't start is treated like one that found nothing.
+    let _ = thread::Builder::new()
+        .name("update-check".to_string())
+        .spawn(move || {
+            if let Some(release) = fetch_latest(&url)
+                && is_newer(&release.version, CURRENT_VERSION)
+                && LATEST.set(release).is_ok()
+            {
+                let _ = tx.send(());
+            }
+        });
+    rx
+}
+
+/// The newer release found by `spawn_check`, if any.
+pub fn latest() -> Option<&'static Release> {
+    LATEST.get()
+}
+
+pub fn notice(release: &Release) -> String {
+    format!(
+        "Sandesh {} is available — you have {}. Type 'changelog' for details.",
+        release.version, CURRENT_VERSION
+    )
+}
+
+pub fn print_changelog() {
+    match latest() {
+        Some(release) => {
+            println!(
+                "{}",
+                format!("--- Sandesh {} ---", release.version).yellow()
+            );
+            println!("{}", release.notes.trim());
+        }
+        None => println!("No newer release known. You are on {}.", CURRENT_VERSION),
+    }
+}
+
+fn fetch_latest(url: &str) -> Option<Release> {
+    let agent = ureq::AgentBuilder::new().timeout(FETCH_TIMEOUT).build();
+    // Only the version goes out; no other headers identify the user.
+    let doc: serde_json::Value = agent
+        .get(url)
+        .set("User-Agent", &format!("sandesh/{}", CURRENT_VERSION))
+        .call()
+        .ok()?
+        .into_json()
+        .ok()?;
+
+    let version = doc["tag_name"]
+        .as_str()?
+        .trim_start_matches('v')
+        .to_string();
+    let notes = doc["body"].as_str().unwrap_or_default().replace('\r', "");
+    Some(Release { version, notes })
+}
+
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let core = version.split(['-', '+']).next()?;
+    let mut parts = core.split('.').map(|p| p.parse::<u64>().ok());
+    let major = parts.next()??;
+    let minor = parts.next().flatten().unwrap_or(0);
+    let patch = parts.next().flatten().unwrap_or(0);
+    Some((major, minor, patch))
+}
+
+fn is_newer(latest: &str, current: &str) -> bool {
+    match (parse_version(latest), parse_version(current)) {
+        (Some(latest), Some(current)) => latest > current,
+        _ => false,
+    }
+}
@@ -0,0 +1,358 @@
+//! Encryption at rest for the identity key, the known-peers file, the
+//! outbox and the heads of chained transcripts. The
+//! user's passphrase goes through Argon2id into a key that seals each file
+//! with ChaCha20-Poly1305. The salt, the cost settings and a check value
+//! that tells a wrong passphrase apart are kept in `~/.sandesh/vault`,
+//! which can instead say `encrypt off` to keep the files in plaintext.
+
+use crate::argon2::{self, Params};
+use crate::identity::{decode_hex, encode_hex};
+use crate::storage;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::OnceLock;
+use zeroize::Zeroizing;
+
+const VAULT_FILE: &str = "vault";
+const SEALED_PREFIX: &str = "sandesh-sealed-v1 ";
+const CHECK_LABEL: &[u8] = b"sandesh vault check";
+const HISTORY_KEY_INFO: &[u8] = b"sandesh history chain";
+
+/// Around OWASP's recommended minimum for Argon2id. Existing vaults keep
+/// the settings they were created with.
+const PARAMS: Params = Params {
+    memory: 19 * 1024,
+    passes: 2,
+    lanes: 1,
+};
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+static KEY: OnceLock<Zeroizing<[u8; 32]>> = OnceLock::new();
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Status {
+    /// No choice made yet: the first run, or a data directory from before
+    /// encryption existed.
+    Unset,
+    /// `encrypt off`: files are kept in plaintext.
+    Plaintext,
+    /// Files are sealed; `unlock` has to be called before they are read.
+    Encrypted,
+}
+
+struct Header {
+    params: Params,
+    salt: Vec<u8>,
+    check: Vec<u8>,
+}
+
+pub fn status() -> io::Result<Status> {
+    Ok(match read_header()? {
+        None => Status::Unset,
+        Some(None) => Status::Plaintext,
+        Some(Some(_)) => Status::Encrypted,
+    })
+}
+
+/// Derives the key from `passphrase` and keeps it for this run. A wrong
+/// passphrase fails with `PermissionDenied` and leaves every file as it
+/// was.
+pub fn unlock(passphrase: &str) -> io::Result<()> {
+    let Some(Some(header)) = read_header()? else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "the keystore is not encrypted",
+        ));
+    };
+    let _ = KEY.set(header.key_for(passphrase)?);
+    Ok(())
+}
+
+/// Records the first-run choice: with a passphrase, files are sealed from
+/// now on, existing plaintext ones the next time they are read; without
+/// one they stay in plaintext and the user isn't asked again.
+pub fn set_up(passphrase: Option<&str>) -> io::Result<()> {
+    let path = vault_path()?;
+    let Some(passphrase) = passphrase else {
+        return storage::write_atomic(&path, "encrypt off\n");
+    };
+    let (header, key) = Header::new(passphrase, PARAMS)?;
+    storage::write_private(&path, &header.render())?;
+    let _ = KEY.set(key);
+    Ok(())
+}
+
+/// The key transcripts are chained under, kept apart from the sealing key
+/// by HKDF; see `transcript::Integrity`. `None` unless the vault is
+/// unlocked.
+pub fn history_key() -> Option<Zeroizing<[u8; 32]>> {
+    let key = KEY.get()?;
+    let mut history = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(None, key.as_slice())
+        .expand(HISTORY_KEY_INFO, history.as_mut())
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    Some(history)
+}
+
+/// Reads a file that may be sealed. A plaintext one read while the vault
+/// is unlocked was written before encryption was turned on, and is sealed
+/// in place.
+pub fn read(path: &Path) -> io::Result<String> {
+    read_with(KEY.get(), path)
+}
+
+fn read_with(key: Option<&Zeroizing<[u8; 32]>>, path: &Path) -> io::Result<String> {
+    let contents = fs::read_to_string(path)?;
+    let Some(sealed) = contents.strip_prefix(SEALED_PREFIX) else {
+        if key.is_some() {
+            write_with(key, path, &contents)?;
+        }
+        return Ok(contents);
+    };
+    let key = key.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is encrypted and the keystore is locked", path.display()),
+        )
+    })?;
+    decode_hex(sealed.trim())
+        .and_then(|sealed| open(key, &file_label(path), &sealed))
+        .and_then(|plaintext| String::from_utf8(plaintext).ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is damaged or was not sealed by this keystore",
+                    path.display()
+                ),
+            )
+        })
+}
+
+/// Writes a file readable by the owner only, sealed if the vault is
+/// unlocked.
+pub fn write(path: &Path, contents: &str) -> io::Result<()> {
+    write_with(KEY.get(), path, contents)
+}
+
+fn write_with(key: Option<&Zeroizing<[u8; 32]>>, path: &Path, contents: &str) -> io::Result<()> {
+    match key {
+        Some(key) => {
+            let sealed = seal(key, &file_label(path), contents.as_bytes())?;
+            storage::write_private(path, &format!("{}{}\n", SEALED_PREFIX, encode_hex(&sealed)))
+        }
+        None => storage::write_private(path, contents),
+    }
+}
+
+fn vault_path() -> io::Result<std::path::PathBuf> {
+    storage::data_file(VAULT_FILE).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "no home directory to store the keystore in",
+        )
+    })
+}
+
+/// `None` if there is no vault file, `Some(None)` for `encrypt off`.
+fn read_header() -> io::Result<Option<Option<Header>>> {
+    let contents = match fs::read_to_string(vault_path()?) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Header::parse(&contents).map(Some)
+}
+
+impl Header {
+    /// A fresh salt and check value for `passphrase`, with the key they
+    /// lead to.
+    fn new(passphrase: &str, params: Params) -> io::Result<(Header, Zeroizing<[u8; 32]>)> {
+        let mut salt = vec![0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let key = derive_key(passphrase, &salt, params);
+        let check = seal(&key, VAULT_FILE, CHECK_LABEL)?;
+        Ok((
+            Header {
+                params,
+                salt,
+                check,
+            },
+            key,
+        ))
+    }
+
+    fn render(&self) -> String {
+        format!(
+            "encrypt argon2id {} {} {} {} {}\n",
+            self.params.memory,
+            self.params.passes,
+            self.params.lanes,
+            encode_hex(&self.salt),
+            encode_hex(&self.check)
+        )
+    }
+
+    /// `None` for `encrypt off`.
+    fn parse(contents: &str) -> io::Result<Option<Header>> {
+        let fields: Vec<&str> = contents.split_whitespace().collect();
+        Ok(match fields.as_slice() {
+            ["encrypt", "off"] => None,
+            ["encrypt", "argon2id", memory, passes, lanes, salt, check] => {
+                let params = Params {
+                    memory: memory.parse().map_err(|_| malformed())?,
+                    passes: passes.parse().map_err(|_| malformed())?,
+                    lanes: lanes.parse().map_err(|_| malformed())?,
+                };
+                Some(Header {
+                    params,
+                    salt: decode_hex(salt).ok_or_else(malformed)?,
+                    check: decode_hex(check).ok_or_else(malformed)?,
+                })
+            }
+            _ => return Err(malformed()),
+        })
+    }
+
+    /// The key `passphrase` leads to, or `PermissionDenied` if it doesn't
+    /// open the check value.
+    fn key_for(&self, passphrase: &str) -> io::Result<Zeroizing<[u8; 32]>> {
+        let key = derive_key(passphrase, &self.salt, self.params);
+        open(&key, VAULT_FILE, &self.check)
+            .filter(|check| check.as_slice() == CHECK_LABEL)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "Wrong passphrase"))?;
+        Ok(key)
+    }
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "~/.sandesh/vault is not a valid keystore header",
+    )
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], params: Params) -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    argon2::hash(passphrase.as_bytes(), salt, params, key.as_mut());
+    key
+}
+
+/// Each file is bound to its name, so sealed files can't be swapped.
+fn file_label(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// `nonce || ciphertext`, under a fresh random nonce.
+fn seal(key: &[u8; 32], label: &str, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: label.as_bytes(),
+            },
+        )
+        .map_err(|_| io::Error::other("Encryption failed"))?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn open(key: &[u8; 32], label: &str, sealed: &[u8]) -> Option<Vec<u8>> {
+    let (nonce, ciphertext) = sealed.split_at_checked(NONCE_LEN)?;
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: label.as_bytes(),
+            },
+        )
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+
+    /// Cheap settings, so the tests don't spend seconds in Argon2.
+    const FAST: Params = Params {
+        memory: 64,
+        passes: 1,
+        lanes: 1,
+    };
+
+    #[test]
+    fn header_round_trips_and_checks_the_passphrase() {
+        let (header, key) = Header::new("correct horse", FAST).unwrap();
+        let parsed = Header::parse(&header.render()).unwrap().unwrap();
+        assert_eq!(parsed.params, FAST);
+        assert_eq!(*parsed.key_for("correct horse").unwrap(), *key);
+        let wrong = parsed.key_for("correct horsf").unwrap_err();
+        assert_eq!(wrong.kind(), io::ErrorKind::PermissionDenied);
+        assert!(Header::parse("encrypt off\n").unwrap().is_none());
+        assert!(Header::parse("encrypt argon2id 64 1").is_err());
+    }
+
+    #[test]
+    fn sealed_files_open_only_under_their_key() {
+        let dir = scratch_dir("vault-seal");
+        let path = dir.join("known_peers");
+        let (_, key) = Header::new("one", FAST).unwrap();
+        let (_, other) = Header::new("two", FAST).unwrap();
+
+        write_with(Some(&key), &path, "pins\n").unwrap();
+        let on_disk = fs::read_to_string(&path).unwrap();
+        assert!(on_disk.starts_with(SEALED_PREFIX));
+        assert!(!on_disk.contains("pins"));
+        assert_eq!(read_with(Some(&key), &path).unwrap(), "pins\n");
+
+        let locked = read_with(None, &path).unwrap_err();
+        assert_eq!(locked.kind(), io::ErrorKind::PermissionDenied);
+        let wrong = read_with(Some(&other), &path).unwrap_err();
+        assert_eq!(wrong.kind(), io::ErrorKind::InvalidData);
+        // Nothing was overwritten by the failed reads.
+        assert_eq!(fs::read_to_string(&path).unwrap(), on_disk);
+
+        // Sealed under one file's name, it won't open as another's.
+        let moved = dir.join("outbox");
+        fs::rename(&path, &moved).unwrap();
+        assert!(read_with(Some(&key), &moved).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn plaintext_files_are_sealed_when_first_read_unlocked() {
+        let dir = scratch_dir("vault-migrate");
+        let path = dir.join("identity");
+        fs::write(&path, "old plaintext\n").unwrap();
+        let (_, key) = Header::new("passphrase", FAST).unwrap();
+
+        assert_eq!(read_with(None, &path).unwrap(), "old plaintext\n");
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "old plaintext\n",
+            "a locked read leaves the file as it was"
+        );
+        assert_eq!(read_with(Some(&key), &path).unwrap(), "old plaintext\n");
+        assert!(
+            fs::read_to_string(&path)
+                .unwrap()
+                .starts_with(SEALED_PREFIX)
+        );
+        assert_eq!(read_with(Some(&key), &path).unwrap(), "old plaintext\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
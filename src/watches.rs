@@ -0,0 +1,323 @@
+use crate::identity;
+use crate::protocol;
+use crate::state::{PeerInfo, PeerMap};
+use crate::storage;
+use crate::tags::{self, TagBook};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const WATCHES_FILE: &str = "watches";
+const PRESENCE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WatchTarget {
+    Ip(IpAddr),
+    /// Any peer carrying this tag, written `#tag`.
+    Tag(String),
+    /// The peer whose beacons are signed with this key, at any address.
+    Fingerprint(String),
+    /// Any peer announcing this nickname, ignoring case.
+    Nickname(String),
+}
+
+impl WatchTarget {
+    pub fn matches(&self, ip: IpAddr, info: &PeerInfo, tag_book: &TagBook) -> bool {
+        match self {
+            WatchTarget::Ip(watched) => *watched == ip,
+            WatchTarget::Tag(tag) => tag_book.has_tag(ip, tag),
+            WatchTarget::Fingerprint(fingerprint) => info
+                .identity
+                .as_ref()
+                .is_some_and(|key| identity::fingerprint(key) == *fingerprint),
+            WatchTarget::Nickname(name) => info
+                .nickname
+                .as_ref()
+                .is_some_and(|n| n.to_lowercase() == name.to_lowercase()),
+        }
+    }
+
+    /// Parses `#tag`, a bare IP, a fingerprint, or else a nickname. A
+    /// nickname with spaces can't be told apart from the rest of the line.
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        if let Some(tag) = arg.strip_prefix('#') {
+            tags::validate_tag(tag).map_err(|e| format!("Invalid tag '{}': {}", tag, e))?;
+            return Ok(WatchTarget::Tag(tag.to_string()));
+        }
+        if let Ok(ip) = arg.parse() {
+            return Ok(WatchTarget::Ip(ip));
+        }
+        if let Some(fingerprint) = identity::normalize_fingerprint(arg) {
+            return Ok(WatchTarget::Fingerprint(fingerprint));
+        }
+        if protocol::valid_nickname(arg) && !arg.contains(char::is_whitespace) {
+            return Ok(WatchTarget::Nickname(arg.to_string()));
+        }
+        Err(format!(
+            "Not an IP address, fingerprint, nickname or #tag: {}",
+            arg
+        ))
+    }
+}
+
+impl fmt::Display for WatchTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchTarget::Ip(ip) => write!(f, "{}", ip),
+            WatchTarget::Tag(tag) => write!(f, "#{}", tag),
+            WatchTarget::Fingerprint(text) | WatchTarget::Nickname(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+pub struct Watch {
+    pub target: WatchTarget,
+    /// Dial the peer as soon as it shows up.
+    pub then_connect: bool,
+}
+
+/// Peers to alert on when they come online, persisted to
+/// `~/.sandesh/watches` as one `<ip|#tag|fingerprint|name> [connect]`
+/// line per watch.
+pub struct WatchList {
+    watches: Vec<Watch>,
+    path: Option<PathBuf>,
+}
+
+impl WatchList {
+    /// Loads the watch file. A missing or unreadable file gives an empty
+    /// list; malformed lines are skipped.
+    pub fn load() -> Self {
+        let path = storage::data_file(WATCHES_FILE);
+        let mut watches = Vec::new();
+
+        if let Some(contents) = path.as_ref().and_then(|p| fs::read_to_string(p).ok()) {
+            for line in contents.lines() {
+                let mut fields = line.split_whitespace();
+                let Some(Ok(target)) = fields.next().map(WatchTarget::parse) else {
+                    continue;
+                };
+                watches.push(Watch {
+                    target,
+                    then_connect: fields.next() == Some("connect"),
+                });
+            }
+        }
+
+        WatchList { watches, path }
+    }
+
+    /// Adds a watch, or updates the action of an existing one.
+    pub fn add(&mut self, target: WatchTarget, then_connect: bool) -> io::Result<()> {
+        match self.watches.iter_mut().find(|w| w.target == target) {
+            Some(existing) => existing.then_connect = then_connect,
+            None => self.watches.push(Watch {
+                target,
+                then_connect,
+            }),
+        }
+        self.save()
+    }
+
+    /// Returns false if there was no such watch.
+    pub fn remove(&mut self, target: &WatchTarget) -> io::Result<bool> {
+        let before = self.watches.len();
+        self.watches.retain(|w| w.target != *target);
+        if self.watches.len() == before {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Watch> {
+        self.watches.iter()
+    }
+
+    /// The first watch that matches the peer `info` at `ip`.
+    pub fn matching(&self, ip: IpAddr, info: &PeerInfo, tag_book: &TagBook) -> Option<&Watch> {
+        self.watches
+            .iter()
+            .find(|w| w.target.matches(ip, info, tag_book))
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no home directory to store watches in",
+            ));
+        };
+        let mut contents = String::new();
+        for watch in &self.watches {
+            contents.push_str(&watch.target.to_string());
+            if watch.then_connect {
+                contents.push_str(" connect");
+            }
+            contents.push('\n');
+        }
+        storage::write_atomic(path, &contents)
+    }
+}
+
+/// Notices peers coming online by comparing the peer map against the
+/// previous look. Tracks IPs so a peer re-announcing from another port
+/// doesn't count as new, while one that timed out and came back does.
+pub struct PresenceTracker {
+    online: HashSet<IpAddr>,
+    last_check: Option<Instant>,
+}
+
+impl Default for PresenceTracker {
+    fn default() -> Self {
+        PresenceTracker::new()
+    }
+}
+
+impl PresenceTracker {
+    pub fn new() -> Self {
+        PresenceTracker {
+            online: HashSet::new(),
+            last_check: None,
+        }
+    }
+
+    /// Peers whose IP was not online at the previous check. Re-reads the
+    /// peer map at most once per interval.
+    pub fn arrivals(&mut self, peers: &PeerMap) -> Vec<SocketAddr> {
+        self.arrivals_at(peers, Instant::now())
+    }
+
+    fn arrivals_at(&mut self, peers: &PeerMap, now: Instant) -> Vec<SocketAddr> {
+        if self
+            .last_check
+            .is_some_and(|t| now.duration_since(t) < PRESENCE_CHECK_INTERVAL)
+        {
+            return Vec::new();
+        }
+        self.last_check = Some(now);
+
+        let current: Vec<SocketAddr> = peers.read().unwrap().keys().copied().collect();
+        let mut arrivals: Vec<SocketAddr> = current
+            .iter()
+            .filter(|addr| !self.online.contains(&addr.ip()))
+            .copied()
+            .collect();
+        arrivals.sort();
+        arrivals.dedup_by_key(|addr| addr.ip());
+        self.online = current.iter().map(|addr| addr.ip()).collect();
+        arrivals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+
+    fn watching(targets: &[&str]) -> WatchList {
+        WatchList {
+            watches: targets
+                .iter()
+                .map(|t| Watch {
+                    target: WatchTarget::parse(t).unwrap(),
+                    then_connect: false,
+                })
+                .collect(),
+            path: None,
+        }
+    }
+
+    fn peer(key: &SigningKey, nickname: &str) -> PeerInfo {
+        PeerInfo {
+            identity: Some(key.verifying_key()),
+            nickname: Some(nickname.to_string()),
+            ..PeerInfo::legacy(Instant::now())
+        }
+    }
+
+    #[test]
+    fn watches_match_by_nickname_fingerprint_and_ip() {
+        let tag_book = TagBook::empty();
+        let key = SigningKey::generate(&mut OsRng);
+        let riya = peer(&key, "Riya");
+        let ip: IpAddr = "192.168.1.34".parse().unwrap();
+        let elsewhere: IpAddr = "10.0.0.8".parse().unwrap();
+        let stranger = peer(&SigningKey::generate(&mut OsRng), "sam");
+
+        let by_name = watching(&["riya"]);
+        assert!(by_name.matching(elsewhere, &riya, &tag_book).is_some());
+        assert!(by_name.matching(ip, &stranger, &tag_book).is_none());
+
+        let fingerprint = identity::fingerprint(&key.verifying_key());
+        // With `:` separators it would read as an IPv6 address.
+        let typed = fingerprint.to_uppercase();
+        let by_key = watching(&[&typed]);
+        assert_eq!(
+            by_key.iter().next().unwrap().target,
+            WatchTarget::Fingerprint(fingerprint)
+        );
+        assert!(by_key.matching(elsewhere, &riya, &tag_book).is_some());
+        assert!(by_key.matching(ip, &stranger, &tag_book).is_none());
+        let renamed = PeerInfo {
+            nickname: Some("laptop".to_string()),
+            ..riya.clone()
+        };
+        assert!(by_key.matching(ip, &renamed, &tag_book).is_some());
+
+        let by_ip = watching(&["192.168.1.34"]);
+        assert!(by_ip.matching(ip, &stranger, &tag_book).is_some());
+        assert!(by_ip.matching(elsewhere, &riya, &tag_book).is_none());
+
+        assert!(WatchTarget::parse("two words").is_err());
+    }
+
+    #[test]
+    fn a_watch_fires_once_and_again_at_a_new_address() {
+        let tag_book = TagBook::empty();
+        let watches = watching(&["riya"]);
+        let riya = peer(&SigningKey::generate(&mut OsRng), "riya");
+        let first: SocketAddr = "192.168.1.34:3000".parse().unwrap();
+        let moved: SocketAddr = "192.168.1.77:3000".parse().unwrap();
+        let peers: PeerMap = Arc::new(RwLock::new(HashMap::new()));
+        let mut presence = PresenceTracker::new();
+        let start = Instant::now();
+        let mut alerts = |secs: u64| -> Vec<SocketAddr> {
+            let now = start + PRESENCE_CHECK_INTERVAL * secs as u32;
+            let arrivals = presence.arrivals_at(&peers, now);
+            let peers = peers.read().unwrap();
+            arrivals
+                .into_iter()
+                .filter(|addr| {
+                    watches
+                        .matching(addr.ip(), &peers[addr], &tag_book)
+                        .is_some()
+                })
+                .collect()
+        };
+
+        assert!(alerts(0).is_empty());
+        peers.write().unwrap().insert(first, riya.clone());
+        assert_eq!(alerts(1), [first]);
+        assert!(alerts(2).is_empty(), "still online");
+        assert!(alerts(3).is_empty());
+
+        // Beacons now come from a new address; the old entry times out.
+        peers.write().unwrap().insert(moved, riya.clone());
+        assert_eq!(alerts(4), [moved]);
+        peers.write().unwrap().remove(&first);
+        assert!(alerts(5).is_empty());
+
+        peers.write().unwrap().clear();
+        assert!(alerts(6).is_empty());
+        peers.write().unwrap().insert(moved, riya);
+        assert_eq!(alerts(7), [moved], "back after going offline");
+    }
+}
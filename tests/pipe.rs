@@ -0,0 +1,115 @@
+//! `sandesh push` and `sandesh pull` run as separate processes, talking
+//! over loopback.
+
+use ed25519_dalek::SigningKey;
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A home directory whose identity key is made from `seed`, returning its
+/// fingerprint.
+fn home_with_identity(home: &Path, seed: u8) -> String {
+    let key = SigningKey::from_bytes(&[seed; 32]);
+    let hex: String = key
+        .to_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    fs::create_dir_all(home.join(".sandesh")).unwrap();
+    fs::write(home.join(".sandesh/identity"), hex).unwrap();
+    sandesh::identity::fingerprint(&key.verifying_key())
+}
+
+fn sandesh(home: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_sandesh"));
+    command
+        .args(args)
+        .env("HOME", home)
+        .env_remove("SANDESH_PASSPHRASE");
+    command
+}
+
+/// Pushes until the pull side is listening, or gives up after a while.
+fn push(home: &Path, addr: &str, file: &Path) -> Output {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let output = sandesh(home, &["push", addr, file.to_str().unwrap()])
+            .output()
+            .unwrap();
+        let refused = String::from_utf8_lossy(&output.stderr).contains("Cannot connect");
+        if !refused || Instant::now() > deadline {
+            return output;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn pushes_a_file_between_processes() {
+    let base: PathBuf = std::env::temp_dir().join(format!("sandesh-pipe-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&base);
+    let sender = home_with_identity(&base.join("sender"), 7);
+    home_with_identity(&base.join("stranger"), 9);
+    let out = base.join("out");
+    let file = base.join("data.bin");
+    // More than one chunk, and not a whole number of them.
+    let mut seed = 1u32;
+    let data: Vec<u8> = (0..200_000)
+        .map(|_| {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (seed >> 24) as u8
+        })
+        .collect();
+    fs::write(&file, &data).unwrap();
+
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut pull = sandesh(
+        &base.join("receiver"),
+        &[
+            "pull",
+            "--accept-from",
+            &sender,
+            "--out",
+            out.to_str().unwrap(),
+            "--port",
+            &port.to_string(),
+        ],
+    )
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .unwrap();
+    let addr = format!("127.0.0.1:{}", port);
+
+    // Another key is turned away, and the pull goes on waiting.
+    let stranger = push(&base.join("stranger"), &addr, &file);
+    assert_eq!(stranger.status.code(), Some(1));
+
+    let pushed = push(&base.join("sender"), &addr, &file);
+    if !pushed.status.success() {
+        let _ = pull.kill();
+    }
+    assert!(
+        pushed.status.success(),
+        "push failed: {}",
+        String::from_utf8_lossy(&pushed.stderr)
+    );
+    let pulled = pull.wait_with_output().unwrap();
+    assert!(
+        pulled.status.success(),
+        "pull failed: {}",
+        String::from_utf8_lossy(&pulled.stderr)
+    );
+    let saved = String::from_utf8(pulled.stdout).unwrap();
+    assert_eq!(Path::new(saved.trim()), out.join("data.bin"));
+    assert_eq!(fs::read(saved.trim()).unwrap(), data);
+
+    let _ = fs::remove_dir_all(&base);
+}